@@ -0,0 +1,68 @@
+;; exception handling proposal, `wat2wasm --enable-exceptions exceptions.wat` to create `exceptions.wasm`
+(module
+  (type $t (func (param i32) (result i32)))
+  (type $t_zero (func (param i32)))
+  (type $t_other (func (param i32 i32)))
+  (func $check (type $t) (param $n i32) (result i32)
+    local.get $n
+    i32.const 0
+    i32.eq
+    if $I0
+      i32.const 42
+      throw $zero
+    end
+    local.get $n
+    return)
+  ;; n + 1, or 142 if check throws
+  (func $safe (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      call $check
+      i32.const 1
+      i32.add
+    catch $zero
+      i32.const 100
+      i32.add
+    end
+    return)
+  ;; n, or 7 if check throws: inner handler doesn't match and throws it further
+  (func $outer (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      call $other
+    catch_all
+      i32.const 7
+    end
+    return)
+  (func $other (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      call $check
+    catch $other
+      i32.add
+    end
+    return)
+;; n + (n + 1), or 44 if check throws: param written in try is seen by both handler and code after try
+  (func $count (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      i32.const 1
+      i32.add
+      local.set $n
+      local.get $n
+      i32.const 1
+      i32.sub
+      call $check
+    catch $zero
+      local.get $n
+      i32.add
+    end
+    local.get $n
+    i32.add
+    return)
+  (tag $zero (type $t_zero))
+  (tag $other (type $t_other))
+  (export "safe" (func $safe))
+  (export "outer" (func $outer))
+  (export "count" (func $count))
+)
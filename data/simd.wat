@@ -0,0 +1,84 @@
+;; vector instructions, `wat2wasm simd.wat` to create `simd.wasm`
+(module
+  (type $t0 (func (param v128 v128 v128) (result v128)))
+  (type $t1 (func (param v128) (result i32)))
+  (type $t2 (func (param v128) (result v128)))
+  (type $t3 (func (param v128 i32) (result i32)))
+  (type $t4 (func (param v128 v128) (result v128)))
+  (type $t5 (func (param v128 i64) (result v128)))
+  ;; a * b + c
+  (func $madd (type $t0) (param $a v128) (param $b v128) (param $c v128) (result v128)
+    local.get $a
+    local.get $b
+    i32x4.mul
+    local.get $c
+    i32x4.add)
+  (func $hsum (type $t1) (param $v v128) (result i32)
+    local.get $v
+    i32x4.extract_lane 0
+    local.get $v
+    i32x4.extract_lane 1
+    i32.add
+    local.get $v
+    i32x4.extract_lane 2
+    i32.add
+    local.get $v
+    i32x4.extract_lane 3
+    i32.add)
+  (func $reverse (type $t2) (param $v v128) (result v128)
+    local.get $v
+    local.get $v
+    i8x16.shuffle 15 14 13 12 11 10 9 8 7 6 5 4 3 2 1 0)
+  ;; number of lanes greater than $x, lanes of mask are -1
+  (func $count_greater (type $t3) (param $v v128) (param $x i32) (result i32)
+    i32.const 0
+    local.get $v
+    local.get $x
+    i32x4.splat
+    i32x4.gt_s
+    call $hsum
+    i32.sub)
+  ;; a * b + 1.5
+  (func $fmadd (type $t4) (param $a v128) (param $b v128) (result v128)
+    local.get $a
+    local.get $b
+    f32x4.mul
+    v128.const f32x4 1.5 1.5 1.5 1.5
+    f32x4.add)
+  (func $set_high (type $t5) (param $v v128) (param $x i64) (result v128)
+    local.get $v
+    local.get $x
+    i64x2.replace_lane 1)
+  ;; last byte zero and sign extended
+  (func $last_byte (type $t1) (param $v v128) (result i32)
+    local.get $v
+    i8x16.extract_lane_u 15
+    local.get $v
+    i8x16.extract_lane_s 15
+    i32.add)
+  (func $ne16 (type $t4) (param $a v128) (param $b v128) (result v128)
+    local.get $a
+    local.get $b
+    i16x8.ne)
+  (func $lt64 (type $t4) (param $a v128) (param $b v128) (result v128)
+    local.get $a
+    local.get $b
+    f64x2.lt)
+  ;; a * b - a with 64 bit multiplication and byte subtraction
+  (func $mul_sub (type $t4) (param $a v128) (param $b v128) (result v128)
+    local.get $a
+    local.get $b
+    i64x2.mul
+    local.get $a
+    i8x16.sub)
+  (export "madd" (func $madd))
+  (export "hsum" (func $hsum))
+  (export "reverse" (func $reverse))
+  (export "count_greater" (func $count_greater))
+  (export "fmadd" (func $fmadd))
+  (export "set_high" (func $set_high))
+  (export "last_byte" (func $last_byte))
+  (export "ne16" (func $ne16))
+  (export "lt64" (func $lt64))
+  (export "mul_sub" (func $mul_sub))
+)
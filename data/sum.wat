@@ -0,0 +1,20 @@
+;; tail recursive sum of 1..n, `wat2wasm --enable-tail-call sum.wat` to create `sum.wasm`
+(module
+  (type $t (func (param i32 i32) (result i32)))
+  (func $sum (type $t) (param $n i32) (param $acc i32) (result i32)
+    local.get $n
+    i32.const 0
+    i32.eq
+    if $I0
+      local.get $acc
+      return
+    end
+    local.get $n
+    i32.const 1
+    i32.sub
+    local.get $acc
+    local.get $n
+    i32.add
+    return_call $sum)
+  (export "sum" (func $sum))
+)
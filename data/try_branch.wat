@@ -0,0 +1,12 @@
+;; rejected by parser, try body runs in a frame of its own
+(module
+  (type $t (func (param i32) (result i32)))
+  (func $leave (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      br 0
+    catch_all
+      i32.const 0
+    end)
+  (export "leave" (func $leave))
+)
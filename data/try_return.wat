@@ -0,0 +1,12 @@
+;; rejected by parser, try body runs in a frame of its own
+(module
+  (type $t (func (param i32) (result i32)))
+  (func $early (type $t) (param $n i32) (result i32)
+    try (result i32)
+      local.get $n
+      return
+    catch_all
+      i32.const 0
+    end)
+  (export "early" (func $early))
+)
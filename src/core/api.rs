@@ -5,12 +5,45 @@ pub trait Node: Hash + Eq + Sized + Debug + Clone {
     fn get(&self) -> NodeKind<Self>;
 }
 
+// Node which identity is stable between processes: equal nodes have equal keys and vice versa.
+// Used to persist driver state, see `Driver::save`.
+pub trait PersistentNode: Node {
+    fn key(&self) -> Vec<u8>;
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum NodeKind<N> {
     Command { command: Command, next: N },
     Branch { condition: Condition, if_true: N, if_false: N },
     Call { offset: u32, call: N, next: N },
     Final,
+    // Suspends innermost running coroutine, its resumer goes on (see `Resume`). Outside of coroutines suspends
+    // whole evaluation with its frames, host continues it from `next` later (see `Stop::Yield`).
+    // Evaluation which can't be suspended (like `interpreter::eval`) just goes on to `next`.
+    Yield { next: N },
+    // Makes coroutine which runs `call` with frame at `Stack(offset)` once resumed, puts its 4 byte handle at `handle`
+    // and goes on to `next`. Coroutine's frame should be kept intact until it's finished. Coroutines belong to the run
+    // which made them, unfinished ones are dropped with it. Evaluation which can't be suspended panics.
+    Coroutine { offset: u32, call: N, handle: Ref, next: N },
+    // Runs suspended coroutine `handle` until it yields or returns, then goes on to `next`. Coroutines might resume
+    // each other, but not the ones which are running already. Frame of coroutine can't be below the resumer's one.
+    // Run is aborted on resume of coroutine which can't be resumed, see `Fault::Resume`.
+    Resume { handle: Ref, next: N },
+    // Call which result is result of current frame, callee takes place of current frame instead of adding one,
+    // so tail recursion runs in constant memory (apart from data stack if `offset` isn't 0).
+    TailCall { offset: u32, call: N },
+    // Call with exception handler: if callee (or anything it calls) throws, its frames are dropped,
+    // payload is put at `Stack(offset)`, where callee's frame would start, and evaluation goes on to `catch`.
+    Try { offset: u32, call: N, next: N, catch: N },
+    // Throws `size` bytes at `op` as payload to the nearest `Try`, driver returns it as `Stop::Uncaught` if there is none.
+    Throw { op: Ref, size: u32 },
+    // Call which result (first `results` bytes of callee frame) depends on first `args` bytes of callee frame only,
+    // so it might be skipped if it was made with the same arguments before (see `Driver::set_memoization`).
+    // Caller shouldn't rely on the rest of callee frame afterwards. Evaluation without memoization treats it as `Call`.
+    PureCall { offset: u32, call: N, next: N, args: u32, results: u32 },
+    // Suspends whole evaluation like `Yield` for host to run its `function` on frame at `Stack(offset)`,
+    // evaluation goes on to `next` once host continues it (see `Stop::HostCall`). Evaluation which can't be suspended ends on it.
+    HostCall { function: u32, offset: u32, next: N },
 
     // More complicated version of Call where next node depends on returned value ctx, final => next
     // CallDynamic { offset: u32, call: N, next: fn(N, N) -> N },
@@ -22,6 +55,8 @@ pub enum NodeKind<N> {
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum Ref {
     Stack(u32),
+    // offset in global data area of the driver (see `Globals`), same for every frame
+    Global(u32),
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
@@ -42,6 +77,144 @@ pub enum Command {
 
     Add { size: u32, dst: Ref, op1: Ref, op2: Ref },
     Sub { size: u32, dst: Ref, op1: Ref, op2: Ref },
+
+    // Sequentially consistent operations on `size` (4 or 8) bytes at `mem`.
+    // Globals are the only memory shared between threads, global `mem` should be aligned by `size`.
+    // Driver aborts runs reaching atomics of other sizes or unaligned ones with `Fault::Unsupported`.
+    // Stack isn't shared, so atomics on it are plain accesses.
+    AtomicLoad { size: u32, dst: Ref, mem: Ref },
+    AtomicStore { size: u32, mem: Ref, op: Ref },
+    // `dst` gets previous value at `mem`, which is replaced by `op` applied to it and `value`
+    AtomicRmw { size: u32, op: RmwOp, dst: Ref, mem: Ref, value: Ref },
+    // value at `mem` is replaced with `replacement` if it equals `expected`, `expected` gets previous value either way
+    AtomicCmpXchg { size: u32, mem: Ref, expected: Ref, replacement: Ref },
+    Fence,
+
+    // 16 byte vectors of little endian lanes, `shape` gives their type and count.
+    // Comparisons set lanes where they hold to all ones and the rest to zeros.
+    VecBinary { op: VecOp, shape: VecShape, dst: Ref, op1: Ref, op2: Ref },
+    // every lane gets lane sized value at `op`
+    VecSplat { shape: VecShape, dst: Ref, op: Ref },
+    // 8 and 16 bit lanes are extended to 4 bytes, with sign if `signed`, the rest are copied as is
+    VecExtractLane { shape: VecShape, signed: bool, lane: u8, dst: Ref, op: Ref },
+    // `dst` gets vector `op` with `lane` replaced by lane sized value at `value`
+    VecReplaceLane { shape: VecShape, lane: u8, dst: Ref, op: Ref, value: Ref },
+    // byte `i` of `dst` is byte `lanes[i]` of `op1` followed by `op2`
+    VecShuffle { lanes: [u8; 16], dst: Ref, op1: Ref, op2: Ref },
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum RmwOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    // replaces value
+    Xchg,
+}
+
+impl RmwOp {
+    pub fn apply(&self, previous: u64, value: u64) -> u64 {
+        match self {
+            RmwOp::Add => { previous.wrapping_add(value) }
+            RmwOp::Sub => { previous.wrapping_sub(value) }
+            RmwOp::And => { previous & value }
+            RmwOp::Or => { previous | value }
+            RmwOp::Xor => { previous ^ value }
+            RmwOp::Xchg => { value }
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum VecShape {
+    I8x16,
+    I16x8,
+    I32x4,
+    I64x2,
+    F32x4,
+    F64x2,
+}
+
+impl VecShape {
+    pub const ALL: [VecShape; 6] = [VecShape::I8x16, VecShape::I16x8, VecShape::I32x4, VecShape::I64x2, VecShape::F32x4, VecShape::F64x2];
+
+    pub fn lane_size(&self) -> u32 {
+        match self {
+            VecShape::I8x16 => { 1 }
+            VecShape::I16x8 => { 2 }
+            VecShape::I32x4 | VecShape::F32x4 => { 4 }
+            VecShape::I64x2 | VecShape::F64x2 => { 8 }
+        }
+    }
+
+    pub fn lanes(&self) -> u32 { 16 / self.lane_size() }
+
+    pub fn is_float(&self) -> bool { matches!(self, VecShape::F32x4 | VecShape::F64x2) }
+}
+
+// `Lt`, `Le`, `Gt` and `Ge` are signed for integer lanes, unsigned versions are for integer lanes only
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum VecOp {
+    Add,
+    Sub,
+    Mul,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LtU,
+    LeU,
+    GtU,
+    GeU,
+}
+
+impl VecOp {
+    pub const ALL: [VecOp; 13] = [
+        VecOp::Add, VecOp::Sub, VecOp::Mul, VecOp::Eq, VecOp::Ne,
+        VecOp::Lt, VecOp::Le, VecOp::Gt, VecOp::Ge, VecOp::LtU, VecOp::LeU, VecOp::GtU, VecOp::GeU,
+    ];
+
+    // lanes are given as their bits in low bytes, result has the same form
+    pub fn apply(&self, shape: VecShape, op1: u64, op2: u64) -> u64 {
+        let bits = shape.lane_size() * 8;
+        let mask = if bits == 64 { u64::MAX } else { (1 << bits) - 1 };
+        let result = match (self, shape) {
+            (VecOp::Add, VecShape::F32x4) => { (f32::from_bits(op1 as u32) + f32::from_bits(op2 as u32)).to_bits() as u64 }
+            (VecOp::Sub, VecShape::F32x4) => { (f32::from_bits(op1 as u32) - f32::from_bits(op2 as u32)).to_bits() as u64 }
+            (VecOp::Mul, VecShape::F32x4) => { (f32::from_bits(op1 as u32) * f32::from_bits(op2 as u32)).to_bits() as u64 }
+            (VecOp::Add, VecShape::F64x2) => { (f64::from_bits(op1) + f64::from_bits(op2)).to_bits() }
+            (VecOp::Sub, VecShape::F64x2) => { (f64::from_bits(op1) - f64::from_bits(op2)).to_bits() }
+            (VecOp::Mul, VecShape::F64x2) => { (f64::from_bits(op1) * f64::from_bits(op2)).to_bits() }
+            (VecOp::Add, _) => { op1.wrapping_add(op2) }
+            (VecOp::Sub, _) => { op1.wrapping_sub(op2) }
+            (VecOp::Mul, _) => { op1.wrapping_mul(op2) }
+            (op, _) => {
+                let ordering = match shape {
+                    VecShape::F32x4 => { f32::from_bits(op1 as u32).partial_cmp(&f32::from_bits(op2 as u32)) }
+                    VecShape::F64x2 => { f64::from_bits(op1).partial_cmp(&f64::from_bits(op2)) }
+                    _ if matches!(op, VecOp::LtU | VecOp::LeU | VecOp::GtU | VecOp::GeU) => { (op1 & mask).partial_cmp(&(op2 & mask)) }
+                    // sign extended from lane bits
+                    _ => { (((op1 << (64 - bits)) as i64) >> (64 - bits)).partial_cmp(&(((op2 << (64 - bits)) as i64) >> (64 - bits))) }
+                };
+                let holds = match op {
+                    VecOp::Eq => { ordering == Some(std::cmp::Ordering::Equal) }
+                    // unordered floats aren't equal
+                    VecOp::Ne => { ordering != Some(std::cmp::Ordering::Equal) }
+                    VecOp::Lt | VecOp::LtU => { ordering == Some(std::cmp::Ordering::Less) }
+                    VecOp::Le | VecOp::LeU => { matches!(ordering, Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)) }
+                    VecOp::Gt | VecOp::GtU => { ordering == Some(std::cmp::Ordering::Greater) }
+                    VecOp::Ge | VecOp::GeU => { matches!(ordering, Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)) }
+                    VecOp::Add | VecOp::Sub | VecOp::Mul => { unreachable!() }
+                };
+                if holds { u64::MAX } else { 0 }
+            }
+        };
+        result & mask
+    }
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -50,6 +223,89 @@ pub enum Condition {
     Ne0 { size: u32, op: Ref }, // if (op)
 }
 
+// `offset` methods give the same operation addressing stack `by` bytes further, i.e. as seen from the caller's frame
+
+impl Ref {
+    pub fn offset(self, by: u32) -> Ref {
+        match self {
+            Ref::Stack(offset) => { Ref::Stack(offset + by) }
+            Ref::Global(offset) => { Ref::Global(offset) }
+        }
+    }
+}
+
+impl Command {
+    pub fn offset(&self, by: u32) -> Command {
+        match self.clone() {
+            Command::Noop => { Command::Noop }
+            Command::PoisonFrom { dst } => { Command::PoisonFrom { dst: dst.offset(by) } }
+            Command::Set { dst, bytes } => { Command::Set { dst: dst.offset(by), bytes } }
+            Command::Copy { dst, size, op } => { Command::Copy { dst: dst.offset(by), size, op: op.offset(by) } }
+            Command::Add { size, dst, op1, op2 } => { Command::Add { size, dst: dst.offset(by), op1: op1.offset(by), op2: op2.offset(by) } }
+            Command::Sub { size, dst, op1, op2 } => { Command::Sub { size, dst: dst.offset(by), op1: op1.offset(by), op2: op2.offset(by) } }
+            Command::AtomicLoad { size, dst, mem } => { Command::AtomicLoad { size, dst: dst.offset(by), mem: mem.offset(by) } }
+            Command::AtomicStore { size, mem, op } => { Command::AtomicStore { size, mem: mem.offset(by), op: op.offset(by) } }
+            Command::AtomicRmw { size, op, dst, mem, value } => {
+                Command::AtomicRmw { size, op, dst: dst.offset(by), mem: mem.offset(by), value: value.offset(by) }
+            }
+            Command::AtomicCmpXchg { size, mem, expected, replacement } => {
+                Command::AtomicCmpXchg { size, mem: mem.offset(by), expected: expected.offset(by), replacement: replacement.offset(by) }
+            }
+            Command::Fence => { Command::Fence }
+            Command::VecBinary { op, shape, dst, op1, op2 } => {
+                Command::VecBinary { op, shape, dst: dst.offset(by), op1: op1.offset(by), op2: op2.offset(by) }
+            }
+            Command::VecSplat { shape, dst, op } => { Command::VecSplat { shape, dst: dst.offset(by), op: op.offset(by) } }
+            Command::VecExtractLane { shape, signed, lane, dst, op } => {
+                Command::VecExtractLane { shape, signed, lane, dst: dst.offset(by), op: op.offset(by) }
+            }
+            Command::VecReplaceLane { shape, lane, dst, op, value } => {
+                Command::VecReplaceLane { shape, lane, dst: dst.offset(by), op: op.offset(by), value: value.offset(by) }
+            }
+            Command::VecShuffle { lanes, dst, op1, op2 } => { Command::VecShuffle { lanes, dst: dst.offset(by), op1: op1.offset(by), op2: op2.offset(by) } }
+        }
+    }
+}
+
+impl Condition {
+    pub fn offset(&self, by: u32) -> Condition {
+        match self.clone() {
+            Condition::Ne { size, op1, op2 } => { Condition::Ne { size, op1: op1.offset(by), op2: op2.offset(by) } }
+            Condition::Ne0 { size, op } => { Condition::Ne0 { size, op: op.offset(by) } }
+        }
+    }
+}
+
+// `refs` methods give every `Ref` the operation accesses along with number of bytes accessed there
+
+impl Command {
+    pub fn refs(&self) -> Vec<(Ref, u32)> {
+        match self {
+            Command::Noop | Command::PoisonFrom { .. } | Command::Fence => { vec![] }
+            Command::Set { dst, bytes } => { vec![(*dst, bytes.len() as u32)] }
+            Command::Copy { dst, size, op } => { vec![(*dst, *size), (*op, *size)] }
+            Command::Add { size, dst, op1, op2 } | Command::Sub { size, dst, op1, op2 } => { vec![(*dst, *size), (*op1, *size), (*op2, *size)] }
+            Command::AtomicLoad { size, dst, mem } => { vec![(*dst, *size), (*mem, *size)] }
+            Command::AtomicStore { size, mem, op } => { vec![(*mem, *size), (*op, *size)] }
+            Command::AtomicRmw { size, dst, mem, value, .. } => { vec![(*dst, *size), (*mem, *size), (*value, *size)] }
+            Command::AtomicCmpXchg { size, mem, expected, replacement } => { vec![(*mem, *size), (*expected, *size), (*replacement, *size)] }
+            Command::VecBinary { dst, op1, op2, .. } | Command::VecShuffle { dst, op1, op2, .. } => { vec![(*dst, 16), (*op1, 16), (*op2, 16)] }
+            Command::VecSplat { shape, dst, op } => { vec![(*dst, 16), (*op, shape.lane_size())] }
+            Command::VecExtractLane { shape, dst, op, .. } => { vec![(*dst, shape.lane_size().max(4)), (*op, 16)] }
+            Command::VecReplaceLane { shape, dst, op, value, .. } => { vec![(*dst, 16), (*op, 16), (*value, shape.lane_size())] }
+        }
+    }
+}
+
+impl Condition {
+    pub fn refs(&self) -> Vec<(Ref, u32)> {
+        match self {
+            Condition::Ne { size, op1, op2 } => { vec![(*op1, *size), (*op2, *size)] }
+            Condition::Ne0 { size, op } => { vec![(*op, *size)] }
+        }
+    }
+}
+
 // There is more dynamic available using Box<dyn> approach.
 // It might enable mixing different nodes in one runtime for example.
 // But I think it's not worth changing so far.
@@ -117,5 +373,6 @@ pub enum Condition {
 
 #[test]
 fn test_sizes() {
-    assert_eq!(4, std::mem::size_of::<Ref>())
+    // u32 offset and tag of `Stack` or `Global`
+    assert_eq!(8, std::mem::size_of::<Ref>())
 }
\ No newline at end of file
@@ -53,6 +53,44 @@ impl<'a, N: Node> Cache<'a, N>{
                         }
                     }
                     NodeKind::Final => { NodeKind::Final }
+                    NodeKind::Yield { next } => {
+                        NodeKind::Yield { next: CachedNode { cache: self, id: Self::get_inner(cache, next)} }
+                    }
+                    NodeKind::Coroutine { offset, call, handle, next } => {
+                        NodeKind::Coroutine {
+                            offset,
+                            call: CachedNode { cache: self, id: Self::get_inner(cache, call)},
+                            handle,
+                            next: CachedNode { cache: self, id: Self::get_inner(cache, next)}
+                        }
+                    }
+                    NodeKind::Resume { handle, next } => {
+                        NodeKind::Resume { handle, next: CachedNode { cache: self, id: Self::get_inner(cache, next)} }
+                    }
+                    NodeKind::TailCall { offset, call } => {
+                        NodeKind::TailCall { offset, call: CachedNode { cache: self, id: Self::get_inner(cache, call)} }
+                    }
+                    NodeKind::Try { offset, call, next, catch } => {
+                        NodeKind::Try {
+                            offset,
+                            call:  CachedNode { cache: self, id: Self::get_inner(cache, call)},
+                            next:  CachedNode { cache: self, id: Self::get_inner(cache, next)},
+                            catch:  CachedNode { cache: self, id: Self::get_inner(cache, catch)}
+                        }
+                    }
+                    NodeKind::Throw { op, size } => { NodeKind::Throw { op, size } }
+                    NodeKind::PureCall { offset, call, next, args, results } => {
+                        NodeKind::PureCall {
+                            offset,
+                            call:  CachedNode { cache: self, id: Self::get_inner(cache, call)},
+                            next:  CachedNode { cache: self, id: Self::get_inner(cache, next)},
+                            args,
+                            results
+                        }
+                    }
+                    NodeKind::HostCall { function, offset, next } => {
+                        NodeKind::HostCall { function, offset, next: CachedNode { cache: self, id: Self::get_inner(cache, next)} }
+                    }
                 };
                 *(cache.cached.get_mut(id as usize).unwrap()) = Some(computed.clone());
                 computed
@@ -61,12 +99,11 @@ impl<'a, N: Node> Cache<'a, N>{
     }
 
     fn get_inner(cache: &mut CacheInner<'a, N>, node: N) -> u32 {
-        let id = if !cache.idx.contains_key(&node) {
-            cache.originals.len() as u32
-        } else {
-            *cache.idx.get(&node).unwrap()
-        };
+        if let Some(id) = cache.idx.get(&node) {
+            return *id;
+        }
 
+        let id = cache.originals.len() as u32;
         cache.originals.push(node.clone());
         cache.idx.insert(node, id);
         cache.cached.push(None);
@@ -1,2 +1,4 @@
 pub mod cached_node;
+pub mod partial_eval;
+pub mod shared_cache;
 pub mod specialized_interpreter_engine;
\ No newline at end of file
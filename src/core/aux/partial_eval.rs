@@ -0,0 +1,390 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use crate::core::api::{Command, Condition, Node, NodeKind, Ref};
+use crate::core::globals::Globals;
+use crate::core::interpreter::{eval_command, eval_condition};
+
+// Stack bytes of a frame which are known before node is executed, by offset.
+// Trailing unknown bytes are dropped, so equal states are equal.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Known(Vec<Option<u8>>);
+
+impl Known {
+    pub fn new() -> Known { Known::default() }
+
+    pub fn with(mut self, offset: u32, bytes: &[u8]) -> Known {
+        self.set(offset, bytes);
+        self
+    }
+
+    // globals are never known, other threads and runs might change them
+    fn get(&self, r: Ref, size: u32) -> Option<Vec<u8>> {
+        let Ref::Stack(offset) = r else { return None; };
+        (offset..offset + size).map(|offset| self.0.get(offset as usize).copied().flatten()).collect()
+    }
+
+    fn set(&mut self, offset: u32, bytes: &[u8]) {
+        let end = offset as usize + bytes.len();
+        if self.0.len() < end { self.0.resize(end, None) }
+        self.0[offset as usize..end].iter_mut().zip(bytes).for_each(|(known, byte)| *known = Some(*byte));
+    }
+
+    fn forget(&mut self, offset: u32, size: u32) {
+        self.0.iter_mut().skip(offset as usize).take(size as usize).for_each(|known| *known = None);
+        self.trim();
+    }
+
+    // bytes from `offset` on are unknown
+    fn truncated(&self, offset: u32) -> Known {
+        let mut known = Known(self.0.iter().take(offset as usize).copied().collect());
+        known.trim();
+        known
+    }
+
+    // as seen from frame starting at `offset`
+    fn shifted(&self, offset: u32) -> Known { Known(self.0.iter().skip(offset as usize).copied().collect()) }
+
+    // bytes from `offset` on are replaced by `other`, i.e. state of callee frame starting there
+    fn replaced(&self, offset: u32, other: &Known) -> Known {
+        let mut known = self.0.clone();
+        known.resize(offset as usize, None);
+        known.extend(other.0.iter());
+        let mut known = Known(known);
+        known.trim();
+        known
+    }
+
+    fn trim(&mut self) {
+        while let Some(None) = self.0.last() { self.0.pop(); }
+    }
+}
+
+// stack bytes written by command with known operands, `Noop` and `PoisonFrom` are handled by caller.
+// Writes to globals and atomics aren't folded, since they have to happen anyway.
+fn fold_command(command: &Command, known: &Known) -> Option<(u32, Vec<u8>)> {
+    let (dst, bytes) = match command {
+        Command::Set { dst, bytes } => { (*dst, bytes.clone()) }
+        Command::Copy { dst, size, op } => { (*dst, known.get(*op, *size)?) }
+        Command::Add { size, dst, op1, op2 } | Command::Sub { size, dst, op1, op2 } => {
+            let operands = vec![known.get(*op1, *size)?, known.get(*op2, *size)?];
+            (*dst, eval_scratch(operands, *size, |ops, result| match command {
+                Command::Add { size, .. } => { Command::Add { size: *size, dst: result, op1: ops[0], op2: ops[1] } }
+                _ => { Command::Sub { size: *size, dst: result, op1: ops[0], op2: ops[1] } }
+            }))
+        }
+        Command::Noop | Command::PoisonFrom { .. } => { unreachable!("command doesn't write") }
+        // atomics synchronize with other threads, so they're kept as is
+        Command::AtomicLoad { .. } | Command::AtomicStore { .. } | Command::AtomicRmw { .. }
+            | Command::AtomicCmpXchg { .. } | Command::Fence => { return None; }
+        Command::VecBinary { op, shape, dst, op1, op2 } => {
+            let operands = vec![known.get(*op1, 16)?, known.get(*op2, 16)?];
+            (*dst, eval_scratch(operands, 16, |ops, result| Command::VecBinary { op: *op, shape: *shape, dst: result, op1: ops[0], op2: ops[1] }))
+        }
+        Command::VecSplat { shape, dst, op } => {
+            let operands = vec![known.get(*op, shape.lane_size())?];
+            (*dst, eval_scratch(operands, 16, |ops, result| Command::VecSplat { shape: *shape, dst: result, op: ops[0] }))
+        }
+        // small lanes are extended to 4 bytes
+        Command::VecExtractLane { shape, signed, lane, dst, op } => {
+            let operands = vec![known.get(*op, 16)?];
+            let size = shape.lane_size().max(4);
+            (*dst, eval_scratch(operands, size, |ops, result| Command::VecExtractLane { shape: *shape, signed: *signed, lane: *lane, dst: result, op: ops[0] }))
+        }
+        Command::VecReplaceLane { shape, lane, dst, op, value } => {
+            let operands = vec![known.get(*op, 16)?, known.get(*value, shape.lane_size())?];
+            (*dst, eval_scratch(operands, 16, |ops, result| Command::VecReplaceLane { shape: *shape, lane: *lane, dst: result, op: ops[0], value: ops[1] }))
+        }
+        Command::VecShuffle { lanes, dst, op1, op2 } => {
+            let operands = vec![known.get(*op1, 16)?, known.get(*op2, 16)?];
+            (*dst, eval_scratch(operands, 16, |ops, result| Command::VecShuffle { lanes: *lanes, dst: result, op1: ops[0], op2: ops[1] }))
+        }
+    };
+    let Ref::Stack(dst) = dst else { return None; };
+    Some((dst, bytes))
+}
+
+// `size` bytes of result of command evaluated on scratch stack with `operands` at its start, followed by the result
+fn eval_scratch<F: FnOnce(&[Ref], Ref) -> Command>(operands: Vec<Vec<u8>>, size: u32, command: F) -> Vec<u8> {
+    let mut refs = vec![];
+    let mut scratch = vec![];
+    for operand in operands {
+        refs.push(Ref::Stack(scratch.len() as u32));
+        scratch.extend(operand);
+    }
+    let result = scratch.len();
+    scratch.resize(result + size as usize, 0);
+    eval_command(&command(&refs, Ref::Stack(result as u32)), &mut scratch, &Globals::default());
+    scratch.split_off(result)
+}
+
+// stack ranges written by command which isn't folded, known bytes there are forgotten
+fn stack_writes(command: &Command) -> Vec<(u32, u32)> {
+    let writes = match command {
+        Command::Copy { dst, size, .. } | Command::Add { dst, size, .. } | Command::Sub { dst, size, .. }
+            | Command::AtomicLoad { size, dst, .. } | Command::AtomicStore { size, mem: dst, .. } => { vec![(*dst, *size)] }
+        Command::AtomicRmw { size, dst, mem, .. } => { vec![(*dst, *size), (*mem, *size)] }
+        Command::AtomicCmpXchg { size, mem, expected, .. } => { vec![(*mem, *size), (*expected, *size)] }
+        Command::Set { dst, bytes } => { vec![(*dst, bytes.len() as u32)] }
+        Command::VecBinary { dst, .. } | Command::VecSplat { dst, .. } | Command::VecReplaceLane { dst, .. }
+            | Command::VecShuffle { dst, .. } => { vec![(*dst, 16)] }
+        Command::VecExtractLane { shape, dst, .. } => { vec![(*dst, shape.lane_size().max(4))] }
+        Command::Noop | Command::PoisonFrom { .. } | Command::Fence => { vec![] }
+    };
+    writes.into_iter().filter_map(|(dst, size)| match dst { Ref::Stack(dst) => { Some((dst, size)) } Ref::Global(_) => { None } }).collect()
+}
+
+fn fold_condition(condition: &Condition, known: &Known) -> Option<bool> {
+    let mut scratch = match condition {
+        Condition::Ne { size, op1, op2 } => { [known.get(*op1, *size)?, known.get(*op2, *size)?].concat() }
+        Condition::Ne0 { size, op } => { known.get(*op, *size)? }
+    };
+    let condition = match condition {
+        Condition::Ne { size, .. } => { Condition::Ne { size: *size, op1: Ref::Stack(0), op2: Ref::Stack(*size) } }
+        Condition::Ne0 { size, .. } => { Condition::Ne0 { size: *size, op: Ref::Stack(0) } }
+    };
+    Some(eval_condition(&condition, &mut scratch, &Globals::default()))
+}
+
+// Effect of code evaluated on known bytes: state of its frame once it's finished and offsets it wrote.
+#[derive(Clone)]
+struct Fold {
+    known: Known,
+    written: Vec<u32>,
+}
+
+impl Fold {
+    // writes of still known bytes as `(offset, bytes)` runs, moved by `offset`
+    fn writes(&self, offset: u32) -> Vec<(u32, Vec<u8>)> {
+        let mut writes: Vec<(u32, Vec<u8>)> = vec![];
+        for written in &self.written {
+            let Some(byte) = self.known.0.get(*written as usize).copied().flatten() else { continue; };
+            match writes.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() as u32 == written + offset => { bytes.push(byte) }
+                _ => { writes.push((written + offset, vec![byte])) }
+            }
+        }
+        writes
+    }
+}
+
+// successful folds recorded at most, callees are evaluated again on other known bytes once there are that many
+const MAX_FOLDS: usize = 4096;
+
+struct Folder<N: Node> {
+    // number of nodes evaluated at most when folding
+    limit: usize,
+    // successful folds of callees by their known bytes, up to `MAX_FOLDS`
+    folds: Mutex<HashMap<(N, Known), Fold>>,
+}
+
+impl<N: Node> Folder<N> {
+    fn fold(&self, node: &N, known: &Known) -> Option<Fold> { self.fold_inner(node, known, &mut self.limit.clone()) }
+
+    // Evaluates node on known bytes of its frame until it returns. None if it needs unknown byte, runs out of `steps`,
+    // yields or throws, evaluation can't be skipped then.
+    fn fold_inner(&self, node: &N, known: &Known, steps: &mut usize) -> Option<Fold> {
+        let key = (node.clone(), known.clone());
+        if let Some(fold) = self.folds.lock().unwrap().get(&key) { return Some(fold.clone()); }
+
+        let mut known = known.clone();
+        let mut written = BTreeSet::new();
+        // frame start moved by tail calls
+        let mut base = 0;
+        let mut current = node.clone();
+        loop {
+            if *steps == 0 { return None; }
+            *steps -= 1;
+            current = match current.get() {
+                NodeKind::Command { command, next } => {
+                    match command.offset(base) {
+                        Command::Noop => {}
+                        Command::PoisonFrom { dst: Ref::Stack(dst) } => { known = known.truncated(dst) }
+                        Command::PoisonFrom { dst: Ref::Global(_) } => {}
+                        command => {
+                            let (dst, bytes) = fold_command(&command, &known)?;
+                            written.extend(dst..dst + bytes.len() as u32);
+                            known.set(dst, &bytes);
+                        }
+                    }
+                    next
+                }
+                NodeKind::Branch { condition, if_true, if_false } => {
+                    if fold_condition(&condition.offset(base), &known)? { if_true } else { if_false }
+                }
+                NodeKind::Call { offset, call, next } | NodeKind::PureCall { offset, call, next, .. } => {
+                    let start = base + offset;
+                    let fold = self.fold_inner(&call, &known.shifted(start), steps)?;
+                    known = known.replaced(start, &fold.known);
+                    written.extend(fold.written.iter().map(|offset| start + offset));
+                    next
+                }
+                NodeKind::TailCall { offset, call } => {
+                    base += offset;
+                    call
+                }
+                NodeKind::Final => { break; }
+                NodeKind::Yield { .. } | NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. }
+                    | NodeKind::Try { .. } | NodeKind::Throw { .. } => { return None; }
+            };
+        }
+
+        let fold = Fold { known, written: written.into_iter().collect() };
+        let mut folds = self.folds.lock().unwrap();
+        if folds.len() < MAX_FOLDS {
+            folds.insert(key, fold.clone());
+        }
+        Some(fold)
+    }
+}
+
+// Partial evaluation: nodes are specialized to stack bytes known before them. Commands on known bytes become `Set`,
+// branches on them are taken right away, calls which need known bytes only are replaced with writes they do.
+// Known bytes are forgotten at branches on unknown ones, so loops with unknown bounds aren't unrolled.
+pub struct PartialEvaluator<N: Node> {
+    folder: Arc<Folder<N>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Residual<N> {
+    Node(N, Known),
+    // writes of folded code followed by `then`
+    Writes(Vec<(u32, Vec<u8>)>, Box<Residual<N>>),
+    Final,
+}
+
+#[derive(Clone)]
+pub struct PartialNode<N: Node> {
+    folder: Arc<Folder<N>>,
+    residual: Residual<N>,
+}
+
+impl<N: Node> PartialEvaluator<N> {
+    // `limit` is number of nodes evaluated at most when folding a call
+    pub fn new(limit: usize) -> PartialEvaluator<N> {
+        PartialEvaluator { folder: Arc::new(Folder { limit, folds: Mutex::new(HashMap::new()) }) }
+    }
+
+    // node as if it's run with `known` bytes at the start of its frame, whole node is folded if it can be
+    pub fn specialize(&self, node: N, known: Known) -> PartialNode<N> {
+        let residual = match self.folder.fold(&node, &known) {
+            Some(fold) => { Residual::Writes(fold.writes(0), Box::new(Residual::Final)) }
+            None => { Residual::Node(node, known) }
+        };
+        PartialNode { folder: self.folder.clone(), residual }
+    }
+}
+
+impl<N: Node> PartialNode<N> {
+    fn wrap(&self, residual: Residual<N>) -> PartialNode<N> { PartialNode { folder: self.folder.clone(), residual } }
+
+    fn node(&self, node: N, known: Known) -> PartialNode<N> { self.wrap(Residual::Node(node, known)) }
+
+    fn specialize(&self, node: N, known: Known) -> NodeKind<Self> {
+        match node.get() {
+            NodeKind::Command { command: command @ (Command::Noop | Command::PoisonFrom { dst: Ref::Global(_) }), next } => {
+                NodeKind::Command { command, next: self.node(next, known) }
+            }
+            NodeKind::Command { command: Command::PoisonFrom { dst: Ref::Stack(dst) }, next } => {
+                NodeKind::Command { command: Command::PoisonFrom { dst: Ref::Stack(dst) }, next: self.node(next, known.truncated(dst)) }
+            }
+            NodeKind::Command { command, next } => {
+                match fold_command(&command, &known) {
+                    Some((dst, bytes)) => {
+                        let next = self.node(next, known.with(dst, &bytes));
+                        NodeKind::Command { command: Command::Set { dst: Ref::Stack(dst), bytes }, next }
+                    }
+                    None => {
+                        // writes to globals don't change known bytes
+                        let mut known = known;
+                        for (dst, size) in stack_writes(&command) { known.forget(dst, size) }
+                        NodeKind::Command { command, next: self.node(next, known) }
+                    }
+                }
+            }
+            NodeKind::Branch { condition, if_true, if_false } => {
+                match fold_condition(&condition, &known) {
+                    Some(taken) => { NodeKind::Command { command: Command::Noop, next: self.node(if taken { if_true } else { if_false }, known) } }
+                    None => { NodeKind::Branch { condition, if_true: self.node(if_true, Known::new()), if_false: self.node(if_false, Known::new()) } }
+                }
+            }
+            NodeKind::Call { offset, call, next } => {
+                match self.folder.fold(&call, &known.shifted(offset)) {
+                    Some(fold) => { self.writes(&fold, offset, next, known) }
+                    None => { NodeKind::Call { offset, call: self.node(call, known.shifted(offset)), next: self.node(next, known.truncated(offset)) } }
+                }
+            }
+            NodeKind::PureCall { offset, call, next, args, results } => {
+                match self.folder.fold(&call, &known.shifted(offset)) {
+                    Some(fold) => { self.writes(&fold, offset, next, known) }
+                    None => {
+                        let (call, next) = (self.node(call, known.shifted(offset)), self.node(next, known.truncated(offset)));
+                        NodeKind::PureCall { offset, call, next, args, results }
+                    }
+                }
+            }
+            NodeKind::Final => { NodeKind::Final }
+            // resumer of coroutine might change the frame meanwhile
+            NodeKind::Yield { next } => { NodeKind::Yield { next: self.node(next, Known::new()) } }
+            // host might change its frame as callee would
+            NodeKind::HostCall { function, offset, next } => { NodeKind::HostCall { function, offset, next: self.node(next, known.truncated(offset)) } }
+            // coroutine starts once it's resumed, when frame might be different
+            NodeKind::Coroutine { offset, call, handle, next } => {
+                let mut known = known;
+                if let Ref::Stack(handle) = handle { known.forget(handle, 4) }
+                NodeKind::Coroutine { offset, call: self.node(call, Known::new()), handle, next: self.node(next, known) }
+            }
+            // coroutine might change any part of the frame
+            NodeKind::Resume { handle, next } => { NodeKind::Resume { handle, next: self.node(next, Known::new()) } }
+            NodeKind::TailCall { offset, call } => { NodeKind::TailCall { offset, call: self.node(call, known.shifted(offset)) } }
+            NodeKind::Try { offset, call, next, catch } => {
+                let (call, next, catch) = (self.node(call, known.shifted(offset)), self.node(next, known.truncated(offset)), self.node(catch, known.truncated(offset)));
+                NodeKind::Try { offset, call, next, catch }
+            }
+            NodeKind::Throw { op, size } => { NodeKind::Throw { op, size } }
+        }
+    }
+
+    // folded call at `offset` continuing to `next`
+    fn writes(&self, fold: &Fold, offset: u32, next: N, known: Known) -> NodeKind<Self> {
+        let then = Residual::Node(next, known.replaced(offset, &fold.known));
+        NodeKind::Command { command: Command::Noop, next: self.wrap(Residual::Writes(fold.writes(offset), Box::new(then))) }
+    }
+}
+
+impl<N: Node> Node for PartialNode<N> {
+    fn get(&self) -> NodeKind<Self> {
+        match &self.residual {
+            Residual::Node(node, known) => { self.specialize(node.clone(), known.clone()) }
+            Residual::Writes(writes, then) => {
+                match writes.split_first() {
+                    None => { self.wrap(then.as_ref().clone()).get() }
+                    Some(((dst, bytes), rest)) => {
+                        let next = self.wrap(Residual::Writes(rest.to_vec(), then.clone()));
+                        NodeKind::Command { command: Command::Set { dst: Ref::Stack(*dst), bytes: bytes.clone() }, next }
+                    }
+                }
+            }
+            Residual::Final => { NodeKind::Final }
+        }
+    }
+}
+
+// nodes of different evaluators are different even if they're specialized the same way
+impl<N: Node> PartialEq for PartialNode<N> {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.folder, &other.folder) && self.residual == other.residual }
+}
+
+impl<N: Node> Eq for PartialNode<N> {}
+
+impl<N: Node> Hash for PartialNode<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(Arc::as_ptr(&self.folder) as usize);
+        self.residual.hash(state);
+    }
+}
+
+impl<N: Node> Debug for PartialNode<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { self.residual.fmt(f) }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use crate::core::api::{Node, NodeKind};
+
+// Memoizes `Node::get` of wrapped nodes, can be shared between threads.
+// Holds at most `capacity` kinds, least recently used quarter is evicted once it's full, evicted kinds are computed again on demand.
+// Unlike `Cache` nodes don't borrow the cache, each one keeps it alive and remembers its original node.
+pub struct SharedCache<N: Node> {
+    inner: Arc<Mutex<SharedCacheInner<N>>>,
+}
+
+struct SharedCacheInner<N: Node> {
+    capacity: usize,
+    kinds: HashMap<N, (NodeKind<N>, u64)>,
+    tick: u64,
+}
+
+#[derive(Clone)]
+pub struct SharedCachedNode<N: Node> {
+    cache: Arc<Mutex<SharedCacheInner<N>>>,
+    node: N,
+}
+
+impl<N: Node> SharedCache<N> {
+    pub fn new(capacity: usize) -> SharedCache<N> {
+        assert!(capacity > 0, "cache should hold at least one kind");
+        SharedCache { inner: Arc::new(Mutex::new(SharedCacheInner { capacity, kinds: HashMap::new(), tick: 0 })) }
+    }
+
+    pub fn cache(&self, node: N) -> SharedCachedNode<N> { SharedCachedNode { cache: self.inner.clone(), node } }
+
+    // number of cached kinds
+    pub fn len(&self) -> usize { self.inner.lock().unwrap().kinds.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<N: Node> SharedCacheInner<N> {
+    fn get(&mut self, node: &N) -> Option<NodeKind<N>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.kinds.get_mut(node).map(|(kind, last_used)| {
+            *last_used = tick;
+            kind.clone()
+        })
+    }
+
+    fn insert(&mut self, node: N, kind: NodeKind<N>) {
+        self.tick += 1;
+        self.kinds.insert(node, (kind, self.tick));
+        if self.kinds.len() > self.capacity {
+            let mut used: Vec<(u64, N)> = self.kinds.iter().map(|(node, (_, last_used))| (*last_used, node.clone())).collect();
+            used.sort_by_key(|(last_used, _)| *last_used);
+            let to_evict = self.kinds.len() - self.capacity + self.capacity / 4;
+            used.into_iter().take(to_evict).for_each(|(_, node)| { self.kinds.remove(&node); });
+        }
+    }
+}
+
+impl<N: Node> SharedCachedNode<N> {
+    fn wrap(&self, node: N) -> SharedCachedNode<N> { SharedCachedNode { cache: self.cache.clone(), node } }
+}
+
+impl<N: Node> Node for SharedCachedNode<N> {
+    fn get(&self) -> NodeKind<Self> {
+        let cached = self.cache.lock().unwrap().get(&self.node);
+        let kind = match cached {
+            Some(kind) => { kind }
+            None => {
+                // original `get` might be slow, so it's computed without lock, racing threads compute the same kind
+                let kind = self.node.get();
+                self.cache.lock().unwrap().insert(self.node.clone(), kind.clone());
+                kind
+            }
+        };
+        match kind {
+            NodeKind::Command { command, next } => { NodeKind::Command { command, next: self.wrap(next) } }
+            NodeKind::Branch { condition, if_true, if_false } => {
+                NodeKind::Branch { condition, if_true: self.wrap(if_true), if_false: self.wrap(if_false) }
+            }
+            NodeKind::Call { offset, call, next } => { NodeKind::Call { offset, call: self.wrap(call), next: self.wrap(next) } }
+            NodeKind::Final => { NodeKind::Final }
+            NodeKind::Yield { next } => { NodeKind::Yield { next: self.wrap(next) } }
+            NodeKind::HostCall { function, offset, next } => { NodeKind::HostCall { function, offset, next: self.wrap(next) } }
+            NodeKind::Coroutine { offset, call, handle, next } => { NodeKind::Coroutine { offset, call: self.wrap(call), handle, next: self.wrap(next) } }
+            NodeKind::Resume { handle, next } => { NodeKind::Resume { handle, next: self.wrap(next) } }
+            NodeKind::TailCall { offset, call } => { NodeKind::TailCall { offset, call: self.wrap(call) } }
+            NodeKind::Try { offset, call, next, catch } => {
+                NodeKind::Try { offset, call: self.wrap(call), next: self.wrap(next), catch: self.wrap(catch) }
+            }
+            NodeKind::Throw { op, size } => { NodeKind::Throw { op, size } }
+            NodeKind::PureCall { offset, call, next, args, results } => {
+                NodeKind::PureCall { offset, call: self.wrap(call), next: self.wrap(next), args, results }
+            }
+        }
+    }
+}
+
+// nodes of different caches are different even if originals are equal
+impl<N: Node> PartialEq for SharedCachedNode<N> {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.cache, &other.cache) && self.node == other.node }
+}
+
+impl<N: Node> Eq for SharedCachedNode<N> {}
+
+impl<N: Node> Hash for SharedCachedNode<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(Arc::as_ptr(&self.cache) as usize);
+        self.node.hash(state);
+    }
+}
+
+impl<N: Node> Debug for SharedCachedNode<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { self.node.fmt(f) }
+}
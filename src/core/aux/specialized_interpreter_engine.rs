@@ -1,6 +1,13 @@
 use std::num::Wrapping;
 use crate::core::api::{Command, Condition, NodeKind, Ref};
 use crate::core::driver::driver::{Frame, RunState, NodeId, Engine};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::core::driver::fuel::Fuel;
+use crate::core::driver::observer::Observer;
+use crate::core::driver::profile::{NodeProfile, Profile};
+use crate::core::driver::stats::{EngineStats, Stats};
+use crate::core::globals::Globals;
 use crate::core::interpreter::{eval_command, eval_condition, get_u32, put_u32};
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -22,6 +29,8 @@ fn small_id(ctx: NodeId, id: NodeId) -> Option<SmallNodeId> {
 fn small_ref(r: Ref) -> Option<SmallStackRef> {
     match r {
         Ref::Stack(offset) => { offset.try_into().ok().map(|id| SmallStackRef(id)) }
+        // nodes using globals are kept in full representation
+        Ref::Global(_) => { None }
     }
 }
 
@@ -50,101 +59,155 @@ enum CompactKind {
 
     Full(u32),
     Final,
+    // yield, coroutine, resume, host call, throw or pure call, driver continues past it
+    Suspend,
 }
 
 pub struct SpecializedInterpreterEngine {
     computed: Vec<CompactKind>,
     full: Vec<NodeKind<NodeId>>,
+    // indexes in `full` of unregistered nodes
+    free_full: Vec<u32>,
+    profile: Option<Profile>,
+    observer: Option<Arc<dyn Observer>>,
+    fuel: Fuel,
+    stats: Stats,
 }
 
 static NOT_COMPUTED: CompactKind = CompactKind::NotComputed;
 
 impl SpecializedInterpreterEngine {
     pub fn new() -> SpecializedInterpreterEngine {
-        SpecializedInterpreterEngine { computed: Vec::new(), full: Vec::new() }
+        SpecializedInterpreterEngine { computed: Vec::new(), full: Vec::new(), free_full: Vec::new(), profile: None, observer: None, fuel: Fuel::new(), stats: Stats::new() }
     }
 
-    pub fn print_stats(&self) {
-        println!("full kinds:");
-        self.full.iter().for_each(|kind| println!("{:?}", kind));
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.observer = observer; }
+
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling { Some(Profile::new()) } else { None };
+    }
+
+    // nodes which didn't fit into compact kind are `full`
+    pub fn stats(&self) -> EngineStats {
+        let registered = self.computed.iter().filter(|kind| **kind != CompactKind::NotComputed).count() as u64;
+        let full = self.computed.iter().filter(|kind| matches!(kind, CompactKind::Full(_))).count() as u64;
+        EngineStats { registered, compact: registered - full, full, ..self.stats.get() }
     }
 
     pub fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) {
+        let start = Instant::now();
         while self.computed.len() <= id.0 as usize {
             self.computed.push(CompactKind::NotComputed);
         }
+        if let Some(profile) = &mut self.profile {
+            profile.register(id, matches!(kind, NodeKind::Branch { .. }));
+        }
         *self.computed.get_mut(id.0 as usize).unwrap() = self.compact(id, kind);
+        self.stats.registered(start);
+    }
+
+    pub fn unregister(&mut self, id: NodeId) {
+        if let Some(kind) = self.computed.get_mut(id.0 as usize) {
+            if let CompactKind::Full(full_id) = kind {
+                self.free_full.push(*full_id);
+            }
+            *kind = CompactKind::NotComputed;
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.unregister(id);
+        }
     }
 
-    // returns true - suspended on unknown node, false - otherwise
-    pub fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool {
+    // returns true - suspended on unknown node, driver's one or out of fuel, false - otherwise
+    pub fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool {
         let offset = state.offset();
         let frame = state.frames.pop().unwrap();
-        match self.run_internal(frame.id, &mut stack[offset..]) {
+        match self.run_internal(frame.id, &mut stack[offset..], globals) {
             None => { false }
             Some(mut suspended_in) => {
                 suspended_in.reverse();
                 suspended_in.first_mut().unwrap().offset += frame.offset;
                 state.frames.append(&mut suspended_in);
+                if let Some(observer) = &self.observer { observer.on_suspend(state.frames.last().unwrap().id) }
+                self.stats.suspended();
                 true
             }
         }
     }
 
-    fn run_internal(&self, node: NodeId, stack: &mut [u8]) -> Option<Vec<Frame>> {
+    fn run_internal(&self, node: NodeId, stack: &mut [u8], globals: &Globals) -> Option<Vec<Frame>> {
+        let mut shift = 0;
+        let mut trace = self.run_function(node, stack, globals, &mut shift)?;
+        // tail calls moved the frame by `shift`
+        trace.last_mut().unwrap().offset += shift;
+        Some(trace)
+    }
+
+    fn run_function(&self, node: NodeId, mut stack: &mut [u8], globals: &Globals, shift: &mut usize) -> Option<Vec<Frame>> {
         let mut current = node;
         loop {
-            match self.computed.get(current.0 as usize).unwrap_or(&NOT_COMPUTED) {
+            let id = current;
+            let kind = self.computed.get(current.0 as usize).unwrap_or(&NOT_COMPUTED);
+            if self.fuel.is_metered() && !matches!(kind, CompactKind::NotComputed) && !self.fuel.charge() {
+                return Some(vec![Frame { id: current, offset: 0 }]);
+            }
+            if self.profile.is_some() || self.observer.is_some() { self.enter(current, kind, stack) }
+            match kind {
                 CompactKind::Final => { return None; }
+                CompactKind::Suspend => { return Some(vec![Frame { id: current, offset: 0 }]); }
                 CompactKind::Set4 { dst, value, next } => {
-                    put_u32((*dst).into(), stack, *value);
+                    put_u32((*dst).into(), stack, globals, *value);
                     current = next.get(current);
                 }
                 CompactKind::Set4N { dst, value } => {
-                    put_u32((*dst).into(), stack, *value);
+                    put_u32((*dst).into(), stack, globals, *value);
                     current = current.next();
                 }
                 CompactKind::Copy4 { dst, op , next } => {
-                    put_u32((*dst).into(), stack, get_u32((*op).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op).into(), stack, globals));
                     current = next.get(current);
                 }
                 CompactKind::Copy4N { dst, op  } => {
-                    put_u32((*dst).into(), stack, get_u32((*op).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op).into(), stack, globals));
                     current = current.next();
                 }
                 CompactKind::Add4 { dst, op1, op2, next } => {
-                    put_u32((*dst).into(), stack, get_u32((*op1).into(), stack) + get_u32((*op2).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op1).into(), stack, globals) + get_u32((*op2).into(), stack, globals));
                     current = next.get(current);
                 }
                 CompactKind::Add4N { dst, op1, op2 } => {
-                    put_u32((*dst).into(), stack, get_u32((*op1).into(), stack) + get_u32((*op2).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op1).into(), stack, globals) + get_u32((*op2).into(), stack, globals));
                     current = current.next();
                 }
                 CompactKind::Sub4 { dst, op1, op2, next } => {
-                    put_u32((*dst).into(), stack, get_u32((*op1).into(), stack) - get_u32((*op2).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op1).into(), stack, globals) - get_u32((*op2).into(), stack, globals));
                     current = next.get(current);
                 }
                 CompactKind::Sub4N { dst, op1, op2 } => {
-                    put_u32((*dst).into(), stack, get_u32((*op1).into(), stack) - get_u32((*op2).into(), stack));
+                    put_u32((*dst).into(), stack, globals, get_u32((*op1).into(), stack, globals) - get_u32((*op2).into(), stack, globals));
                     current = current.next();
                 }
                 CompactKind::Ne4 { op1, op2, if_true, if_false } => {
-                    current = if get_u32((*op1).into(), stack) != get_u32((*op2).into(), stack) {
+                    current = if get_u32((*op1).into(), stack, globals) != get_u32((*op2).into(), stack, globals) {
+                        self.branched(current, true, stack);
                         if_true.get(current)
                     } else {
+                        self.branched(current, false, stack);
                         if_false.get(current)
                     }
                 }
                 CompactKind::Ne04 { op, if_true, if_false } => {
-                    current = if get_u32((*op).into(), stack) != Wrapping(0) {
+                    current = if get_u32((*op).into(), stack, globals) != Wrapping(0) {
+                        self.branched(current, true, stack);
                         if_true.get(current)
                     } else {
+                        self.branched(current, false, stack);
                         if_false.get(current)
                     }
                 }
                 CompactKind::Call { offset, call, next } => {
                     let offset = offset.0 as usize;
-                    match self.run_internal(call.get(current), &mut stack[offset..]) {
+                    match self.run_internal(call.get(current), &mut stack[offset..], globals) {
                         None => {
                             current = next.get(current);
                         }
@@ -157,19 +220,21 @@ impl SpecializedInterpreterEngine {
                     let id = *id as usize;
                     match self.full.get(id).unwrap() {
                         NodeKind::Command { command, next } => {
-                            eval_command(command, stack);
+                            eval_command(command, stack, globals);
                             current = *next;
                         }
                         NodeKind::Branch { condition, if_true, if_false } => {
-                            if eval_condition(condition, stack) {
+                            if eval_condition(condition, stack, globals) {
+                                self.branched(current, true, stack);
                                 current = *if_true;
                             } else {
+                                self.branched(current, false, stack);
                                 current = *if_false;
                             }
                         }
                         NodeKind::Call { offset, call, next } => {
                             let offset = *offset as usize;
-                            match self.run_internal(*call, &mut stack[offset..]) {
+                            match self.run_internal(*call, &mut stack[offset..], globals) {
                                 None => {
                                     current = *next;
                                 }
@@ -178,11 +243,55 @@ impl SpecializedInterpreterEngine {
                                 }
                             }
                         }
+                        NodeKind::TailCall { offset, call } => {
+                            let offset = *offset as usize;
+                            stack = &mut std::mem::take(&mut stack)[offset..];
+                            *shift += offset;
+                            current = *call;
+                        }
                         _ => { panic!("full command can't be neither of command, branch or call") }
                     }
                 }
                 CompactKind::NotComputed => { return Some(vec![Frame { id: current, offset: 0 }]); }
             }
+            if let Some(observer) = &self.observer {
+                if self.is_command(kind) { observer.on_command(id, stack) }
+            }
+        }
+    }
+
+    // profiling and observer hooks on node entry
+    fn enter(&self, id: NodeId, kind: &CompactKind, stack: &[u8]) {
+        if let CompactKind::NotComputed = kind { return; }
+        if let Some(counters) = self.profile.as_ref().and_then(|profile| profile.counters(id)) { counters.executed() }
+        if let Some(observer) = &self.observer {
+            observer.on_node(id, stack);
+            match kind {
+                CompactKind::Call { offset, call, .. } => { observer.on_call(id, call.get(id), offset.0 as u32, stack) }
+                CompactKind::Full(full) => {
+                    if let NodeKind::Call { offset, call, .. } | NodeKind::TailCall { offset, call } = self.full.get(*full as usize).unwrap() {
+                        observer.on_call(id, *call, *offset, stack)
+                    }
+                }
+                CompactKind::Final => { observer.on_return(id, stack) }
+                _ => {}
+            }
+        }
+    }
+
+    fn branched(&self, id: NodeId, taken: bool, stack: &[u8]) {
+        if taken {
+            if let Some(counters) = self.profile.as_ref().and_then(|profile| profile.counters(id)) { counters.taken() }
+        }
+        if let Some(observer) = &self.observer { observer.on_branch(id, taken, stack) }
+    }
+
+    fn is_command(&self, kind: &CompactKind) -> bool {
+        match kind {
+            CompactKind::Set4 { .. } | CompactKind::Copy4 { .. } | CompactKind::Set4N { .. } | CompactKind::Copy4N { .. } |
+            CompactKind::Add4 { .. } | CompactKind::Add4N { .. } | CompactKind::Sub4 { .. } | CompactKind::Sub4N { .. } => { true }
+            CompactKind::Full(full) => { matches!(self.full.get(*full as usize).unwrap(), NodeKind::Command { .. }) }
+            _ => { false }
         }
     }
 
@@ -218,6 +327,12 @@ impl SpecializedInterpreterEngine {
             NodeKind::Final => {
                 return CompactKind::Final
             }
+            NodeKind::Yield { .. } | NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. }
+                | NodeKind::Throw { .. } | NodeKind::PureCall { .. } => {
+                return CompactKind::Suspend
+            }
+            NodeKind::TailCall { .. } => {}
+            NodeKind::Try { .. } => { unreachable!("driver registers try as call") }
         }
         self.full_kind(kind)
     }
@@ -229,12 +344,12 @@ impl SpecializedInterpreterEngine {
                 if next == SmallNodeId(1) {
                     CompactKind::Set4N {
                         dst: small_ref(*dst).unwrap(),
-                        value: get_u32(Ref::Stack(0), bytes.as_slice()),
+                        value: Wrapping(u32::from_le_bytes(bytes.as_slice().try_into().unwrap())),
                     }
                 } else {
                     CompactKind::Set4 {
                         dst: small_ref(*dst).unwrap(),
-                        value: get_u32(Ref::Stack(0), bytes.as_slice()),
+                        value: Wrapping(u32::from_le_bytes(bytes.as_slice().try_into().unwrap())),
                         next,
                     }
                 }
@@ -309,18 +424,33 @@ impl SpecializedInterpreterEngine {
     }
 
     fn full_kind(&mut self, kind: NodeKind<NodeId>) -> CompactKind {
-        self.full.push(kind);
-        CompactKind::Full((self.full.len() - 1) as u32)
+        match self.free_full.pop() {
+            Some(id) => {
+                *self.full.get_mut(id as usize).unwrap() = kind;
+                CompactKind::Full(id)
+            }
+            None => {
+                self.full.push(kind);
+                CompactKind::Full((self.full.len() - 1) as u32)
+            }
+        }
     }
 }
 
 impl Engine for SpecializedInterpreterEngine {
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.register(id, kind) }
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool { self.run(state, stack) }
+    fn unregister(&mut self, id: NodeId) { self.unregister(id) }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.run(state, stack, globals) }
+    fn set_profiling(&mut self, profiling: bool) { self.set_profiling(profiling) }
+    fn profile(&self, id: NodeId) -> Option<NodeProfile> { self.profile.as_ref()?.get(id) }
+    fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.set_observer(observer) }
+    fn set_fuel(&mut self, fuel: Option<u64>) { self.fuel.set(fuel) }
+    fn fuel(&self) -> Option<u64> { self.fuel.get() }
+    fn stats(&self) -> EngineStats { self.stats() }
 }
 
 #[test]
 fn test_sizes() {
     assert_eq!(8, std::mem::size_of::<CompactKind>());
-    assert_eq!(40, std::mem::size_of::<NodeKind<NodeId>>());
+    assert_eq!(48, std::mem::size_of::<NodeKind<NodeId>>());
 }
\ No newline at end of file
@@ -0,0 +1,358 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use crate::core::api::{Command, Condition, Node, NodeKind, Ref, RmwOp, VecOp, VecShape};
+use crate::core::utils::traverse_node;
+
+// Ahead of time translation of node graph into self-contained C file.
+//
+// Every call target (and the root node) becomes separate C function `static void f<idx>(uint8_t *stack)`,
+// where `stack` points to the start of the frame, the same way `offset` works for `Call` in interpreter.
+// Every node reachable from call target without going through `call` becomes label inside of this function,
+// so nodes shared between functions are duplicated.
+// Values are stored in little endian order, loads/stores go through `memcpy` so unaligned refs are fine.
+// `Try` installs `setjmp` handler around the call, `Throw` jumps to the innermost one with pointer to payload.
+// Calls go through `run` trampoline, `TailCall` leaves the call to it after returning, so tail recursion doesn't grow native stack.
+//
+// `Ref::Global` goes through `globals` pointer, which is set on entry.
+//
+// Resulting file exposes single function `void <entry>(uint8_t *stack, uint8_t *globals)`.
+pub fn translate_to_c<N: Node>(node: N, entry: &str) -> String {
+    let kinds: HashMap<N, NodeKind<N>> = traverse_node(node.clone()).into_iter()
+        .map(|node| { let kind = node.get(); (node, kind) })
+        .collect();
+    let mut translator = Translator { kinds, labels: HashMap::new(), functions: HashMap::new(), pending: vec![] };
+
+    let root = translator.function(&node);
+    let mut bodies = String::new();
+    while let Some(function) = translator.pending.pop() {
+        translator.function_body(function, &mut bodies);
+    }
+
+    let mut out = String::new();
+    out.push_str(PRELUDE);
+    (0..translator.functions.len()).for_each(|idx| writeln!(out, "static void f{}(uint8_t *stack);", idx).unwrap());
+    out.push('\n');
+    out.push_str(&bodies);
+    writeln!(out, "void {}(uint8_t *stack, uint8_t *area) {{ globals = area; run(f{}, stack); }}", entry, root).unwrap();
+    out
+}
+
+const PRELUDE: &str = "#include <setjmp.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+static inline uint32_t ld4(const uint8_t *p) { uint32_t v; memcpy(&v, p, 4); return v; }
+static inline uint64_t ld8(const uint8_t *p) { uint64_t v; memcpy(&v, p, 8); return v; }
+static inline void st4(uint8_t *p, uint32_t v) { memcpy(p, &v, 4); }
+static inline void st8(uint8_t *p, uint64_t v) { memcpy(p, &v, 8); }
+static inline uint64_t ldn(const uint8_t *p, size_t n) { uint64_t v = 0; memcpy(&v, p, n); return v; }
+static inline void stn(uint8_t *p, uint64_t v, size_t n) { memcpy(p, &v, n); }
+
+typedef uint8_t u8x16 __attribute__((vector_size(16)));
+typedef uint16_t u16x8 __attribute__((vector_size(16)));
+typedef uint32_t u32x4 __attribute__((vector_size(16)));
+typedef uint64_t u64x2 __attribute__((vector_size(16)));
+typedef int8_t i8x16 __attribute__((vector_size(16)));
+typedef int16_t i16x8 __attribute__((vector_size(16)));
+typedef int32_t i32x4 __attribute__((vector_size(16)));
+typedef int64_t i64x2 __attribute__((vector_size(16)));
+typedef float f32x4 __attribute__((vector_size(16)));
+typedef double f64x2 __attribute__((vector_size(16)));
+
+static uint8_t *globals;
+static jmp_buf *handler;
+static const uint8_t *thrown;
+static uint32_t thrown_size;
+static void (*tail)(uint8_t *stack);
+static uint8_t *tail_stack;
+
+// calls `f` along with tail calls made by it
+static void run(void (*f)(uint8_t *stack), uint8_t *stack) {
+  f(stack);
+  while (tail) { f = tail; tail = NULL; f(tail_stack); }
+}
+
+";
+
+struct Translator<N: Node> {
+    kinds: HashMap<N, NodeKind<N>>,
+    labels: HashMap<N, usize>,
+    functions: HashMap<N, usize>,
+    // functions which were referenced but body wasn't emitted yet
+    pending: Vec<N>,
+}
+
+impl<N: Node> Translator<N> {
+    fn function(&mut self, node: &N) -> usize {
+        if let Some(idx) = self.functions.get(node) { return *idx; }
+        let idx = self.functions.len();
+        self.functions.insert(node.clone(), idx);
+        self.pending.push(node.clone());
+        idx
+    }
+
+    fn label(&mut self, node: &N) -> usize {
+        if let Some(idx) = self.labels.get(node) { return *idx; }
+        let idx = self.labels.len();
+        self.labels.insert(node.clone(), idx);
+        idx
+    }
+
+    fn function_body(&mut self, entry: N, out: &mut String) {
+        writeln!(out, "static void f{}(uint8_t *stack) {{", self.functions.get(&entry).unwrap()).unwrap();
+
+        let mut emitted: HashSet<N> = HashSet::new();
+        let mut queue = vec![entry];
+        while let Some(node) = queue.pop() {
+            if !emitted.insert(node.clone()) { continue; }
+
+            let label = self.label(&node);
+            write!(out, "  n{}: ", label).unwrap();
+            match self.kinds.get(&node).unwrap().clone() {
+                NodeKind::Command { command, next } => {
+                    out.push_str(&Self::command(&command));
+                    writeln!(out, " goto n{};", self.label(&next)).unwrap();
+                    queue.push(next);
+                }
+                NodeKind::Branch { condition, if_true, if_false } => {
+                    writeln!(out, "if ({}) goto n{}; else goto n{};",
+                             Self::condition(&condition), self.label(&if_true), self.label(&if_false)).unwrap();
+                    queue.push(if_false);
+                    queue.push(if_true);
+                }
+                NodeKind::Call { offset, call, next } | NodeKind::PureCall { offset, call, next, .. } => {
+                    let function = self.function(&call);
+                    writeln!(out, "run(f{}, stack + {}); goto n{};", function, offset, self.label(&next)).unwrap();
+                    queue.push(next);
+                }
+                NodeKind::Final => {
+                    writeln!(out, "return;").unwrap();
+                }
+                NodeKind::TailCall { offset, call } => {
+                    let function = self.function(&call);
+                    writeln!(out, "tail = f{}; tail_stack = stack + {}; return;", function, offset).unwrap();
+                }
+                NodeKind::Try { offset, call, next, catch } => {
+                    let function = self.function(&call);
+                    writeln!(out, "{{ jmp_buf buf; jmp_buf *outer = handler; handler = &buf; \
+                                   if (!setjmp(buf)) {{ run(f{}, stack + {}); handler = outer; goto n{}; }} \
+                                   handler = outer; memmove(stack + {}, thrown, thrown_size); goto n{}; }}",
+                             function, offset, self.label(&next), offset, self.label(&catch)).unwrap();
+                    queue.push(catch);
+                    queue.push(next);
+                }
+                NodeKind::Throw { op, size } => {
+                    writeln!(out, "if (!handler) abort(); thrown = {}; thrown_size = {}; longjmp(*handler, 1);", Self::ptr(op), size).unwrap();
+                }
+                // translated code runs to completion, same as `interpreter::eval`
+                NodeKind::Yield { next } => {
+                    writeln!(out, "goto n{};", self.label(&next)).unwrap();
+                    queue.push(next);
+                }
+                // there is no host to run it, nor a way to suspend coroutines
+                NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. } => {
+                    writeln!(out, "abort();").unwrap();
+                }
+            }
+        }
+
+        out.push_str("}\n\n");
+    }
+
+    fn command(command: &Command) -> String {
+        match command {
+            Command::Noop => { ";".to_string() }
+            Command::PoisonFrom { .. } => { ";".to_string() }
+            Command::Set { dst, bytes } => {
+                let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+                format!("memcpy({}, (const uint8_t[]){{{}}}, {});", Self::ptr(*dst), bytes.join(", "), bytes.len())
+            }
+            Command::Copy { dst, size, op } => {
+                format!("memmove({}, {}, {});", Self::ptr(*dst), Self::ptr(*op), size)
+            }
+            Command::Add { size, dst, op1, op2 } => { Self::binary_op(*size, "+", *dst, *op1, *op2) }
+            Command::Sub { size, dst, op1, op2 } => { Self::binary_op(*size, "-", *dst, *op1, *op2) }
+            // stack isn't shared and might be unaligned, so only globals go through atomic builtins
+            Command::AtomicLoad { size, dst, mem: mem @ Ref::Stack(_) } => { format!("memmove({}, {}, {});", Self::ptr(*dst), Self::ptr(*mem), size) }
+            Command::AtomicStore { size, mem: mem @ Ref::Stack(_), op } => { format!("memmove({}, {}, {});", Self::ptr(*mem), Self::ptr(*op), size) }
+            Command::AtomicRmw { size, op, dst, mem: mem @ Ref::Stack(_), value } => {
+                let result = match op {
+                    RmwOp::Add => { "p + v" }
+                    RmwOp::Sub => { "p - v" }
+                    RmwOp::And => { "p & v" }
+                    RmwOp::Or => { "p | v" }
+                    RmwOp::Xor => { "p ^ v" }
+                    RmwOp::Xchg => { "v" }
+                };
+                format!("{{ uint{}_t v = {}, p = {}; st{}({}, {}); st{}({}, p); }}",
+                        size * 8, Self::load(*size, *value), Self::load(*size, *mem), size, Self::ptr(*mem), result, size, Self::ptr(*dst))
+            }
+            Command::AtomicCmpXchg { size, mem: mem @ Ref::Stack(_), expected, replacement } => {
+                format!("{{ uint{}_t e = {}, r = {}, p = {}; if (p == e) st{}({}, r); st{}({}, p); }}",
+                        size * 8, Self::load(*size, *expected), Self::load(*size, *replacement), Self::load(*size, *mem),
+                        size, Self::ptr(*mem), size, Self::ptr(*expected))
+            }
+            Command::AtomicLoad { size, dst, mem } => {
+                format!("st{}({}, __atomic_load_n({}, __ATOMIC_SEQ_CST));", size, Self::ptr(*dst), Self::atomic_ptr(*size, *mem))
+            }
+            Command::AtomicStore { size, mem, op } => {
+                format!("__atomic_store_n({}, {}, __ATOMIC_SEQ_CST);", Self::atomic_ptr(*size, *mem), Self::load(*size, *op))
+            }
+            Command::AtomicRmw { size, op, dst, mem, value } => {
+                let function = match op {
+                    RmwOp::Add => { "__atomic_fetch_add" }
+                    RmwOp::Sub => { "__atomic_fetch_sub" }
+                    RmwOp::And => { "__atomic_fetch_and" }
+                    RmwOp::Or => { "__atomic_fetch_or" }
+                    RmwOp::Xor => { "__atomic_fetch_xor" }
+                    RmwOp::Xchg => { "__atomic_exchange_n" }
+                };
+                format!("st{}({}, {}({}, {}, __ATOMIC_SEQ_CST));", size, Self::ptr(*dst), function, Self::atomic_ptr(*size, *mem), Self::load(*size, *value))
+            }
+            // builtin writes previous value to `e` if it differs
+            Command::AtomicCmpXchg { size, mem, expected, replacement } => {
+                format!("{{ uint{}_t e = {}; __atomic_compare_exchange_n({}, &e, {}, 0, __ATOMIC_SEQ_CST, __ATOMIC_SEQ_CST); st{}({}, e); }}",
+                        size * 8, Self::load(*size, *expected), Self::atomic_ptr(*size, *mem), Self::load(*size, *replacement), size, Self::ptr(*expected))
+            }
+            Command::Fence => { "__atomic_thread_fence(__ATOMIC_SEQ_CST);".to_string() }
+            // vector extensions of C compiler, comparisons give -1 in lanes where they hold
+            Command::VecBinary { op, shape, dst, op1, op2 } => {
+                let (tpe, operator) = match op {
+                    VecOp::Add => { (Self::vec_type(*shape, false), "+") }
+                    VecOp::Sub => { (Self::vec_type(*shape, false), "-") }
+                    VecOp::Mul => { (Self::vec_type(*shape, false), "*") }
+                    VecOp::Eq => { (Self::vec_type(*shape, false), "==") }
+                    VecOp::Ne => { (Self::vec_type(*shape, false), "!=") }
+                    VecOp::Lt => { (Self::vec_type(*shape, true), "<") }
+                    VecOp::Le => { (Self::vec_type(*shape, true), "<=") }
+                    VecOp::Gt => { (Self::vec_type(*shape, true), ">") }
+                    VecOp::Ge => { (Self::vec_type(*shape, true), ">=") }
+                    VecOp::LtU => { (Self::vec_type(*shape, false), "<") }
+                    VecOp::LeU => { (Self::vec_type(*shape, false), "<=") }
+                    VecOp::GtU => { (Self::vec_type(*shape, false), ">") }
+                    VecOp::GeU => { (Self::vec_type(*shape, false), ">=") }
+                };
+                format!("{{ {} a, b; memcpy(&a, {}, 16); memcpy(&b, {}, 16); __typeof__(a {} b) r = a {} b; memcpy({}, &r, 16); }}",
+                        tpe, Self::ptr(*op1), Self::ptr(*op2), operator, operator, Self::ptr(*dst))
+            }
+            // lane is copied aside first, since it usually overlaps with destination
+            Command::VecSplat { shape, dst, op } => {
+                let size = shape.lane_size();
+                format!("{{ uint8_t t[8]; memcpy(t, {}, {}); for (int i = 0; i < 16; i += {}) memcpy({} + i, t, {}); }}", Self::ptr(*op), size, size, Self::ptr(*dst), size)
+            }
+            Command::VecExtractLane { shape, signed, lane, dst, op } => {
+                let size = shape.lane_size();
+                let src = format!("{} + {}", Self::ptr(*op), *lane as u32 * size);
+                match size {
+                    1 | 2 => {
+                        format!("{{ {}int{}_t x; memcpy(&x, {}, {}); st4({}, (uint32_t) x); }}", if *signed { "" } else { "u" }, size * 8, src, size, Self::ptr(*dst))
+                    }
+                    _ => { format!("memmove({}, {}, {});", Self::ptr(*dst), src, size) }
+                }
+            }
+            Command::VecReplaceLane { shape, lane, dst, op, value } => {
+                let size = shape.lane_size();
+                format!("{{ uint8_t t[16]; memcpy(t, {}, 16); memcpy(t + {}, {}, {}); memcpy({}, t, 16); }}",
+                        Self::ptr(*op), *lane as u32 * size, Self::ptr(*value), size, Self::ptr(*dst))
+            }
+            Command::VecShuffle { lanes, dst, op1, op2 } => {
+                let lanes: Vec<String> = lanes.iter().map(|lane| lane.to_string()).collect();
+                format!("{{ static const uint8_t l[16] = {{{}}}; uint8_t t[32], r[16]; memcpy(t, {}, 16); memcpy(t + 16, {}, 16); \
+                         for (int i = 0; i < 16; i++) r[i] = t[l[i]]; memcpy({}, r, 16); }}",
+                        lanes.join(", "), Self::ptr(*op1), Self::ptr(*op2), Self::ptr(*dst))
+            }
+        }
+    }
+
+    // lanes of integer shapes are signed for signed comparisons only, so other operations wrap around
+    fn vec_type(shape: VecShape, signed: bool) -> &'static str {
+        match (shape, signed) {
+            (VecShape::I8x16, false) => { "u8x16" }
+            (VecShape::I16x8, false) => { "u16x8" }
+            (VecShape::I32x4, false) => { "u32x4" }
+            (VecShape::I64x2, false) => { "u64x2" }
+            (VecShape::I8x16, true) => { "i8x16" }
+            (VecShape::I16x8, true) => { "i16x8" }
+            (VecShape::I32x4, true) => { "i32x4" }
+            (VecShape::I64x2, true) => { "i64x2" }
+            (VecShape::F32x4, _) => { "f32x4" }
+            (VecShape::F64x2, _) => { "f64x2" }
+        }
+    }
+
+    // globals are aligned, see `Globals`
+    fn atomic_ptr(size: u32, mem: Ref) -> String { format!("(uint{}_t *) ({})", size * 8, Self::ptr(mem)) }
+
+    fn binary_op(size: u32, op: &str, dst: Ref, op1: Ref, op2: Ref) -> String {
+        Self::store(size, dst, &format!("{} {} {}", Self::load(size, op1), op, Self::load(size, op2)))
+    }
+
+    fn condition(condition: &Condition) -> String {
+        match condition {
+            Condition::Ne { size, op1, op2 } => { format!("{} != {}", Self::load(*size, *op1), Self::load(*size, *op2)) }
+            Condition::Ne0 { size, op } => { format!("{} != 0", Self::load(*size, *op)) }
+        }
+    }
+
+    // value of up to 8 bytes, zero extended to `uint64_t` if it's not 4 or 8 bytes
+    fn load(size: u32, op: Ref) -> String {
+        match size {
+            4 | 8 => { format!("ld{}({})", size, Self::ptr(op)) }
+            1..8 => { format!("ldn({}, {})", Self::ptr(op), size) }
+            _ => { todo!("unsupported size: {}", size) }
+        }
+    }
+
+    // low `size` bytes of value
+    fn store(size: u32, dst: Ref, value: &str) -> String {
+        match size {
+            4 | 8 => { format!("st{}({}, {});", size, Self::ptr(dst), value) }
+            1..8 => { format!("stn({}, {}, {});", Self::ptr(dst), value, size) }
+            _ => { todo!("unsupported size: {}", size) }
+        }
+    }
+
+    fn ptr(r: Ref) -> String {
+        match r {
+            Ref::Stack(offset) => { format!("stack + {}", offset) }
+            Ref::Global(offset) => { format!("globals + {}", offset) }
+        }
+    }
+}
+
+// Translates node, compiles it with system C compiler and runs on given stack.
+// Used by tests to compare translated code against engines, requires `cc` in PATH. There are no globals.
+pub fn compile_and_eval<N: Node>(node: N, stack: &mut [u8]) {
+    use std::process::Command as Process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!("leshy-c-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut source = translate_to_c(node, "leshy_entry");
+    writeln!(source, "
+#include <stdio.h>
+#include <stdlib.h>
+int main(int argc, char **argv) {{
+  size_t size = argc - 1;
+  uint8_t *stack = calloc(size, 1);
+  for (size_t i = 0; i < size; i++) stack[i] = (uint8_t) atoi(argv[i + 1]);
+  leshy_entry(stack, NULL);
+  for (size_t i = 0; i < size; i++) printf(\"%d \", stack[i]);
+  return 0;
+}}").unwrap();
+    std::fs::write(dir.join("main.c"), source).unwrap();
+
+    // -O1 doesn't turn calls in tail position into jumps by itself, so translated tail calls are checked as they are
+    let compiled = Process::new("cc").arg("-O1").arg("-o").arg(dir.join("main")).arg(dir.join("main.c")).output().unwrap();
+    assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+
+    let output = Process::new(dir.join("main")).args(stack.iter().map(|byte| byte.to_string())).output().unwrap();
+    assert!(output.status.success());
+    let result: Vec<u8> = String::from_utf8(output.stdout).unwrap().split_whitespace().map(|byte| byte.parse().unwrap()).collect();
+    stack.copy_from_slice(result.as_slice());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
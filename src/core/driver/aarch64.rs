@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicU64;
 use dynasm::dynasm;
 use dynasmrt::aarch64::Aarch64Relocation;
 use dynasmrt::{AssemblyOffset, DynasmApi, VecAssembler};
@@ -6,9 +8,11 @@ use dynasmrt::relocations::Relocation;
 use dynasmrt::mmap::MutableBuffer;
 use lazy_static::lazy_static;
 use libc::size_t;
-use crate::core::api::{Command, Condition, NodeKind, Ref};
+use crate::core::api::{Command, Condition, NodeKind, Ref, RmwOp, VecOp, VecShape};
 use crate::core::driver::code_generator_engine::ReturnInfo;
 use crate::core::driver::driver::NodeId;
+use crate::core::driver::profile::Counters;
+use crate::core::driver::snapshot::{SnapshotReader, SnapshotWriter};
 use dynasmrt::DynasmLabelApi;
 
 macro_rules! asm {
@@ -18,8 +22,10 @@ macro_rules! asm {
             ; .alias data_stack, x0
             ; .alias unwind_stack, x2
             ; .alias unwind_stack_end, x0
+            ; .alias tail_shift, x12
             ; .alias lr, x30
             ; .alias store_tmp, w15
+            ; .alias globals, x20
             $($t)*
         )
     }
@@ -30,70 +36,371 @@ lazy_static! {
         let mut m = HashMap::new();
         // todo: got this from debuging internals of generated dynasm code. figure out where it comes from in macro!
         m.insert("ne", vec![1, 0, 0, 84]);
+        m.insert("mi", vec![4, 0, 0, 84]);
         m
     };
 }
 
-pub fn insert_debug<T: DynasmApi>(api: &mut T, id: NodeId, debug_fn: extern "C" fn (*const u8, *const u8, *const u8, u64)) {
-    // save x0, x1, x2, x3, x4, lr; put id to x3; put debug_fn to x4; call x4; restore
+// Observer hooks called from generated code: `handler(observer, event, id, arg, frame start, stack end)`.
+#[derive(Clone, Copy)]
+pub struct Callout {
+    pub handler: extern "C" fn(u64, u32, u32, u64, *const u8, *const u8),
+    pub observer: u64,
+}
+
+pub const ON_NODE: u32 = 0;
+pub const ON_COMMAND: u32 = 1;
+pub const ON_BRANCH: u32 = 2;
+pub const ON_CALL: u32 = 3;
+pub const ON_RETURN: u32 = 4;
+
+// Calls hook with `arg`, or x9 if it's `None`. Registers except x16 & x17 are preserved, flags are not.
+fn insert_callout<T: DynasmApi>(api: &mut T, callout: Callout, event: u32, id: NodeId, arg: Option<u64>) {
+    // x3..x7 might hold cached stack slots, x9 holds argument of branch hook
     asm!(api
         ; stp x0, x1, [sp, #-16]!
         ; stp x2, x3, [sp, #-16]!
-        ; stp x4, lr, [sp, #-16]!
+        ; stp x4, x5, [sp, #-16]!
+        ; stp x6, x7, [sp, #-16]!
+        ; stp x8, x9, [sp, #-16]!
+        ; stp tail_shift, lr, [sp, #-16]!
+        ; mov x4, x0
+        ; mov x5, x1
     );
-    mov_u64(api, 3, id.0 as u64);
-    mov_u64(api, 4, debug_fn as u64);
+    match arg {
+        Some(arg) => { mov_u64(api, 3, arg) }
+        None => { asm!(api ; mov x3, x9) }
+    }
+    mov_u64(api, 0, callout.observer);
+    mov_u32(api, 1, event);
+    mov_u32(api, 2, id.0);
+    mov_u64(api, 16, callout.handler as u64);
     asm!(api
-        ; blr x4
-        ; ldp x4, lr, [sp], #16
+        ; blr x16
+        ; ldp tail_shift, lr, [sp], #16
+        ; ldp x8, x9, [sp], #16
+        ; ldp x6, x7, [sp], #16
+        ; ldp x4, x5, [sp], #16
         ; ldp x2, x3, [sp], #16
         ; ldp x0, x1, [sp], #16
     );
 }
 
+// Entry point of generated code: `trampoline(stack start, stack end, unwind, fuel, code, globals)`.
+// Fuel is kept in x8 while generated code runs and written back on exit, x19 holds its address.
+// Neither is atomic: concurrent runs sharing the engine (see `SharedDriver`) overwrite each other's fuel on exit,
+// so fuel limits runs of one thread exactly and of several ones approximately.
+// `globals` holds start of global data area for `Ref::Global`.
+// `tail_shift` is distance current frame was moved by tail calls, it's 0 on entry to every frame.
+pub fn trampoline<T: DynasmApi>(api: &mut T) {
+    asm!(api
+        ; stp x29, lr, [sp, #-16]!
+        ; stp x19, x20, [sp, #-16]!
+        ; mov x19, x3
+        ; mov globals, x5
+        ; mov tail_shift, xzr
+        ; ldr x8, [x19]
+        ; blr x4
+        ; str x8, [x19]
+        ; ldp x19, x20, [sp], #16
+        ; ldp x29, lr, [sp], #16
+        ; ret
+    );
+}
+
+// Entry of node used by jumps, calls and engine: takes one unit of fuel if `metered`, loads cached slots
+// and jumps to node code at `start`. Node placed right after its predecessor is entered without it.
+// Running out of fuel suspends on the node, so it's entered through the same entry again after refueling.
+// It charges x8 only, which isn't shared with other runs, see `trampoline`.
+pub fn entry<T: DynasmApi>(api: &mut T, id: NodeId, cache: &RegisterCache, metered: bool, start: AssemblyOffset) {
+    if metered {
+        asm!(api
+            ; subs x8, x8, 1
+        );
+        bcond(api, "mi", ((cache.len() + 2) * 4) as isize);
+    }
+    cache.prologue(api);
+    let from = api.offset();
+    b(api, start.0 as isize - from.0 as isize);
+    if metered { suspend(api, id) }
+}
+
+// 0 if node can be entered at `start` directly
+pub fn entry_size(cache: &RegisterCache, metered: bool) -> usize {
+    if !metered && cache.is_empty() { return 0; }
+    (cache.len() + 1) * 4 + if metered { 9 * 4 } else { 0 }
+}
+
+// Stack slots cached in registers between commands.
+// Values loaded from data stack stay in registers and results are written only to registers ("dirty" slots),
+// dirty slots are spilled to data stack at branches, calls, final and suspension points.
+// State at the end of node is kept in `ReturnInfo`, so if next node is generated right after (fall through),
+// it continues with the same registers. Every other way to enter the node (patched jumps, engine) goes through
+// prologue which loads cached slots from data stack.
+const CACHE_REGISTERS: [u32; 5] = [3, 4, 5, 6, 7];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegisterCache {
+    slots: Vec<CachedSlot>,
+    tick: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CachedSlot {
+    register: u32,
+    offset: u32,
+    size: u32,
+    dirty: bool,
+    last_used: u32,
+}
+
+impl CachedSlot {
+    fn overlaps(&self, offset: u32, size: u32) -> bool {
+        self.offset < offset + size && offset < self.offset + self.size
+    }
+}
+
+impl RegisterCache {
+    pub fn new() -> RegisterCache { RegisterCache::default() }
+
+    pub fn len(&self) -> usize { self.slots.len() }
+
+    pub fn is_empty(&self) -> bool { self.slots.is_empty() }
+
+    pub fn write(&self, writer: &mut SnapshotWriter) {
+        writer.u32(self.tick);
+        writer.usize(self.slots.len());
+        self.slots.iter().for_each(|slot| {
+            writer.u32(slot.register);
+            writer.u32(slot.offset);
+            writer.u32(slot.size);
+            writer.u8(slot.dirty as u8);
+            writer.u32(slot.last_used);
+        });
+    }
+
+    pub fn read(reader: &mut SnapshotReader) -> io::Result<RegisterCache> {
+        let tick = reader.u32()?;
+        let slots = (0..reader.usize()?).map(|_| Ok(CachedSlot {
+            register: reader.u32()?,
+            offset: reader.u32()?,
+            size: reader.u32()?,
+            dirty: reader.u8()? != 0,
+            last_used: reader.u32()?,
+        })).collect::<io::Result<Vec<CachedSlot>>>()?;
+        let valid = |slot: &CachedSlot| CACHE_REGISTERS.contains(&slot.register) && Self::cacheable(slot.size, Ref::Stack(slot.offset)).is_some();
+        if slots.len() > CACHE_REGISTERS.len() || !slots.iter().all(valid) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed register cache"));
+        }
+        Ok(RegisterCache { slots, tick })
+    }
+
+    // only slots accessible with single `ldr`/`str` are cached, so prologue is one instruction per slot
+    fn cacheable(size: u32, op: Ref) -> Option<u32> {
+        match op {
+            Ref::Stack(offset) => {
+                if (size == 4 || size == 8) && offset <= 255 && offset.is_multiple_of(size) { Some(offset) } else { None }
+            }
+            // globals might be changed by other threads, so they're always accessed in memory
+            Ref::Global(_) => { None }
+        }
+    }
+
+    // returns register containing `size` bytes from `op`, `tmp` is used if `op` can't be cached
+    fn load<T: DynasmApi>(&mut self, api: &mut T, size: u32, op: Ref, tmp: u32, pinned: &[u32]) -> u32 {
+        match Self::cacheable(size, op) {
+            None => {
+                self.spill_overlapping(api, op, size);
+                load(api, size, tmp, op);
+                tmp
+            }
+            Some(offset) => {
+                self.tick += 1;
+                if let Some(slot) = self.slots.iter_mut().find(|slot| slot.offset == offset && slot.size == size) {
+                    slot.last_used = self.tick;
+                    return slot.register;
+                }
+                self.spill_overlapping(api, op, size);
+                let register = self.allocate(api, pinned);
+                load(api, size, register, op);
+                self.slots.push(CachedSlot { register, offset, size, dirty: false, last_used: self.tick });
+                register
+            }
+        }
+    }
+
+    // `compute` writes value into provided register, which becomes new (not yet written) value of `dst`
+    fn store<T: DynasmApi, F: FnOnce(&mut T, u32)>(&mut self, api: &mut T, size: u32, dst: Ref, pinned: &[u32], compute: F) {
+        match Self::cacheable(size, dst) {
+            None => {
+                self.drop_overlapping(api, dst, size, None);
+                compute(api, 11);
+                store(api, size, 11, dst);
+            }
+            Some(offset) => {
+                self.tick += 1;
+                self.drop_overlapping(api, dst, size, Some(offset));
+                let register = match self.slots.iter().position(|slot| slot.offset == offset && slot.size == size) {
+                    Some(pos) => { self.slots.remove(pos).register }
+                    None => { self.allocate(api, pinned) }
+                };
+                compute(api, register);
+                self.slots.push(CachedSlot { register, offset, size, dirty: true, last_used: self.tick });
+            }
+        }
+    }
+
+    // stores dirty slots to data stack, state itself isn't changed: used on paths leaving node
+    fn spill<T: DynasmApi>(&self, api: &mut T) {
+        self.slots.iter().filter(|slot| slot.dirty).for_each(|slot| {
+            store(api, slot.size, slot.register, Ref::Stack(slot.offset))
+        });
+    }
+
+    fn spill_all<T: DynasmApi>(&mut self, api: &mut T) {
+        self.spill(api);
+        self.slots.iter_mut().for_each(|slot| slot.dirty = false);
+    }
+
+    // loads all cached slots, exactly one instruction per slot
+    pub fn prologue<T: DynasmApi>(&self, api: &mut T) {
+        self.slots.iter().for_each(|slot| load(api, slot.size, slot.register, Ref::Stack(slot.offset)));
+    }
+
+    fn spill_overlapping<T: DynasmApi>(&mut self, api: &mut T, op: Ref, size: u32) {
+        let Ref::Stack(offset) = op else { return; };
+        for slot in self.slots.iter_mut() {
+            if slot.dirty && slot.overlaps(offset, size) {
+                store(api, slot.size, slot.register, Ref::Stack(slot.offset));
+                slot.dirty = false;
+            }
+        }
+    }
+
+    // removes all slots overlapping with `dst` except exact match
+    fn drop_overlapping<T: DynasmApi>(&mut self, api: &mut T, dst: Ref, size: u32, exact: Option<u32>) {
+        let Ref::Stack(offset) = dst else { return; };
+        let mut idx = 0;
+        while idx < self.slots.len() {
+            let slot = self.slots[idx];
+            if slot.overlaps(offset, size) && !(exact == Some(slot.offset) && slot.size == size) {
+                if slot.dirty { store(api, slot.size, slot.register, Ref::Stack(slot.offset)) }
+                self.slots.remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+
+    fn allocate<T: DynasmApi>(&mut self, api: &mut T, pinned: &[u32]) -> u32 {
+        if let Some(register) = CACHE_REGISTERS.iter()
+            .find(|register| !pinned.contains(register) && !self.slots.iter().any(|slot| slot.register == **register)) {
+            return *register;
+        }
+        let (pos, slot) = self.slots.iter().enumerate()
+            .filter(|(_, slot)| !pinned.contains(&slot.register))
+            .min_by_key(|(_, slot)| slot.last_used)
+            .unwrap();
+        if slot.dirty { store(api, slot.size, slot.register, Ref::Stack(slot.offset)) }
+        self.slots.remove(pos).register
+    }
+}
+
 // todo: kind should be more like NodeKind<NodeId | AssemblyOffset>
-pub fn generate<T: DynasmApi>(api: &mut T, kind: NodeKind<NodeId>) -> Vec<ReturnInfo> {
+// dirty slots are spilled before hooks, so observer sees actual stack
+pub fn generate<T: DynasmApi>(api: &mut T, id: NodeId, kind: NodeKind<NodeId>, cache: &mut RegisterCache, counters: Option<&Counters>, callout: Option<Callout>) -> Vec<ReturnInfo> {
+    if let Some(counters) = counters { increment(api, &counters.executions) }
+    if let Some(callout) = callout {
+        cache.spill_all(api);
+        insert_callout(api, callout, ON_NODE, id, Some(0));
+    }
     match kind {
         NodeKind::Command { command: command_value, next } => {
-            command(api, command_value);
-            vec![ret_suspend(api, next)]
+            command(api, command_value, cache);
+            if let Some(callout) = callout {
+                cache.spill_all(api);
+                insert_callout(api, callout, ON_COMMAND, id, Some(0));
+            }
+            vec![ret_suspend(api, next, cache)]
         }
         NodeKind::Branch { condition: condition_value, if_true, if_false } => {
+            compare(api, condition_value, cache);
+            if let Some(counters) = counters { increment_ne(api, &counters.taken) }
+            if let Some(callout) = callout {
+                asm!(api
+                    ; cset x9, ne
+                );
+                insert_callout(api, callout, ON_BRANCH, id, None);
+                asm!(api
+                    ; cmp x9, 0
+                );
+            }
             // todo: remove this const by calculation
-            condition(api, condition_value, 8 * 4);
+            bcond(api, "ne", 8 * 4);
             vec![
-                ret_suspend(api, if_false),
-                ret_suspend(api, if_true),
+                ret_suspend(api, if_false, cache),
+                ret_suspend(api, if_true, cache),
             ]
         }
         NodeKind::Call { offset, call, next } => {
+            cache.spill_all(api);
+            if let Some(callout) = callout {
+                insert_callout(api, callout, ON_CALL, id, Some(((call.0 as u64) << 32) | offset as u64));
+            }
             ret_call(api, offset, call, next)
         }
+        // moves current frame by `offset` and jumps to callee, its final returns from the frame
+        NodeKind::TailCall { offset, call } => {
+            cache.spill_all(api);
+            if let Some(callout) = callout {
+                insert_callout(api, callout, ON_CALL, id, Some(((call.0 as u64) << 32) | offset as u64));
+            }
+            mov_u32(api, 13, offset);
+            asm!(api
+                ; add data_stack, data_stack, x13
+                ; add tail_shift, tail_shift, x13
+            );
+            vec![ret_suspend(api, call, &RegisterCache::new())]
+        }
         NodeKind::Final => {
+            cache.spill_all(api);
+            if let Some(callout) = callout {
+                insert_callout(api, callout, ON_RETURN, id, Some(0));
+            }
             ret_final(api);
             vec![]
         }
+        // suspends on itself, driver continues past it, for throw native frames are already unwound by suspension
+        NodeKind::Yield { .. } | NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. }
+            | NodeKind::Throw { .. } | NodeKind::PureCall { .. } => {
+            cache.spill_all(api);
+            suspend(api, id);
+            vec![]
+        }
+        NodeKind::Try { .. } => { unreachable!("driver registers try as call") }
     }
 }
 
 fn ret_call<T: DynasmApi>(api: &mut T, offset: u32, call: NodeId, next: NodeId) -> Vec<ReturnInfo> {
-    // store `data_stack` and lc to stack
+    // store `tail_shift`, `data_stack` and lc to stack, callee starts with zero `tail_shift`
     // increase `data_stack` by offset
     // bl to ret_suspend_call
     //   ret_suspend call
     // if ret != 0 branch to unwind
     //   restore lc from stack
     //   `unwind_stack_end` is destination address to write (0, node id)
-    //   modify elements of unwind struct
+    //   restore `tail_shift`, modify elements of unwind struct
     //   increase `unwind_stack_end` by 8 & ret
-    // restore lc and `data_stack`
+    // restore lc, `data_stack` and `tail_shift`
     // ret_suspend next
 
     let mut intermediate: VecAssembler<Aarch64Relocation> = VecAssembler::new(0); // todo: not sure why we need baseaddr
     let mut infos: Vec<ReturnInfo> = Vec::new();
 
     asm!(intermediate
+            ; str tail_shift, [sp, #-16]!
             ; stp data_stack, lr, [sp, #-16]!
+            ; mov tail_shift, xzr
         );
     mov_u32(&mut intermediate, 13, offset);
     asm!(intermediate
@@ -102,31 +409,37 @@ fn ret_call<T: DynasmApi>(api: &mut T, offset: u32, call: NodeId, next: NodeId)
             ; cmp unwind_stack_end, unwind_stack
             ; b.ne >unwind
             ; ldp data_stack, lr, [sp], #16
+            ; ldr tail_shift, [sp], #16
         );
-    infos.push(ret_suspend(&mut intermediate, next));
+    infos.push(ret_suspend(&mut intermediate, next, &RegisterCache::new()));
 
     asm!(intermediate
             ; call:
         );
-    infos.push(ret_suspend(&mut intermediate, call));
+    infos.push(ret_suspend(&mut intermediate, call, &RegisterCache::new()));
 
     asm!(intermediate
             ; unwind:
         );
     mov_u32(&mut intermediate, 13, offset);
     mov_u32(&mut intermediate, 9, next.0);
+    // callee's outermost entry is relative to its frame, which might be moved by its tail calls
     // todo: remove unnesessary instructions
     asm!(intermediate
             ; ldp xzr, lr, [sp], #16
+            ; ldr tail_shift, [sp], #16
             ; sub unwind_stack_end, unwind_stack_end, 8
-            ; str w13, [unwind_stack_end]
+            ; ldr w10, [unwind_stack_end]
+            ; add w10, w10, w13
+            ; str w10, [unwind_stack_end]
             ; add unwind_stack_end, unwind_stack_end, 8
-            ; stp wzr, w9, [unwind_stack_end]
+            ; stp w12, w9, [unwind_stack_end]
             ; add unwind_stack_end, unwind_stack_end, 8
             ; ret
         );
 
     for info in &mut infos {
+        info.spill.0 += api.offset().0;
         info.from.0 += api.offset().0;
         info.to.0 += api.offset().0;
     }
@@ -142,130 +455,519 @@ fn ret_final<T: DynasmApi>(api: &mut T) {
     );
 }
 
-fn ret_suspend<T: DynasmApi>(api: &mut T, id: NodeId) -> ReturnInfo {
-    let mut return_info = ReturnInfo { id, from: api.offset(), to: AssemblyOffset(0) };
+fn ret_suspend<T: DynasmApi>(api: &mut T, id: NodeId, cache: &RegisterCache) -> ReturnInfo {
+    let spill = api.offset();
+    cache.spill(api);
+    let mut return_info = ReturnInfo { id, source: NodeId(0), chunk: 0, spill, from: api.offset(), to: AssemblyOffset(0), cache: cache.clone() };
+    suspend(api, id);
+    return_info.to = api.offset();
+    return_info
+}
+
+// bytes written by `suspend`
+pub const SUSPEND_SIZE: usize = 7 * 4;
 
+// writes (tail shift, id) into unwind stack and returns, 7 instructions, jump to another node is written over it
+pub fn suspend<T: DynasmApi>(api: &mut T, id: NodeId) {
     mov_u32(api, 0, 1); // 1 element written
     mov_u32(api, 9, id.0);
     asm!(api
-        ; stp wzr, w9, [unwind_stack, 0]
+        ; stp w12, w9, [unwind_stack, 0]
         ; add unwind_stack_end, unwind_stack, 8
         ; ret
     );
-
-    return_info.to = api.offset();
-    return_info
 }
 
-fn command<T: DynasmApi>(api: &mut T, command: Command) {
+fn command<T: DynasmApi>(api: &mut T, command: Command, cache: &mut RegisterCache) {
     match command {
-        Command::Noop => { panic!("can't happen") }
+        // landing nodes of driver
+        Command::Noop => {}
         Command::PoisonFrom { .. } => { panic!("can't happen") }
-        Command::Set { dst, bytes } => { set(api, dst, bytes) }
-        Command::Copy { dst, size, op } => { copy(api, size, dst, op) }
-        Command::Add { size, dst, op1, op2 } => { add(api, size, dst, op1, op2) }
-        Command::Sub { size, dst, op1, op2 } => { sub(api, size, dst, op1, op2) }
+        Command::Set { dst, bytes } => { set(api, dst, bytes, cache) }
+        Command::Copy { dst, size, op } => { copy(api, size, dst, op, cache) }
+        Command::Add { size, dst, op1, op2 } => { add(api, size, dst, op1, op2, cache) }
+        Command::Sub { size, dst, op1, op2 } => { sub(api, size, dst, op1, op2, cache) }
+        // stack isn't shared, so atomics on it are plain operations on cached slots
+        Command::AtomicLoad { size, dst, mem: mem @ Ref::Stack(_) } => { copy(api, size, dst, mem, cache) }
+        Command::AtomicStore { size, mem: mem @ Ref::Stack(_), op } => { copy(api, size, mem, op, cache) }
+        Command::AtomicRmw { size, op, dst, mem: mem @ Ref::Stack(_), value } => { stack_rmw(api, size, op, dst, mem, value, cache) }
+        Command::AtomicCmpXchg { size, mem: mem @ Ref::Stack(_), expected, replacement } => {
+            stack_cmpxchg(api, size, mem, expected, replacement, cache)
+        }
+        Command::AtomicLoad { size, dst, mem: Ref::Global(offset) } => { atomic_load(api, size, dst, offset, cache) }
+        Command::AtomicStore { size, mem: Ref::Global(offset), op } => { atomic_store(api, size, offset, op, cache) }
+        Command::AtomicRmw { size, op, dst, mem: Ref::Global(offset), value } => { atomic_rmw(api, size, op, dst, offset, value, cache) }
+        Command::AtomicCmpXchg { size, mem: Ref::Global(offset), expected, replacement } => {
+            atomic_cmpxchg(api, size, offset, expected, replacement, cache)
+        }
+        Command::Fence => {
+            asm!(api
+                ; dmb ish
+            );
+        }
+        Command::VecBinary { op, shape, dst, op1, op2 } => { vec_binary(api, op, shape, dst, op1, op2, cache) }
+        Command::VecSplat { shape, dst, op } => { vec_splat(api, shape, dst, op, cache) }
+        Command::VecExtractLane { shape, signed, lane, dst, op } => { vec_extract_lane(api, shape, signed, lane, dst, op, cache) }
+        Command::VecReplaceLane { shape, lane, dst, op, value } => { vec_replace_lane(api, shape, lane, dst, op, value, cache) }
+        Command::VecShuffle { lanes, dst, op1, op2 } => { vec_shuffle(api, lanes, dst, op1, op2, cache) }
     }
 }
 
-fn set<T: DynasmApi>(api: &mut T, dst: Ref, bytes: Vec<u8>) {
+fn set<T: DynasmApi>(api: &mut T, dst: Ref, bytes: Vec<u8>, cache: &mut RegisterCache) {
     match bytes.len() {
         4 => {
-            mov_u32(api, 9, u32::from_le_bytes(bytes.as_slice().try_into().unwrap()));
-            store_u32(api, 9, dst);
+            let value = u32::from_le_bytes(bytes.as_slice().try_into().unwrap());
+            cache.store(api, 4, dst, &[], |api, register| mov_u32(api, register, value));
         }
         8 => {
-            mov_u64(api, 14, u64::from_le_bytes(bytes.as_slice().try_into().unwrap()));
-            store_u64(api, 14, dst);
+            let value = u64::from_le_bytes(bytes.as_slice().try_into().unwrap());
+            cache.store(api, 8, dst, &[], |api, register| mov_u64(api, register, value));
         }
         _ => { todo!() }
     }
 }
 
-fn copy<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op: Ref) {
+fn copy<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op: Ref, cache: &mut RegisterCache) {
+    match len {
+        4 | 8 => {
+            let op = cache.load(api, len, op, 9, &[]);
+            cache.store(api, len, dst, &[op], |api, register| {
+                if register != op {
+                    asm!(api
+                        ; mov X(register), X(op)
+                    );
+                }
+            });
+        }
+        // vectors
+        16 => {
+            vec_load(api, 17, op, cache);
+            vec_store(api, 17, dst, cache);
+        }
+        _ => { todo!() }
+    }
+}
+
+fn add<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op1: Ref, op2: Ref, cache: &mut RegisterCache) {
     match len {
         4 => {
-            load_u32(api, 9, op);
-            store_u32(api, 9, dst);
+            let op1 = cache.load(api, 4, op1, 9, &[]);
+            let op2 = cache.load(api, 4, op2, 10, &[op1]);
+            cache.store(api, 4, dst, &[op1, op2], |api, register| asm!(api
+                ; add W(register), W(op1), W(op2)
+            ));
         }
         8 => {
-            load_u64(api, 14, op);
-            store_u64(api, 14, dst);
+            let op1 = cache.load(api, 8, op1, 9, &[]);
+            let op2 = cache.load(api, 8, op2, 10, &[op1]);
+            cache.store(api, 8, dst, &[op1, op2], |api, register| asm!(api
+                ; add X(register), X(op1), X(op2)
+            ));
         }
         _ => { todo!() }
     }
 }
 
-fn add<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op1: Ref, op2: Ref) {
+fn sub<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op1: Ref, op2: Ref, cache: &mut RegisterCache) {
     match len {
         4 => {
-            load_u32(api, 9, op1);
-            load_u32(api, 10, op2);
+            let op1 = cache.load(api, 4, op1, 9, &[]);
+            let op2 = cache.load(api, 4, op2, 10, &[op1]);
+            cache.store(api, 4, dst, &[op1, op2], |api, register| asm!(api
+                ; sub W(register), W(op1), W(op2)
+            ));
+        }
+        _ => { todo!() }
+    }
+}
+
+// `register` = `op1` `op` `op2`
+fn rmw_op<T: DynasmApi>(api: &mut T, size: u32, op: RmwOp, register: u32, op1: u32, op2: u32) {
+    match (size, op) {
+        (4, RmwOp::Add) => { asm!(api ; add W(register), W(op1), W(op2)) }
+        (4, RmwOp::Sub) => { asm!(api ; sub W(register), W(op1), W(op2)) }
+        (4, RmwOp::And) => { asm!(api ; and W(register), W(op1), W(op2)) }
+        (4, RmwOp::Or) => { asm!(api ; orr W(register), W(op1), W(op2)) }
+        (4, RmwOp::Xor) => { asm!(api ; eor W(register), W(op1), W(op2)) }
+        (4, RmwOp::Xchg) => { asm!(api ; mov W(register), W(op2)) }
+        (8, RmwOp::Add) => { asm!(api ; add X(register), X(op1), X(op2)) }
+        (8, RmwOp::Sub) => { asm!(api ; sub X(register), X(op1), X(op2)) }
+        (8, RmwOp::And) => { asm!(api ; and X(register), X(op1), X(op2)) }
+        (8, RmwOp::Or) => { asm!(api ; orr X(register), X(op1), X(op2)) }
+        (8, RmwOp::Xor) => { asm!(api ; eor X(register), X(op1), X(op2)) }
+        (8, RmwOp::Xchg) => { asm!(api ; mov X(register), X(op2)) }
+        _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+    }
+}
+
+// previous value is kept in x14 while `mem` slot is replaced
+fn stack_rmw<T: DynasmApi>(api: &mut T, size: u32, op: RmwOp, dst: Ref, mem: Ref, value: Ref, cache: &mut RegisterCache) {
+    let value = cache.load(api, size, value, 9, &[]);
+    let previous = cache.load(api, size, mem, 10, &[value]);
+    asm!(api
+        ; mov x14, X(previous)
+    );
+    cache.store(api, size, mem, &[value], |api, register| rmw_op(api, size, op, register, 14, value));
+    cache.store(api, size, dst, &[], |api, register| asm!(api
+        ; mov X(register), x14
+    ));
+}
+
+// `mem` gets `replacement` or stays the same (csel), previous value is kept in x14
+fn stack_cmpxchg<T: DynasmApi>(api: &mut T, size: u32, mem: Ref, expected: Ref, replacement: Ref, cache: &mut RegisterCache) {
+    let expected_value = cache.load(api, size, expected, 9, &[]);
+    let replacement = cache.load(api, size, replacement, 10, &[expected_value]);
+    let previous = cache.load(api, size, mem, 14, &[expected_value, replacement]);
+    asm!(api
+        ; mov x14, X(previous)
+    );
+    cache.store(api, size, mem, &[expected_value, replacement], |api, register| {
+        match size {
+            4 => {
+                asm!(api
+                    ; cmp w14, W(expected_value)
+                    ; csel W(register), W(replacement), w14, eq
+                );
+            }
+            8 => {
+                asm!(api
+                    ; cmp x14, X(expected_value)
+                    ; csel X(register), X(replacement), x14, eq
+                );
+            }
+            _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+        }
+    });
+    cache.store(api, size, expected, &[], |api, register| asm!(api
+        ; mov X(register), x14
+    ));
+}
+
+// x16 = address of global, which is accessed by exclusive/ordered instructions only with base register.
+// Driver registers only atomic accesses aligned by their size, so `offset` is.
+fn global_address<T: DynasmApi>(api: &mut T, offset: u32) {
+    mov_u32(api, 16, offset);
+    asm!(api
+        ; add x16, globals, x16
+    );
+}
+
+fn atomic_load<T: DynasmApi>(api: &mut T, size: u32, dst: Ref, offset: u32, cache: &mut RegisterCache) {
+    cache.store(api, size, dst, &[], |api, register| {
+        global_address(api, offset);
+        match size {
+            4 => { asm!(api ; ldar W(register), [x16]) }
+            8 => { asm!(api ; ldar X(register), [x16]) }
+            _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+        }
+    });
+}
+
+fn atomic_store<T: DynasmApi>(api: &mut T, size: u32, offset: u32, op: Ref, cache: &mut RegisterCache) {
+    let op = cache.load(api, size, op, 9, &[]);
+    global_address(api, offset);
+    match size {
+        4 => { asm!(api ; stlr W(op), [x16]) }
+        8 => { asm!(api ; stlr X(op), [x16]) }
+        _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+    }
+}
+
+// ldaxr/stlxr loop: x14 = previous value, x17 = new value, w15 = store status
+fn atomic_rmw<T: DynasmApi>(api: &mut T, size: u32, op: RmwOp, dst: Ref, offset: u32, value: Ref, cache: &mut RegisterCache) {
+    let value = cache.load(api, size, value, 9, &[]);
+    cache.store(api, size, dst, &[value], |api, register| {
+        global_address(api, offset);
+        match size {
+            4 => { asm!(api ; ldaxr w14, [x16]) }
+            8 => { asm!(api ; ldaxr x14, [x16]) }
+            _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+        }
+        rmw_op(api, size, op, 17, 14, value);
+        match size {
+            4 => { asm!(api ; stlxr w15, w17, [x16]) }
+            _ => { asm!(api ; stlxr w15, x17, [x16]) }
+        }
+        cbnz_w15(api, -12);
+        asm!(api
+            ; mov X(register), x14
+        );
+    });
+}
+
+// same loop as `atomic_rmw`, leaves it without storing if previous value differs from expected,
+// `clrex` drops exclusive monitor `ldaxr` left open then, after successful store it does nothing
+fn atomic_cmpxchg<T: DynasmApi>(api: &mut T, size: u32, offset: u32, expected: Ref, replacement: Ref, cache: &mut RegisterCache) {
+    let expected_value = cache.load(api, size, expected, 9, &[]);
+    let replacement = cache.load(api, size, replacement, 10, &[expected_value]);
+    cache.store(api, size, expected, &[expected_value, replacement], |api, register| {
+        global_address(api, offset);
+        match size {
+            4 => {
+                asm!(api
+                    ; ldaxr w14, [x16]
+                    ; cmp w14, W(expected_value)
+                );
+                bcond(api, "ne", 12);
+                asm!(api
+                    ; stlxr w15, W(replacement), [x16]
+                );
+            }
+            8 => {
+                asm!(api
+                    ; ldaxr x14, [x16]
+                    ; cmp x14, X(expected_value)
+                );
+                bcond(api, "ne", 12);
+                asm!(api
+                    ; stlxr w15, X(replacement), [x16]
+                );
+            }
+            _ => { unreachable!("driver registers atomics of 4 and 8 bytes only") }
+        }
+        cbnz_w15(api, -16);
+        asm!(api
+            ; clrex
+            ; mov X(register), x14
+        );
+    });
+}
+
+// Vectors aren't cached: operands are loaded into v17 and v18, result is computed in v16 and stored right away.
+// Overlapping cached slots are spilled before loads and dropped before stores. Addresses go through x16.
+
+// `$op v16, $op1, $op2` with arrangement of `$shape`, which should be one of listed ones
+macro_rules! lanes {
+    ($api:ident, $shape:expr, $op:ident, $op1:ident, $op2:ident, [$($lanes:ident => $arrangement:ident),*]) => {
+        match $shape {
+            $(VecShape::$lanes => { asm!($api ; $op v16.$arrangement, $op1.$arrangement, $op2.$arrangement) })*
+            shape => { panic!("{} doesn't support {:?}", stringify!($op), shape) }
+        }
+    };
+}
+
+macro_rules! int_lanes {
+    ($api:ident, $shape:expr, $op:ident, $op1:ident, $op2:ident) => {
+        lanes!($api, $shape, $op, $op1, $op2, [I8x16 => b16, I16x8 => h8, I32x4 => s4, I64x2 => d2])
+    };
+}
+
+macro_rules! float_lanes {
+    ($api:ident, $shape:expr, $op:ident, $op1:ident, $op2:ident) => {
+        lanes!($api, $shape, $op, $op1, $op2, [F32x4 => s4, F64x2 => d2])
+    };
+}
+
+fn vec_address<T: DynasmApi>(api: &mut T, op: Ref) {
+    match op {
+        Ref::Stack(offset) => {
+            mov_u32(api, 16, offset);
             asm!(api
-                ; add w11, w9, w10
+                ; add x16, data_stack, x16
             );
-            store_u32(api, 11, dst);
         }
-        8 => {
-            load_u64(api, 9, op1);
-            load_u64(api, 10, op2);
+        Ref::Global(offset) => {
+            mov_u32(api, 16, offset);
             asm!(api
-                ; add x11, x9, x10
+                ; add x16, globals, x16
             );
-            store_u64(api, 11, dst);
         }
-        _ => { todo!() }
     }
 }
 
-fn sub<T: DynasmApi>(api: &mut T, len: u32, dst: Ref, op1: Ref, op2: Ref) {
-    match len {
-        4 => {
-            load_u32(api, 9, op1);
-            load_u32(api, 10, op2);
+fn vec_load<T: DynasmApi>(api: &mut T, register: u32, op: Ref, cache: &mut RegisterCache) {
+    cache.spill_overlapping(api, op, 16);
+    vec_address(api, op);
+    asm!(api
+        ; ldr Q(register), [x16]
+    );
+}
+
+fn vec_store<T: DynasmApi>(api: &mut T, register: u32, dst: Ref, cache: &mut RegisterCache) {
+    cache.drop_overlapping(api, dst, 16, None);
+    vec_address(api, dst);
+    asm!(api
+        ; str Q(register), [x16]
+    );
+}
+
+// comparisons swap operands for "less" ones, there are no 64 bit lanes in NEON `mul`, so they're multiplied one by one
+fn vec_binary<T: DynasmApi>(api: &mut T, op: VecOp, shape: VecShape, dst: Ref, op1: Ref, op2: Ref, cache: &mut RegisterCache) {
+    vec_load(api, 17, op1, cache);
+    vec_load(api, 18, op2, cache);
+    match (op, shape.is_float()) {
+        (VecOp::Add, false) => { int_lanes!(api, shape, add, v17, v18) }
+        (VecOp::Add, true) => { float_lanes!(api, shape, fadd, v17, v18) }
+        (VecOp::Sub, false) => { int_lanes!(api, shape, sub, v17, v18) }
+        (VecOp::Sub, true) => { float_lanes!(api, shape, fsub, v17, v18) }
+        (VecOp::Mul, false) if shape == VecShape::I64x2 => {
             asm!(api
-                ; sub w11, w9, w10
+                ; umov x14, v17.d[0]
+                ; umov x15, v18.d[0]
+                ; mul x14, x14, x15
+                ; ins v16.d[0], x14
+                ; umov x14, v17.d[1]
+                ; umov x15, v18.d[1]
+                ; mul x14, x14, x15
+                ; ins v16.d[1], x14
             );
-            store_u32(api, 11, dst);
         }
-        _ => { todo!() }
+        (VecOp::Mul, false) => { lanes!(api, shape, mul, v17, v18, [I8x16 => b16, I16x8 => h8, I32x4 => s4]) }
+        (VecOp::Mul, true) => { float_lanes!(api, shape, fmul, v17, v18) }
+        (VecOp::Eq | VecOp::Ne, false) => { int_lanes!(api, shape, cmeq, v17, v18) }
+        (VecOp::Eq | VecOp::Ne, true) => { float_lanes!(api, shape, fcmeq, v17, v18) }
+        (VecOp::Lt, false) => { int_lanes!(api, shape, cmgt, v18, v17) }
+        (VecOp::Lt, true) => { float_lanes!(api, shape, fcmgt, v18, v17) }
+        (VecOp::Le, false) => { int_lanes!(api, shape, cmge, v18, v17) }
+        (VecOp::Le, true) => { float_lanes!(api, shape, fcmge, v18, v17) }
+        (VecOp::Gt, false) => { int_lanes!(api, shape, cmgt, v17, v18) }
+        (VecOp::Gt, true) => { float_lanes!(api, shape, fcmgt, v17, v18) }
+        (VecOp::Ge, false) => { int_lanes!(api, shape, cmge, v17, v18) }
+        (VecOp::Ge, true) => { float_lanes!(api, shape, fcmge, v17, v18) }
+        (VecOp::LtU, _) => { int_lanes!(api, shape, cmhi, v18, v17) }
+        (VecOp::LeU, _) => { int_lanes!(api, shape, cmhs, v18, v17) }
+        (VecOp::GtU, _) => { int_lanes!(api, shape, cmhi, v17, v18) }
+        (VecOp::GeU, _) => { int_lanes!(api, shape, cmhs, v17, v18) }
+    }
+    if op == VecOp::Ne {
+        asm!(api
+            ; not v16.b16, v16.b16
+        );
+    }
+    vec_store(api, 16, dst, cache);
+}
+
+// lane is loaded from memory to every lane
+fn vec_splat<T: DynasmApi>(api: &mut T, shape: VecShape, dst: Ref, op: Ref, cache: &mut RegisterCache) {
+    cache.spill_overlapping(api, op, shape.lane_size());
+    vec_address(api, op);
+    match shape.lane_size() {
+        1 => { asm!(api ; ld1r {v16.b16}, [x16]) }
+        2 => { asm!(api ; ld1r {v16.h8}, [x16]) }
+        4 => { asm!(api ; ld1r {v16.s4}, [x16]) }
+        _ => { asm!(api ; ld1r {v16.d2}, [x16]) }
+    }
+    vec_store(api, 16, dst, cache);
+}
+
+fn vec_extract_lane<T: DynasmApi>(api: &mut T, shape: VecShape, signed: bool, lane: u8, dst: Ref, op: Ref, cache: &mut RegisterCache) {
+    vec_load(api, 17, op, cache);
+    let lane = lane as u32;
+    let size = shape.lane_size().max(4);
+    cache.store(api, size, dst, &[], |api, register| {
+        match (shape.lane_size(), signed) {
+            (1, true) => { asm!(api ; smov W(register), v17.b[lane]) }
+            (1, false) => { asm!(api ; umov W(register), v17.b[lane]) }
+            (2, true) => { asm!(api ; smov W(register), v17.h[lane]) }
+            (2, false) => { asm!(api ; umov W(register), v17.h[lane]) }
+            (4, _) => { asm!(api ; umov W(register), v17.s[lane]) }
+            _ => { asm!(api ; umov X(register), v17.d[lane]) }
+        }
+    });
+}
+
+// lane is loaded from memory right into the vector
+fn vec_replace_lane<T: DynasmApi>(api: &mut T, shape: VecShape, lane: u8, dst: Ref, op: Ref, value: Ref, cache: &mut RegisterCache) {
+    vec_load(api, 17, op, cache);
+    cache.spill_overlapping(api, value, shape.lane_size());
+    vec_address(api, value);
+    let lane = lane as u32;
+    match shape.lane_size() {
+        1 => { asm!(api ; ld1 {v17.b}[lane], [x16]) }
+        2 => { asm!(api ; ld1 {v17.h}[lane], [x16]) }
+        4 => { asm!(api ; ld1 {v17.s}[lane], [x16]) }
+        _ => { asm!(api ; ld1 {v17.d}[lane], [x16]) }
     }
+    vec_store(api, 17, dst, cache);
 }
 
-fn condition<T: DynasmApi>(api: &mut T, condition: Condition, ret_true_offset: isize) {
+// `tbl` over consecutive v17 and v18 with indices in v19
+fn vec_shuffle<T: DynasmApi>(api: &mut T, lanes: [u8; 16], dst: Ref, op1: Ref, op2: Ref, cache: &mut RegisterCache) {
+    vec_load(api, 17, op1, cache);
+    vec_load(api, 18, op2, cache);
+    mov_u64(api, 14, u64::from_le_bytes(lanes[0..8].try_into().unwrap()));
+    mov_u64(api, 15, u64::from_le_bytes(lanes[8..16].try_into().unwrap()));
+    asm!(api
+        ; ins v19.d[0], x14
+        ; ins v19.d[1], x15
+        ; tbl v16.b16, {v17.b16, v18.b16}, v19.b16
+    );
+    vec_store(api, 16, dst, cache);
+}
+
+// sets "ne" flag if condition holds
+// dirty slots are spilled after operands are loaded, so both branches continue with clean cache
+fn compare<T: DynasmApi>(api: &mut T, condition: Condition, cache: &mut RegisterCache) {
     match condition {
-        Condition::Ne { size, op1, op2 } => { ne(api,size, op1, op2, ret_true_offset) }
-        Condition::Ne0 { size, op } => { ne0(api, size, op, ret_true_offset) }
+        Condition::Ne { size, op1, op2 } => { ne(api,size, op1, op2, cache) }
+        Condition::Ne0 { size, op } => { ne0(api, size, op, cache) }
     }
 }
 
-fn ne<T: DynasmApi>(api: &mut T, len: u32, op1: Ref, op2: Ref, ret_true_offset: isize) {
+// adds 1 to counter, x16 & x17 are free between nodes
+fn increment<T: DynasmApi>(api: &mut T, counter: &AtomicU64) {
+    mov_u64(api, 16, counter.as_ptr() as u64);
+    asm!(api
+        ; ldr x17, [x16]
+        ; add x17, x17, 1
+        ; str x17, [x16]
+    );
+}
+
+// adds 1 to counter if "ne" flag is set, flags stay intact
+fn increment_ne<T: DynasmApi>(api: &mut T, counter: &AtomicU64) {
+    mov_u64(api, 16, counter.as_ptr() as u64);
+    asm!(api
+        ; cset x17, ne
+        ; ldr x9, [x16]
+        ; add x9, x9, x17
+        ; str x9, [x16]
+    );
+}
+
+fn ne<T: DynasmApi>(api: &mut T, len: u32, op1: Ref, op2: Ref, cache: &mut RegisterCache) {
     match len {
         4 => {
-            load_u32(api, 9, op1);
-            load_u32(api, 10, op2);
+            let op1 = cache.load(api, 4, op1, 9, &[]);
+            let op2 = cache.load(api, 4, op2, 10, &[op1]);
+            cache.spill_all(api);
             asm!(api
-                ; cmp w9, w10
+                ; cmp W(op1), W(op2)
             );
-            bcond(api, "ne", ret_true_offset);
         }
         _ => { todo!() }
     }
 }
 
-fn ne0<T: DynasmApi>(api: &mut T, len: u32, op: Ref, ret_true_offset: isize) {
+fn ne0<T: DynasmApi>(api: &mut T, len: u32, op: Ref, cache: &mut RegisterCache) {
     match len {
         4 => {
-            load_u32(api, 9, op);
+            let op = cache.load(api, 4, op, 9, &[]);
+            cache.spill_all(api);
             asm!(api
-                ; cmp w9, 0
+                ; cmp W(op), 0
             );
-            bcond(api, "ne", ret_true_offset);
         }
         _ => { todo!() }
     }
 }
 
+fn load<T: DynasmApi>(api: &mut T, size: u32, register: u32, op: Ref) {
+    match size {
+        4 => { load_u32(api, register, op) }
+        8 => { load_u64(api, register, op) }
+        _ => { todo!() }
+    }
+}
+
+fn store<T: DynasmApi>(api: &mut T, size: u32, register: u32, dst: Ref) {
+    match size {
+        4 => { store_u32(api, register, dst) }
+        8 => { store_u64(api, register, dst) }
+        _ => { todo!() }
+    }
+}
+
 fn store_u32<T: DynasmApi>(api: &mut T, register: u32, dst: Ref) {
     match dst {
         Ref::Stack(offset) => {
@@ -276,6 +978,18 @@ fn store_u32<T: DynasmApi>(api: &mut T, register: u32, dst: Ref) {
                 );
             } else { todo!() }
         }
+        Ref::Global(offset) => {
+            if (offset <= 255) && (offset % 4 == 0) {
+                asm!(api
+                    ; str W(register), [globals, offset]
+                );
+            } else {
+                mov_u32(api, 15, offset);
+                asm!(api
+                    ; str W(register), [globals, x15]
+                );
+            }
+        }
     }
 }
 
@@ -294,6 +1008,18 @@ fn store_u64<T: DynasmApi>(api: &mut T, register: u32, dst: Ref) {
                 );
             }
         }
+        Ref::Global(offset) => {
+            if (offset <= 255) && (offset % 8 == 0) {
+                asm!(api
+                    ; str X(register), [globals, offset]
+                );
+            } else {
+                mov_u32(api, 15, offset);
+                asm!(api
+                    ; str X(register), [globals, x15]
+                );
+            }
+        }
     }
 }
 
@@ -307,6 +1033,18 @@ fn load_u32<T: DynasmApi>(api: &mut T, register: u32, op: Ref) {
                 );
             } else { todo!() }
         }
+        Ref::Global(offset) => {
+            if (offset <= 255) && (offset % 4 == 0) {
+                asm!(api
+                    ; ldr W(register), [globals, offset]
+                );
+            } else {
+                mov_u32(api, 15, offset);
+                asm!(api
+                    ; ldr W(register), [globals, x15]
+                );
+            }
+        }
     }
 }
 
@@ -325,6 +1063,18 @@ fn load_u64<T: DynasmApi>(api: &mut T, register: u32, op: Ref) {
                 );
             }
         }
+        Ref::Global(offset) => {
+            if (offset <= 255) && (offset % 8 == 0) {
+                asm!(api
+                    ; ldr X(register), [globals, offset]
+                );
+            } else {
+                mov_u32(api, 15, offset);
+                asm!(api
+                    ; ldr X(register), [globals, x15]
+                );
+            }
+        }
     }
 }
 
@@ -370,6 +1120,26 @@ fn bcond<T: DynasmApi>(api: &mut T, modifier: &'static str, offset: isize) {
     api.extend(bytes.as_slice());
 }
 
+// `cbnz w15, offset`, encoded the same way as `bcond`
+fn cbnz_w15<T: DynasmApi>(api: &mut T, offset: isize) {
+    let mut bytes = vec![0x0F, 0, 0, 0x35];
+    Aarch64Relocation::BCOND.write_value(&mut bytes, offset).unwrap();
+    api.extend(bytes.as_slice());
+}
+
+// `b` if destination is within its range, otherwise absolute jump through x16, at most 5 instructions
+pub fn jump<T: DynasmApi>(api: &mut T, from: usize, to: usize) {
+    let rel_dst = to as isize - from as isize;
+    if (-(1 << 27)..(1 << 27)).contains(&rel_dst) {
+        b(api, rel_dst);
+    } else {
+        mov_u64(api, 16, to as u64);
+        asm!(api
+            ; br x16
+        );
+    }
+}
+
 pub fn b<T: DynasmApi>(api: &mut T, rel_dst: isize) {
     // todo: got this from debuging internals of generated dynasm code. figure out where it comes from in macro!
     let mut template = vec!(0, 0, 0, 20);
@@ -1,16 +1,27 @@
 use std::collections::HashMap;
 use std::{io, mem};
-use dynasmrt::{AssemblyOffset, DynasmApi, ExecutableBuffer};
+use std::sync::Arc;
+use std::sync::atomic::AtomicI64;
+use std::time::Instant;
+use dynasmrt::{AssemblyOffset, DynasmApi, ExecutableBuffer, VecAssembler};
+use dynasmrt::aarch64::Aarch64Relocation;
 use dynasmrt::mmap::MutableBuffer;
 use crate::core::api::NodeKind;
-use crate::core::driver::driver::{Engine, Frame, NodeId, RunState};
-use crate::core::driver::aarch64::{b, insert_debug, flush_code_cache, generate};
+use crate::core::driver::driver::{Engine, Frame, NodeId, Region, RunState};
+use crate::core::driver::fuel::Fuel;
+use crate::core::globals::Globals;
+use crate::core::driver::observer::Observer;
+use crate::core::driver::profile::{NodeProfile, Profile};
+use crate::core::driver::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::core::driver::stats::{EngineStats, Stats};
+use crate::core::driver::aarch64::{entry, entry_size, flush_code_cache, generate, jump, suspend, trampoline, Callout, RegisterCache, ON_BRANCH, ON_CALL, ON_COMMAND, ON_NODE, ON_RETURN, SUSPEND_SIZE};
 use multimap::MultiMap;
 
 // engine <-> generated code interop/call conventions:
 //   X0 pointer to data stack start
 //   X1 pointer to data stack end // never changes during execution
 //   X2 pointer to result struct // never changes during execution, no need for now, will be needed for stack unwinding
+//   X8 fuel left, see `trampoline`
 // X1 & X2 can/should be moved to thread local variables since they never change during execution trace
 //
 // execution might abort/finish due to following reasons:
@@ -21,6 +32,8 @@ use multimap::MultiMap;
 // and only if there is no guarantee on particular point we can check stack size and increase size if needed!
 // this way we don't need to keep data stack end in a register and compare to it all the time
 
+fn invalid(message: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, message) }
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct SuspendTrace {
@@ -28,89 +41,297 @@ struct SuspendTrace {
     id: NodeId
 }
 
-extern "C" fn on_debug(stack_start: *const u8, stack_end: *const u8, unwind_start: *const u8, node_id: u64) {
-    // println!("{:?} {:?} {:?} {:?}", stack_start, stack_end, unwind_start, node_id);
-    println!("run {:?}", node_id);
-
-    // todo: lens shouldn't be hardcoded
-    let print_from = stack_end as usize - 1000; // because it's stack size
-    let print_until = stack_start as usize + 24;
-    let print_count = (print_until - print_from) / 4;
-    (0..print_count).for_each(|num| unsafe {
-        let pos = (print_from + num * 4) as *const u32;
-        print!("{} ", pos.read());
-    });
-    println!()
+extern "C" fn on_event(observer: u64, event: u32, id: u32, arg: u64, stack_start: *const u8, stack_end: *const u8) {
+    let observer = unsafe { &*(observer as *const Arc<dyn Observer>) };
+    let stack = unsafe { std::slice::from_raw_parts(stack_start, stack_end as usize - stack_start as usize) };
+    let id = NodeId(id);
+    match event {
+        ON_NODE => { observer.on_node(id, stack) }
+        ON_COMMAND => { observer.on_command(id, stack) }
+        ON_BRANCH => { observer.on_branch(id, arg != 0, stack) }
+        ON_CALL => { observer.on_call(id, NodeId((arg >> 32) as u32), arg as u32, stack) }
+        ON_RETURN => { observer.on_return(id, stack) }
+        _ => { unreachable!() }
+    }
 }
 
-fn interop(fn_ptr: *const u8, stack_start: *mut u8, stack_end: *const u8, unwind_dst: *mut SuspendTrace) -> usize {
+fn interop(trampoline: *const u8, fn_ptr: *const u8, stack_start: *mut u8, stack_end: *const u8, unwind_dst: *mut SuspendTrace, fuel: &Fuel, globals: &Globals) -> usize {
     // Can't have input as input struct because such structs being passed in memory
     // But output is fine, I guess because it's under two fields
     // checked with godbolt
-    let call: extern "C" fn(*mut u8, *const u8, *mut SuspendTrace) -> u64 = unsafe { mem::transmute(fn_ptr) };
-    call(stack_start, stack_end, unwind_dst) as usize
+    let call: extern "C" fn(*mut u8, *const u8, *mut SuspendTrace, *const AtomicI64, *const u8, *mut u8) -> u64 = unsafe { mem::transmute(trampoline) };
+    call(stack_start, stack_end, unwind_dst, fuel.ptr(), fn_ptr, globals.as_ptr()) as usize
 }
 
 // place corresponds to: put (id, 0) into unwind_dst, ret 1. i.e the one which triggers suspend on unknown node
+// `spill..from` stores dirty cached slots, it's skipped if node `id` is generated right after (`to` is end of code)
 #[derive(Debug, Clone)]
 pub struct ReturnInfo {
     pub id: NodeId,
+    // node which code contains this return
+    pub source: NodeId,
+    pub chunk: usize,
+    pub spill: AssemblyOffset,
     pub from: AssemblyOffset,
     pub to: AssemblyOffset,
+    pub cache: RegisterCache,
 }
 
-pub struct CodeGeneratorEngine {
-    size: usize,
-    code: Option<ExecutableBuffer>,
+impl ReturnInfo {
+    fn location(&self) -> CodeLocation { CodeLocation { chunk: self.chunk, offset: self.from } }
+
+    fn write(&self, writer: &mut SnapshotWriter) {
+        writer.id(self.id);
+        writer.id(self.source);
+        writer.usize(self.chunk);
+        writer.usize(self.spill.0);
+        writer.usize(self.from.0);
+        writer.usize(self.to.0);
+        self.cache.write(writer);
+    }
+
+    fn read(reader: &mut SnapshotReader) -> io::Result<ReturnInfo> {
+        Ok(ReturnInfo {
+            id: reader.id()?,
+            source: reader.id()?,
+            chunk: reader.usize()?,
+            spill: AssemblyOffset(reader.usize()?),
+            from: AssemblyOffset(reader.usize()?),
+            to: AssemblyOffset(reader.usize()?),
+            cache: RegisterCache::read(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CodeLocation {
+    chunk: usize,
     offset: AssemblyOffset,
-    offsets: HashMap<NodeId, AssemblyOffset>,
+}
+
+// Code is written into chunks of fixed size, new chunk is allocated once node doesn't fit into the current one.
+// Each node's code lives within one chunk, jumps between chunks go through veneers if they're out of `b` range.
+struct Chunk {
+    code: ChunkCode,
+    // number of registered nodes with code in this chunk, chunk is freed once there are none
+    live: usize,
+    // bytes of unregistered nodes' code, chunk is reclaimed once it's at least half of the chunk, see `reclaimable`
+    dead: usize,
+    offset: AssemblyOffset,
+    // prologues loading cached registers are written from the end of the chunk backwards
+    prologue_offset: AssemblyOffset,
+}
+
+enum ChunkCode {
+    Exec(ExecutableBuffer),
+    Mut(MutableBuffer),
+    Freed,
+}
+
+impl Chunk {
+    fn new(size: usize) -> io::Result<Chunk> {
+        let mut buffer = MutableBuffer::new(size)?;
+        // buffer is indexed only until length
+        buffer.set_len(size);
+        Ok(Chunk { code: ChunkCode::Mut(buffer), live: 0, dead: 0, offset: AssemblyOffset(0), prologue_offset: AssemblyOffset(size) })
+    }
+
+    fn free(&self) -> usize { self.prologue_offset.0 - self.offset.0 }
+
+    fn ptr(&self, offset: AssemblyOffset) -> *const u8 {
+        match &self.code {
+            ChunkCode::Exec(buffer) => { buffer.ptr(offset) }
+            ChunkCode::Mut(buffer) => { &buffer[offset.0] as *const u8 }
+            ChunkCode::Freed => { panic!("access to freed chunk") }
+        }
+    }
+
+    fn assembler(&mut self, offset: AssemblyOffset) -> Assembler<'_> {
+        if let ChunkCode::Exec(buffer) = &mut self.code {
+            self.code = ChunkCode::Mut(mem::take(buffer).make_mut().unwrap());
+        }
+        match &mut self.code {
+            ChunkCode::Mut(buffer) => { Assembler { buffer, offset } }
+            ChunkCode::Exec(_) => { unreachable!() }
+            ChunkCode::Freed => { panic!("access to freed chunk") }
+        }
+    }
+
+    // switches to executable mode if chunk was modified
+    fn seal(&mut self) {
+        if let ChunkCode::Mut(buffer) = &mut self.code {
+            flush_code_cache(buffer);
+            self.code = ChunkCode::Exec(mem::take(buffer).make_exec().unwrap());
+        }
+    }
+}
+
+pub struct CodeGeneratorEngine {
+    chunk_size: usize,
+    chunks: Vec<Chunk>,
+    // chunk new code is written to
+    current: usize,
+    // place to enter node with all values in data stack, either start of node code or its prologue
+    offsets: HashMap<NodeId, CodeLocation>,
+    // bytes of node code and its prologue
+    sizes: HashMap<NodeId, usize>,
+    // returns not yet replaced with jumps, by node they return to
     returns: MultiMap<NodeId, ReturnInfo>,
+    // returns replaced with jumps, kept to restore them once target is unregistered
+    jumps: MultiMap<NodeId, ReturnInfo>,
+    // node -> node which code continues into its code without jump
+    fallthrough: HashMap<NodeId, NodeId>,
+    profile: Option<Profile>,
+    // boxed so generated code can refer to it
+    observer: Option<Box<Arc<dyn Observer>>>,
+    fuel: Fuel,
+    // node entries have fuel checks
+    fuel_checks: bool,
+    // see `set_region`
+    region: Region,
     do_jumps: bool,
-    do_debug: bool,
+    stats: Stats,
+    // see `EngineStats`
+    code_bytes: u64,
+    patches: u64,
 }
 
 impl CodeGeneratorEngine {
-    pub fn new(size: usize, do_debug: bool) -> io::Result<CodeGeneratorEngine> {
+    // `chunk_size` is size of each code chunk, code of single node should fit into it
+    pub fn new(chunk_size: usize) -> io::Result<CodeGeneratorEngine> {
+        // first chunk starts with `trampoline` and is never freed
+        let mut first = Chunk::new(chunk_size)?;
+        let mut ops = first.assembler(AssemblyOffset(0));
+        trampoline(&mut ops);
+        first.offset = ops.offset;
+        first.live = 1;
+        first.seal();
         Ok(CodeGeneratorEngine {
-            size,
-            code: Some(ExecutableBuffer::new(size)?),
-            offset: AssemblyOffset(0),
+            chunk_size,
+            chunks: vec![first],
+            current: 0,
             offsets: HashMap::new(),
+            sizes: HashMap::new(),
             returns: MultiMap::new(),
+            jumps: MultiMap::new(),
+            fallthrough: HashMap::new(),
+            profile: None,
+            observer: None,
+            fuel: Fuel::new(),
+            fuel_checks: false,
+            region: Region::Node,
             do_jumps: true,
-            do_debug,
+            stats: Stats::new(),
+            code_bytes: 0,
+            patches: 0,
         })
     }
 
+    // counters are incremented by generated code, so profiling can't be switched once code is generated
+    pub fn set_profiling(&mut self, profiling: bool) {
+        assert!(self.offsets.is_empty(), "profiling should be set before any node is registered");
+        self.profile = if profiling { Some(Profile::new()) } else { None };
+    }
+
+    // hooks are called from generated code, so observer can't be switched once code is generated
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) {
+        assert!(self.offsets.is_empty(), "observer should be set before any node is registered");
+        self.observer = observer.map(Box::new);
+    }
+
+    // Nodes of the region are generated together, e.g. `Region::Function` lays out function code in one go
+    // instead of suspending on each node.
+    pub fn set_region(&mut self, region: Region) { self.region = region; }
+
+    // checks are generated into node entries, so metering can't be turned on once code is generated without them
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        assert!(self.fuel_checks || fuel.is_none() || self.offsets.is_empty(), "fuel should be set before any node is registered");
+        self.fuel_checks |= fuel.is_some();
+        self.fuel.set(fuel);
+    }
+
+    fn fuel(&self) -> Option<u64> { self.fuel.get() }
+
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) {
+        let start = Instant::now();
+        self.register_inner(id, kind);
+        self.chunks.iter_mut().for_each(|chunk| chunk.seal());
+        self.stats.registered(start);
+    }
+
+    // chunks are sealed once for the whole batch, depth first order lets nodes continue into each other without jumps
+    fn register_batch(&mut self, nodes: Vec<(NodeId, NodeKind<NodeId>)>) {
+        let start = Instant::now();
+        nodes.into_iter().for_each(|(id, kind)| self.register_inner(id, kind));
+        self.chunks.iter_mut().for_each(|chunk| chunk.seal());
+        self.stats.registered(start);
+    }
+
+    fn register_inner(&mut self, id: NodeId, kind: NodeKind<NodeId>) {
         if self.offsets.contains_key(&id) { return; }
 
-        if self.do_debug { println!("{:?} <- {:?}", id, kind) }
+        if let Some(profile) = &mut self.profile {
+            profile.register(id, matches!(kind, NodeKind::Branch { .. }));
+        }
 
-        let mut writable = match std::mem::replace(&mut self.code, None) {
-            Some(buffer) => { buffer.make_mut().unwrap() }
-            None => { panic!() }
-        };
-        // why do we need this?
-        writable.set_len(self.size);
+        // node continues right after return to it if it's last code in the chunk and it fits there
+        let mut placed = None;
         if self.do_jumps {
-            self.remove_last_return_if_needed(id);
+            if let Some(ret) = self.last_return(id) {
+                let (code, returns) = self.generate(id, kind.clone(), &ret.cache);
+                let chunk = self.chunks.get(self.current).unwrap();
+                if code.len() + entry_size(&ret.cache, self.fuel_checks) <= chunk.prologue_offset.0 - ret.spill.0 {
+                    let returns_to_id = self.returns.get_vec_mut(&id).unwrap();
+                    returns_to_id.retain(|e| !(e.chunk == ret.chunk && e.to == ret.to));
+                    self.fallthrough.insert(id, ret.source);
+                    // spilling and return at the end of source code are overwritten
+                    *self.sizes.get_mut(&ret.source).unwrap() -= ret.to.0 - ret.spill.0;
+                    placed = Some((CodeLocation { chunk: ret.chunk, offset: ret.spill }, code, returns, ret.cache));
+                }
+            }
         }
-        let mut ops = Assembler { buffer: writable, offset: self.offset };
-        if self.do_debug { insert_debug(&mut ops, id, on_debug) }
-        let returns = generate(&mut ops, kind.clone());
+        let (start, code, mut returns, entry_cache) = match placed {
+            Some(placed) => { placed }
+            None => {
+                let (code, returns) = self.generate(id, kind, &RegisterCache::new());
+                let size = code.len() + entry_size(&RegisterCache::new(), self.fuel_checks);
+                (self.reserve(size), code, returns, RegisterCache::new())
+            }
+        };
 
-        self.offsets.insert(id, self.offset);
+        let chunk = self.chunks.get_mut(start.chunk).unwrap();
+        let mut ops = chunk.assembler(start.offset);
+        ops.extend(code.iter());
+        chunk.offset = ops.offset;
+        chunk.live += 1;
+        returns.iter_mut().for_each(|ret| {
+            ret.source = id;
+            ret.chunk = start.chunk;
+            ret.spill.0 += start.offset.0;
+            ret.from.0 += start.offset.0;
+            ret.to.0 += start.offset.0;
+        });
 
-        self.offset = ops.offset;
+        let entry_size = entry_size(&entry_cache, self.fuel_checks);
+        self.code_bytes += (code.len() + entry_size) as u64;
+        let entry = if entry_size == 0 {
+            start
+        } else {
+            chunk.prologue_offset.0 -= entry_size;
+            let prologue = chunk.prologue_offset;
+            entry(&mut chunk.assembler(prologue), id, &entry_cache, self.fuel_checks, start.offset);
+            CodeLocation { chunk: start.chunk, offset: prologue }
+        };
+        assert!(chunk.offset.0 <= chunk.prologue_offset.0, "out of code chunk space");
+        self.offsets.insert(id, entry);
+        self.sizes.insert(id, code.len() + entry_size);
 
         if self.do_jumps {
             // replace returns
             for ret in returns {
-                if let Some(offset) = self.offsets.get(&ret.id) {
-                    // todo: what if more than 1mb size difference?
-                    ops.offset = ret.from;
-                    b(&mut ops, offset.0 as isize - ret.from.0 as isize);
+                if let Some(location) = self.offsets.get(&ret.id).copied() {
+                    self.jump(ret.location(), location);
+                    self.jumps.insert(ret.id, ret);
                 } else {
                     self.returns.insert(ret.id, ret)
                 }
@@ -122,68 +343,318 @@ impl CodeGeneratorEngine {
                     clone
                 } else { vec![] };
 
-            let offset = *self.offsets.get(&id).unwrap();
             for ret in replacements {
-                ops.offset = ret.from;
-                b(&mut ops, offset.0 as isize - ret.from.0 as isize);
+                self.jump(ret.location(), entry);
+                self.jumps.insert(id, ret);
             }
         }
+    }
 
-        flush_code_cache(&ops.buffer);
-        std::mem::replace(&mut self.code, Some(ops.buffer.make_exec().unwrap()));
+    fn unregister(&mut self, id: NodeId) {
+        self.unregister_inner(id);
+        self.chunks.iter_mut().for_each(|chunk| chunk.seal());
     }
 
-    fn remove_last_return_if_needed(&mut self, id: NodeId) {
-        if let Some(returns) = self.returns.get_vec_mut(&id) {
-            if let Some(pos) = returns.iter().position(|e| e.to == self.offset) {
-                self.offset = returns.get(pos).unwrap().from;
-                returns.remove(pos);
+    fn unregister_inner(&mut self, id: NodeId) {
+        let location = match self.offsets.remove(&id) {
+            Some(location) => { location }
+            None => { return; }
+        };
+        if let Some(profile) = &mut self.profile {
+            profile.unregister(id);
+        }
+
+        // code continuing into this node's code can't stay without it
+        if let Some(source) = self.fallthrough.remove(&id) {
+            self.unregister_inner(source);
+        }
+        self.fallthrough.retain(|_, source| *source != id);
+
+        // code of this node is dead now, as well as returns in it
+        self.returns.iter_all_mut().for_each(|(_, returns)| returns.retain(|ret| ret.source != id));
+        self.jumps.iter_all_mut().for_each(|(_, jumps)| jumps.retain(|ret| ret.source != id));
+
+        // jumps to this node become returns again
+        for ret in self.jumps.remove(&id).unwrap_or_default() {
+            let chunk = self.chunks.get_mut(ret.chunk).unwrap();
+            suspend(&mut chunk.assembler(ret.from), id);
+            self.patches += 1;
+            self.returns.insert(id, ret);
+        }
+
+        let chunk = self.chunks.get_mut(location.chunk).unwrap();
+        chunk.live -= 1;
+        chunk.dead += self.sizes.remove(&id).unwrap();
+        if chunk.live == 0 {
+            if location.chunk == self.current {
+                // nothing refers to its code anymore, so it's written from the start again
+                chunk.dead = 0;
+                chunk.offset = AssemblyOffset(0);
+                chunk.prologue_offset = AssemblyOffset(self.chunk_size);
+            } else {
+                chunk.code = ChunkCode::Freed;
             }
         }
     }
 
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool {
-        if let Some(executable) = &self.code {
-            let frame = state.frames.pop().unwrap();
+    // Nodes with code in chunks which are at least half dead, so chunks can be freed once they're evicted.
+    // Otherwise few long living nodes could keep any number of chunks with code of unregistered nodes.
+    fn reclaimable(&self) -> Vec<NodeId> {
+        // first chunk has trampoline, it's never freed
+        let chunks: Vec<usize> = self.chunks.iter().enumerate().skip(1)
+            .filter(|(_, chunk)| chunk.live > 0 && chunk.dead * 2 >= self.chunk_size)
+            .map(|(chunk, _)| chunk)
+            .collect();
+        if chunks.is_empty() { return vec![]; }
+        self.offsets.iter().filter(|(_, location)| chunks.contains(&location.chunk)).map(|(id, _)| *id).collect()
+    }
+
+    // bytes of allocated code chunks
+    fn code_memory(&self) -> usize {
+        self.chunks.iter().filter(|chunk| !matches!(chunk.code, ChunkCode::Freed)).count() * self.chunk_size
+    }
 
-            match self.offsets.get(&frame.id) {
+    // node which code continues into code of `id`
+    fn dependents(&self, id: NodeId) -> Vec<NodeId> {
+        self.fallthrough.get(&id).copied().into_iter().filter(|source| self.offsets.contains_key(source)).collect()
+    }
+
+    fn generate(&self, id: NodeId, kind: NodeKind<NodeId>, entry_cache: &RegisterCache) -> (Vec<u8>, Vec<ReturnInfo>) {
+        let mut ops: VecAssembler<Aarch64Relocation> = VecAssembler::new(0);
+        let mut cache = entry_cache.clone();
+        let counters = self.profile.as_ref().and_then(|profile| profile.counters(id));
+        let callout = self.observer.as_ref().map(|observer| Callout {
+            handler: on_event,
+            observer: observer.as_ref() as *const Arc<dyn Observer> as u64,
+        });
+        let returns = generate(&mut ops, id, kind, &mut cache, counters, callout);
+        (ops.finalize().unwrap(), returns)
+    }
+
+    // returns place for code of given size, allocating new chunk in place of freed one if needed
+    fn reserve(&mut self, size: usize) -> CodeLocation {
+        if self.chunks.get(self.current).unwrap().free() < size {
+            assert!(size <= self.chunk_size, "node code doesn't fit into code chunk");
+            let chunk = Chunk::new(self.chunk_size).unwrap();
+            match self.chunks.iter().position(|chunk| matches!(chunk.code, ChunkCode::Freed)) {
+                Some(freed) => {
+                    *self.chunks.get_mut(freed).unwrap() = chunk;
+                    self.current = freed;
+                }
                 None => {
-                    state.frames.push(frame);
-                    true
+                    self.chunks.push(chunk);
+                    self.current = self.chunks.len() - 1;
                 }
-                Some(code_offset) => {
-                    let data_offset = state.offset() + frame.offset;
-                    let mut unwind_dst = [SuspendTrace { offset: 0, id: NodeId(0) }; 1024];
-                    let output = interop(executable.ptr(*code_offset), stack[data_offset..].as_mut_ptr(), stack.as_ptr_range().end, unwind_dst.as_mut_ptr());
-                    let suspended_entries = (output - (unwind_dst.as_ptr() as usize)) / 8;
-                    let mut entries = unwind_dst[0..suspended_entries].to_vec();
-                    entries.reverse();
-                    if !entries.is_empty() {
-                        entries.first_mut().unwrap().offset += frame.offset as u32;
-                        entries.iter().for_each(|entry| {
-                            state.frames.push(Frame { id: entry.id, offset: entry.offset as usize })
-                        });
-                    }
-                    true // todo: not necessary!
+            }
+        }
+        CodeLocation { chunk: self.current, offset: self.chunks.get(self.current).unwrap().offset }
+    }
+
+    fn jump(&mut self, from: CodeLocation, to: CodeLocation) {
+        let to_ptr = self.chunks.get(to.chunk).unwrap().ptr(to.offset) as usize;
+        let chunk = self.chunks.get_mut(from.chunk).unwrap();
+        let from_ptr = chunk.ptr(from.offset) as usize;
+        jump(&mut chunk.assembler(from.offset), from_ptr, to_ptr);
+        self.patches += 1;
+    }
+
+    // return to `id` which is last code in the current chunk, i.e. `id` can be generated right after it
+    fn last_return(&self, id: NodeId) -> Option<ReturnInfo> {
+        let offset = self.chunks.get(self.current).unwrap().offset;
+        self.returns.get_vec(&id)?.iter().find(|e| e.chunk == self.current && e.to == offset).cloned()
+    }
+
+    // Code is saved as is, it's position independent except of jumps, which are written again on load.
+    // Observer and profiling code refer to hooks and counters by absolute address, so it isn't saved.
+    fn save(&self) -> Option<Vec<u8>> {
+        if self.observer.is_some() || self.profile.is_some() { return None; }
+
+        let mut writer = SnapshotWriter::new();
+        writer.u8(self.fuel_checks as u8);
+        writer.usize(self.chunk_size);
+        writer.usize(self.chunks.len());
+        for chunk in &self.chunks {
+            if let ChunkCode::Freed = chunk.code {
+                writer.u8(0);
+                continue;
+            }
+            writer.u8(1);
+            writer.usize(chunk.live);
+            writer.usize(chunk.dead);
+            writer.usize(chunk.offset.0);
+            writer.usize(chunk.prologue_offset.0);
+            let code = unsafe { std::slice::from_raw_parts(chunk.ptr(AssemblyOffset(0)), self.chunk_size) };
+            writer.bytes(&code[..chunk.offset.0]);
+            writer.bytes(&code[chunk.prologue_offset.0..]);
+        }
+
+        writer.usize(self.current);
+        writer.usize(self.offsets.len());
+        self.offsets.iter().for_each(|(id, location)| {
+            writer.id(*id);
+            writer.usize(location.chunk);
+            writer.usize(location.offset.0);
+            writer.usize(self.sizes[id]);
+        });
+        for returns in [&self.returns, &self.jumps] {
+            let returns: Vec<&ReturnInfo> = returns.iter_all().flat_map(|(_, returns)| returns.iter()).collect();
+            writer.usize(returns.len());
+            returns.iter().for_each(|ret| ret.write(&mut writer));
+        }
+        writer.usize(self.fallthrough.len());
+        self.fallthrough.iter().for_each(|(id, source)| {
+            writer.id(*id);
+            writer.id(*source);
+        });
+        Some(writer.bytes)
+    }
+
+    fn load(&mut self, saved: &[u8]) -> io::Result<()> {
+        let mut reader = SnapshotReader::new(saved);
+        if (reader.u8()? != 0) != self.fuel_checks {
+            return Err(invalid("fuel metering differs from snapshot"));
+        }
+        if reader.usize()? != self.chunk_size {
+            return Err(invalid("code chunk size differs from snapshot"));
+        }
+        self.chunks = vec![];
+        for _ in 0..reader.usize()? {
+            let mut chunk = Chunk::new(self.chunk_size)?;
+            if reader.u8()? == 0 {
+                chunk.code = ChunkCode::Freed;
+            } else {
+                chunk.live = reader.usize()?;
+                chunk.dead = reader.usize()?;
+                chunk.offset = AssemblyOffset(reader.usize()?);
+                chunk.prologue_offset = AssemblyOffset(reader.usize()?);
+                let code = reader.bytes()?;
+                let prologues = reader.bytes()?;
+                if code.len() != chunk.offset.0 || chunk.offset > chunk.prologue_offset || chunk.prologue_offset.0 + prologues.len() != self.chunk_size {
+                    return Err(invalid("malformed code chunk"));
                 }
+                chunk.assembler(AssemblyOffset(0)).extend(code.iter());
+                chunk.assembler(chunk.prologue_offset).extend(prologues.iter());
+            }
+            self.chunks.push(chunk);
+        }
+
+        // locations are checked against chunks, so corrupted snapshot fails to load instead of panicking later
+        self.current = reader.usize()?;
+        if !self.allocated(self.current) {
+            return Err(invalid("malformed current chunk"));
+        }
+        self.offsets = HashMap::new();
+        self.sizes = HashMap::new();
+        for _ in 0..reader.usize()? {
+            let id = reader.id()?;
+            let location = CodeLocation { chunk: reader.usize()?, offset: AssemblyOffset(reader.usize()?) };
+            if !self.allocated(location.chunk) || location.offset.0 >= self.chunk_size {
+                return Err(invalid("malformed node location"));
+            }
+            self.offsets.insert(id, location);
+            self.sizes.insert(id, reader.usize()?);
+        }
+        let mut live: Vec<usize> = (0..self.chunks.len()).map(|chunk| (chunk == 0) as usize).collect();
+        self.offsets.values().for_each(|location| *live.get_mut(location.chunk).unwrap() += 1);
+        if self.chunks.iter().zip(live).any(|(chunk, live)| chunk.live != live) {
+            return Err(invalid("malformed code chunk"));
+        }
+        self.returns = MultiMap::new();
+        for _ in 0..reader.usize()? {
+            let ret = self.read_return(&mut reader)?;
+            self.returns.insert(ret.id, ret);
+        }
+        self.jumps = MultiMap::new();
+        for _ in 0..reader.usize()? {
+            let ret = self.read_return(&mut reader)?;
+            if !self.offsets.contains_key(&ret.id) {
+                return Err(invalid("jump to node without code"));
+            }
+            self.jumps.insert(ret.id, ret);
+        }
+        self.fallthrough = HashMap::new();
+        for _ in 0..reader.usize()? {
+            self.fallthrough.insert(reader.id()?, reader.id()?);
+        }
+
+        // chunks are at different addresses now
+        let jumps: Vec<ReturnInfo> = self.jumps.iter_all().flat_map(|(_, jumps)| jumps.clone()).collect();
+        for ret in jumps {
+            let to = *self.offsets.get(&ret.id).unwrap();
+            self.jump(ret.location(), to);
+        }
+        self.chunks.iter_mut().for_each(|chunk| chunk.seal());
+        Ok(())
+    }
+
+    fn allocated(&self, chunk: usize) -> bool {
+        self.chunks.get(chunk).is_some_and(|chunk| !matches!(chunk.code, ChunkCode::Freed))
+    }
+
+    // return in code of registered node, it's overwritten by jumps
+    fn read_return(&self, reader: &mut SnapshotReader) -> io::Result<ReturnInfo> {
+        let ret = ReturnInfo::read(reader)?;
+        if !self.offsets.contains_key(&ret.source) || !self.allocated(ret.chunk) || ret.spill > ret.from
+            || ret.from.0 + SUSPEND_SIZE != ret.to.0 || ret.to.0 > self.chunk_size {
+            return Err(invalid("malformed return"));
+        }
+        Ok(ret)
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            registered: self.offsets.len() as u64,
+            code_bytes: self.code_bytes,
+            code_memory: self.code_memory() as u64,
+            patches: self.patches,
+            ..self.stats.get()
+        }
+    }
+
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool {
+        let frame = state.frames.pop().unwrap();
+
+        match self.offsets.get(&frame.id) {
+            None => {
+                if let Some(observer) = &self.observer { observer.on_suspend(frame.id) }
+                state.frames.push(frame);
+                self.stats.suspended();
+                true
+            }
+            Some(location) => {
+                let data_offset = state.offset() + frame.offset;
+                let mut unwind_dst = [SuspendTrace { offset: 0, id: NodeId(0) }; 1024];
+                let code = self.chunks.get(location.chunk).unwrap().ptr(location.offset);
+                let trampoline = self.chunks.first().unwrap().ptr(AssemblyOffset(0));
+                let output = interop(trampoline, code, stack[data_offset..].as_mut_ptr(), stack.as_ptr_range().end, unwind_dst.as_mut_ptr(), &self.fuel, globals);
+                let suspended_entries = (output - (unwind_dst.as_ptr() as usize)) / 8;
+                let mut entries = unwind_dst[0..suspended_entries].to_vec();
+                entries.reverse();
+                if !entries.is_empty() {
+                    self.stats.suspended();
+                    entries.first_mut().unwrap().offset += frame.offset as u32;
+                    entries.iter().for_each(|entry| {
+                        state.frames.push(Frame { id: entry.id, offset: entry.offset as usize })
+                    });
+                    if let Some(observer) = &self.observer { observer.on_suspend(entries.last().unwrap().id) }
+                }
+                true // todo: not necessary!
             }
-        } else {
-            panic!()
         }
     }
 }
 
-struct Assembler {
-    buffer: MutableBuffer,
+struct Assembler<'a> {
+    buffer: &'a mut MutableBuffer,
     offset: AssemblyOffset,
 }
 
-impl Extend<u8> for Assembler {
+impl<'a> Extend<u8> for Assembler<'a> {
     fn extend<T: IntoIterator<Item=u8>>(&mut self, iter: T) { todo!() }
 }
 
-impl<'a> Extend<&'a u8> for Assembler {
-    fn extend<T: IntoIterator<Item=&'a u8>>(&mut self, iter: T) {
+impl<'a, 'b> Extend<&'b u8> for Assembler<'a> {
+    fn extend<T: IntoIterator<Item=&'b u8>>(&mut self, iter: T) {
         for byte in iter {
             *self.buffer.get_mut(self.offset.0).unwrap() = *byte;
             self.offset.0 += 1;
@@ -191,7 +662,7 @@ impl<'a> Extend<&'a u8> for Assembler {
     }
 }
 
-impl DynasmApi for Assembler {
+impl<'a> DynasmApi for Assembler<'a> {
     fn offset(&self) -> AssemblyOffset { self.offset }
     fn push(&mut self, byte: u8) { todo!() }
     fn align(&mut self, alignment: usize, with: u8) { todo!() }
@@ -199,5 +670,18 @@ impl DynasmApi for Assembler {
 
 impl Engine for CodeGeneratorEngine {
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.register(id, kind) }
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool { self.run(state, stack) }
+    fn region(&self) -> Region { self.region }
+    fn register_batch(&mut self, nodes: Vec<(NodeId, NodeKind<NodeId>)>) { self.register_batch(nodes) }
+    fn unregister(&mut self, id: NodeId) { self.unregister(id) }
+    fn dependents(&self, id: NodeId) -> Vec<NodeId> { self.dependents(id) }
+    fn reclaimable(&self) -> Vec<NodeId> { self.reclaimable() }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.run(state, stack, globals) }
+    fn save(&self) -> Option<Vec<u8>> { self.save() }
+    fn load(&mut self, saved: &[u8]) -> io::Result<()> { self.load(saved) }
+    fn set_profiling(&mut self, profiling: bool) { self.set_profiling(profiling) }
+    fn profile(&self, id: NodeId) -> Option<NodeProfile> { self.profile.as_ref()?.get(id) }
+    fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.set_observer(observer) }
+    fn set_fuel(&mut self, fuel: Option<u64>) { self.set_fuel(fuel) }
+    fn fuel(&self) -> Option<u64> { self.fuel() }
+    fn stats(&self) -> EngineStats { self.stats() }
 }
@@ -1,6 +1,16 @@
-use std::collections::HashMap;
-use crate::core::api::{Node, NodeKind};
-use crate::core::interpreter::get_final_kind;
+use std::collections::{HashMap, HashSet};
+use std::num::Wrapping;
+use std::io;
+use std::path::Path;
+use crate::core::api::{Command, Node, NodeKind, PersistentNode, Ref};
+use std::sync::Arc;
+use crate::core::driver::execution::Execution;
+use crate::core::driver::observer::Observer;
+use crate::core::driver::profile::NodeProfile;
+use crate::core::driver::stats::EngineStats;
+use crate::core::driver::snapshot::{MAGIC, SnapshotReader, SnapshotWriter};
+use crate::core::globals::Globals;
+use crate::core::interpreter::{eval_command, eval_condition, get_final_kind, get_u32, put_u32, read};
 
 // never has id < 16, so this ids can be used for marking usages
 const MIN_NODE_ID: usize = 16;
@@ -14,58 +24,979 @@ impl NodeId {
 pub trait Engine {
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>);
 
+    // part of the graph driver registers at once when engine suspends on unknown node
+    fn region(&self) -> Region { Region::Node }
+
+    // nodes of the region in depth first order, first one is the node engine suspended on
+    fn register_batch(&mut self, nodes: Vec<(NodeId, NodeKind<NodeId>)>) {
+        nodes.into_iter().for_each(|(id, kind)| self.register(id, kind))
+    }
+
+    // forgets node and everything computed for it, engine should suspend on it as on unknown node afterwards
+    fn unregister(&mut self, id: NodeId);
+
+    // registered nodes which engine can't keep without `id`, driver evicts them before unregistering `id`
+    fn dependents(&self, _id: NodeId) -> Vec<NodeId> { vec![] }
+
+    // registered nodes engine asks to evict, e.g. so memory shared with unregistered nodes can be freed
+    fn reclaimable(&self) -> Vec<NodeId> { vec![] }
+
     // returns true - suspended on unknown node, false - otherwise
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool;
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool;
+
+    // engine state for `Driver::save`, `None` if it can't be persisted and nodes should be registered again on load
+    fn save(&self) -> Option<Vec<u8>> { None }
+
+    // restores state returned by `save` in another process, node ids are the same
+    fn load(&mut self, _saved: &[u8]) -> io::Result<()> { Ok(()) }
+
+    // enables per node counters, engine might require it to be set before any node is registered
+    fn set_profiling(&mut self, _profiling: bool) {}
+
+    // counters of registered node since its registration, `calls` is filled by driver
+    fn profile(&self, _id: NodeId) -> Option<NodeProfile> { None }
+
+    // engine might require it to be set before any node is registered
+    fn set_observer(&mut self, _observer: Option<Arc<dyn Observer>>) {}
+
+    // `None` turns fuel metering off, engine might require metering to be on before any node is registered
+    fn set_fuel(&mut self, _fuel: Option<u64>) {}
+
+    // engine suspends once it's zero
+    fn fuel(&self) -> Option<u64> { None }
+
+    // counters for monitoring, see `EngineStats`
+    fn stats(&self) -> EngineStats { EngineStats::default() }
 }
 
+#[derive(Clone)]
 pub struct Frame {
     pub id: NodeId,
     // offset represents with what offset *this and subsequent* nodes should be executed
     pub offset: usize,
 }
 
+#[derive(Clone)]
 pub struct RunState {
     pub frames: Vec<Frame>,
+    // argument bytes of memoized calls in progress, one per their memo return frame
+    pub(crate) args: Vec<Vec<u8>>,
+    // coroutines made by the run by their handles, see `NodeKind::Coroutine`
+    pub(crate) coroutines: Vec<Coroutine>,
+    // running coroutines, innermost last
+    pub(crate) resumed: Vec<Resumed>,
 }
 
 impl RunState {
     pub fn offset(&self) -> usize { self.frames.iter().map(|f| f.offset).sum() }
+
+    // frames of the run along with the ones of suspended coroutines
+    fn all_frames(&self) -> impl Iterator<Item = &Frame> {
+        self.frames.iter().chain(self.coroutines.iter().flat_map(|coroutine| coroutine.frames.iter()))
+    }
+
+    // unfinished coroutines are dropped with finished run
+    pub(crate) fn drop_coroutines_if_finished(&mut self) {
+        if self.frames.is_empty() {
+            self.coroutines.clear();
+            self.resumed.clear();
+        }
+    }
+}
+
+// Coroutine made by the run, it keeps its frames while it's suspended.
+#[derive(Clone)]
+pub(crate) struct Coroutine {
+    // offset of its outermost frame from the stack start
+    base: usize,
+    // outermost one is at `base`, there are none while coroutine runs and once it's finished
+    frames: Vec<Frame>,
+    // see `RunState::args`
+    args: Vec<Vec<u8>>,
+    finished: bool,
+}
+
+// Running coroutine, its frames and args are the ones past these counts.
+#[derive(Clone, Copy)]
+pub(crate) struct Resumed {
+    handle: u32,
+    frames: usize,
+    args: usize,
 }
 
 pub struct Driver<N: Node, E: Engine> {
     nodes: Vec<Option<N>>,
     idx: HashMap<N, NodeId>,
+    info: Vec<NodeInfo>,
+    // ids of released nodes, reused for new ones
+    free: Vec<NodeId>,
+    registered: usize,
+    tick: u64,
+    budget: Option<usize>,
+
+    // nodes read by `load` which aren't matched with `N` yet, by their keys
+    loaded: HashMap<Vec<u8>, NodeId>,
+    key: Option<fn(&N) -> Vec<u8>>,
+
+    // nodes kept unregistered in engine, so engine suspends once it reaches them
+    breakpoints: HashSet<NodeId>,
+
+    // nodes made by driver itself, i.e. inlined callee copies, they keep references to their successors
+    synthetic: HashMap<NodeId, NodeKind<NodeId>>,
+    // see `set_inlining`
+    inlining: Option<usize>,
+    // call -> callee nodes it inlined, call keeps reference to callee
+    inlined: HashMap<NodeId, Vec<NodeId>>,
+    // inlined copy -> node it copies, copy keeps reference to it, see `attribute`
+    copies: HashMap<NodeId, NodeId>,
+    // try -> node its callee returns to, see `landing`
+    landings: HashMap<NodeId, NodeId>,
+    // landing -> catch and offset of its try
+    catches: HashMap<NodeId, (NodeId, u32)>,
+    // see `set_memoization`
+    memoization: Option<usize>,
+    // callee -> its results by argument bytes
+    memo: HashMap<NodeId, HashMap<Vec<u8>, Vec<u8>>>,
+    // pure call -> node its callee returns to, see `memo_return`
+    memo_returns: HashMap<NodeId, NodeId>,
+    // memo return -> call it records results of
+    memo_calls: HashMap<NodeId, MemoCall>,
+    // resume -> node coroutine it resumed returns to, see `coroutine_return`
+    coroutine_returns: HashMap<NodeId, NodeId>,
+    // coroutine return -> node resumer goes on to
+    resumers: HashMap<NodeId, NodeId>,
+    // see `set_globals`
+    globals: Arc<Globals>,
 
     engine: E,
 }
 
+// Nodes registered together, see `Engine::region`. Nodes which are already registered or breakpoints are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Node,
+    // nodes reachable within given number of steps
+    Steps(u32),
+    // nodes reachable without entering calls, i.e. the rest of function
+    Function,
+}
+
+// Why `resume` or `step` returned control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    // breakpoint node is on top of frames, it isn't executed yet
+    Breakpoint(NodeId),
+    Step,
+    OutOfFuel,
+    // `Yield` node was reached, frames are already past it
+    Yield,
+    // `HostCall` node was reached, frames are already past it, host runs `function` on stack from `offset` before resuming
+    HostCall { function: u32, offset: usize },
+    Finished,
+    // thrown payload no try caught, frames are dropped, so run is finished
+    Uncaught(Vec<u8>),
+    // run can't go on, frames are dropped, so run is finished
+    Fault(Fault),
+}
+
+// Misuse of nodes run is aborted on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    // `Resume` of coroutine `handle` which can't be resumed: there is no such one, it's finished or running,
+    // or its frame is below the resumer's one
+    Resume(u32),
+    // node engines can't run: it accesses globals past their end, atomic of other size than 4 or 8 bytes,
+    // or atomic global which isn't aligned by its size
+    Unsupported(NodeId),
+}
+
+// Pure call which results are recorded once its callee returns.
+#[derive(Clone, Copy)]
+struct MemoCall {
+    call: NodeId,
+    next: NodeId,
+    offset: u32,
+    results: u32,
+}
+
+// Unfinished runs are pinned, see `Driver::start`.
+pub enum Outcome {
+    Finished,
+    // run can be continued with `Driver::resume` once fuel is added
+    OutOfFuel(RunState),
+    // run can be continued with `Driver::resume`
+    Yielded(RunState),
+    // run can be continued with `Driver::resume` once host ran `function` on stack from `offset`
+    HostCall { ctx: RunState, function: u32, offset: usize },
+    // thrown payload no try caught, run is finished
+    Uncaught(Vec<u8>),
+    // run is aborted, see `Stop::Fault`
+    Fault(Fault),
+}
+
+#[derive(Default, Clone)]
+struct NodeInfo {
+    // present if node is registered in engine
+    kind: Option<NodeKind<NodeId>>,
+    // number of registered nodes referring to this one, while it's positive id can't be reused
+    references: u32,
+    last_used: u64,
+    // counters collected by engine before node was evicted
+    profile: NodeProfile,
+}
+
 impl<N: Node, E: Engine> Driver<N, E> {
     pub fn new(engine: E) -> Driver<N, E> {
         let mut nodes = vec![];
         (0..MIN_NODE_ID).for_each(|_| nodes.push(None));
-        Driver { nodes, idx: HashMap::new(), engine }
+        let info = vec![NodeInfo::default(); MIN_NODE_ID];
+        Driver {
+            nodes, idx: HashMap::new(), info, free: vec![], registered: 0, tick: 0, budget: None,
+            loaded: HashMap::new(), key: None, breakpoints: HashSet::new(),
+            synthetic: HashMap::new(), inlining: None, inlined: HashMap::new(), copies: HashMap::new(),
+            landings: HashMap::new(), catches: HashMap::new(),
+            memoization: None, memo: HashMap::new(), memo_returns: HashMap::new(), memo_calls: HashMap::new(),
+            coroutine_returns: HashMap::new(), resumers: HashMap::new(),
+            globals: Arc::new(Globals::default()), engine
+        }
+    }
+
+    // Limits number of nodes registered in engine, least recently used ones are evicted after each `eval`.
+    // Usage is tracked on registration and `eval` entry only, evicted node which is still needed is registered again.
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+        self.collect();
+    }
+
+    // same as `set_budget`, but nothing is evicted until next `collect`
+    pub(crate) fn set_budget_deferred(&mut self, budget: Option<usize>) { self.budget = budget; }
+
+    pub fn registered(&self) -> usize { self.registered }
+
+    // Calls of callees without loops and with at most `limit` nodes are replaced with callee copy once registered,
+    // so they don't cost a frame. Affects only calls registered afterwards.
+    pub fn set_inlining(&mut self, limit: Option<usize>) { self.inlining = limit; }
+
+    // Results of `PureCall` callees are remembered by their arguments, at most `limit` per callee,
+    // and calls with remembered arguments are skipped. Affects only pure calls registered afterwards, without it they're plain calls.
+    // Results are forgotten once callee is evicted or invalidated, turning memoization off forgets all of them.
+    pub fn set_memoization(&mut self, limit: Option<usize>) {
+        self.memoization = limit;
+        if limit.is_none() { self.memo.clear(); }
+    }
+
+    // Replaces global data area addressed by `Ref::Global` with zeroed one of `size` bytes, there is none initially.
+    // Runs reaching nodes accessing globals past the area are aborted with `Fault::Unsupported`, registered ones are evicted right away.
+    pub fn set_globals(&mut self, size: usize) {
+        self.globals = Arc::new(Globals::new(size));
+        self.evict_past_globals();
+    }
+
+    pub fn globals(&self) -> &Arc<Globals> { &self.globals }
+
+    pub fn set_profiling(&mut self, profiling: bool) { self.engine.set_profiling(profiling) }
+
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.engine.set_observer(observer) }
+
+    // Execution counters of nodes with profiling enabled, nodes which weren't executed are omitted.
+    // Counters survive eviction, but are dropped once node is forgotten completely.
+    // Inlined code counts for the callee, as if call wasn't inlined. Nodes are given by ids, see `node`.
+    pub fn profile(&self) -> HashMap<NodeId, NodeProfile> {
+        let mut profiles: Vec<NodeProfile> = self.info.iter().map(|info| info.profile).collect();
+        for (id, info) in self.info.iter().enumerate() {
+            if let Some(profile) = self.engine.profile(NodeId(id as u32)) {
+                for (target, profile) in self.attribute(NodeId(id as u32), info.kind.as_ref(), profile) {
+                    profiles.get_mut(target.0 as usize).unwrap().add(&profile);
+                }
+            }
+        }
+        self.idx.values()
+            .map(|id| (*id, *profiles.get(id.0 as usize).unwrap()))
+            .filter(|(_, profile)| *profile != NodeProfile::default())
+            .collect()
+    }
+
+    // Engine counters for monitoring, e.g. how much code is generated and how often runs suspend.
+    pub fn stats(&self) -> EngineStats { self.engine.stats() }
+
+    // Fuel left, see `set_fuel`.
+    pub fn fuel(&self) -> Option<u64> { self.engine.fuel() }
+
+    // Limits number of nodes engine executes, `eval` and `resume` return once it's exhausted.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) { self.engine.set_fuel(fuel) }
+
+    pub fn eval(&mut self, node: N, stack: &mut [u8]) -> Outcome {
+        let mut ctx = self.start(node);
+        match self.run_pinned(&mut ctx, stack, Self::eval_inner) {
+            Stop::OutOfFuel => { Outcome::OutOfFuel(ctx) }
+            Stop::Yield => { Outcome::Yielded(ctx) }
+            Stop::HostCall { function, offset } => { Outcome::HostCall { ctx, function, offset } }
+            stop => {
+                self.collect();
+                match stop {
+                    Stop::Uncaught(payload) => { Outcome::Uncaught(payload) }
+                    Stop::Fault(fault) => { Outcome::Fault(fault) }
+                    _ => { Outcome::Finished }
+                }
+            }
+        }
     }
 
-    pub fn eval(&mut self, node: N, stack: &mut [u8]) {
+    // Drops everything engine computed for the node, it's going to be re-read with `Node::get` once reached again.
+    // If nothing refers to the node anymore it's forgotten completely and its id is reused.
+    // Calls which inlined the node are dropped as well.
+    pub fn invalidate(&mut self, node: &N) {
+        if let Some(id) = self.idx.get(node).copied() {
+            self.evict_inlining(id);
+            self.evict(id);
+        }
+    }
+
+    // evicts calls which inlined the node, so they reach the node itself once registered again
+    fn evict_inlining(&mut self, id: NodeId) {
+        let calls: Vec<NodeId> = self.inlined.iter()
+            .filter(|(_, body)| body.contains(&id))
+            .map(|(call, _)| *call)
+            .collect();
+        calls.into_iter().for_each(|call| self.evict(call));
+    }
+
+    // breakpoints are ignored
+    fn eval_inner(&mut self, ctx: &mut RunState, stack: &mut[u8]) -> Stop {
+        while !ctx.frames.is_empty() {
+            if self.engine.run(ctx, stack, &self.globals) {
+                if self.out_of_fuel(ctx) {
+                    return Stop::OutOfFuel;
+                }
+                let stop = if self.at_breakpoint(ctx).is_some() {
+                    self.step_node(ctx, stack)
+                } else {
+                    self.continue_suspended(ctx, stack)
+                };
+                if let Some(stop) = stop {
+                    return stop;
+                }
+            }
+        }
+        Stop::Finished
+    }
+
+    pub(crate) fn out_of_fuel(&self, ctx: &RunState) -> bool {
+        !ctx.frames.is_empty() && self.engine.fuel() == Some(0)
+    }
+
+    pub fn id(&self, node: &N) -> Option<NodeId> { self.idx.get(node).copied() }
+
+    pub fn node(&self, id: NodeId) -> Option<&N> { self.nodes.get(id.0 as usize)?.as_ref() }
+
+    // Evaluation of the node which is run piece by piece with `Execution::resume`, see `Execution`.
+    pub fn execution(&mut self, node: N, stack: Vec<u8>) -> Execution {
+        Execution { state: self.start(node), stack }
+    }
+
+    // Execution stops before breakpoint node in `resume`, `eval` ignores breakpoints.
+    pub fn set_breakpoint(&mut self, node: N) -> NodeId {
         let id = self.get_id(node);
-        let mut ctx = RunState { frames: vec![Frame { id, offset: 0 }] };
-        self.eval_inner(&mut ctx, stack);
-        assert!(ctx.frames.is_empty());
+        self.breakpoints.insert(id);
+        self.evict_inlining(id);
+        self.evict(id);
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, node: &N) {
+        if let Some(id) = self.id(node) {
+            self.breakpoints.remove(&id);
+        }
+    }
+
+    // State for stepping through evaluation of the node with `resume` and `step`.
+    // Stack can be inspected and modified between them, frames can be inspected.
+    // Nodes in frames are pinned, so their ids aren't reused until run is finished or `cancel`ed.
+    pub fn start(&mut self, node: N) -> RunState {
+        let id = self.get_id(node);
+        self.touch(id);
+        let ctx = RunState { frames: vec![Frame { id, offset: 0 }], args: vec![], coroutines: vec![], resumed: vec![] };
+        self.pin(&ctx);
+        ctx
+    }
+
+    // Drops unfinished run, nodes in its frames can be forgotten afterwards.
+    pub fn cancel(&mut self, ctx: RunState) { self.unpin(&ctx) }
+
+    // Runs until breakpoint, yield, host call, fuel exhaustion or the end. Node on top of frames is executed even if it's breakpoint,
+    // so resuming from breakpoint doesn't stop on it again.
+    pub fn resume(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Stop {
+        let stop = self.run_pinned(ctx, stack, Self::resume_inner);
+        if matches!(stop, Stop::Finished | Stop::Uncaught(_) | Stop::Fault(_)) {
+            self.collect();
+        }
+        stop
     }
 
-    fn eval_inner(&mut self, ctx: &mut RunState, stack: &mut[u8]) {
+    // Executes single node on top of frames, bypassing engine.
+    pub fn step(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Stop {
+        self.run_pinned(ctx, stack, Self::step_inner)
+    }
+
+    // keeps frames left by the run pinned instead of the ones it started with
+    fn run_pinned(&mut self, ctx: &mut RunState, stack: &mut [u8], run: fn(&mut Self, &mut RunState, &mut [u8]) -> Stop) -> Stop {
+        // old frames are unpinned after the run, otherwise top one could be forgotten before it's registered
+        let pinned = ctx.clone();
+        let stop = run(self, ctx, stack);
+        ctx.drop_coroutines_if_finished();
+        self.pin(ctx);
+        self.unpin(&pinned);
+        stop
+    }
+
+    fn resume_inner(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Stop {
+        if self.at_breakpoint(ctx).is_some() {
+            if let Some(stop) = self.step_node(ctx, stack) {
+                return stop;
+            }
+        }
         while !ctx.frames.is_empty() {
-            if self.engine.run(ctx, stack) {
-                if !ctx.frames.is_empty() {
-                    let id_to_register = ctx.frames.last().unwrap().id;
-                    let kind = self.get_kind(id_to_register);
-                    self.engine.register(id_to_register, kind);
+            if self.engine.run(ctx, stack, &self.globals) {
+                if self.out_of_fuel(ctx) {
+                    return Stop::OutOfFuel;
+                }
+                if let Some(id) = self.at_breakpoint(ctx) {
+                    return Stop::Breakpoint(id);
                 }
+                if let Some(stop) = self.continue_suspended(ctx, stack) {
+                    return stop;
+                }
+            }
+        }
+        Stop::Finished
+    }
+
+    fn step_inner(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Stop {
+        if !ctx.frames.is_empty() {
+            if let Some(stop) = self.step_node(ctx, stack) {
+                return stop;
             }
         }
+        if ctx.frames.is_empty() { Stop::Finished } else { Stop::Step }
     }
 
+    fn at_breakpoint(&self, ctx: &RunState) -> Option<NodeId> {
+        ctx.frames.last().map(|frame| frame.id).filter(|id| self.breakpoints.contains(id))
+    }
+
+    // same as `InterpreterEngine` does for single node, inlined calls are stepped into as calls.
+    // Returns stop if node suspends the run: `Yield`, `HostCall` or uncaught `Throw`.
+    fn step_node(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Option<Stop> {
+        let offset = ctx.offset();
+        let frame = ctx.frames.pop().unwrap();
+        self.touch(frame.id);
+        if let Some(memo_call) = self.memo_calls.get(&frame.id).copied() {
+            let start = offset + memo_call.offset as usize;
+            let args = ctx.args.pop().unwrap();
+            if let Some(limit) = self.memoization {
+                let memo = self.memo.entry(memo_call.call).or_default();
+                if memo.len() < limit {
+                    memo.insert(args, stack[start..start + memo_call.results as usize].to_vec());
+                }
+            }
+            ctx.frames.push(Frame { id: memo_call.next, offset: frame.offset });
+            return None;
+        }
+        if let Some(next) = self.resumers.get(&frame.id).copied() {
+            // coroutine returned
+            let resumed = ctx.resumed.pop().unwrap();
+            ctx.coroutines.get_mut(resumed.handle as usize).unwrap().finished = true;
+            ctx.frames.push(Frame { id: next, offset: frame.offset });
+            return None;
+        }
+        let kind = self.original_kind(frame.id);
+        if !self.supported(&kind) {
+            return Some(abort(ctx, Fault::Unsupported(frame.id)));
+        }
+        match kind {
+            NodeKind::Command { command, next } => {
+                eval_command(&command, &mut stack[offset..], &self.globals);
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+            }
+            NodeKind::Branch { condition, if_true, if_false } => {
+                let next = if eval_condition(&condition, &mut stack[offset..], &self.globals) { if_true } else { if_false };
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+            }
+            NodeKind::Call { offset: call_offset, call, next } => {
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+                ctx.frames.push(Frame { id: call, offset: call_offset as usize });
+            }
+            NodeKind::Final => {}
+            NodeKind::Yield { next } => {
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+                if ctx.resumed.is_empty() {
+                    return Some(Stop::Yield);
+                }
+                self.suspend_coroutine(ctx);
+            }
+            NodeKind::Coroutine { offset: call_offset, call, handle, next } => {
+                let made = ctx.coroutines.len() as u32;
+                let base = offset + call_offset as usize;
+                ctx.coroutines.push(Coroutine { base, frames: vec![Frame { id: call, offset: 0 }], args: vec![], finished: false });
+                put_u32(handle, &mut stack[offset..], &self.globals, Wrapping(made));
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+            }
+            NodeKind::Resume { handle, next } => {
+                let handle = get_u32(handle, &stack[offset..], &self.globals).0;
+                let returns_to = self.coroutine_return(frame.id, next);
+                ctx.frames.push(Frame { id: returns_to, offset: frame.offset });
+                if let Err(fault) = self.resume_coroutine(ctx, handle) {
+                    return Some(abort(ctx, fault));
+                }
+            }
+            NodeKind::HostCall { function, offset: call_offset, next } => {
+                ctx.frames.push(Frame { id: next, offset: frame.offset });
+                return Some(Stop::HostCall { function, offset: offset + call_offset as usize });
+            }
+            NodeKind::TailCall { offset: call_offset, call } => {
+                ctx.frames.push(Frame { id: call, offset: frame.offset + call_offset as usize });
+            }
+            NodeKind::Try { offset: call_offset, call, next, catch } => {
+                let landing = self.landing(frame.id, call_offset, next, catch);
+                ctx.frames.push(Frame { id: landing, offset: frame.offset });
+                ctx.frames.push(Frame { id: call, offset: call_offset as usize });
+            }
+            NodeKind::Throw { op, size } => {
+                let payload = read(op, size, &stack[offset..], &self.globals);
+                return self.throw(ctx, stack, payload).map(Stop::Uncaught);
+            }
+            NodeKind::PureCall { offset: call_offset, call, next, args, results } => {
+                let start = offset + call_offset as usize;
+                let key = &stack[start..start + args as usize];
+                let known = self.memo.get(&call).and_then(|memo| memo.get(key)).cloned();
+                if let (Some(_), Some(known)) = (self.memoization, known) {
+                    stack[start..start + results as usize].copy_from_slice(&known);
+                    ctx.frames.push(Frame { id: next, offset: frame.offset });
+                    return None;
+                }
+                let returns_to = if self.memoization.is_some() {
+                    ctx.args.push(key.to_vec());
+                    self.memo_return(frame.id, call_offset, call, next, results)
+                } else { next };
+                ctx.frames.push(Frame { id: returns_to, offset: frame.offset });
+                ctx.frames.push(Frame { id: call, offset: call_offset as usize });
+            }
+        }
+        None
+    }
+
+    // Moves frames of coroutine on top of the run, above frame returning to the resumer.
+    fn resume_coroutine(&mut self, ctx: &mut RunState, handle: u32) -> Result<(), Fault> {
+        let offset = ctx.offset();
+        // running one has no frames
+        let Some(coroutine) = ctx.coroutines.get_mut(handle as usize)
+            .filter(|coroutine| !coroutine.finished && !coroutine.frames.is_empty() && coroutine.base >= offset) else { return Err(Fault::Resume(handle)) };
+        let mut frames = std::mem::take(&mut coroutine.frames);
+        frames[0].offset = coroutine.base - offset;
+        ctx.resumed.push(Resumed { handle, frames: ctx.frames.len(), args: ctx.args.len() });
+        ctx.args.append(&mut coroutine.args);
+        ctx.frames.extend(frames);
+        Ok(())
+    }
+
+    // Moves frames of innermost running coroutine back to it, resumer goes on past its resume.
+    fn suspend_coroutine(&mut self, ctx: &mut RunState) {
+        let resumed = ctx.resumed.pop().unwrap();
+        let mut frames = ctx.frames.split_off(resumed.frames);
+        let args = ctx.args.split_off(resumed.args);
+        let coroutine = ctx.coroutines.get_mut(resumed.handle as usize).unwrap();
+        // tail calls might have moved outermost frame
+        coroutine.base = ctx.frames.iter().map(|frame| frame.offset).sum::<usize>() + frames[0].offset;
+        frames[0].offset = 0;
+        (coroutine.frames, coroutine.args) = (frames, args);
+        let returns_to = ctx.frames.pop().unwrap();
+        ctx.frames.push(Frame { id: self.resumers[&returns_to.id], offset: returns_to.offset });
+    }
+
+    // Drops frames up to the one returning to landing of some try, which is replaced by its catch.
+    // Returns payload back if there is no such frame, all frames are dropped then.
+    fn throw(&mut self, ctx: &mut RunState, stack: &mut [u8], payload: Vec<u8>) -> Option<Vec<u8>> {
+        while let Some(frame) = ctx.frames.pop() {
+            // callee didn't return, so there is nothing to record
+            if self.memo_calls.contains_key(&frame.id) {
+                ctx.args.pop();
+            }
+            // exception leaves coroutine, it can't be resumed anymore
+            if self.resumers.contains_key(&frame.id) {
+                let resumed = ctx.resumed.pop().unwrap();
+                ctx.coroutines.get_mut(resumed.handle as usize).unwrap().finished = true;
+            }
+            if let Some((catch, offset)) = self.catches.get(&frame.id).copied() {
+                let dst = ctx.offset() + frame.offset + offset as usize;
+                stack[dst..dst + payload.len()].copy_from_slice(&payload);
+                ctx.frames.push(Frame { id: catch, offset: frame.offset });
+                return None;
+            }
+        }
+        Some(payload)
+    }
+
+    pub(crate) fn engine(&self) -> &E { &self.engine }
+
+    // Registers node execution got suspended on, or steps over it if it's handled by driver:
+    // `Yield`, `Coroutine`, `Resume`, `HostCall`, `Throw`, `PureCall`, memo or coroutine return.
+    // Returns stop if it suspends the run, see `step_node`.
+    pub(crate) fn continue_suspended(&mut self, ctx: &mut RunState, stack: &mut [u8]) -> Option<Stop> {
+        // engine suspends on registered yield, coroutine, resume, host call, throw and pure call too
+        if !self.at_driver_node(ctx) {
+            if let Err(fault) = self.register_suspended(ctx) {
+                return Some(abort(ctx, fault));
+            }
+            if !self.at_driver_node(ctx) { return None; }
+        }
+        self.step_node(ctx, stack)
+    }
+
+    fn at_driver_node(&self, ctx: &RunState) -> bool {
+        matches!(self.top_kind(ctx), Some(NodeKind::Yield { .. } | NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. }
+            | NodeKind::Throw { .. } | NodeKind::PureCall { .. }))
+            || ctx.frames.last().is_some_and(|frame| self.memo_calls.contains_key(&frame.id) || self.resumers.contains_key(&frame.id))
+    }
+
+    fn top_kind(&self, ctx: &RunState) -> Option<&NodeKind<NodeId>> {
+        self.info.get(ctx.frames.last()?.id.0 as usize).unwrap().kind.as_ref()
+    }
+
+    // registers node execution got suspended on, unless engines can't run it
+    fn register_suspended(&mut self, ctx: &RunState) -> Result<(), Fault> {
+        if let Some(frame) = ctx.frames.last() {
+            let nodes = self.region(frame.id);
+            if !self.supported(&nodes[0].1) {
+                return Err(Fault::Unsupported(frame.id));
+            }
+            nodes.iter().for_each(|(id, kind)| self.mark_registered(*id, kind));
+            self.engine.register_batch(nodes);
+        }
+        Ok(())
+    }
+
+    // `id` and unregistered nodes of engine's region around it, in depth first order
+    fn region(&mut self, id: NodeId) -> Vec<(NodeId, NodeKind<NodeId>)> {
+        let region = self.engine.region();
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        let mut queue = vec![(id, 0)];
+        while let Some((id, steps)) = queue.pop() {
+            if !visited.insert(id) { continue; }
+            // engine might forget suspended node by itself, so it's registered anyway
+            if !nodes.is_empty() && (self.info.get(id.0 as usize).unwrap().kind.is_some() || self.breakpoints.contains(&id)) {
+                continue;
+            }
+            let kind = self.get_kind(id);
+            // unsupported nodes are left to fault once execution gets to them
+            if !nodes.is_empty() && !self.supported(&kind) {
+                continue;
+            }
+            let next = match (region, &kind) {
+                (Region::Node, _) => { vec![] }
+                (Region::Steps(limit), _) if steps >= limit => { vec![] }
+                (Region::Function, NodeKind::Call { next, .. } | NodeKind::PureCall { next, .. } | NodeKind::Coroutine { next, .. }) => { vec![*next] }
+                (Region::Function, NodeKind::TailCall { .. }) => { vec![] }
+                _ => { successors(&kind) }
+            };
+            // first successor is visited first
+            next.into_iter().rev().for_each(|next| queue.push((next, steps + 1)));
+            nodes.push((id, kind));
+        }
+        nodes
+    }
+
+    // engines access globals without bounds checks, atomic ones without size and alignment checks
+    fn supported(&self, kind: &NodeKind<NodeId>) -> bool { globals_used(kind) <= self.globals.len() as u64 && atomics_supported(kind) }
+
+    // so engines don't keep code accessing memory past globals
+    fn evict_past_globals(&mut self) {
+        let past: Vec<NodeId> = self.info.iter().enumerate()
+            .filter(|(_, info)| info.kind.as_ref().is_some_and(|kind| !self.supported(kind)))
+            .map(|(id, _)| NodeId(id as u32))
+            .collect();
+        past.into_iter().for_each(|id| self.evict(id));
+    }
+
+    pub(crate) fn touch(&mut self, id: NodeId) {
+        self.tick += 1;
+        self.info.get_mut(id.0 as usize).unwrap().last_used = self.tick;
+    }
+
+    fn mark_registered(&mut self, id: NodeId, kind: &NodeKind<NodeId>) {
+        self.touch(id);
+        // engine might forget node by itself, in such case it's still registered for driver
+        if self.info.get(id.0 as usize).unwrap().kind.is_some() { return; }
+
+        successors(kind).iter().for_each(|successor| self.info.get_mut(successor.0 as usize).unwrap().references += 1);
+        self.info.get_mut(id.0 as usize).unwrap().kind = Some(kind.clone());
+        self.registered += 1;
+    }
+
+    pub(crate) fn collect(&mut self) {
+        self.engine.reclaimable().into_iter().for_each(|id| self.evict(id));
+        if let Some(budget) = self.budget {
+            if self.registered <= budget { return; }
+            let mut registered: Vec<(u64, NodeId)> = self.info.iter().enumerate()
+                .filter(|(_, info)| info.kind.is_some())
+                .map(|(id, info)| (info.last_used, NodeId(id as u32)))
+                .collect();
+            registered.sort_by_key(|(last_used, _)| *last_used);
+            let to_evict = self.registered - budget;
+            registered.iter().take(to_evict).for_each(|(_, id)| self.evict(*id));
+        }
+    }
+
+    fn evict(&mut self, id: NodeId) {
+        // so their counters are kept as well
+        self.engine.dependents(id).into_iter().for_each(|dependent| self.evict(dependent));
+        if let Some(kind) = self.info.get_mut(id.0 as usize).unwrap().kind.take() {
+            if let Some(profile) = self.engine.profile(id) {
+                for (target, profile) in self.attribute(id, Some(&kind), profile) {
+                    self.info.get_mut(target.0 as usize).unwrap().profile.add(&profile);
+                }
+            }
+            self.engine.unregister(id);
+            self.registered -= 1;
+            successors(&kind).iter().for_each(|successor| {
+                self.info.get_mut(successor.0 as usize).unwrap().references -= 1;
+                self.release(*successor);
+            });
+        }
+        if let Some(body) = self.inlined.remove(&id) {
+            self.info.get_mut(body[0].0 as usize).unwrap().references -= 1;
+            self.release(body[0]);
+        }
+        // it's made again from current kind of try
+        self.drop_landing(id);
+        self.drop_memo_return(id);
+        self.drop_coroutine_return(id);
+        self.memo.remove(&id);
+        self.release(id);
+    }
+
+    // Counters engine has for the node attributed to nodes they count for: inlined copy counts for node it copies,
+    // inlined call is executed along with callee entry it's replaced by, callee counts calls made at runtime only.
+    fn attribute(&self, id: NodeId, kind: Option<&NodeKind<NodeId>>, profile: NodeProfile) -> Vec<(NodeId, NodeProfile)> {
+        let mut attributed = vec![];
+        let mut target = self.copies.get(&id).copied().unwrap_or(id);
+        if let Some(body) = self.inlined.get(&id) {
+            attributed.push((id, NodeProfile { executions: profile.executions, ..NodeProfile::default() }));
+            target = body[0];
+        }
+        attributed.push((target, profile));
+        if let Some(NodeKind::Call { call, .. } | NodeKind::TailCall { call, .. }) = kind {
+            attributed.push((*call, NodeProfile { calls: profile.executions, ..NodeProfile::default() }));
+        }
+        attributed
+    }
+
+    // Keeps nodes in frames of suspended run from being forgotten, they might still be evicted from engine.
+    pub(crate) fn pin(&mut self, ctx: &RunState) {
+        ctx.all_frames().for_each(|frame| self.info.get_mut(frame.id.0 as usize).unwrap().references += 1);
+    }
+
+    pub(crate) fn unpin(&mut self, ctx: &RunState) {
+        ctx.all_frames().for_each(|frame| {
+            self.info.get_mut(frame.id.0 as usize).unwrap().references -= 1;
+            self.release(frame.id);
+        });
+    }
+
+    // forgets node if it's neither registered nor referenced by registered or synthetic nodes
+    fn release(&mut self, id: NodeId) {
+        let info = self.info.get(id.0 as usize).unwrap();
+        if info.references != 0 || info.kind.is_some() || self.breakpoints.contains(&id) { return; }
+        let mut referenced = vec![];
+        if let Some(node) = self.nodes.get_mut(id.0 as usize).unwrap().take() {
+            self.idx.remove(&node);
+        } else if let Some(kind) = self.synthetic.remove(&id) {
+            referenced = successors(&kind);
+            if let Some((catch, _)) = self.catches.remove(&id) { referenced.push(catch) }
+            if let Some(original) = self.copies.remove(&id) { referenced.push(original) }
+        } else if let Some(memo_call) = self.memo_calls.remove(&id) {
+            referenced = vec![memo_call.call, memo_call.next];
+        } else if let Some(next) = self.resumers.remove(&id) {
+            referenced = vec![next];
+        } else if !self.loaded.values().any(|loaded| *loaded == id) {
+            return;
+        } else {
+            self.loaded.retain(|_, loaded| *loaded != id);
+        }
+        *self.info.get_mut(id.0 as usize).unwrap() = NodeInfo::default();
+        self.free.push(id);
+        referenced.into_iter().for_each(|successor| {
+            self.info.get_mut(successor.0 as usize).unwrap().references -= 1;
+            self.release(successor);
+        });
+        self.drop_landing(id);
+        self.drop_memo_return(id);
+    }
+
+    // Node callee of try returns to, it's unique for the try, so frame with it marks where exception is caught.
+    // It's synthetic no-op command continuing to `next`, which keeps references to `next` and `catch`,
+    // try itself keeps reference to it until it's evicted.
+    fn landing(&mut self, id: NodeId, offset: u32, next: NodeId, catch: NodeId) -> NodeId {
+        if let Some(landing) = self.landings.get(&id) { return *landing; }
+        let landing = self.new_id();
+        [next, catch, landing].iter().for_each(|id| self.info.get_mut(id.0 as usize).unwrap().references += 1);
+        self.synthetic.insert(landing, NodeKind::Command { command: Command::Noop, next });
+        self.catches.insert(landing, (catch, offset));
+        self.landings.insert(id, landing);
+        landing
+    }
+
+    fn drop_landing(&mut self, id: NodeId) {
+        if let Some(landing) = self.landings.remove(&id) {
+            self.info.get_mut(landing.0 as usize).unwrap().references -= 1;
+            self.release(landing);
+        }
+    }
+
+    // Node pure callee returns to, unique for the pure call, it records callee results for arguments put aside by the call.
+    // It's never registered, so engine suspends on it. It keeps references to callee and `next`,
+    // pure call keeps reference to it until it's evicted.
+    fn memo_return(&mut self, id: NodeId, offset: u32, call: NodeId, next: NodeId, results: u32) -> NodeId {
+        if let Some(memo_return) = self.memo_returns.get(&id) { return *memo_return; }
+        let memo_return = self.new_id();
+        [call, next, memo_return].iter().for_each(|id| self.info.get_mut(id.0 as usize).unwrap().references += 1);
+        self.memo_calls.insert(memo_return, MemoCall { call, next, offset, results });
+        self.memo_returns.insert(id, memo_return);
+        memo_return
+    }
+
+    fn drop_memo_return(&mut self, id: NodeId) {
+        if let Some(memo_return) = self.memo_returns.remove(&id) {
+            self.info.get_mut(memo_return.0 as usize).unwrap().references -= 1;
+            self.release(memo_return);
+        }
+    }
+
+    // Node coroutine resumed by `id` returns to, unique for the resume. It's never registered, so engine suspends on it.
+    // It keeps reference to `next`, resume keeps reference to it until it's evicted.
+    fn coroutine_return(&mut self, id: NodeId, next: NodeId) -> NodeId {
+        if let Some(returns_to) = self.coroutine_returns.get(&id) { return *returns_to; }
+        let returns_to = self.new_id();
+        [next, returns_to].iter().for_each(|id| self.info.get_mut(id.0 as usize).unwrap().references += 1);
+        self.resumers.insert(returns_to, next);
+        self.coroutine_returns.insert(id, returns_to);
+        returns_to
+    }
+
+    fn drop_coroutine_return(&mut self, id: NodeId) {
+        if let Some(returns_to) = self.coroutine_returns.remove(&id) {
+            self.info.get_mut(returns_to.0 as usize).unwrap().references -= 1;
+            self.release(returns_to);
+        }
+    }
+
+    // kind to register, calls might be inlined
     fn get_kind(&mut self, node: NodeId) -> NodeKind<NodeId> {
+        if let Some(kind) = &self.info.get(node.0 as usize).unwrap().kind {
+            return kind.clone();
+        }
+        let mut kind = self.original_kind(node);
+        // without memoization engine runs pure call itself
+        if let (None, NodeKind::PureCall { offset, call, next, .. }) = (self.memoization, &kind) {
+            kind = NodeKind::Call { offset: *offset, call: *call, next: *next };
+        }
+        if let NodeKind::Call { offset, call, next } = kind {
+            // calls in inlined code aren't inlined, so recursion is unrolled only once
+            if !self.synthetic.contains_key(&node) {
+                if let Some((inlined, body)) = self.inline(offset, call, next) {
+                    self.info.get_mut(call.0 as usize).unwrap().references += 1;
+                    self.inlined.insert(node, body);
+                    return inlined;
+                }
+            }
+        }
+        // engines see try as call returning to its landing
+        if let NodeKind::Try { offset, call, next, catch } = kind {
+            return NodeKind::Call { offset, call, next: self.landing(node, offset, next, catch) };
+        }
+        kind
+    }
+
+    // Copies callee nodes with refs moved by `offset` as synthetic nodes, callee's final nodes continue to `next`.
+    // Returns kind of the callee entry copy, which replaces the call, and ids of copied nodes.
+    fn inline(&mut self, offset: u32, call: NodeId, next: NodeId) -> Option<(NodeKind<NodeId>, Vec<NodeId>)> {
+        let limit = self.inlining?;
+        let mut body = vec![];
+        if !self.callee_body(call, limit, &mut body, &mut vec![]) { return None; }
+        if let [(_, NodeKind::Final)] = body.as_slice() { return None; }
+
+        // entry isn't referenced within callee without loops, so only its kind is needed
+        let mut ids: HashMap<NodeId, NodeId> = HashMap::new();
+        for (id, kind) in &body {
+            if let NodeKind::Final = kind {
+                ids.insert(*id, next);
+            } else if *id != call {
+                ids.insert(*id, self.new_id());
+            }
+        }
+        let mut entry = None;
+        let copied = body.iter().map(|(id, _)| *id).collect();
+        for (id, kind) in body {
+            let copy = match kind {
+                NodeKind::Command { command, next } => { NodeKind::Command { command: command.offset(offset), next: ids[&next] } }
+                NodeKind::Branch { condition, if_true, if_false } => {
+                    NodeKind::Branch { condition: condition.offset(offset), if_true: ids[&if_true], if_false: ids[&if_false] }
+                }
+                NodeKind::Call { offset: call_offset, call, next } => { NodeKind::Call { offset: call_offset + offset, call, next: ids[&next] } }
+                NodeKind::Final => { continue; }
+                NodeKind::Yield { next } => { NodeKind::Yield { next: ids[&next] } }
+                NodeKind::HostCall { function, offset: call_offset, next } => { NodeKind::HostCall { function, offset: call_offset + offset, next: ids[&next] } }
+                NodeKind::Coroutine { offset: call_offset, call, handle, next } => {
+                    NodeKind::Coroutine { offset: call_offset + offset, call, handle: handle.offset(offset), next: ids[&next] }
+                }
+                NodeKind::Resume { handle, next } => { NodeKind::Resume { handle: handle.offset(offset), next: ids[&next] } }
+                NodeKind::TailCall { .. } | NodeKind::Try { .. } => { unreachable!("callee with tail calls or tries isn't inlined") }
+                NodeKind::Throw { op, size } => { NodeKind::Throw { op: op.offset(offset), size } }
+                NodeKind::PureCall { offset: call_offset, call, next, args, results } => {
+                    NodeKind::PureCall { offset: call_offset + offset, call, next: ids[&next], args, results }
+                }
+            };
+            if id == call {
+                entry = Some(copy);
+            } else {
+                successors(&copy).iter().chain([&id]).for_each(|successor| self.info.get_mut(successor.0 as usize).unwrap().references += 1);
+                self.synthetic.insert(ids[&id], copy);
+                self.copies.insert(ids[&id], id);
+            }
+        }
+        entry.map(|entry| (entry, copied))
+    }
+
+    // Collects callee nodes up to calls in depth first order, false if callee has loops, tail calls or more than `limit` nodes.
+    fn callee_body(&mut self, id: NodeId, limit: usize, body: &mut Vec<(NodeId, NodeKind<NodeId>)>, path: &mut Vec<NodeId>) -> bool {
+        // breakpoint should be reached in the callee
+        if path.contains(&id) || self.breakpoints.contains(&id) { return false; }
+        if body.iter().any(|(known, _)| *known == id) { return true; }
+        if body.len() == limit { return false; }
+        let kind = self.original_kind(id);
+        let inner = match &kind {
+            NodeKind::Call { next, .. } | NodeKind::PureCall { next, .. } | NodeKind::Coroutine { next, .. } => { vec![*next] }
+            // it would finish caller's frame instead of continuing after the call
+            NodeKind::TailCall { .. } => { return false; }
+            // landing is made for try node only
+            NodeKind::Try { .. } => { return false; }
+            _ => { successors(&kind) }
+        };
+        body.push((id, kind));
+        path.push(id);
+        let collected = inner.into_iter().all(|successor| self.callee_body(successor, limit, body, path));
+        path.pop();
+        collected
+    }
+
+    // kind as defined by `N` or synthetic node
+    fn original_kind(&mut self, node: NodeId) -> NodeKind<NodeId> {
+        if let Some(kind) = self.synthetic.get(&node) {
+            return kind.clone();
+        }
+        if self.nodes.get(node.0 as usize).unwrap().is_none() {
+            self.resolve(node);
+        }
         match get_final_kind(self.nodes.get(node.0 as usize).unwrap().as_ref().unwrap()) {
             NodeKind::Command { command, next } => {
                 NodeKind::Command { command, next: self.get_id(next) }
@@ -77,17 +1008,251 @@ impl<N: Node, E: Engine> Driver<N, E> {
                 NodeKind::Call { offset, call: self.get_id(call), next: self.get_id(next) }
             }
             NodeKind::Final => { NodeKind::Final }
+            NodeKind::Yield { next } => { NodeKind::Yield { next: self.get_id(next) } }
+            NodeKind::HostCall { function, offset, next } => { NodeKind::HostCall { function, offset, next: self.get_id(next) } }
+            NodeKind::Coroutine { offset, call, handle, next } => {
+                NodeKind::Coroutine { offset, call: self.get_id(call), handle, next: self.get_id(next) }
+            }
+            NodeKind::Resume { handle, next } => { NodeKind::Resume { handle, next: self.get_id(next) } }
+            NodeKind::TailCall { offset, call } => { NodeKind::TailCall { offset, call: self.get_id(call) } }
+            NodeKind::Try { offset, call, next, catch } => {
+                NodeKind::Try { offset, call: self.get_id(call), next: self.get_id(next), catch: self.get_id(catch) }
+            }
+            NodeKind::Throw { op, size } => { NodeKind::Throw { op, size } }
+            NodeKind::PureCall { offset, call, next, args, results } => {
+                NodeKind::PureCall { offset, call: self.get_id(call), next: self.get_id(next), args, results }
+            }
         }
     }
 
-    fn get_id(&mut self, node: N) -> NodeId {
+    pub(crate) fn get_id(&mut self, node: N) -> NodeId {
         if self.idx.contains_key(&node) {
             *self.idx.get(&node).unwrap()
+        } else if let Some(id) = self.key.and_then(|key| self.loaded.remove(&key(&node))) {
+            self.bind(id, node);
+            id
         } else {
-            self.nodes.push(Some(node.clone()));
-            let id = NodeId((self.nodes.len() - 1) as u32);
+            let id = self.new_id();
+            *self.nodes.get_mut(id.0 as usize).unwrap() = Some(node.clone());
             self.idx.insert(node, id);
             id
         }
     }
+
+    fn new_id(&mut self) -> NodeId {
+        self.free.pop().unwrap_or_else(|| {
+            self.nodes.push(None);
+            self.info.push(NodeInfo::default());
+            NodeId((self.nodes.len() - 1) as u32)
+        })
+    }
+
+    // Finds `N` for node read by `load`, starting from known nodes and following nodes read by `load` only.
+    // Such node is always reachable this way since it was referenced by some node when saved.
+    fn resolve(&mut self, id: NodeId) {
+        let key = self.key.unwrap();
+        let mut queue: Vec<N> = self.nodes.iter().flatten().cloned().collect();
+        while self.nodes.get(id.0 as usize).unwrap().is_none() {
+            let node = queue.pop().expect("node read from snapshot isn't reachable from known nodes");
+            let kind = get_final_kind(&node);
+            for successor in successors(&kind) {
+                if let Some(successor_id) = self.loaded.remove(&key(&successor)) {
+                    self.bind(successor_id, successor.clone());
+                    queue.push(successor);
+                }
+            }
+        }
+    }
+
+    fn bind(&mut self, id: NodeId, node: N) {
+        *self.nodes.get_mut(id.0 as usize).unwrap() = Some(node.clone());
+        self.idx.insert(node, id);
+    }
+}
+
+impl<N: PersistentNode, E: Engine> Driver<N, E> {
+    // Writes node table with what engine computed for it, so `load` in another process can skip
+    // computing node kinds and (if engine supports it) compilation.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = SnapshotWriter::new();
+        writer.bytes(MAGIC);
+        writer.usize(self.nodes.len());
+
+        let mut keys: HashMap<NodeId, Vec<u8>> = self.loaded.iter().map(|(key, id)| (*id, key.clone())).collect();
+        self.idx.iter().for_each(|(node, id)| { keys.insert(*id, node.key()); });
+        writer.usize(keys.len());
+        for (id, key) in keys {
+            writer.id(id);
+            writer.bytes(&key);
+            self.write_registered(&mut writer, id);
+        }
+        writer.usize(self.inlined.len());
+        for (call, body) in &self.inlined {
+            writer.id(*call);
+            writer.usize(body.len());
+            body.iter().for_each(|id| writer.id(*id));
+        }
+        writer.usize(self.synthetic.len());
+        for (id, kind) in &self.synthetic {
+            writer.id(*id);
+            writer.kind(kind, |writer, id| writer.id(*id));
+            match self.copies.get(id) {
+                None => { writer.u8(0) }
+                Some(original) => {
+                    writer.u8(1);
+                    writer.id(*original);
+                }
+            }
+            self.write_registered(&mut writer, *id);
+        }
+        writer.usize(self.landings.len());
+        for (id, landing) in &self.landings {
+            let (catch, offset) = self.catches[landing];
+            writer.id(*id);
+            writer.id(*landing);
+            writer.id(catch);
+            writer.u32(offset);
+        }
+
+        match self.engine.save() {
+            None => { writer.u8(0) }
+            Some(saved) => {
+                writer.u8(1);
+                writer.bytes(&saved);
+            }
+        }
+        std::fs::write(path, writer.bytes)
+    }
+
+    pub fn load(engine: E, path: &Path) -> io::Result<Driver<N, E>> {
+        let bytes = std::fs::read(path)?;
+        let mut reader = SnapshotReader::new(&bytes);
+        if reader.bytes()? != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a snapshot"));
+        }
+
+        let mut driver = Driver::new(engine);
+        driver.key = Some(N::key);
+        let len = reader.usize()?;
+        if !(MIN_NODE_ID..=u32::MAX as usize).contains(&len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed node table size"));
+        }
+        driver.nodes.resize(len, None);
+        driver.info.resize(len, NodeInfo::default());
+
+        // every id is checked against table size, so corrupted snapshot fails to load instead of panicking later
+        for _ in 0..reader.usize()? {
+            let id = reader.id_below(len)?;
+            let key = reader.bytes()?.to_vec();
+            driver.loaded.insert(key, id);
+            driver.read_registered(&mut reader, id, len)?;
+        }
+        for _ in 0..reader.usize()? {
+            let call = reader.id_below(len)?;
+            let body = (0..reader.usize()?).map(|_| reader.id_below(len)).collect::<io::Result<Vec<NodeId>>>()?;
+            let Some(callee) = body.first() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "inlined call without callee"));
+            };
+            driver.info.get_mut(callee.0 as usize).unwrap().references += 1;
+            driver.inlined.insert(call, body);
+        }
+        for _ in 0..reader.usize()? {
+            let id = reader.id_below(len)?;
+            let kind = reader.kind(|reader| reader.id_below(len))?;
+            successors(&kind).iter().for_each(|successor| driver.info.get_mut(successor.0 as usize).unwrap().references += 1);
+            driver.synthetic.insert(id, kind);
+            if reader.u8()? == 1 {
+                let original = reader.id_below(len)?;
+                driver.info.get_mut(original.0 as usize).unwrap().references += 1;
+                driver.copies.insert(id, original);
+            }
+            driver.read_registered(&mut reader, id, len)?;
+        }
+        for _ in 0..reader.usize()? {
+            let (id, landing, catch, offset) = (reader.id_below(len)?, reader.id_below(len)?, reader.id_below(len)?, reader.u32()?);
+            [catch, landing].iter().for_each(|id| driver.info.get_mut(id.0 as usize).unwrap().references += 1);
+            driver.landings.insert(id, landing);
+            driver.catches.insert(landing, (catch, offset));
+        }
+        let used: HashSet<NodeId> = driver.loaded.values().chain(driver.synthetic.keys()).copied().collect();
+        driver.free = (MIN_NODE_ID..len).map(|id| NodeId(id as u32)).filter(|id| !used.contains(id)).collect();
+
+        if reader.u8()? == 1 {
+            driver.engine.load(reader.bytes()?)?;
+        } else {
+            for (id, info) in driver.info.iter().enumerate() {
+                if let Some(kind) = &info.kind {
+                    driver.engine.register(NodeId(id as u32), kind.clone());
+                }
+            }
+        }
+        // globals aren't saved, nodes accessing them are registered again after `set_globals`
+        driver.evict_past_globals();
+        Ok(driver)
+    }
+
+    fn write_registered(&self, writer: &mut SnapshotWriter, id: NodeId) {
+        match &self.info.get(id.0 as usize).unwrap().kind {
+            None => { writer.u8(0) }
+            Some(kind) => {
+                writer.u8(1);
+                writer.kind(kind, |writer, id| writer.id(*id));
+            }
+        }
+    }
+
+    fn read_registered(&mut self, reader: &mut SnapshotReader, id: NodeId, len: usize) -> io::Result<()> {
+        if reader.u8()? == 1 {
+            let kind = reader.kind(|reader| reader.id_below(len))?;
+            self.mark_registered(id, &kind);
+        }
+        Ok(())
+    }
+}
+
+// number of bytes of globals kind needs
+fn globals_used<N>(kind: &NodeKind<N>) -> u64 {
+    let refs = match kind {
+        NodeKind::Command { command, .. } => { command.refs() }
+        NodeKind::Branch { condition, .. } => { condition.refs() }
+        NodeKind::Throw { op, size } => { vec![(*op, *size)] }
+        _ => { vec![] }
+    };
+    refs.into_iter()
+        .filter_map(|(at, size)| if let Ref::Global(offset) = at { Some(offset as u64 + size as u64) } else { None })
+        .max()
+        .unwrap_or(0)
+}
+
+// whether atomic command is of 4 or 8 bytes and accesses global aligned by its size
+fn atomics_supported<N>(kind: &NodeKind<N>) -> bool {
+    match kind {
+        NodeKind::Command { command: Command::AtomicLoad { size, mem, .. } | Command::AtomicStore { size, mem, .. }
+            | Command::AtomicRmw { size, mem, .. } | Command::AtomicCmpXchg { size, mem, .. }, .. } => {
+            matches!(size, 4 | 8) && !matches!(mem, Ref::Global(offset) if !offset.is_multiple_of(*size))
+        }
+        _ => { true }
+    }
+}
+
+// drops frames of run which can't go on
+fn abort(ctx: &mut RunState, fault: Fault) -> Stop {
+    ctx.frames.clear();
+    ctx.args.clear();
+    Stop::Fault(fault)
+}
+
+fn successors<N: Clone>(kind: &NodeKind<N>) -> Vec<N> {
+    match kind {
+        NodeKind::Command { next, .. } => { vec![next.clone()] }
+        NodeKind::Branch { if_true, if_false, .. } => { vec![if_true.clone(), if_false.clone()] }
+        NodeKind::Call { call, next, .. } => { vec![call.clone(), next.clone()] }
+        NodeKind::Final => { vec![] }
+        NodeKind::Yield { next } | NodeKind::HostCall { next, .. } | NodeKind::Resume { next, .. } => { vec![next.clone()] }
+        NodeKind::Coroutine { call, next, .. } => { vec![call.clone(), next.clone()] }
+        NodeKind::TailCall { call, .. } => { vec![call.clone()] }
+        NodeKind::Try { call, next, catch, .. } => { vec![call.clone(), next.clone(), catch.clone()] }
+        NodeKind::Throw { .. } => { vec![] }
+        NodeKind::PureCall { call, next, .. } => { vec![call.clone(), next.clone()] }
+    }
 }
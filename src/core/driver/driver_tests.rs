@@ -1,63 +1,117 @@
+use std::collections::HashMap;
 use std::ops::Deref;
-use crate::core::api::{Command, Condition, Node, NodeKind, Ref};
+use std::sync::Arc;
+use crate::core::api::{Command, Condition, Node, NodeKind, Ref, RmwOp, VecOp, VecShape};
+use crate::core::aux::partial_eval::{Known, PartialEvaluator};
 use crate::core::aux::specialized_interpreter_engine::SpecializedInterpreterEngine;
+use crate::core::c_translator::compile_and_eval;
 use crate::core::driver::code_generator_engine::CodeGeneratorEngine;
-use crate::core::driver::driver::{Driver, Engine, NodeId, RunState};
+use crate::core::driver::driver::{Driver, Engine, Fault, NodeId, Outcome, Region, RunState, Stop};
+use crate::core::driver::execution::Execution;
 use crate::core::driver::interpreter_engine::InterpreterEngine;
-use crate::core::interpreter::eval;
+use crate::core::driver::observer::{Event, Observer, Tracer};
+use crate::core::driver::profile::NodeProfile;
+use crate::core::driver::shared_driver::SharedDriver;
+use crate::core::driver::stats::EngineStats;
+use crate::core::globals::Globals;
+use crate::core::interpreter::{eval, eval_with_globals};
+use crate::core::utils::traverse_node;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-struct TestNode(Box<NodeKind<TestNode>>);
+pub(super) struct TestNode(Box<NodeKind<TestNode>>);
 
-fn node(kind: NodeKind<TestNode>) -> TestNode { TestNode(Box::new(kind)) }
+pub(super) fn node(kind: NodeKind<TestNode>) -> TestNode { TestNode(Box::new(kind)) }
 
 impl Node for TestNode {
     fn get(&self) -> NodeKind<Self> { self.0.deref().clone() }
 }
 
 // todo: this seems like unnessesary boiler place, how to avoid it?
-struct EngineBox(Box<dyn Engine>);
+pub(super) struct EngineBox(Box<dyn Engine>);
 
 impl Engine for EngineBox {
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.0.register(id, kind) }
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool { self.0.run(state, stack) }
+    fn region(&self) -> Region { self.0.region() }
+    fn register_batch(&mut self, nodes: Vec<(NodeId, NodeKind<NodeId>)>) { self.0.register_batch(nodes) }
+    fn unregister(&mut self, id: NodeId) { self.0.unregister(id) }
+    fn dependents(&self, id: NodeId) -> Vec<NodeId> { self.0.dependents(id) }
+    fn reclaimable(&self) -> Vec<NodeId> { self.0.reclaimable() }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.0.run(state, stack, globals) }
+    fn save(&self) -> Option<Vec<u8>> { self.0.save() }
+    fn load(&mut self, saved: &[u8]) -> std::io::Result<()> { self.0.load(saved) }
+    fn set_profiling(&mut self, profiling: bool) { self.0.set_profiling(profiling) }
+    fn profile(&self, id: NodeId) -> Option<NodeProfile> { self.0.profile(id) }
+    fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.0.set_observer(observer) }
+    fn set_fuel(&mut self, fuel: Option<u64>) { self.0.set_fuel(fuel) }
+    fn fuel(&self) -> Option<u64> { self.0.fuel() }
+    fn stats(&self) -> EngineStats { self.0.stats() }
 }
 
-fn engines() -> Vec<(&'static str, EngineBox)> {
+pub(super) fn engines() -> Vec<(&'static str, EngineBox)> {
     vec![
-        ("interpreter", EngineBox(Box::new(InterpreterEngine::new(false)))),
+        ("interpreter", EngineBox(Box::new(InterpreterEngine::new()))),
         ("specialized", EngineBox(Box::new(SpecializedInterpreterEngine::new()))),
-        ("code generator", EngineBox(Box::new(CodeGeneratorEngine::new(1024, false).unwrap()))),
+        // generated code runs on aarch64 only
+        #[cfg(target_arch = "aarch64")]
+        ("code generator", EngineBox(Box::new(CodeGeneratorEngine::new(1024).unwrap()))),
     ]
 }
 
 const TEST_STACK_SIZE: usize = 24;
+// vector commands take two 16 bytes operands and a result
+const VEC_TEST_STACK_SIZE: usize = 48;
 
-fn test_node(input: Vec<u8>, node: TestNode) {
-    let mut expected = [0u8; TEST_STACK_SIZE];
+fn test_node(input: Vec<u8>, node: TestNode) { test_node_on::<TEST_STACK_SIZE>(input, node) }
+
+fn test_node_on<const SIZE: usize>(input: Vec<u8>, node: TestNode) {
+    let mut expected = [0u8; SIZE];
     expected[0..input.len()].copy_from_slice(input.as_slice());
     // todo: eval should track poisons and return poison information
     // todo: otherwise it's impossible to compare between results since impl can do whatever in poisoned bytes (i.e. do not clean them)
     eval(node.clone(), &mut expected);
 
     for (name, engine) in engines() {
-        let mut actual = [0u8; TEST_STACK_SIZE];
+        let mut actual = [0u8; SIZE];
         actual[0..input.len()].copy_from_slice(input.as_slice());
         Driver::<TestNode, EngineBox>::new(engine).eval(node.clone(), &mut actual);
         assert_eq!(expected, actual, "\"{}\" output differs from expected for {:?} on {:?}", name, node, input);
     }
 }
 
+// same as `test_node` for code translated to c, it's compiled with system compiler so it's checked in `test_c_translator` only
+fn test_translated(input: Vec<u8>, node: TestNode) { test_translated_on::<TEST_STACK_SIZE>(input, node) }
+
+fn test_translated_on<const SIZE: usize>(input: Vec<u8>, node: TestNode) {
+    let mut expected = [0u8; SIZE];
+    expected[0..input.len()].copy_from_slice(input.as_slice());
+    eval(node.clone(), &mut expected);
+
+    let mut translated = [0u8; SIZE];
+    translated[0..input.len()].copy_from_slice(input.as_slice());
+    compile_and_eval(node.clone(), &mut translated);
+    assert_eq!(expected, translated, "translated to c output differs from expected for {:?} on {:?}", node, input);
+}
+
+fn command_node(command: Command) -> TestNode { node(NodeKind::Command { command, next: node(NodeKind::Final) }) }
+
 fn test_command(input: Vec<u8>, command: Command) {
-    test_node(input,node(NodeKind::Command { command, next: node(NodeKind::Final) }))
+    test_node(input, command_node(command))
+}
+
+fn test_vec_command(input: Vec<u8>, command: Command) {
+    test_node_on::<VEC_TEST_STACK_SIZE>(input, command_node(command))
 }
 
 fn test_condition(input: Vec<u8>, condition: Condition) {
-    test_node(input, node(NodeKind::Branch {
+    test_node(input, branch_node(condition))
+}
+
+fn branch_node(condition: Condition) -> TestNode {
+    node(NodeKind::Branch {
         condition,
         if_true: write_node(vec![2, 2, 2, 2]),
         if_false: write_node(vec![3, 3, 3, 3]),
-    }))
+    })
 }
 
 fn write_node(bytes: Vec<u8>) -> TestNode {
@@ -104,6 +158,132 @@ fn test_sub() {
     test_command(vec![1, 2, 3, 4, 5, 6, 7, 8],Command::Sub { size: 4, dst: Ref::Stack(8), op1: Ref::Stack(0), op2: Ref::Stack(4) });
 }
 
+#[test]
+fn test_stack_atomics() {
+    test_command(vec![1, 2, 3, 4], Command::AtomicLoad { size: 4, dst: Ref::Stack(4), mem: Ref::Stack(0) });
+    test_command(vec![1, 2, 3, 4, 5, 6, 7, 8], Command::AtomicStore { size: 8, mem: Ref::Stack(8), op: Ref::Stack(0) });
+    for op in [RmwOp::Add, RmwOp::Sub, RmwOp::And, RmwOp::Or, RmwOp::Xor, RmwOp::Xchg] {
+        test_command(vec![9, 2, 3, 4, 5, 6, 7, 8], Command::AtomicRmw { size: 4, op, dst: Ref::Stack(8), mem: Ref::Stack(0), value: Ref::Stack(4) });
+        test_command(vec![9, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8],
+                     Command::AtomicRmw { size: 8, op, dst: Ref::Stack(16), mem: Ref::Stack(0), value: Ref::Stack(8) });
+        // previous value overwrites new one
+        test_command(vec![9, 2, 3, 4, 5, 6, 7, 8], Command::AtomicRmw { size: 4, op, dst: Ref::Stack(0), mem: Ref::Stack(0), value: Ref::Stack(4) });
+    }
+    let cmpxchg = Command::AtomicCmpXchg { size: 4, mem: Ref::Stack(0), expected: Ref::Stack(4), replacement: Ref::Stack(8) };
+    test_command(vec![1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8], cmpxchg.clone());
+    test_command(vec![1, 2, 3, 4, 1, 2, 3, 5, 5, 6, 7, 8], cmpxchg);
+    test_command(vec![1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 9, 9, 9, 9, 9, 9, 9],
+                 Command::AtomicCmpXchg { size: 8, mem: Ref::Stack(0), expected: Ref::Stack(8), replacement: Ref::Stack(16) });
+    test_command(vec![7, 7, 7, 7], Command::Fence);
+}
+
+#[test]
+fn test_vectors() {
+    // integer operands are equal in the first 8 bytes
+    let op1: Vec<u8> = (0..16).map(|byte: u32| (byte * 37 + 3) as u8).collect();
+    let op2: Vec<u8> = (0..16).map(|byte: u32| if byte < 8 { (byte * 37 + 3) as u8 } else { (byte * 91 + 200) as u8 }).collect();
+    let f32s: Vec<u8> = [1.5f32, -2.0, 3.0, 0.0, 1.5, 4.0, -1.0, -0.0].iter().flat_map(|lane| lane.to_le_bytes()).collect();
+    let f64s: Vec<u8> = [2.5f64, -1.0, 2.5, 3.0].iter().flat_map(|lane| lane.to_le_bytes()).collect();
+    for shape in VecShape::ALL {
+        let input = match shape {
+            VecShape::F32x4 => { f32s.clone() }
+            VecShape::F64x2 => { f64s.clone() }
+            _ => { [op1.clone(), op2.clone()].concat() }
+        };
+        for op in VecOp::ALL {
+            if shape.is_float() && matches!(op, VecOp::LtU | VecOp::LeU | VecOp::GtU | VecOp::GeU) { continue; }
+            test_vec_command(input.clone(), Command::VecBinary { op, shape, dst: Ref::Stack(32), op1: Ref::Stack(0), op2: Ref::Stack(16) });
+        }
+        test_vec_command(input.clone(), Command::VecSplat { shape, dst: Ref::Stack(16), op: Ref::Stack(8) });
+        // splat of value in its place
+        test_vec_command(input.clone(), Command::VecSplat { shape, dst: Ref::Stack(0), op: Ref::Stack(0) });
+        for signed in [false, true] {
+            test_vec_command(input.clone(), Command::VecExtractLane { shape, signed, lane: shape.lanes() as u8 - 1, dst: Ref::Stack(32), op: Ref::Stack(0) });
+        }
+        test_vec_command(input.clone(), Command::VecReplaceLane { shape, lane: 1, dst: Ref::Stack(0), op: Ref::Stack(0), value: Ref::Stack(16) });
+    }
+    let lanes = [31, 0, 17, 2, 16, 16, 5, 30, 8, 9, 10, 11, 12, 13, 14, 15];
+    test_vec_command([op1.clone(), op2.clone()].concat(), Command::VecShuffle { lanes, dst: Ref::Stack(32), op1: Ref::Stack(0), op2: Ref::Stack(16) });
+    test_vec_command([op1, op2].concat(), Command::VecShuffle { lanes, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(16) });
+}
+
+#[test]
+fn test_c_translator() {
+    test_translated(vec![1, 2, 3, 4], node(NodeKind::Final));
+    test_translated(vec![7, 7, 7, 7, 7, 7, 7, 7], command_node(Command::Set { dst: Ref::Stack(0), bytes: vec![1, 2, 3, 4, 5, 6, 7, 8] }));
+    test_translated(vec![1, 2, 3, 4], command_node(Command::Copy { size: 4, dst: Ref::Stack(4), op: Ref::Stack(0) }));
+    test_translated(vec![1, 2, 3, 4, 5, 6, 7, 8], command_node(Command::Copy { size: 8, dst: Ref::Stack(8), op: Ref::Stack(0) }));
+    test_translated(vec![1, 2, 3, 4, 5, 6, 7, 8], command_node(Command::Add { size: 4, dst: Ref::Stack(8), op1: Ref::Stack(0), op2: Ref::Stack(4) }));
+    test_translated(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+                    command_node(Command::Add { size: 8, dst: Ref::Stack(16), op1: Ref::Stack(0), op2: Ref::Stack(8) }));
+    test_translated(vec![1, 2, 3, 4, 5, 6, 7, 8], command_node(Command::Sub { size: 4, dst: Ref::Stack(8), op1: Ref::Stack(0), op2: Ref::Stack(4) }));
+    for op in [RmwOp::Add, RmwOp::Sub, RmwOp::And, RmwOp::Or, RmwOp::Xor, RmwOp::Xchg] {
+        test_translated(vec![9, 2, 3, 4, 5, 6, 7, 8], command_node(Command::AtomicRmw { size: 4, op, dst: Ref::Stack(8), mem: Ref::Stack(0), value: Ref::Stack(4) }));
+    }
+    test_translated(vec![1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8],
+                    command_node(Command::AtomicCmpXchg { size: 4, mem: Ref::Stack(0), expected: Ref::Stack(4), replacement: Ref::Stack(8) }));
+    test_translated(vec![1, 2, 3, 4, 1, 2, 3, 4], branch_node(Condition::Ne { size: 4, op1: Ref::Stack(0), op2: Ref::Stack(4) }));
+    test_translated(vec![1, 2, 3, 4, 5, 6, 7, 8], branch_node(Condition::Ne { size: 4, op1: Ref::Stack(0), op2: Ref::Stack(4) }));
+    test_translated(vec![0, 0, 0, 0], branch_node(Condition::Ne0 { size: 4, op: Ref::Stack(0) }));
+    test_translated(vec![], node(NodeKind::Call { offset: 4, call: write_node(vec![1, 2, 3, 4]), next: write_node(vec![5, 6, 7, 8]) }));
+    test_translated(vec![], try_node(false));
+    test_translated(vec![], try_node(true));
+
+    let vectors: Vec<u8> = (0..32).map(|byte: u32| (byte * 37 + 3) as u8).collect();
+    for op in VecOp::ALL {
+        test_translated_on::<VEC_TEST_STACK_SIZE>(vectors.clone(), command_node(Command::VecBinary { op, shape: VecShape::I16x8, dst: Ref::Stack(32), op1: Ref::Stack(0), op2: Ref::Stack(16) }));
+    }
+    let f64s: Vec<u8> = [2.5f64, -1.0, 2.5, 3.0].iter().flat_map(|lane| lane.to_le_bytes()).collect();
+    for op in [VecOp::Mul, VecOp::Lt] {
+        test_translated_on::<VEC_TEST_STACK_SIZE>(f64s.clone(), command_node(Command::VecBinary { op, shape: VecShape::F64x2, dst: Ref::Stack(32), op1: Ref::Stack(0), op2: Ref::Stack(16) }));
+    }
+    test_translated_on::<VEC_TEST_STACK_SIZE>(vectors.clone(), command_node(Command::VecSplat { shape: VecShape::I32x4, dst: Ref::Stack(0), op: Ref::Stack(0) }));
+    test_translated_on::<VEC_TEST_STACK_SIZE>(vectors.clone(), command_node(Command::VecExtractLane { shape: VecShape::I8x16, signed: true, lane: 15, dst: Ref::Stack(32), op: Ref::Stack(0) }));
+    test_translated_on::<VEC_TEST_STACK_SIZE>(vectors.clone(), command_node(Command::VecReplaceLane { shape: VecShape::I64x2, lane: 1, dst: Ref::Stack(0), op: Ref::Stack(0), value: Ref::Stack(16) }));
+    let lanes = [31, 0, 17, 2, 16, 16, 5, 30, 8, 9, 10, 11, 12, 13, 14, 15];
+    test_translated_on::<VEC_TEST_STACK_SIZE>(vectors, command_node(Command::VecShuffle { lanes, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(16) }));
+
+    // interpreter has 4 and 8 bytes arithmetic only, other sizes wrap around within their bytes
+    let mut stack = [255, 255, 1, 0, 7, 7];
+    compile_and_eval(command_node(Command::Add { size: 2, dst: Ref::Stack(4), op1: Ref::Stack(0), op2: Ref::Stack(2) }), &mut stack);
+    assert_eq!([255, 255, 1, 0, 0, 0], stack);
+    let mut stack = [1, 2, 3, 0, 0, 0];
+    compile_and_eval(branch_node(Condition::Ne0 { size: 3, op: Ref::Stack(3) }), &mut stack);
+    assert_eq!([3, 3, 3, 3, 0, 0], stack);
+}
+
+#[test]
+fn test_partial_eval_vectors() {
+    let vectors: Vec<u8> = (0..32).map(|byte: u32| (byte * 37 + 3) as u8).collect();
+    let lanes = [31, 0, 17, 2, 16, 16, 5, 30, 8, 9, 10, 11, 12, 13, 14, 15];
+    let node = commands(vec![
+        Command::Set { dst: Ref::Stack(0), bytes: vectors },
+        Command::VecBinary { op: VecOp::Add, shape: VecShape::I32x4, dst: Ref::Stack(32), op1: Ref::Stack(0), op2: Ref::Stack(16) },
+        Command::VecShuffle { lanes, dst: Ref::Stack(32), op1: Ref::Stack(32), op2: Ref::Stack(0) },
+        Command::VecExtractLane { shape: VecShape::I8x16, signed: true, lane: 15, dst: Ref::Stack(48), op: Ref::Stack(32) },
+        // lane at `Stack(64)` is unknown, so splat overwrites known bytes with unknown ones
+        Command::VecSplat { shape: VecShape::I32x4, dst: Ref::Stack(0), op: Ref::Stack(64) },
+        Command::Copy { size: 16, dst: Ref::Stack(80), op: Ref::Stack(0) },
+    ]);
+    let mut expected = [0u8; 96];
+    expected[64] = 9;
+    let mut actual = expected;
+    eval(node.clone(), &mut expected);
+    let specialized = PartialEvaluator::new(100).specialize(node, Known::new());
+    eval(specialized.clone(), &mut actual);
+    assert_eq!(expected, actual);
+
+    // commands on known vectors become writes
+    let residual: Vec<Command> = traverse_node(specialized).into_iter()
+        .filter_map(|node| if let NodeKind::Command { command, .. } = node.get() { Some(command) } else { None })
+        .filter(|command| !matches!(command, Command::Set { .. }))
+        .collect();
+    assert_eq!(2, residual.len(), "{:?}", residual);
+    assert!(residual.contains(&Command::VecSplat { shape: VecShape::I32x4, dst: Ref::Stack(0), op: Ref::Stack(64) }), "{:?}", residual);
+    // copy of bytes splat wrote isn't folded to bytes known before
+    assert!(residual.contains(&Command::Copy { size: 16, dst: Ref::Stack(80), op: Ref::Stack(0) }), "{:?}", residual);
+}
+
 #[test]
 fn test_eq() {
     let condition = Condition::Ne { size: 4, op1: Ref::Stack(0), op2: Ref::Stack(4) };
@@ -125,4 +305,913 @@ fn test_call() {
         call: write_node(vec![1, 2, 3, 4]),
         next: write_node(vec![5, 6, 7, 8])
     }))
-}
\ No newline at end of file
+}
+#[test]
+fn test_tail_call() {
+    // callee's final returns from its caller's frame, both offsets are relative to the caller
+    test_node(vec![], node(NodeKind::Call {
+        offset: 4,
+        call: node(NodeKind::Command {
+            command: Command::Set { dst: Ref::Stack(0), bytes: vec![1, 2, 3, 4] },
+            next: node(NodeKind::TailCall { offset: 4, call: write_node(vec![5, 6, 7, 8]) }),
+        }),
+        next: write_node(vec![9, 10, 11, 12])
+    }))
+}
+
+// callee calls node writing and throwing its first 4 bytes, handler writes on its own
+fn try_node(throw: bool) -> TestNode {
+    let thrower = node(NodeKind::Command {
+        command: Command::Set { dst: Ref::Stack(0), bytes: vec![1, 2, 3, 4] },
+        next: node(if throw { NodeKind::Throw { op: Ref::Stack(0), size: 4 } } else { NodeKind::Final }),
+    });
+    node(NodeKind::Try {
+        offset: 4,
+        call: node(NodeKind::Call { offset: 4, call: thrower, next: write_node(vec![9, 10, 11, 12]) }),
+        next: write_node(vec![5, 6, 7, 8]),
+        catch: write_node(vec![13, 14, 15, 16]),
+    })
+}
+
+#[test]
+fn test_try() {
+    test_node(vec![], try_node(false));
+    test_node(vec![], try_node(true));
+
+    let mut expected = [0u8; TEST_STACK_SIZE];
+    eval(try_node(true), &mut expected);
+    assert_eq!([13, 14, 15, 16, 1, 2, 3, 4], expected[0..8]);
+    for (name, engine) in engines() {
+        // landings are dropped and made again with evicted tries
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_budget(Some(1));
+        for throw in [true, false, true] {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            driver.eval(try_node(throw), &mut stack);
+            let mut expected = [0u8; TEST_STACK_SIZE];
+            eval(try_node(throw), &mut expected);
+            assert_eq!(expected, stack, "\"{}\" output differs with budget", name);
+        }
+    }
+}
+
+// thrower of `try_node` called without try
+fn uncaught_node() -> TestNode {
+    let thrower = node(NodeKind::Command {
+        command: Command::Set { dst: Ref::Stack(0), bytes: vec![1, 2, 3, 4] },
+        next: node(NodeKind::Throw { op: Ref::Stack(0), size: 4 }),
+    });
+    node(NodeKind::Call { offset: 4, call: thrower, next: write_node(vec![5, 6, 7, 8]) })
+}
+
+#[test]
+fn test_uncaught_interpreter() {
+    assert_eq!(Stop::Uncaught(vec![1, 2, 3, 4]), eval(uncaught_node(), &mut [0u8; TEST_STACK_SIZE]));
+}
+
+#[test]
+fn test_uncaught() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        let Outcome::Uncaught(payload) = driver.eval(uncaught_node(), &mut stack) else { panic!("\"{}\" exception is caught", name) };
+        assert_eq!(vec![1, 2, 3, 4], payload, "\"{}\" payload", name);
+        // node after the call isn't reached
+        assert_eq!([0, 0, 0, 0, 1, 2, 3, 4], stack[0..8], "\"{}\" stack", name);
+
+        let mut ctx = driver.start(uncaught_node());
+        assert_eq!(Stop::Uncaught(vec![1, 2, 3, 4]), driver.resume(&mut ctx, &mut stack), "\"{}\" resume", name);
+        assert!(ctx.frames.is_empty(), "\"{}\" frames are left", name);
+    }
+    let driver = SharedDriver::new(InterpreterEngine::new());
+    assert!(matches!(driver.eval(uncaught_node(), &mut [0u8; TEST_STACK_SIZE]), Outcome::Uncaught(payload) if payload == vec![1, 2, 3, 4]));
+}
+
+fn doubler() -> TestNode {
+    let double = Command::Add { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(0) };
+    node(NodeKind::Command { command: double, next: node(NodeKind::Final) })
+}
+
+// doubles 4 bytes at `Stack(4)`
+fn pure_call_node() -> TestNode {
+    node(NodeKind::PureCall { offset: 4, call: doubler(), next: node(NodeKind::Final), args: 4, results: 4 })
+}
+
+#[test]
+fn test_memoization() {
+    test_node(vec![0, 0, 0, 0, 3], pure_call_node());
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_memoization(Some(1));
+        driver.set_profiling(true);
+        for (input, output) in [(3, 6), (3, 6), (5, 10), (5, 10), (3, 6)] {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            stack[4] = input;
+            driver.eval(pure_call_node(), &mut stack);
+            assert_eq!(output, stack[4], "\"{}\" result for {}", name, input);
+        }
+        // result for 3 is remembered, there is no place left for 5
+        assert_eq!(3, driver.profile()[&driver.id(&doubler()).unwrap()].executions, "\"{}\" callee executions", name);
+    }
+}
+
+fn call_node() -> TestNode {
+    node(NodeKind::Call {
+        offset: 4,
+        call: write_node(vec![1, 2, 3, 4]),
+        next: write_node(vec![5, 6, 7, 8])
+    })
+}
+
+#[test]
+fn test_invalidate() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        let mut expected = [0u8; TEST_STACK_SIZE];
+        driver.eval(call_node(), &mut expected);
+
+        driver.invalidate(&call_node());
+        driver.invalidate(&write_node(vec![1, 2, 3, 4]));
+        let mut actual = [0u8; TEST_STACK_SIZE];
+        driver.eval(call_node(), &mut actual);
+        assert_eq!(expected, actual, "\"{}\" output differs after invalidation", name);
+    }
+}
+
+#[test]
+fn test_budget() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_budget(Some(2));
+        let mut expected = [0u8; TEST_STACK_SIZE];
+        driver.eval(call_node(), &mut expected);
+        assert!(driver.registered() <= 2, "\"{}\" keeps more nodes than budget", name);
+
+        let mut actual = [0u8; TEST_STACK_SIZE];
+        driver.eval(call_node(), &mut actual);
+        assert_eq!(expected, actual, "\"{}\" output differs after eviction", name);
+
+        // lower budget applies right away
+        driver.set_budget(Some(1));
+        assert!(driver.registered() <= 1, "\"{}\" keeps more nodes than lowered budget", name);
+    }
+}
+
+#[test]
+fn test_profile() {
+    let branch = node(NodeKind::Branch {
+        condition: Condition::Ne0 { size: 4, op: Ref::Stack(0) },
+        if_true: write_node(vec![0, 0, 0, 0]),
+        if_false: call_node(),
+    });
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_profiling(true);
+        for input in [1, 0, 1] {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            stack[0] = input;
+            driver.eval(branch.clone(), &mut stack);
+        }
+
+        let profile = driver.profile();
+        let of = |node: &TestNode| profile[&driver.id(node).unwrap()];
+        assert_eq!(NodeProfile { executions: 3, taken: 2, not_taken: 1, calls: 0 }, of(&branch), "\"{}\" branch", name);
+        assert_eq!(NodeProfile { executions: 1, calls: 1, ..NodeProfile::default() }, of(&write_node(vec![1, 2, 3, 4])), "\"{}\" call", name);
+        assert_eq!(2, of(&write_node(vec![0, 0, 0, 0])).executions, "\"{}\" command", name);
+    }
+}
+
+#[test]
+fn test_stats() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.eval(call_node(), &mut [0u8; TEST_STACK_SIZE]);
+        let stats = driver.stats();
+        assert_eq!(4, stats.registered, "\"{}\" registered", name);
+        assert!(stats.suspensions > 0, "\"{}\" suspensions", name);
+        match name {
+            "specialized" => { assert_eq!(stats.registered, stats.compact + stats.full, "\"{}\" kinds", name) }
+            "code generator" => { assert!(stats.code_bytes > 0 && stats.patches > 0, "\"{}\" code", name) }
+            _ => { assert_eq!((0, 0), (stats.code_bytes, stats.patches), "\"{}\" code", name) }
+        }
+
+        // everything is registered already
+        driver.eval(call_node(), &mut [0u8; TEST_STACK_SIZE]);
+        assert_eq!(stats, driver.stats(), "\"{}\" stats after second eval", name);
+    }
+}
+
+// adds argument to counter at `Global(0)`, copies counter to frame, to `Global(260)` and throws it
+fn globals_node() -> TestNode {
+    let counter = Ref::Global(0);
+    let callee = node(NodeKind::Command {
+        command: Command::Add { size: 4, dst: counter, op1: counter, op2: Ref::Stack(0) },
+        next: node(NodeKind::Command {
+            command: Command::Copy { size: 8, dst: Ref::Global(260), op: counter },
+            next: node(NodeKind::Command { command: Command::Copy { size: 4, dst: Ref::Stack(4), op: counter }, next: node(NodeKind::Final) }),
+        }),
+    });
+    let throw = node(NodeKind::Throw { op: counter, size: 4 });
+    node(NodeKind::Command {
+        command: Command::Set { dst: Ref::Stack(8), bytes: vec![3, 0, 0, 0] },
+        next: node(NodeKind::Call {
+            offset: 8,
+            call: callee,
+            next: node(NodeKind::Try { offset: 16, call: throw, next: node(NodeKind::Final), catch: node(NodeKind::Final) }),
+        }),
+    })
+}
+
+#[test]
+fn test_code_generator_reclaim() {
+    // few nodes are kept registered while chunks they're in fill up with code of evicted ones
+    let mut driver = Driver::<TestNode, CodeGeneratorEngine>::new(CodeGeneratorEngine::new(1024).unwrap());
+    driver.set_budget(Some(16));
+    let mut hot = vec![];
+    for i in 0..2000u32 {
+        if i % 20 == 0 {
+            hot.push(write_node(i.to_le_bytes().to_vec()));
+            if hot.len() > 4 { hot.remove(0); }
+        }
+        driver.eval(write_node([i.to_le_bytes(), [1, 1, 1, 1]].concat()), &mut [0u8; TEST_STACK_SIZE]);
+        for node in &hot {
+            let (mut stack, mut expected) = ([0u8; TEST_STACK_SIZE], [0u8; TEST_STACK_SIZE]);
+            driver.eval(node.clone(), &mut stack);
+            eval(node.clone(), &mut expected);
+            assert_eq!(expected, stack);
+        }
+    }
+    // first chunk, current one and at most one more which code is mostly alive
+    assert!(driver.stats().code_memory <= 3 * 1024, "{:?}", driver.stats());
+}
+
+#[test]
+fn test_globals() {
+    let mut stack = [0u8; TEST_STACK_SIZE];
+    let globals = Globals::new(272);
+    eval_with_globals(globals_node(), &mut stack, &globals);
+    let mut counter = [0u8; 12];
+    globals.read(0, &mut counter[0..4]);
+    globals.read(260, &mut counter[4..12]);
+    assert_eq!([3, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0], counter);
+    assert_eq!([3, 0, 0, 0, 3, 0, 0, 0], stack[12..20]);
+
+    // writes to globals aren't folded away
+    eval_with_globals(PartialEvaluator::new(100).specialize(globals_node(), Known::new()), &mut stack, &globals);
+    globals.read(0, &mut counter[0..4]);
+    assert_eq!([6, 0, 0, 0], counter[0..4]);
+
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_globals(272);
+        // counter is kept between runs
+        for expected in [3, 6] {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            driver.eval(globals_node(), &mut stack);
+            let mut counter = [0u8; 4];
+            driver.globals().read(0, &mut counter);
+            assert_eq!([expected, 0, 0, 0], counter, "\"{}\" counter", name);
+            driver.globals().read(260, &mut counter);
+            assert_eq!([expected, 0, 0, 0], counter, "\"{}\" copy of counter", name);
+            assert_eq!([expected, 0, 0, 0, expected, 0, 0, 0], stack[12..20], "\"{}\" stack", name);
+        }
+    }
+}
+
+#[test]
+fn test_globals_out_of_range() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_globals(264);
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        // copy to `Global(260)` aborts the run, increment before it is done
+        assert!(matches!(driver.eval(globals_node(), &mut stack), Outcome::Fault(Fault::Unsupported(_))), "\"{}\"", name);
+        let mut counter = [0u8; 4];
+        driver.globals().read(0, &mut counter);
+        assert_eq!([3, 0, 0, 0], counter, "\"{}\" counter", name);
+        assert_eq!([0, 0, 0, 0], stack[12..16], "\"{}\" stack", name);
+    }
+}
+
+#[test]
+fn test_unsupported_atomics() {
+    let unaligned = || commands(vec![
+        Command::Set { dst: Ref::Stack(0), bytes: vec![1, 0, 0, 0] },
+        Command::AtomicStore { size: 4, mem: Ref::Global(2), op: Ref::Stack(0) },
+    ]);
+    // atomics of other sizes than 4 and 8 bytes aren't run either, even on stack
+    let sized = |size, mem| commands(vec![Command::AtomicLoad { size, dst: Ref::Stack(0), mem }]);
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_globals(32);
+        for node in [unaligned(), sized(2, Ref::Stack(16)), sized(16, Ref::Global(16))] {
+            assert!(matches!(driver.eval(node.clone(), &mut [0u8; 32]), Outcome::Fault(Fault::Unsupported(_))), "\"{}\" {:?}", name, node);
+        }
+    }
+    // without driver such access isn't atomic, but it's done
+    let globals = Globals::new(8);
+    eval_with_globals(unaligned(), &mut [0u8; 8], &globals);
+    let mut bytes = [0u8; 8];
+    globals.read(0, &mut bytes);
+    assert_eq!([0, 0, 1, 0, 0, 0, 0, 0], bytes);
+}
+
+#[test]
+fn test_smaller_globals() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_globals(272);
+        driver.eval(globals_node(), &mut [0u8; TEST_STACK_SIZE]);
+        let registered = driver.stats().registered;
+        // nodes accessing globals are evicted, the rest is kept
+        driver.set_globals(4);
+        assert!((1..registered).contains(&driver.stats().registered), "\"{}\" {:?}", name, driver.stats());
+        driver.set_globals(272);
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        driver.eval(globals_node(), &mut stack);
+        assert_eq!([3, 0, 0, 0, 3, 0, 0, 0], stack[12..20], "\"{}\" stack", name);
+    }
+}
+
+fn commands(commands: Vec<Command>) -> TestNode {
+    commands.into_iter().rev().fold(node(NodeKind::Final), |next, command| node(NodeKind::Command { command, next }))
+}
+
+// every read-modify-write on `Global(8)`, previous values are collected on stack, then compare-exchange on `Global(16)`
+// fails once and succeeds once
+fn atomics_node() -> TestNode {
+    let (mem, value) = (Ref::Global(8), Ref::Stack(0));
+    let mut list = vec![
+        Command::Set { dst: value, bytes: vec![6, 0, 0, 0] },
+        Command::AtomicStore { size: 4, mem, op: value },
+    ];
+    list.extend([RmwOp::Add, RmwOp::Sub, RmwOp::And, RmwOp::Or, RmwOp::Xor, RmwOp::Xchg].into_iter().enumerate().map(|(idx, op)| {
+        Command::AtomicRmw { size: 4, op, dst: Ref::Stack(4 + 4 * idx as u32), mem, value: Ref::Stack(0) }
+    }));
+    list.extend([
+        Command::Set { dst: Ref::Stack(0), bytes: vec![3, 0, 0, 0, 0, 0, 0, 1] },
+        Command::Copy { size: 8, dst: Ref::Global(16), op: Ref::Stack(0) },
+        Command::Set { dst: Ref::Stack(0), bytes: vec![4, 0, 0, 0, 0, 0, 0, 1] },
+        Command::Set { dst: Ref::Stack(32), bytes: vec![5, 0, 0, 0, 0, 0, 0, 2] },
+        Command::AtomicCmpXchg { size: 8, mem: Ref::Global(16), expected: Ref::Stack(0), replacement: Ref::Stack(32) },
+        Command::AtomicCmpXchg { size: 8, mem: Ref::Global(16), expected: Ref::Stack(0), replacement: Ref::Stack(32) },
+        Command::Fence,
+        Command::AtomicLoad { size: 8, dst: Ref::Stack(40), mem: Ref::Global(16) },
+    ]);
+    commands(list)
+}
+
+#[test]
+fn test_atomics() {
+    let mut expected = [0u8; 48];
+    let globals = Globals::new(24);
+    eval_with_globals(atomics_node(), &mut expected, &globals);
+    // previous values of -6, &6, |6, ^6 and xchg 6, the one of +6 is overwritten below
+    assert_eq!([12, 0, 0, 0, 6, 0, 0, 0, 6, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0], expected[8..28]);
+    // failed exchange updates expected value
+    assert_eq!([3, 0, 0, 0, 0, 0, 0, 1], expected[0..8]);
+    assert_eq!([5, 0, 0, 0, 0, 0, 0, 2], expected[40..48]);
+    let mut global = [0u8; 16];
+    globals.read(8, &mut global);
+    assert_eq!([6, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 2], global);
+
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_globals(24);
+        let mut actual = [0u8; 48];
+        driver.eval(atomics_node(), &mut actual);
+        assert_eq!(expected, actual, "\"{}\" stack", name);
+        let mut actual_global = [0u8; 16];
+        driver.globals().read(8, &mut actual_global);
+        assert_eq!(global, actual_global, "\"{}\" globals", name);
+    }
+}
+
+#[test]
+fn test_shared_unfinished_run() {
+    let mut expected = [0u8; TEST_STACK_SIZE];
+    eval(call_node(), &mut expected);
+    let driver = SharedDriver::new(InterpreterEngine::new());
+    driver.set_budget(Some(1));
+    driver.set_fuel(Some(1));
+    let mut stack = [0u8; TEST_STACK_SIZE];
+    let Outcome::OutOfFuel(ctx) = driver.eval(call_node(), &mut stack) else { panic!("finished with 1 fuel") };
+
+    // unfinished run doesn't keep others from evicting nodes
+    driver.set_fuel(None);
+    driver.eval(write_node(vec![1, 2, 3, 4]), &mut [0u8; TEST_STACK_SIZE]);
+    assert!(driver.stats().registered <= 1, "{:?}", driver.stats());
+    assert!(matches!(driver.resume(ctx, &mut stack), Outcome::Finished));
+    assert_eq!(expected, stack);
+}
+
+// `count` increments of `Global(0)` by 1
+fn increments(count: usize) -> TestNode {
+    let mut list = vec![Command::Set { dst: Ref::Stack(0), bytes: vec![1, 0, 0, 0, 0, 0, 0, 0] }];
+    list.extend((0..count).map(|_| Command::AtomicRmw { size: 8, op: RmwOp::Add, dst: Ref::Stack(8), mem: Ref::Global(0), value: Ref::Stack(0) }));
+    commands(list)
+}
+
+fn run_shared_atomics<E: Engine + Send + Sync>(engine: E) {
+    let driver = SharedDriver::new(engine);
+    driver.set_globals(8);
+    std::thread::scope(|scope| {
+        (0..4).for_each(|_| {
+            let driver = &driver;
+            scope.spawn(move || (0..50).for_each(|_| { driver.eval(increments(100), &mut [0u8; 16]); }));
+        });
+    });
+    let mut counter = [0u8; 8];
+    driver.globals().read(0, &mut counter);
+    assert_eq!(4 * 50 * 100, u64::from_le_bytes(counter));
+}
+
+#[test]
+fn test_shared_atomics() {
+    run_shared_atomics(InterpreterEngine::new());
+    run_shared_atomics(SpecializedInterpreterEngine::new());
+}
+
+#[test]
+fn test_code_generator_shared_atomics() {
+    run_shared_atomics(CodeGeneratorEngine::new(64 * 1024).unwrap());
+}
+
+#[test]
+fn test_observer() {
+    let branch = node(NodeKind::Branch {
+        condition: Condition::Ne0 { size: 4, op: Ref::Stack(0) },
+        if_true: write_node(vec![0, 0, 0, 0]),
+        if_false: call_node(),
+    });
+    for (name, engine) in engines() {
+        let tracer = Arc::new(Tracer::new());
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_observer(Some(tracer.clone()));
+        driver.eval(branch.clone(), &mut [0u8; TEST_STACK_SIZE]);
+
+        // ids are given to successors once node is registered, 17 is not taken `if_true`
+        let (branch, call, callee, next, last) = (NodeId(16), NodeId(18), NodeId(19), NodeId(20), NodeId(21));
+        assert_eq!(vec![
+            Event::Suspend(branch), Event::Node(branch), Event::Branch(branch, false),
+            Event::Suspend(call), Event::Node(call), Event::Call(call, callee),
+            Event::Suspend(callee), Event::Node(callee), Event::Command(callee),
+            Event::Suspend(last), Event::Node(last), Event::Return(last),
+            Event::Suspend(next), Event::Node(next), Event::Command(next),
+            Event::Node(last), Event::Return(last),
+        ], tracer.take(), "\"{}\" events differ", name);
+    }
+}
+
+#[test]
+fn test_observer_suspensions() {
+    let second = write_node(vec![2, 2, 2, 2]);
+    let yielding = node(NodeKind::Yield { next: second });
+    let run = node(NodeKind::Call { offset: 4, call: write_node(vec![1, 1, 1, 1]), next: yielding.clone() });
+    for (name, engine) in engines() {
+        let tracer = Arc::new(Tracer::new());
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_observer(Some(tracer.clone()));
+        // nodes are known to engine once they're run
+        let Outcome::Yielded(mut ctx) = driver.eval(run.clone(), &mut [0u8; TEST_STACK_SIZE]) else { panic!("\"{}\" didn't yield", name) };
+        assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut [0u8; TEST_STACK_SIZE]), "\"{}\"", name);
+        tracer.take();
+
+        driver.set_fuel(Some(1));
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        let Outcome::OutOfFuel(mut ctx) = driver.eval(run.clone(), &mut stack) else { panic!("\"{}\" finished with 1 fuel", name) };
+        let out_of_fuel = ctx.frames.last().unwrap().id;
+        driver.set_fuel(None);
+        assert_eq!(Stop::Yield, driver.resume(&mut ctx, &mut stack), "\"{}\"", name);
+        assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\"", name);
+        // node fuel ran out on and yield handled by driver are reported, though they're known
+        let events = tracer.take();
+        assert!(events.contains(&Event::Suspend(out_of_fuel)), "\"{}\" {:?}", name, events);
+        assert!(events.contains(&Event::Suspend(driver.id(&yielding).unwrap())), "\"{}\" {:?}", name, events);
+    }
+}
+
+#[test]
+fn test_debugger() {
+    let callee = write_node(vec![1, 2, 3, 4]);
+    for inlining in [None, Some(2)] {
+        for (name, engine) in engines() {
+            let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+            driver.set_inlining(inlining);
+            // engine has callee compiled (or inlined into the call) before breakpoint is set
+            driver.eval(call_node(), &mut [0u8; TEST_STACK_SIZE]);
+            let registered = driver.stats().registered;
+            let breakpoint = driver.set_breakpoint(callee.clone());
+            // breakpoint node is evicted
+            assert!(driver.stats().registered < registered, "\"{}\" {:?}", name, driver.stats());
+
+            for _ in 0..2 {
+                let mut stack = [0u8; TEST_STACK_SIZE];
+                let mut ctx = driver.start(call_node());
+                assert_eq!(Stop::Breakpoint(breakpoint), driver.resume(&mut ctx, &mut stack), "\"{}\" breakpoint with inlining {:?}", name, inlining);
+                assert_eq!(4, ctx.offset());
+                assert_eq!(Some(&callee), driver.node(ctx.frames.last().unwrap().id));
+
+                assert_eq!(Stop::Step, driver.step(&mut ctx, &mut stack));
+                assert_eq!([1, 2, 3, 4], stack[4..8]);
+                stack[4] = 9;
+                assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\" finish", name);
+                assert_eq!([5, 6, 7, 8, 9, 2, 3, 4], stack[0..8], "\"{}\" stack", name);
+            }
+
+            // eval doesn't stop on breakpoints
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            driver.eval(call_node(), &mut stack);
+            assert_eq!([5, 6, 7, 8, 1, 2, 3, 4], stack[0..8], "\"{}\" eval", name);
+
+            driver.remove_breakpoint(&callee);
+            let mut ctx = driver.start(call_node());
+            assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut [0u8; TEST_STACK_SIZE]), "\"{}\" removed breakpoint", name);
+        }
+    }
+}
+
+#[test]
+fn test_fuel() {
+    for (name, engine) in engines() {
+        let mut expected = [0u8; TEST_STACK_SIZE];
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.eval(call_node(), &mut expected);
+
+        driver.set_fuel(Some(1));
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        let Outcome::OutOfFuel(mut ctx) = driver.eval(call_node(), &mut stack) else { panic!("\"{}\" finished with 1 fuel", name) };
+        assert_eq!(Some(0), driver.fuel());
+        let mut refuels = 0;
+        while driver.resume(&mut ctx, &mut stack) == Stop::OutOfFuel {
+            driver.set_fuel(Some(1));
+            refuels += 1;
+        }
+        assert!(refuels > 0, "\"{}\" finished after first refuel", name);
+        assert_eq!(expected, stack, "\"{}\" output differs after refuels", name);
+
+        driver.set_fuel(None);
+        assert!(matches!(driver.eval(call_node(), &mut stack), Outcome::Finished), "\"{}\" no fuel limit", name);
+    }
+}
+
+#[test]
+fn test_pinned_runs() {
+    let mut expected = [0u8; TEST_STACK_SIZE];
+    eval(call_node(), &mut expected);
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.set_budget(Some(0));
+        driver.set_fuel(Some(1));
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        let Outcome::OutOfFuel(mut ctx) = driver.eval(call_node(), &mut stack) else { panic!("\"{}\" finished with 1 fuel", name) };
+
+        // evals in between evict everything, ids of nodes which aren't in frames are reused
+        driver.set_fuel(None);
+        (0..8).for_each(|byte| { driver.eval(write_node(vec![byte; 4]), &mut [0u8; TEST_STACK_SIZE]); });
+        assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\" resume", name);
+        assert_eq!(expected, stack, "\"{}\" output differs after evals in between", name);
+
+        let ctx = driver.start(call_node());
+        driver.cancel(ctx);
+        assert_eq!(0, driver.registered(), "\"{}\" registered", name);
+    }
+}
+
+#[test]
+fn test_executions() {
+    for (name, engine) in engines() {
+        let mut expected = [0u8; TEST_STACK_SIZE];
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        driver.eval(call_node(), &mut expected);
+
+        // finished executions evict nodes others are suspended in
+        driver.set_budget(Some(1));
+        driver.set_fuel(Some(1));
+        let mut executions: Vec<Execution> = (0..3).map(|_| driver.execution(call_node(), vec![0u8; TEST_STACK_SIZE])).collect();
+        while executions.iter().any(|execution| !execution.is_finished()) {
+            for execution in executions.iter_mut().filter(|execution| !execution.is_finished()) {
+                driver.set_fuel(Some(1));
+                execution.resume(&mut driver);
+            }
+        }
+        executions.iter().for_each(|execution| assert_eq!(expected, execution.stack[..], "\"{}\" output differs", name));
+
+        let mut cancelled = driver.execution(call_node(), vec![0u8; TEST_STACK_SIZE]);
+        assert_eq!(Stop::Step, cancelled.step(&mut driver));
+        assert_eq!(vec![0u8; TEST_STACK_SIZE], cancelled.cancel(&mut driver));
+    }
+}
+
+// callee yields between two writes
+fn yield_node() -> TestNode {
+    let second = node(NodeKind::Command { command: Command::Set { dst: Ref::Stack(0), bytes: vec![2, 2, 2, 2] }, next: node(NodeKind::Final) });
+    node(NodeKind::Call {
+        offset: 4,
+        call: node(NodeKind::Command {
+            command: Command::Set { dst: Ref::Stack(0), bytes: vec![1, 1, 1, 1] },
+            next: node(NodeKind::Yield { next: second }),
+        }),
+        next: write_node(vec![5, 6, 7, 8]),
+    })
+}
+
+#[test]
+fn test_yield() {
+    let mut expected = [0u8; TEST_STACK_SIZE];
+    eval(yield_node(), &mut expected);
+    assert_eq!([5, 6, 7, 8, 2, 2, 2, 2], expected[0..8]);
+
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        // second time yield is registered in engine
+        for _ in 0..2 {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            let Outcome::Yielded(mut ctx) = driver.eval(yield_node(), &mut stack) else { panic!("\"{}\" didn't yield", name) };
+            assert_eq!([0, 0, 0, 0, 1, 1, 1, 1], stack[0..8], "\"{}\" yielded stack", name);
+            assert_eq!(4, ctx.offset(), "\"{}\" frames aren't preserved", name);
+            assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\" resume", name);
+            assert_eq!(expected, stack, "\"{}\" output differs", name);
+        }
+
+        // generators are interleaved with executions
+        let mut executions: Vec<Execution> = (0..2).map(|_| driver.execution(yield_node(), vec![0u8; TEST_STACK_SIZE])).collect();
+        executions.iter_mut().for_each(|execution| assert_eq!(Stop::Yield, execution.resume(&mut driver), "\"{}\" execution", name));
+        executions.iter_mut().for_each(|execution| assert_eq!(Stop::Finished, execution.resume(&mut driver), "\"{}\" execution", name));
+        executions.iter().for_each(|execution| assert_eq!(expected, execution.stack[..], "\"{}\" execution output", name));
+    }
+}
+
+fn resume(handle: u32, next: TestNode) -> TestNode { node(NodeKind::Resume { handle: Ref::Stack(handle), next }) }
+
+// puts 1 at `Stack(0)` and yields, then puts 2 there and yields within a call, then puts 3 there and returns
+fn generator_node() -> TestNode {
+    let set = |value: u8, next: TestNode| node(NodeKind::Command { command: Command::Set { dst: Ref::Stack(0), bytes: vec![value, 0, 0, 0] }, next });
+    let callee = set(2, node(NodeKind::Yield { next: set(3, node(NodeKind::Final)) }));
+    set(1, node(NodeKind::Yield { next: node(NodeKind::Call { offset: 0, call: callee, next: node(NodeKind::Final) }) }))
+}
+
+// adds what generator puts at `Stack(8)` to `Stack(4)` after each resume
+fn coroutine_node() -> TestNode {
+    let add = |next: TestNode| node(NodeKind::Command { command: Command::Add { size: 4, dst: Ref::Stack(4), op1: Ref::Stack(4), op2: Ref::Stack(8) }, next });
+    node(NodeKind::Coroutine {
+        offset: 8,
+        call: generator_node(),
+        handle: Ref::Stack(0),
+        next: resume(0, add(resume(0, add(resume(0, add(node(NodeKind::Final))))))),
+    })
+}
+
+// Generator is resumed by relay coroutine, which adds its values to `Stack(8)` and yields in turn.
+// Relay is resumed twice only, so generator is left unfinished.
+fn relay_node() -> TestNode {
+    let add = |next: TestNode| node(NodeKind::Command { command: Command::Add { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(8) }, next });
+    // relay's frame is at `Stack(8)`, generator's one at `Stack(16)`, its handle is at `Stack(4)` of relay
+    let relay = resume(4, add(node(NodeKind::Yield { next: resume(4, add(node(NodeKind::Final))) })));
+    node(NodeKind::Coroutine {
+        offset: 16,
+        call: generator_node(),
+        handle: Ref::Stack(12),
+        next: node(NodeKind::Coroutine { offset: 8, call: relay, handle: Ref::Stack(0), next: resume(0, resume(0, node(NodeKind::Final))) }),
+    })
+}
+
+#[test]
+fn test_coroutines() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        // second time nodes are registered in engine
+        for _ in 0..2 {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            assert!(matches!(driver.eval(coroutine_node(), &mut stack), Outcome::Finished), "\"{}\" coroutine", name);
+            assert_eq!([0, 0, 0, 0, 6, 0, 0, 0, 3, 0, 0, 0], stack[0..12], "\"{}\" coroutine stack", name);
+
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            assert!(matches!(driver.eval(relay_node(), &mut stack), Outcome::Finished), "\"{}\" relay", name);
+            // generator is made first
+            assert_eq!([1, 0, 0, 0], stack[0..4], "\"{}\" relay handle", name);
+            assert_eq!([3, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0], stack[8..20], "\"{}\" relay stack", name);
+        }
+
+        // suspended coroutines are kept with suspended run
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        driver.set_fuel(Some(8));
+        let Outcome::OutOfFuel(mut ctx) = driver.eval(coroutine_node(), &mut stack) else { panic!("\"{}\" finished with 8 fuel", name) };
+        driver.set_fuel(None);
+        driver.set_budget(Some(0));
+        assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\" resume", name);
+        assert_eq!([0, 0, 0, 0, 6, 0, 0, 0, 3, 0, 0, 0], stack[0..12], "\"{}\" resumed stack", name);
+        driver.set_budget(None);
+    }
+}
+
+#[test]
+fn test_invalid_resume() {
+    let finished = node(NodeKind::Coroutine { offset: 8, call: node(NodeKind::Final), handle: Ref::Stack(0), next: resume(0, resume(0, node(NodeKind::Final))) });
+    let unknown = resume(0, node(NodeKind::Final));
+    // coroutine's frame is where its handle is put
+    let running = node(NodeKind::Coroutine { offset: 0, call: resume(0, node(NodeKind::Final)), handle: Ref::Stack(0), next: resume(0, node(NodeKind::Final)) });
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        for (case, invalid) in [("finished", finished.clone()), ("unknown", unknown.clone()), ("running", running.clone())] {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            assert!(matches!(driver.eval(invalid.clone(), &mut stack), Outcome::Fault(Fault::Resume(0))), "\"{}\" {}", name, case);
+            let mut ctx = driver.start(invalid);
+            assert_eq!(Stop::Fault(Fault::Resume(0)), driver.resume(&mut ctx, &mut stack), "\"{}\" {} resume", name, case);
+            assert!(ctx.frames.is_empty(), "\"{}\" {} frames are left", name, case);
+        }
+    }
+}
+
+// callee asks host to run function 7 on its frame from `Stack(4)`, then doubles the result
+fn host_call_node() -> TestNode {
+    let double = Command::Add { size: 4, dst: Ref::Stack(4), op1: Ref::Stack(4), op2: Ref::Stack(4) };
+    node(NodeKind::Call {
+        offset: 4,
+        call: node(NodeKind::Command {
+            command: Command::Set { dst: Ref::Stack(4), bytes: vec![2, 0, 0, 0] },
+            next: node(NodeKind::HostCall { function: 7, offset: 4, next: node(NodeKind::Command { command: double, next: node(NodeKind::Final) }) }),
+        }),
+        next: write_node(vec![5, 6, 7, 8]),
+    })
+}
+
+// host function 7 adds 10 to 4 bytes at `offset`
+fn run_host_call(function: u32, offset: usize, stack: &mut [u8]) {
+    assert_eq!(7, function);
+    stack[offset] += 10;
+}
+
+#[test]
+fn test_host_call_without_host() {
+    let mut stack = [0u8; TEST_STACK_SIZE];
+    assert_eq!(Stop::HostCall { function: 7, offset: 8 }, eval(host_call_node(), &mut stack));
+    // evaluation ends on host call
+    assert_eq!([0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0], stack[0..12]);
+}
+
+#[test]
+fn test_host_call() {
+    for (name, engine) in engines() {
+        let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+        // second time host call is registered in engine
+        for _ in 0..2 {
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            let Outcome::HostCall { mut ctx, function, offset } = driver.eval(host_call_node(), &mut stack) else { panic!("\"{}\" didn't call host", name) };
+            assert_eq!(8, offset, "\"{}\" host call offset", name);
+            run_host_call(function, offset, &mut stack);
+            assert_eq!(Stop::Finished, driver.resume(&mut ctx, &mut stack), "\"{}\" resume", name);
+            assert_eq!([5, 6, 7, 8, 0, 0, 0, 0, 24, 0, 0, 0], stack[0..12], "\"{}\" output differs", name);
+        }
+
+        let mut execution = driver.execution(host_call_node(), vec![0u8; TEST_STACK_SIZE]);
+        let Stop::HostCall { function, offset } = execution.resume(&mut driver) else { panic!("\"{}\" execution didn't call host", name) };
+        run_host_call(function, offset, &mut execution.stack);
+        assert_eq!(Stop::Finished, execution.resume(&mut driver), "\"{}\" execution", name);
+        assert_eq!(24, execution.stack[8], "\"{}\" execution output", name);
+    }
+
+    let driver = SharedDriver::new(InterpreterEngine::new());
+    let mut stack = [0u8; TEST_STACK_SIZE];
+    let Outcome::HostCall { ctx, function, offset } = driver.eval(host_call_node(), &mut stack) else { panic!("shared driver didn't call host") };
+    run_host_call(function, offset, &mut stack);
+    assert!(matches!(driver.resume(ctx, &mut stack), Outcome::Finished));
+    assert_eq!(24, stack[8]);
+}
+
+// records batches driver registers
+struct BatchEngine {
+    engine: InterpreterEngine,
+    region: Region,
+    batches: Vec<Vec<u32>>,
+}
+
+impl Engine for BatchEngine {
+    fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.engine.register(id, kind) }
+    fn region(&self) -> Region { self.region }
+    fn register_batch(&mut self, nodes: Vec<(NodeId, NodeKind<NodeId>)>) {
+        self.batches.push(nodes.iter().map(|(id, _)| id.0).collect());
+        nodes.into_iter().for_each(|(id, kind)| self.engine.register(id, kind))
+    }
+    fn unregister(&mut self, id: NodeId) { self.engine.unregister(id) }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.engine.run(state, stack, globals) }
+}
+
+#[test]
+fn test_region() {
+    // call is 16, callee 17, next 18, final shared by both 19
+    for (region, batches) in [
+        (Region::Node, vec![vec![16], vec![17], vec![19], vec![18]]),
+        (Region::Steps(1), vec![vec![16, 17, 18], vec![19]]),
+        (Region::Function, vec![vec![16, 18, 19], vec![17]]),
+    ] {
+        let mut driver = Driver::new(BatchEngine { engine: InterpreterEngine::new(), region, batches: vec![] });
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        driver.eval(call_node(), &mut stack);
+        assert_eq!([5, 6, 7, 8, 1, 2, 3, 4], stack[0..8], "{:?} output", region);
+        assert_eq!(batches, driver.engine().batches, "{:?} batches", region);
+    }
+}
+
+#[test]
+fn test_inlining() {
+    let mut expected = [0u8; TEST_STACK_SIZE];
+    eval(call_node(), &mut expected);
+    // callee is command and final
+    for (limit, inlined) in [(Some(2), true), (Some(1), false)] {
+        for (name, engine) in engines() {
+            let tracer = Arc::new(Tracer::new());
+            let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+            driver.set_observer(Some(tracer.clone()));
+            driver.set_inlining(limit);
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            driver.eval(call_node(), &mut stack);
+            assert_eq!(expected, stack, "\"{}\" output with inlining {:?}", name, limit);
+            let calls = tracer.take().iter().filter(|event| matches!(event, Event::Call(..))).count();
+            assert_eq!(inlined, calls == 0, "\"{}\" calls with inlining {:?}", name, limit);
+
+            // inlined copy is dropped with the callee
+            driver.invalidate(&write_node(vec![1, 2, 3, 4]));
+            let mut stack = [0u8; TEST_STACK_SIZE];
+            driver.eval(call_node(), &mut stack);
+            assert_eq!(expected, stack, "\"{}\" output after invalidation with inlining {:?}", name, limit);
+        }
+    }
+}
+
+#[test]
+fn test_inlined_profile() {
+    let quadrupler = node(NodeKind::Command { command: Command::Add { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(0) }, next: doubler() });
+    let call = node(NodeKind::Call { offset: 4, call: quadrupler.clone(), next: node(NodeKind::Final) });
+    let mut expected = None;
+    for inlining in [None, Some(8)] {
+        for (name, engine) in engines() {
+            let mut driver = Driver::<TestNode, EngineBox>::new(engine);
+            driver.set_profiling(true);
+            driver.set_inlining(inlining);
+            (0..3).for_each(|_| { driver.eval(call.clone(), &mut [0u8; TEST_STACK_SIZE]); });
+            // inlined code counts for the callee, which isn't called though, its final node isn't executed either
+            let profile = driver.profile();
+            assert_eq!(if inlining.is_some() { 0 } else { 3 }, profile[&driver.id(&quadrupler).unwrap()].calls, "\"{}\" calls with inlining {:?}", name, inlining);
+            let executions: HashMap<TestNode, u64> = profile.iter()
+                .map(|(id, profile)| (driver.node(*id).unwrap().clone(), profile.executions))
+                .filter(|(executed, _)| *executed != node(NodeKind::Final))
+                .collect();
+            assert_eq!(3, executions[&doubler()], "\"{}\" executions with inlining {:?}", name, inlining);
+            assert_eq!(expected.get_or_insert_with(|| executions.clone()), &executions, "\"{}\" executions with inlining {:?}", name, inlining);
+            // counters of evicted inlined copies are kept too, while runs keep original nodes from being forgotten
+            let pinned: Vec<RunState> = [call.clone(), quadrupler.clone(), doubler(), node(NodeKind::Final)].into_iter()
+                .map(|node| driver.start(node))
+                .collect();
+            driver.set_budget(Some(0));
+            assert_eq!(profile, driver.profile(), "\"{}\" profile after eviction with inlining {:?}", name, inlining);
+            pinned.into_iter().for_each(|ctx| driver.cancel(ctx));
+        }
+    }
+}
+
+// Interpreter which forgets node along with its dependents, as code generator does with code continuing into node's code.
+struct DependentEngine {
+    engine: InterpreterEngine,
+    dependents: Vec<(NodeId, NodeId)>,
+}
+
+impl Engine for DependentEngine {
+    fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.engine.register(id, kind) }
+    fn unregister(&mut self, id: NodeId) {
+        self.dependents(id).into_iter().for_each(|dependent| self.engine.unregister(dependent));
+        self.engine.unregister(id)
+    }
+    fn dependents(&self, id: NodeId) -> Vec<NodeId> {
+        self.dependents.iter().filter(|(node, _)| *node == id).map(|(_, dependent)| *dependent).collect()
+    }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.engine.run(state, stack, globals) }
+    fn set_profiling(&mut self, profiling: bool) { self.engine.set_profiling(profiling) }
+    fn profile(&self, id: NodeId) -> Option<NodeProfile> { self.engine.profile(id) }
+}
+
+#[test]
+fn test_dependents() {
+    let branch = node(NodeKind::Branch {
+        condition: Condition::Ne0 { size: 4, op: Ref::Stack(0) },
+        if_true: write_node(vec![0, 0, 0, 0]),
+        if_false: write_node(vec![1, 1, 1, 1]),
+    });
+    let root = node(NodeKind::Command { command: Command::Set { dst: Ref::Stack(8), bytes: vec![1, 2, 3, 4] }, next: branch.clone() });
+    // root is 16, branch 17, command it continues into 18
+    let mut driver = Driver::new(DependentEngine { engine: InterpreterEngine::new(), dependents: vec![(NodeId(18), NodeId(17))] });
+    driver.set_profiling(true);
+    for input in [1, 0, 1] {
+        let mut stack = [0u8; TEST_STACK_SIZE];
+        stack[0] = input;
+        driver.eval(root.clone(), &mut stack);
+    }
+    assert_eq!(Some(NodeId(18)), driver.id(&write_node(vec![0, 0, 0, 0])));
+
+    // branch stays known since root refers to it, its counters are kept through the eviction
+    driver.invalidate(&write_node(vec![0, 0, 0, 0]));
+    assert_eq!(NodeProfile { executions: 3, taken: 2, not_taken: 1, calls: 0 }, driver.profile()[&driver.id(&branch).unwrap()]);
+    let mut stack = [0u8; TEST_STACK_SIZE];
+    driver.eval(root.clone(), &mut stack);
+    assert_eq!(NodeProfile { executions: 4, taken: 2, not_taken: 2, calls: 0 }, driver.profile()[&driver.id(&branch).unwrap()]);
+}
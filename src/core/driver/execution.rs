@@ -0,0 +1,30 @@
+use crate::core::api::Node;
+use crate::core::driver::driver::{Driver, Engine, RunState, Stop};
+
+// Evaluation of a node with its own stack, created by `Driver::execution`. It doesn't borrow the driver,
+// so an embedder can keep many of them and interleave them on one thread, e.g. giving each some fuel in turn.
+// Nodes in frames of unfinished execution are kept known to the driver, so `cancel` executions which won't be finished.
+pub struct Execution {
+    pub state: RunState,
+    pub stack: Vec<u8>,
+}
+
+impl Execution {
+    pub fn is_finished(&self) -> bool { self.state.frames.is_empty() }
+
+    // Runs until breakpoint, yield, host call, fuel exhaustion or the end, see `Driver::resume`.
+    pub fn resume<N: Node, E: Engine>(&mut self, driver: &mut Driver<N, E>) -> Stop {
+        driver.resume(&mut self.state, &mut self.stack)
+    }
+
+    // Executes single node, see `Driver::step`.
+    pub fn step<N: Node, E: Engine>(&mut self, driver: &mut Driver<N, E>) -> Stop {
+        driver.step(&mut self.state, &mut self.stack)
+    }
+
+    // Drops unfinished execution, returns its stack.
+    pub fn cancel<N: Node, E: Engine>(self, driver: &mut Driver<N, E>) -> Vec<u8> {
+        driver.cancel(self.state);
+        self.stack
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// Fuel left for engine runs, one unit is taken per executed node (or per jump between nodes for generated code).
+// Once it's exhausted engine suspends before the node, so run can be continued after refueling.
+#[derive(Default)]
+pub struct Fuel {
+    // might go below zero in generated code, which treats negative value as exhausted
+    left: AtomicI64,
+    metered: bool,
+}
+
+impl Fuel {
+    pub fn new() -> Fuel { Fuel::default() }
+
+    // `None` turns metering off, generated code which still has checks then just never runs out
+    pub fn set(&mut self, fuel: Option<u64>) {
+        self.metered = fuel.is_some();
+        self.left.store(fuel.unwrap_or(u64::MAX).min(i64::MAX as u64) as i64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        if self.metered { Some(self.left.load(Ordering::Relaxed).max(0) as u64) } else { None }
+    }
+
+    pub fn is_metered(&self) -> bool { self.metered }
+
+    // takes one unit, false if it's metered and there is nothing left
+    pub fn charge(&self) -> bool {
+        if !self.metered { return true; }
+        // runs sharing the engine charge concurrently, so a unit is never taken twice
+        self.left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| if left > 0 { Some(left - 1) } else { None }).is_ok()
+    }
+
+    pub fn is_exhausted(&self) -> bool { self.metered && self.left.load(Ordering::Relaxed) <= 0 }
+
+    pub fn ptr(&self) -> *const AtomicI64 { &self.left }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use crate::core::api::{Command, Condition, Node, NodeKind, Ref, RmwOp};
+
+use crate::core::aux::partial_eval::{Known, PartialEvaluator};
+use crate::core::driver::driver::Driver;
+use crate::core::driver::driver_tests::{engines, EngineBox};
+use crate::core::interpreter::eval;
+
+// Differential testing of engines on random node graphs against `interpreter::eval`.
+// Graphs share nodes and loop back to nodes being generated, a counter at the end of the stack bounds iterations
+// of all loops, so control flow always terminates. Graphs use only commands and sizes every engine supports.
+
+const FUZZ_STACK_SIZE: u32 = 64;
+const FUZZ_LOOPS: u8 = 8;
+
+// Graph of nodes referring to each other by index, node 0 is the root. Each node has the frame it was generated in,
+// frames of callees end where the caller's one does, at the end of the stack.
+type Graph = Vec<(u32, NodeKind<u32>)>;
+
+// loop counter of the frame and constant one to decrement it, they are the last 8 bytes of the stack
+fn counter(frame: u32) -> Ref { Ref::Stack(frame - 8) }
+
+fn one(frame: u32) -> Ref { Ref::Stack(frame - 4) }
+
+#[derive(Clone)]
+struct FuzzNode {
+    graph: Arc<Graph>,
+    id: u32,
+}
+
+impl FuzzNode {
+    fn root(graph: Graph) -> FuzzNode { FuzzNode { graph: Arc::new(graph), id: 0 } }
+}
+
+impl Node for FuzzNode {
+    fn get(&self) -> NodeKind<Self> { map(self.graph[self.id as usize].1.clone(), |id| FuzzNode { graph: self.graph.clone(), id }) }
+}
+
+impl PartialEq for FuzzNode {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.graph, &other.graph) && self.id == other.id }
+}
+
+impl Eq for FuzzNode {}
+
+impl Hash for FuzzNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.graph).hash(state);
+        self.id.hash(state);
+    }
+}
+
+// whole graph is printed with reproducer
+impl Debug for FuzzNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "#{}", self.id) }
+}
+
+// same kind with successors mapped by `f`
+fn map<T>(kind: NodeKind<u32>, mut f: impl FnMut(u32) -> T) -> NodeKind<T> {
+    match kind {
+        NodeKind::Command { command, next } => { NodeKind::Command { command, next: f(next) } }
+        NodeKind::Branch { condition, if_true, if_false } => { NodeKind::Branch { condition, if_true: f(if_true), if_false: f(if_false) } }
+        NodeKind::Call { offset, call, next } => { NodeKind::Call { offset, call: f(call), next: f(next) } }
+        NodeKind::Final => { NodeKind::Final }
+        NodeKind::Yield { next } => { NodeKind::Yield { next: f(next) } }
+        NodeKind::Coroutine { offset, call, handle, next } => { NodeKind::Coroutine { offset, call: f(call), handle, next: f(next) } }
+        NodeKind::Resume { handle, next } => { NodeKind::Resume { handle, next: f(next) } }
+        NodeKind::TailCall { offset, call } => { NodeKind::TailCall { offset, call: f(call) } }
+        NodeKind::Try { offset, call, next, catch } => { NodeKind::Try { offset, call: f(call), next: f(next), catch: f(catch) } }
+        NodeKind::Throw { op, size } => { NodeKind::Throw { op, size } }
+        NodeKind::PureCall { offset, call, next, args, results } => { NodeKind::PureCall { offset, call: f(call), next: f(next), args, results } }
+        NodeKind::HostCall { function, offset, next } => { NodeKind::HostCall { function, offset, next: f(next) } }
+    }
+}
+
+// node checking or decrementing loop counter of its frame
+fn counts(frame: u32, kind: &NodeKind<u32>) -> bool {
+    match kind {
+        NodeKind::Command { command: Command::Sub { dst, .. }, .. } => { *dst == counter(frame) }
+        NodeKind::Branch { condition: Condition::Ne0 { op, .. }, .. } => { *op == counter(frame) }
+        _ => { false }
+    }
+}
+
+// xorshift, good enough for picking nodes
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng { Rng(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1) }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u32) -> u32 { (self.next() % n as u64) as u32 }
+
+    fn pick(&mut self, ids: &[u32]) -> Option<u32> {
+        if ids.is_empty() { None } else { Some(ids[self.below(ids.len() as u32) as usize]) }
+    }
+}
+
+struct Generator {
+    rng: Rng,
+    // nodes left to generate, the rest are final
+    budget: u32,
+    // number of tries around generated node, it throws only if there is one
+    tries: u32,
+    graph: Graph,
+    // generated nodes with whether they were generated in try
+    pool: Vec<(u32, bool)>,
+    // nodes being generated, loops jump back to them
+    open: Vec<u32>,
+}
+
+impl Generator {
+    fn new(seed: u64, budget: u32) -> Generator {
+        Generator { rng: Rng::new(seed), budget, tries: 0, graph: vec![], pool: vec![], open: vec![] }
+    }
+
+    fn graph(&mut self, frame: u32) -> Graph {
+        self.node(frame);
+        std::mem::take(&mut self.graph)
+    }
+
+    // node using first `frame` bytes of the stack, generated or reused one
+    fn node(&mut self, frame: u32) -> u32 {
+        if self.rng.below(4) == 0 {
+            if let Some(id) = self.reused(frame) { return id; }
+        }
+        let id = self.add(frame, NodeKind::Final);
+        if self.budget == 0 { return id; }
+        self.budget -= 1;
+        self.open.push(id);
+        let kind = match self.rng.below(15) {
+            0..=4 => {
+                let command = self.command(frame);
+                NodeKind::Command { command, next: self.node(frame) }
+            }
+            5 | 6 => {
+                let condition = self.condition(frame);
+                NodeKind::Branch { condition, if_true: self.node(frame), if_false: self.node(frame) }
+            }
+            // callee gets at least 16 bytes, offset keeps 8 byte values aligned
+            7 | 8 if frame >= 24 => {
+                let offset = 8 * (1 + self.rng.below((frame - 16) / 8));
+                NodeKind::Call { offset, call: self.node(frame - offset), next: self.node(frame) }
+            }
+            9 if frame >= 24 => {
+                let offset = 8 * self.rng.below((frame - 8) / 8);
+                NodeKind::TailCall { offset, call: self.node(frame - offset) }
+            }
+            // payload fits into callee frame
+            10 if frame >= 24 => {
+                let offset = 8 * (1 + self.rng.below((frame - 16) / 8));
+                self.tries += 1;
+                let call = self.node(frame - offset);
+                self.tries -= 1;
+                NodeKind::Try { offset, call, next: self.node(frame), catch: self.node(frame) }
+            }
+            11 if self.tries > 0 => {
+                let size = 4 * (1 + self.rng.below(2));
+                NodeKind::Throw { op: self.stack_ref(frame, size), size }
+            }
+            // callee uses only its frame, so it's pure if whole frame is both arguments and results, loop counter included
+            12 if frame >= 24 => {
+                let offset = 8 * (1 + self.rng.below((frame - 16) / 8));
+                let size = frame - offset;
+                NodeKind::PureCall { offset, call: self.node(size), next: self.node(frame), args: size, results: size }
+            }
+            // loop counter is at another offset in other frames
+            13 => {
+                let targets: Vec<u32> = self.open.iter().copied().filter(|open| self.graph[*open as usize].0 == frame).collect();
+                let target = self.rng.pick(&targets).unwrap();
+                let decrement = Command::Sub { size: 4, dst: counter(frame), op1: counter(frame), op2: one(frame) };
+                let decrement = self.add(frame, NodeKind::Command { command: decrement, next: target });
+                NodeKind::Branch { condition: Condition::Ne0 { size: 4, op: counter(frame) }, if_true: decrement, if_false: self.node(frame) }
+            }
+            _ => { NodeKind::Final }
+        };
+        self.open.pop();
+        self.graph[id as usize].1 = kind;
+        self.pool.push((id, self.tries > 0));
+        id
+    }
+
+    fn add(&mut self, frame: u32, kind: NodeKind<u32>) -> u32 {
+        self.graph.push((frame, kind));
+        self.graph.len() as u32 - 1
+    }
+
+    // generated node of the same frame, so its loops use the same counter, it throws only in try
+    fn reused(&mut self, frame: u32) -> Option<u32> {
+        let fitting: Vec<u32> = self.pool.iter()
+            .filter(|(id, throws)| self.graph[*id as usize].0 == frame && (!throws || self.tries > 0))
+            .map(|(id, _)| *id)
+            .collect();
+        self.rng.pick(&fitting)
+    }
+
+    fn command(&mut self, frame: u32) -> Command {
+        let size = 4 * (1 + self.rng.below(2));
+        match self.rng.below(6) {
+            0 => {
+                let bytes = (0..size).map(|_| self.rng.below(3) as u8).collect();
+                Command::Set { dst: self.stack_ref(frame, size), bytes }
+            }
+            1 => { Command::Copy { size, dst: self.stack_ref(frame, size), op: self.stack_ref(frame, size) } }
+            2 => { Command::Add { size, dst: self.stack_ref(frame, size), op1: self.stack_ref(frame, size), op2: self.stack_ref(frame, size) } }
+            3 => { Command::Sub { size: 4, dst: self.stack_ref(frame, 4), op1: self.stack_ref(frame, 4), op2: self.stack_ref(frame, 4) } }
+            4 => {
+                let op = [RmwOp::Add, RmwOp::Sub, RmwOp::And, RmwOp::Or, RmwOp::Xor, RmwOp::Xchg][self.rng.below(6) as usize];
+                Command::AtomicRmw { size, op, dst: self.stack_ref(frame, size), mem: self.stack_ref(frame, size), value: self.stack_ref(frame, size) }
+            }
+            _ => {
+                Command::AtomicCmpXchg { size, mem: self.stack_ref(frame, size), expected: self.stack_ref(frame, size), replacement: self.stack_ref(frame, size) }
+            }
+        }
+    }
+
+    fn condition(&mut self, frame: u32) -> Condition {
+        if self.rng.below(2) == 0 {
+            Condition::Ne { size: 4, op1: self.stack_ref(frame, 4), op2: self.stack_ref(frame, 4) }
+        } else {
+            Condition::Ne0 { size: 4, op: self.stack_ref(frame, 4) }
+        }
+    }
+
+    // below loop counter
+    fn stack_ref(&mut self, frame: u32, size: u32) -> Ref { Ref::Stack(size * self.rng.below((frame - 8) / size)) }
+
+    // small values, so conditions go both ways
+    fn input(&mut self) -> Vec<u8> {
+        let mut input: Vec<u8> = (0..FUZZ_STACK_SIZE).map(|offset| if offset % 4 == 0 { self.rng.below(3) as u8 } else { 0 }).collect();
+        set_counter(&mut input);
+        input
+    }
+}
+
+// fresh loop counter and constant one
+fn set_counter(input: &mut [u8]) {
+    let end = FUZZ_STACK_SIZE as usize;
+    input[end - 8..].copy_from_slice(&[FUZZ_LOOPS, 0, 0, 0, 1, 0, 0, 0]);
+}
+
+// first engine (and inlining or memoization limit) which output differs from `interpreter::eval`, panics count as difference
+fn mismatch(graph: &Graph, input: &[u8]) -> Option<String> {
+    let node = FuzzNode::root(graph.clone());
+    let mut expected = input.to_vec();
+    eval(node.clone(), &mut expected);
+    [(None, None), (Some(8), None), (None, Some(8))].into_iter().find_map(|(inlining, memoization)| engines().into_iter().find_map(|(name, engine)| {
+        let mut actual = input.to_vec();
+        let finished = catch_unwind(AssertUnwindSafe(|| {
+            let mut driver = Driver::<FuzzNode, EngineBox>::new(engine);
+            driver.set_inlining(inlining);
+            driver.set_memoization(memoization);
+            driver.eval(node.clone(), &mut actual);
+            // second run takes results of pure calls from the first one
+            if memoization.is_some() && actual == expected {
+                actual = input.to_vec();
+                driver.eval(node.clone(), &mut actual);
+            }
+        }));
+        if finished.is_err() || actual != expected {
+            Some(format!("{} with inlining {:?}, memoization {:?}", name, inlining, memoization))
+        } else { None }
+    }))
+}
+
+// reachable part of the graph with `root` as node 0
+fn compact(graph: &Graph, root: u32) -> Graph {
+    let mut ids = HashMap::new();
+    let mut order = vec![];
+    let mut stack = vec![root];
+    while let Some(id) = stack.pop() {
+        if ids.contains_key(&id) { continue; }
+        ids.insert(id, order.len() as u32);
+        order.push(id);
+        map(graph[id as usize].1.clone(), |next| stack.push(next));
+    }
+    order.into_iter().map(|id| {
+        let (frame, kind) = &graph[id as usize];
+        (*frame, map(kind.clone(), |next| ids[&next]))
+    }).collect()
+}
+
+// whether loop counter is used by nodes reachable from `id`
+fn loops(graph: &Graph, id: u32) -> bool {
+    let reachable = compact(graph, id);
+    reachable.iter().any(|(frame, kind)| counts(*frame, kind))
+}
+
+// Variants of the graph with one node replaced by final or bypassed by its predecessors going to one of its successors.
+// Either makes the graph smaller. Frames nodes run in only grow this way, so variants stay within the stack.
+// Try isn't bypassed to its callee, so throws are still caught. Nodes checking and decrementing loop counter
+// are only replaced by final, and callees with loops aren't moved to another frame, so loops still use their counter
+// and terminate.
+fn shrink(graph: &Graph) -> Vec<Graph> {
+    let mut variants = vec![];
+    for (id, (frame, kind)) in graph.iter().enumerate() {
+        let id = id as u32;
+        if *kind != NodeKind::Final {
+            let mut variant = graph.clone();
+            variant[id as usize].1 = NodeKind::Final;
+            variants.push(compact(&variant, 0));
+        }
+        if counts(*frame, kind) { continue; }
+        let bypasses = match kind {
+            NodeKind::Command { next, .. } | NodeKind::Yield { next } | NodeKind::HostCall { next, .. } | NodeKind::Resume { next, .. } => { vec![*next] }
+            NodeKind::Branch { if_true, if_false, .. } => { vec![*if_true, *if_false] }
+            NodeKind::Call { call, next, .. } | NodeKind::PureCall { call, next, .. } => { vec![*call, *next] }
+            NodeKind::TailCall { call, .. } => { vec![*call] }
+            NodeKind::Try { next, catch, .. } => { vec![*next, *catch] }
+            NodeKind::Coroutine { next, .. } => { vec![*next] }
+            NodeKind::Throw { .. } | NodeKind::Final => { vec![] }
+        };
+        let moves = |bypass: u32| graph[bypass as usize].0 != *frame && loops(graph, bypass);
+        for bypass in bypasses.into_iter().filter(|bypass| *bypass != id && !moves(*bypass)) {
+            let variant: Graph = graph.iter().map(|(frame, kind)| (*frame, map(kind.clone(), |next| if next == id { bypass } else { next }))).collect();
+            variants.push(compact(&variant, if id == 0 { bypass } else { 0 }));
+        }
+    }
+    variants
+}
+
+// smallest graph reachable by `shrink` which is still `failing`
+fn minimise<F: Fn(&Graph) -> bool>(mut current: Graph, failing: F) -> Graph {
+    while let Some(smaller) = shrink(&current).into_iter().find(|variant| failing(variant)) {
+        current = smaller;
+    }
+    current
+}
+
+#[test]
+fn test_minimise() {
+    let sub = Command::Sub { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(4), op2: Ref::Stack(8) };
+    let has_sub = |graph: &Graph| graph.iter().any(|(_, kind)| matches!(kind, NodeKind::Command { command, .. } if *command == sub));
+    let graph = vec![
+        (64, NodeKind::Branch { condition: Condition::Ne0 { size: 4, op: Ref::Stack(0) }, if_true: 1, if_false: 3 }),
+        (64, NodeKind::Command { command: Command::Noop, next: 2 }),
+        (64, NodeKind::Final),
+        (64, NodeKind::Call { offset: 8, call: 4, next: 2 }),
+        (56, NodeKind::Command { command: sub.clone(), next: 5 }),
+        (56, NodeKind::Command { command: Command::Noop, next: 2 }),
+    ];
+    let minimised: Vec<NodeKind<u32>> = minimise(graph, has_sub).into_iter().map(|(_, kind)| kind).collect();
+    assert_eq!(vec![NodeKind::Command { command: sub.clone(), next: 1 }, NodeKind::Final], minimised);
+}
+
+#[test]
+fn test_loops_terminate() {
+    // decrement loops back to the branch checking the counter, callee has a loop of its own on the same counter
+    let frame = FUZZ_STACK_SIZE;
+    let graph = vec![
+        (frame, NodeKind::Branch { condition: Condition::Ne0 { size: 4, op: counter(frame) }, if_true: 1, if_false: 3 }),
+        (frame, NodeKind::Command { command: Command::Sub { size: 4, dst: counter(frame), op1: counter(frame), op2: one(frame) }, next: 2 }),
+        (frame, NodeKind::Command { command: Command::Add { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(4) }, next: 0 }),
+        (frame, NodeKind::Call { offset: 8, call: 4, next: 7 }),
+        (frame - 8, NodeKind::Branch { condition: Condition::Ne0 { size: 4, op: counter(frame - 8) }, if_true: 5, if_false: 7 }),
+        (frame - 8, NodeKind::Command { command: Command::Sub { size: 4, dst: counter(frame - 8), op1: counter(frame - 8), op2: one(frame - 8) }, next: 6 }),
+        (frame - 8, NodeKind::Command { command: Command::Add { size: 4, dst: Ref::Stack(0), op1: Ref::Stack(0), op2: Ref::Stack(0) }, next: 4 }),
+        (frame, NodeKind::Final),
+    ];
+    let mut input = vec![0u8; FUZZ_STACK_SIZE as usize];
+    input[4] = 3;
+    set_counter(&mut input);
+    let mut stack = input.clone();
+    eval(FuzzNode::root(graph.clone()), &mut stack);
+    assert_eq!((3 * FUZZ_LOOPS, 0), (stack[0], stack[FUZZ_STACK_SIZE as usize - 8]));
+    assert_eq!(None, mismatch(&graph, &input));
+}
+
+#[test]
+fn test_generated_graphs() {
+    let graphs: Vec<Graph> = (0..200).map(|seed| Generator::new(seed, 30).graph(FUZZ_STACK_SIZE)).collect();
+    let shared = graphs.iter().filter(|graph| {
+        let mut references = vec![0; graph.len()];
+        graph.iter().for_each(|(_, kind)| { map(kind.clone(), |next| references[next as usize] += 1); });
+        references.iter().any(|count| *count > 1)
+    });
+    let looping = graphs.iter().filter(|graph| graph.iter().any(|(frame, kind)| counts(*frame, kind)));
+    assert!(shared.count() > 50);
+    assert!(looping.count() > 50);
+}
+
+#[test]
+fn test_fuzz_engines() {
+    for seed in 0..200 {
+        let mut generator = Generator::new(seed, 30);
+        let graph = generator.graph(FUZZ_STACK_SIZE);
+        let input = generator.input();
+        if let Some(name) = mismatch(&graph, &input) {
+            let reproducer = minimise(graph, |graph| mismatch(graph, &input).is_some());
+            panic!("{} differs from interpreter on seed {}, reproducer {:?} on {:?}", name, seed, reproducer, input);
+        }
+    }
+}
+
+// first number of known input bytes for which specialized graph computes differently from the original
+fn partial_eval_mismatch(graph: &Graph, input: &[u8]) -> Option<u32> {
+    let node = FuzzNode::root(graph.clone());
+    let mut expected = input.to_vec();
+    eval(node.clone(), &mut expected);
+    [0, 16, FUZZ_STACK_SIZE].into_iter().find(|known| {
+        let mut actual = input.to_vec();
+        let finished = catch_unwind(AssertUnwindSafe(|| {
+            let known = Known::new().with(0, &input[..*known as usize]);
+            eval(PartialEvaluator::new(1000).specialize(node.clone(), known), &mut actual);
+        }));
+        finished.is_err() || actual != expected
+    })
+}
+
+#[test]
+fn test_fuzz_partial_eval() {
+    for seed in 0..200 {
+        let mut generator = Generator::new(seed, 30);
+        let graph = generator.graph(FUZZ_STACK_SIZE);
+        let input = generator.input();
+        if let Some(known) = partial_eval_mismatch(&graph, &input) {
+            let reproducer = minimise(graph, |graph| partial_eval_mismatch(graph, &input).is_some());
+            panic!("partial evaluation with {} known bytes differs on seed {}, reproducer {:?} on {:?}", known, seed, reproducer, input);
+        }
+    }
+}
@@ -1,63 +1,109 @@
 use crate::core::api::NodeKind;
 use crate::core::driver::driver::{Frame, RunState, NodeId, Engine};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::core::driver::fuel::Fuel;
+use crate::core::driver::observer::Observer;
+use crate::core::driver::profile::{NodeProfile, Profile};
+use crate::core::driver::stats::{EngineStats, Stats};
+use crate::core::globals::Globals;
 use crate::core::interpreter::{eval_command, eval_condition};
 
 pub struct InterpreterEngine {
     computed: Vec<Option<NodeKind<NodeId>>>,
-    debug: bool,
+    profile: Option<Profile>,
+    observer: Option<Arc<dyn Observer>>,
+    fuel: Fuel,
+    stats: Stats,
 }
 
 impl InterpreterEngine {
-    pub fn new(debug: bool) -> InterpreterEngine { InterpreterEngine { computed: Vec::new(), debug } }
+    pub fn new() -> InterpreterEngine { InterpreterEngine { computed: Vec::new(), profile: None, observer: None, fuel: Fuel::new(), stats: Stats::new() } }
+
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.observer = observer; }
+
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling { Some(Profile::new()) } else { None };
+    }
 
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) {
+        let start = Instant::now();
         while self.computed.len() <= id.0 as usize {
             self.computed.push(None);
         }
+        if let Some(profile) = &mut self.profile {
+            profile.register(id, matches!(kind, NodeKind::Branch { .. }));
+        }
         *self.computed.get_mut(id.0 as usize).unwrap() = Some(kind);
+        self.stats.registered(start);
+    }
+
+    fn unregister(&mut self, id: NodeId) {
+        if let Some(kind) = self.computed.get_mut(id.0 as usize) {
+            *kind = None;
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.unregister(id);
+        }
     }
 
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool {
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool {
         let mut offset: usize = state.offset();
         while !state.frames.is_empty() {
             let current = state.frames.pop().unwrap();
 
             match self.get(current.id) {
                 None => {
-                    state.frames.push(current);
+                    self.suspend(state, current);
+                    return true;
+                }
+                Some(_) if !self.fuel.charge() => {
+                    self.suspend(state, current);
                     return true;
                 }
                 Some(kind) => {
-                    if self.debug {
-                        println!("run {}", current.id.0);
-                        let count = (offset + 24) / 4;
-                        (0..count).for_each(|num| unsafe {
-                            let val = u32::from_le_bytes(stack[num * 4 .. num * 4 + 4].try_into().unwrap());
-                            print!("{} ", val);
-                        });
-                        println!()
-                    }
+                    let counters = self.profile.as_ref().and_then(|profile| profile.counters(current.id));
+                    if let Some(counters) = counters { counters.executed() }
+                    if let Some(observer) = &self.observer { observer.on_node(current.id, &stack[offset..]) }
                     match kind {
                         NodeKind::Command { command, next } => {
-                            eval_command(command, &mut stack[offset..]);
+                            eval_command(command, &mut stack[offset..], globals);
+                            if let Some(observer) = &self.observer { observer.on_command(current.id, &stack[offset..]) }
                             state.frames.push(Frame { id: *next, offset: current.offset } );
                         }
                         NodeKind::Branch { condition, if_true, if_false } => {
-                            if eval_condition(condition, &mut stack[offset..]) {
+                            let taken = eval_condition(condition, &mut stack[offset..], globals);
+                            if let Some(observer) = &self.observer { observer.on_branch(current.id, taken, &stack[offset..]) }
+                            if taken {
+                                if let Some(counters) = counters { counters.taken() }
                                 state.frames.push(Frame { id: *if_true, offset: current.offset });
                             } else {
                                 state.frames.push(Frame { id: *if_false, offset: current.offset });
                             }
                         }
                         NodeKind::Call { offset: call_offset, call, next } => {
+                            if let Some(observer) = &self.observer { observer.on_call(current.id, *call, *call_offset, &stack[offset..]) }
                             state.frames.push(Frame { id: *next, offset: current.offset });
                             state.frames.push(Frame { id: *call, offset: *call_offset as usize });
                             offset += *call_offset as usize;
                         }
+                        NodeKind::TailCall { offset: call_offset, call } => {
+                            if let Some(observer) = &self.observer { observer.on_call(current.id, *call, *call_offset, &stack[offset..]) }
+                            state.frames.push(Frame { id: *call, offset: current.offset + *call_offset as usize });
+                            offset += *call_offset as usize;
+                        }
                         NodeKind::Final => {
+                            if let Some(observer) = &self.observer { observer.on_return(current.id, &stack[offset..]) }
                             offset -= current.offset;
                             continue;
                         }
+                        // driver continues past it
+                        NodeKind::Yield { .. } | NodeKind::HostCall { .. } | NodeKind::Coroutine { .. } | NodeKind::Resume { .. }
+                            | NodeKind::Throw { .. } | NodeKind::PureCall { .. } => {
+                            self.suspend(state, current);
+                            return true;
+                        }
+                        NodeKind::Try { .. } => { unreachable!("driver registers try as call") }
                     }
                 }
             }
@@ -66,6 +112,16 @@ impl InterpreterEngine {
         false
     }
 
+    fn suspend(&self, state: &mut RunState, frame: Frame) {
+        if let Some(observer) = &self.observer { observer.on_suspend(frame.id) }
+        state.frames.push(frame);
+        self.stats.suspended();
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats { registered: self.computed.iter().filter(|kind| kind.is_some()).count() as u64, ..self.stats.get() }
+    }
+
     fn get(&self, id: NodeId) -> Option<&NodeKind<NodeId>> {
         match self.computed.get(id.0 as usize) {
             None => { None }
@@ -74,12 +130,23 @@ impl InterpreterEngine {
     }
 }
 
+impl Default for InterpreterEngine {
+    fn default() -> InterpreterEngine { InterpreterEngine::new() }
+}
+
 impl Engine for InterpreterEngine {
     fn register(&mut self, id: NodeId, kind: NodeKind<NodeId>) { self.register(id, kind) }
-    fn run(&self, state: &mut RunState, stack: &mut [u8]) -> bool { self.run(state, stack) }
+    fn unregister(&mut self, id: NodeId) { self.unregister(id) }
+    fn run(&self, state: &mut RunState, stack: &mut [u8], globals: &Globals) -> bool { self.run(state, stack, globals) }
+    fn set_profiling(&mut self, profiling: bool) { self.set_profiling(profiling) }
+    fn profile(&self, id: NodeId) -> Option<NodeProfile> { self.profile.as_ref()?.get(id) }
+    fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) { self.set_observer(observer) }
+    fn set_fuel(&mut self, fuel: Option<u64>) { self.fuel.set(fuel) }
+    fn fuel(&self) -> Option<u64> { self.fuel.get() }
+    fn stats(&self) -> EngineStats { self.stats() }
 }
 
 #[test]
 fn test_sizes() {
-    assert_eq!(40, std::mem::size_of::<Option<NodeKind<NodeId>>>());
+    assert_eq!(48, std::mem::size_of::<Option<NodeKind<NodeId>>>());
 }
\ No newline at end of file
@@ -1,5 +1,13 @@
 pub mod driver;
+pub mod shared_driver;
+pub mod execution;
+pub mod snapshot;
+pub mod profile;
+pub mod observer;
+pub mod fuel;
+pub mod stats;
 pub mod code_generator_engine;
 pub mod interpreter_engine;
 pub mod aarch64;
 mod driver_tests;
+mod fuzz_tests;
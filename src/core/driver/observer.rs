@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use crate::core::driver::driver::NodeId;
+
+// Hooks engines call while running nodes, see `Driver::set_observer`.
+// `stack` is data stack starting from current frame, i.e. the same slice node commands address with `Ref::Stack`.
+// Engines might keep values in registers between nodes, so hooks are called only at points where stack is up to date.
+pub trait Observer: Send + Sync {
+    fn on_node(&self, _id: NodeId, _stack: &[u8]) {}
+
+    // called after command of `id` is executed
+    fn on_command(&self, _id: NodeId, _stack: &[u8]) {}
+
+    fn on_branch(&self, _id: NodeId, _taken: bool, _stack: &[u8]) {}
+
+    // called before `call` is entered, `stack` is frame of the caller
+    fn on_call(&self, _id: NodeId, _call: NodeId, _offset: u32, _stack: &[u8]) {}
+
+    // final node `id` is reached, current frame is finished
+    fn on_return(&self, _id: NodeId, _stack: &[u8]) {}
+
+    // execution is suspended before node `id`: it's unknown, it's handled by driver or fuel ran out,
+    // it's going to be entered again once execution continues
+    fn on_suspend(&self, _id: NodeId) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Node(NodeId),
+    Command(NodeId),
+    Branch(NodeId, bool),
+    Call(NodeId, NodeId),
+    Return(NodeId),
+    Suspend(NodeId),
+}
+
+// Records events without stack contents.
+#[derive(Default)]
+pub struct Tracer {
+    pub events: Mutex<Vec<Event>>,
+}
+
+impl Tracer {
+    pub fn new() -> Tracer { Tracer::default() }
+
+    pub fn take(&self) -> Vec<Event> { std::mem::take(&mut self.events.lock().unwrap()) }
+
+    fn push(&self, event: Event) { self.events.lock().unwrap().push(event) }
+}
+
+impl Observer for Tracer {
+    fn on_node(&self, id: NodeId, _stack: &[u8]) { self.push(Event::Node(id)) }
+    fn on_command(&self, id: NodeId, _stack: &[u8]) { self.push(Event::Command(id)) }
+    fn on_branch(&self, id: NodeId, taken: bool, _stack: &[u8]) { self.push(Event::Branch(id, taken)) }
+    fn on_call(&self, id: NodeId, call: NodeId, _offset: u32, _stack: &[u8]) { self.push(Event::Call(id, call)) }
+    fn on_return(&self, id: NodeId, _stack: &[u8]) { self.push(Event::Return(id)) }
+    fn on_suspend(&self, id: NodeId) { self.push(Event::Suspend(id)) }
+}
+
+// Prints every node entry with first `words` u32 values of the frame, replacement of former engine debug output.
+pub struct Printer {
+    pub words: usize,
+}
+
+impl Observer for Printer {
+    fn on_node(&self, id: NodeId, stack: &[u8]) {
+        let words: Vec<String> = stack.chunks_exact(4).take(self.words)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()).to_string())
+            .collect();
+        println!("run {} {}", id.0, words.join(" "));
+    }
+}
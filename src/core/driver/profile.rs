@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::core::driver::driver::NodeId;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NodeProfile {
+    pub executions: u64,
+    // for branches only
+    pub taken: u64,
+    pub not_taken: u64,
+    // number of times node was called by `Call` nodes
+    pub calls: u64,
+}
+
+impl NodeProfile {
+    pub fn add(&mut self, other: &NodeProfile) {
+        self.executions += other.executions;
+        self.taken += other.taken;
+        self.not_taken += other.not_taken;
+        self.calls += other.calls;
+    }
+}
+
+// Counters of single node, generated code increments them by address so layout is fixed.
+// Increments are relaxed (non atomic at all in generated code), so counts are approximate under concurrent runs.
+#[repr(C)]
+#[derive(Default)]
+pub struct Counters {
+    pub executions: AtomicU64,
+    pub taken: AtomicU64,
+}
+
+impl Counters {
+    pub fn executed(&self) { self.executions.fetch_add(1, Ordering::Relaxed); }
+
+    pub fn taken(&self) { self.taken.fetch_add(1, Ordering::Relaxed); }
+}
+
+// Counters of registered nodes of an engine, indexed by `NodeId`.
+// Counters are boxed so their addresses don't change while node is registered.
+#[derive(Default)]
+pub struct Profile {
+    counters: Vec<Option<(Box<Counters>, bool)>>,
+}
+
+impl Profile {
+    pub fn new() -> Profile { Profile::default() }
+
+    // starts counting from zero, `branch` tells if `taken` is meaningful
+    pub fn register(&mut self, id: NodeId, branch: bool) -> &Counters {
+        while self.counters.len() <= id.0 as usize {
+            self.counters.push(None);
+        }
+        let counters = self.counters.get_mut(id.0 as usize).unwrap();
+        *counters = Some((Box::default(), branch));
+        &counters.as_ref().unwrap().0
+    }
+
+    pub fn unregister(&mut self, id: NodeId) {
+        if let Some(counters) = self.counters.get_mut(id.0 as usize) {
+            *counters = None;
+        }
+    }
+
+    pub fn counters(&self, id: NodeId) -> Option<&Counters> {
+        self.counters.get(id.0 as usize)?.as_ref().map(|(counters, _)| counters.as_ref())
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<NodeProfile> {
+        let (counters, branch) = self.counters.get(id.0 as usize)?.as_ref()?;
+        let executions = counters.executions.load(Ordering::Relaxed);
+        let taken = counters.taken.load(Ordering::Relaxed);
+        Some(if *branch {
+            NodeProfile { executions, taken, not_taken: executions - taken, calls: 0 }
+        } else {
+            NodeProfile { executions, ..NodeProfile::default() }
+        })
+    }
+}
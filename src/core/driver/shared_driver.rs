@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use crate::core::api::Node;
+use crate::core::globals::Globals;
+use crate::core::driver::driver::{Driver, Engine, NodeId, Outcome, RunState, Stop};
+use crate::core::driver::profile::NodeProfile;
+use crate::core::driver::stats::EngineStats;
+
+// Driver which can be used from several threads at once, sharing node table and everything engine compiled.
+// Each `eval` has its own `RunState` and stack, engine runs under read lock so threads execute concurrently,
+// registration of new nodes takes write lock and waits for running threads to suspend.
+pub struct SharedDriver<N: Node, E: Engine> {
+    driver: RwLock<Driver<N, E>>,
+    // number of evals and resumes in progress, nodes are evicted only when there is no other one running.
+    // Runs returned unfinished aren't counted, their frames are pinned instead.
+    running: AtomicUsize,
+}
+
+impl<N: Node + Send + Sync, E: Engine + Send + Sync> SharedDriver<N, E> {
+    pub fn new(engine: E) -> SharedDriver<N, E> {
+        SharedDriver { driver: RwLock::new(Driver::new(engine)), running: AtomicUsize::new(0) }
+    }
+
+    // nodes are evicted right away if there is no running eval, otherwise once the last one finishes
+    pub fn set_budget(&self, budget: Option<usize>) {
+        let mut driver = self.driver.write().unwrap();
+        if self.running.load(Ordering::SeqCst) == 0 {
+            driver.set_budget(budget)
+        } else {
+            driver.set_budget_deferred(budget)
+        }
+    }
+
+    pub fn set_profiling(&self, profiling: bool) { self.driver.write().unwrap().set_profiling(profiling) }
+
+    pub fn profile(&self) -> HashMap<NodeId, NodeProfile> { self.driver.read().unwrap().profile() }
+
+    // see `Driver::set_globals`
+    pub fn set_globals(&self, size: usize) { self.driver.write().unwrap().set_globals(size) }
+
+    pub fn globals(&self) -> Arc<Globals> { self.driver.read().unwrap().globals().clone() }
+
+    pub fn stats(&self) -> EngineStats { self.driver.read().unwrap().stats() }
+
+    // fuel is shared by all threads
+    pub fn set_fuel(&self, fuel: Option<u64>) { self.driver.write().unwrap().set_fuel(fuel) }
+
+    pub fn eval(&self, node: N, stack: &mut [u8]) -> Outcome {
+        let running = Running::new(&self.running);
+        let ctx = self.driver.write().unwrap().start(node);
+        self.run(ctx, stack, running)
+    }
+
+    // Continues run which was out of fuel, yielded or made host call.
+    pub fn resume(&self, ctx: RunState, stack: &mut [u8]) -> Outcome { self.run(ctx, stack, Running::new(&self.running)) }
+
+    // Drops run which was out of fuel, yielded or made host call, see `Driver::cancel`.
+    pub fn cancel(&self, ctx: RunState) { self.driver.write().unwrap().cancel(ctx) }
+
+    fn run(&self, mut ctx: RunState, stack: &mut [u8], running: Running) -> Outcome {
+        // frames run started with are pinned, see `Driver::start`
+        let pinned = ctx.clone();
+        let stop = self.run_inner(&mut ctx, stack);
+        ctx.drop_coroutines_if_finished();
+        let mut driver = self.driver.write().unwrap();
+        driver.pin(&ctx);
+        driver.unpin(&pinned);
+        match stop {
+            Stop::OutOfFuel => { Outcome::OutOfFuel(ctx) }
+            Stop::Yield => { Outcome::Yielded(ctx) }
+            Stop::HostCall { function, offset } => { Outcome::HostCall { ctx, function, offset } }
+            stop => {
+                // other evals might refer to nodes which would be evicted
+                drop(running);
+                if self.running.load(Ordering::SeqCst) == 0 {
+                    driver.collect();
+                }
+                match stop {
+                    Stop::Uncaught(payload) => { Outcome::Uncaught(payload) }
+                    Stop::Fault(fault) => { Outcome::Fault(fault) }
+                    _ => { Outcome::Finished }
+                }
+            }
+        }
+    }
+
+    fn run_inner(&self, ctx: &mut RunState, stack: &mut [u8]) -> Stop {
+        while !ctx.frames.is_empty() {
+            let driver = self.driver.read().unwrap();
+            if driver.engine().run(ctx, stack, driver.globals()) {
+                if driver.out_of_fuel(ctx) {
+                    return Stop::OutOfFuel;
+                }
+                drop(driver);
+                if let Some(stop) = self.driver.write().unwrap().continue_suspended(ctx, stack) {
+                    return stop;
+                }
+            }
+        }
+        Stop::Finished
+    }
+}
+
+// Counts eval or resume in progress until it returns, whether run is finished or not.
+struct Running<'a>(&'a AtomicUsize);
+
+impl<'a> Running<'a> {
+    fn new(running: &'a AtomicUsize) -> Running<'a> {
+        running.fetch_add(1, Ordering::SeqCst);
+        Running(running)
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::SeqCst); }
+}
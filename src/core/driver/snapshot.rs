@@ -0,0 +1,302 @@
+use std::io;
+use crate::core::api::{Command, Condition, NodeKind, Ref, RmwOp, VecOp, VecShape};
+use crate::core::driver::driver::NodeId;
+
+// Binary encoding of driver and engine state persisted between processes.
+// Numbers are leb128 encoded, byte strings and lists are prefixed with their length.
+
+pub const MAGIC: &[u8] = b"leshy-snapshot-9";
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    pub bytes: Vec<u8>,
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+}
+
+fn invalid(message: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, message) }
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter { SnapshotWriter::default() }
+
+    pub fn u8(&mut self, value: u8) { self.bytes.push(value) }
+
+    pub fn u64(&mut self, value: u64) { leb128::write::unsigned(&mut self.bytes, value).unwrap(); }
+
+    pub fn u32(&mut self, value: u32) { self.u64(value as u64) }
+
+    pub fn usize(&mut self, value: usize) { self.u64(value as u64) }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn id(&mut self, id: NodeId) { self.u32(id.0) }
+
+    pub fn kind<K, F: Fn(&mut SnapshotWriter, &K)>(&mut self, kind: &NodeKind<K>, node: F) {
+        match kind {
+            NodeKind::Command { command, next } => {
+                self.u8(0);
+                self.command(command);
+                node(self, next);
+            }
+            NodeKind::Branch { condition, if_true, if_false } => {
+                self.u8(1);
+                self.condition(condition);
+                node(self, if_true);
+                node(self, if_false);
+            }
+            NodeKind::Call { offset, call, next } => {
+                self.u8(2);
+                self.u32(*offset);
+                node(self, call);
+                node(self, next);
+            }
+            NodeKind::Final => { self.u8(3) }
+            NodeKind::Yield { next } => {
+                self.u8(5);
+                node(self, next);
+            }
+            NodeKind::Coroutine { offset, call, handle, next } => {
+                self.u8(6);
+                self.u32(*offset);
+                node(self, call);
+                self.r#ref(*handle);
+                node(self, next);
+            }
+            NodeKind::Resume { handle, next } => {
+                self.u8(7);
+                self.r#ref(*handle);
+                node(self, next);
+            }
+            NodeKind::HostCall { function, offset, next } => {
+                self.u8(4);
+                self.u32(*function);
+                self.u32(*offset);
+                node(self, next);
+            }
+            NodeKind::TailCall { offset, call } => {
+                self.u8(8);
+                self.u32(*offset);
+                node(self, call);
+            }
+            NodeKind::Try { offset, call, next, catch } => {
+                self.u8(9);
+                self.u32(*offset);
+                node(self, call);
+                node(self, next);
+                node(self, catch);
+            }
+            NodeKind::Throw { op, size } => {
+                self.u8(10);
+                self.r#ref(*op);
+                self.u32(*size);
+            }
+            NodeKind::PureCall { offset, call, next, args, results } => {
+                self.u8(11);
+                self.u32(*offset);
+                node(self, call);
+                node(self, next);
+                self.u32(*args);
+                self.u32(*results);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &Command) {
+        match command {
+            Command::Noop => { self.u8(0) }
+            Command::PoisonFrom { dst } => { self.u8(1); self.r#ref(*dst) }
+            Command::Set { dst, bytes } => { self.u8(2); self.r#ref(*dst); self.bytes(bytes) }
+            Command::Copy { dst, size, op } => { self.u8(3); self.r#ref(*dst); self.u32(*size); self.r#ref(*op) }
+            Command::Add { size, dst, op1, op2 } => { self.u8(4); self.binary(*size, *dst, *op1, *op2) }
+            Command::Sub { size, dst, op1, op2 } => { self.u8(5); self.binary(*size, *dst, *op1, *op2) }
+            Command::AtomicLoad { size, dst, mem } => { self.u8(6); self.u32(*size); self.r#ref(*dst); self.r#ref(*mem) }
+            Command::AtomicStore { size, mem, op } => { self.u8(7); self.u32(*size); self.r#ref(*mem); self.r#ref(*op) }
+            Command::AtomicRmw { size, op, dst, mem, value } => {
+                self.u8(8);
+                self.u8(*op as u8);
+                self.u32(*size);
+                self.r#ref(*dst);
+                self.r#ref(*mem);
+                self.r#ref(*value);
+            }
+            Command::AtomicCmpXchg { size, mem, expected, replacement } => {
+                self.u8(9);
+                self.u32(*size);
+                self.r#ref(*mem);
+                self.r#ref(*expected);
+                self.r#ref(*replacement);
+            }
+            Command::Fence => { self.u8(10) }
+            Command::VecBinary { op, shape, dst, op1, op2 } => {
+                self.u8(11);
+                self.u8(*op as u8);
+                self.u8(*shape as u8);
+                self.r#ref(*dst);
+                self.r#ref(*op1);
+                self.r#ref(*op2);
+            }
+            Command::VecSplat { shape, dst, op } => { self.u8(12); self.u8(*shape as u8); self.r#ref(*dst); self.r#ref(*op) }
+            Command::VecExtractLane { shape, signed, lane, dst, op } => {
+                self.u8(13);
+                self.u8(*shape as u8);
+                self.u8(*signed as u8);
+                self.u8(*lane);
+                self.r#ref(*dst);
+                self.r#ref(*op);
+            }
+            Command::VecReplaceLane { shape, lane, dst, op, value } => {
+                self.u8(14);
+                self.u8(*shape as u8);
+                self.u8(*lane);
+                self.r#ref(*dst);
+                self.r#ref(*op);
+                self.r#ref(*value);
+            }
+            Command::VecShuffle { lanes, dst, op1, op2 } => { self.u8(15); self.bytes(lanes); self.r#ref(*dst); self.r#ref(*op1); self.r#ref(*op2) }
+        }
+    }
+
+    fn binary(&mut self, size: u32, dst: Ref, op1: Ref, op2: Ref) {
+        self.u32(size);
+        self.r#ref(dst);
+        self.r#ref(op1);
+        self.r#ref(op2);
+    }
+
+    fn condition(&mut self, condition: &Condition) {
+        match condition {
+            Condition::Ne { size, op1, op2 } => { self.u8(0); self.u32(*size); self.r#ref(*op1); self.r#ref(*op2) }
+            Condition::Ne0 { size, op } => { self.u8(1); self.u32(*size); self.r#ref(*op) }
+        }
+    }
+
+    fn r#ref(&mut self, r: Ref) {
+        match r {
+            Ref::Stack(offset) => { self.u8(0); self.u32(offset) }
+            Ref::Global(offset) => { self.u8(1); self.u32(offset) }
+        }
+    }
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> SnapshotReader<'a> { SnapshotReader { bytes } }
+
+    pub fn is_empty(&self) -> bool { self.bytes.is_empty() }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        let (first, rest) = self.bytes.split_first().ok_or_else(|| invalid("unexpected end of snapshot"))?;
+        self.bytes = rest;
+        Ok(*first)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        leb128::read::unsigned(&mut self.bytes).map_err(|_| invalid("malformed number"))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        u32::try_from(self.u64()?).map_err(|_| invalid("number out of range"))
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("number out of range"))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.usize()?;
+        if len > self.bytes.len() { return Err(invalid("unexpected end of snapshot")); }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn id(&mut self) -> io::Result<NodeId> { Ok(NodeId(self.u32()?)) }
+
+    // id of node table with `len` entries
+    pub fn id_below(&mut self, len: usize) -> io::Result<NodeId> {
+        let id = self.id()?;
+        if id.0 as usize >= len { return Err(invalid("node id out of range")); }
+        Ok(id)
+    }
+
+    pub fn kind<K, F: Fn(&mut SnapshotReader<'a>) -> io::Result<K>>(&mut self, node: F) -> io::Result<NodeKind<K>> {
+        Ok(match self.u8()? {
+            0 => { NodeKind::Command { command: self.command()?, next: node(self)? } }
+            1 => { NodeKind::Branch { condition: self.condition()?, if_true: node(self)?, if_false: node(self)? } }
+            2 => { NodeKind::Call { offset: self.u32()?, call: node(self)?, next: node(self)? } }
+            3 => { NodeKind::Final }
+            4 => { NodeKind::HostCall { function: self.u32()?, offset: self.u32()?, next: node(self)? } }
+            5 => { NodeKind::Yield { next: node(self)? } }
+            6 => { NodeKind::Coroutine { offset: self.u32()?, call: node(self)?, handle: self.r#ref()?, next: node(self)? } }
+            7 => { NodeKind::Resume { handle: self.r#ref()?, next: node(self)? } }
+            8 => { NodeKind::TailCall { offset: self.u32()?, call: node(self)? } }
+            9 => { NodeKind::Try { offset: self.u32()?, call: node(self)?, next: node(self)?, catch: node(self)? } }
+            10 => { NodeKind::Throw { op: self.r#ref()?, size: self.u32()? } }
+            11 => { NodeKind::PureCall { offset: self.u32()?, call: node(self)?, next: node(self)?, args: self.u32()?, results: self.u32()? } }
+            _ => { return Err(invalid("unknown node kind")) }
+        })
+    }
+
+    fn command(&mut self) -> io::Result<Command> {
+        Ok(match self.u8()? {
+            0 => { Command::Noop }
+            1 => { Command::PoisonFrom { dst: self.r#ref()? } }
+            2 => { Command::Set { dst: self.r#ref()?, bytes: self.bytes()?.to_vec() } }
+            3 => { Command::Copy { dst: self.r#ref()?, size: self.u32()?, op: self.r#ref()? } }
+            4 => { Command::Add { size: self.u32()?, dst: self.r#ref()?, op1: self.r#ref()?, op2: self.r#ref()? } }
+            5 => { Command::Sub { size: self.u32()?, dst: self.r#ref()?, op1: self.r#ref()?, op2: self.r#ref()? } }
+            6 => { Command::AtomicLoad { size: self.u32()?, dst: self.r#ref()?, mem: self.r#ref()? } }
+            7 => { Command::AtomicStore { size: self.u32()?, mem: self.r#ref()?, op: self.r#ref()? } }
+            8 => {
+                let op = match self.u8()? {
+                    0 => { RmwOp::Add }
+                    1 => { RmwOp::Sub }
+                    2 => { RmwOp::And }
+                    3 => { RmwOp::Or }
+                    4 => { RmwOp::Xor }
+                    5 => { RmwOp::Xchg }
+                    _ => { return Err(invalid("unknown read-modify-write operation")) }
+                };
+                Command::AtomicRmw { op, size: self.u32()?, dst: self.r#ref()?, mem: self.r#ref()?, value: self.r#ref()? }
+            }
+            9 => { Command::AtomicCmpXchg { size: self.u32()?, mem: self.r#ref()?, expected: self.r#ref()?, replacement: self.r#ref()? } }
+            10 => { Command::Fence }
+            11 => {
+                let op = *VecOp::ALL.get(self.u8()? as usize).ok_or_else(|| invalid("unknown vector operation"))?;
+                Command::VecBinary { op, shape: self.vec_shape()?, dst: self.r#ref()?, op1: self.r#ref()?, op2: self.r#ref()? }
+            }
+            12 => { Command::VecSplat { shape: self.vec_shape()?, dst: self.r#ref()?, op: self.r#ref()? } }
+            13 => { Command::VecExtractLane { shape: self.vec_shape()?, signed: self.u8()? != 0, lane: self.u8()?, dst: self.r#ref()?, op: self.r#ref()? } }
+            14 => { Command::VecReplaceLane { shape: self.vec_shape()?, lane: self.u8()?, dst: self.r#ref()?, op: self.r#ref()?, value: self.r#ref()? } }
+            15 => {
+                let lanes = self.bytes()?.try_into().map_err(|_| invalid("shuffle should have 16 lanes"))?;
+                Command::VecShuffle { lanes, dst: self.r#ref()?, op1: self.r#ref()?, op2: self.r#ref()? }
+            }
+            _ => { return Err(invalid("unknown command")) }
+        })
+    }
+
+    fn vec_shape(&mut self) -> io::Result<VecShape> {
+        VecShape::ALL.get(self.u8()? as usize).copied().ok_or_else(|| invalid("unknown vector shape"))
+    }
+
+    fn condition(&mut self) -> io::Result<Condition> {
+        Ok(match self.u8()? {
+            0 => { Condition::Ne { size: self.u32()?, op1: self.r#ref()?, op2: self.r#ref()? } }
+            1 => { Condition::Ne0 { size: self.u32()?, op: self.r#ref()? } }
+            _ => { return Err(invalid("unknown condition")) }
+        })
+    }
+
+    fn r#ref(&mut self) -> io::Result<Ref> {
+        Ok(match self.u8()? {
+            0 => { Ref::Stack(self.u32()?) }
+            1 => { Ref::Global(self.u32()?) }
+            _ => { return Err(invalid("unknown ref")) }
+        })
+    }
+}
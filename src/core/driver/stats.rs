@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Snapshot of engine health counters, fields engine doesn't track stay zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStats {
+    // nodes currently registered
+    pub registered: u64,
+    // registered nodes by representation, for engines with compact node encoding
+    pub compact: u64,
+    pub full: u64,
+    // generated code, counted since engine creation including code of unregistered nodes
+    pub code_bytes: u64,
+    // memory allocated for generated code at the moment
+    pub code_memory: u64,
+    // rewrites of already generated code, i.e. returns replaced with jumps and back
+    pub patches: u64,
+    // runs which returned before finishing: unknown node, yield, throw or out of fuel
+    pub suspensions: u64,
+    // total time spent in `register` and `register_batch`
+    pub register_time: Duration,
+}
+
+// Counters shared by all engines, `run` takes `&self` so suspensions are atomic.
+#[derive(Default)]
+pub struct Stats {
+    suspensions: AtomicU64,
+    register_time: Duration,
+}
+
+impl Stats {
+    pub fn new() -> Stats { Stats::default() }
+
+    pub fn suspended(&self) { self.suspensions.fetch_add(1, Ordering::Relaxed); }
+
+    // adds time since `start` to registration time
+    pub fn registered(&mut self, start: Instant) { self.register_time += start.elapsed(); }
+
+    pub fn get(&self) -> EngineStats {
+        EngineStats {
+            suspensions: self.suspensions.load(Ordering::Relaxed),
+            register_time: self.register_time,
+            ..EngineStats::default()
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+// Data area addressed by `Ref::Global`, one per driver and shared by all its runs.
+// Bytes are atomic so threads of `SharedDriver` use it concurrently, plain accesses aren't synchronized otherwise:
+// racing writes of multi-byte values might interleave. Atomic commands go through `update`.
+pub struct Globals {
+    // words keep the area aligned for atomics
+    words: Box<[AtomicU64]>,
+    size: usize,
+}
+
+impl Globals {
+    // zeroed area of `size` bytes
+    pub fn new(size: usize) -> Globals { Globals { words: (0..size.div_ceil(8)).map(|_| AtomicU64::new(0)).collect(), size } }
+
+    pub fn len(&self) -> usize { self.size }
+
+    pub fn is_empty(&self) -> bool { self.size == 0 }
+
+    pub fn read(&self, offset: u32, dst: &mut [u8]) {
+        let src = &self.bytes()[offset as usize..offset as usize + dst.len()];
+        dst.iter_mut().zip(src).for_each(|(dst, src)| *dst = src.load(Ordering::Relaxed));
+    }
+
+    pub fn write(&self, offset: u32, bytes: &[u8]) {
+        let dst = &self.bytes()[offset as usize..offset as usize + bytes.len()];
+        dst.iter().zip(bytes).for_each(|(dst, byte)| dst.store(*byte, Ordering::Relaxed));
+    }
+
+    // Sequentially consistent update of `size` (4 or 8) bytes at `offset`.
+    // Value is replaced by `update(previous)` unless it's `None`, returns previous value.
+    // Driver doesn't run atomic accesses which aren't aligned by `size`, here they update bytes one by one.
+    pub fn update<F: FnMut(u64) -> Option<u64>>(&self, offset: u32, size: u32, mut update: F) -> u64 {
+        let bytes = &self.bytes()[offset as usize..(offset + size) as usize];
+        match size {
+            4 if offset.is_multiple_of(4) => {
+                let atomic = unsafe { &*(bytes.as_ptr() as *const AtomicU32) };
+                let previous = atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |previous| {
+                    update(u32::from_le(previous) as u64).map(|value| (value as u32).to_le())
+                });
+                u32::from_le(previous.unwrap_or_else(|previous| previous)) as u64
+            }
+            8 if offset.is_multiple_of(8) => {
+                let atomic = unsafe { &*(bytes.as_ptr() as *const AtomicU64) };
+                let previous = atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |previous| {
+                    update(u64::from_le(previous)).map(|value| value.to_le())
+                });
+                u64::from_le(previous.unwrap_or_else(|previous| previous))
+            }
+            _ => {
+                let mut previous = [0; 8];
+                previous.iter_mut().zip(bytes).for_each(|(dst, src)| *dst = src.load(Ordering::SeqCst));
+                let previous = u64::from_le_bytes(previous);
+                if let Some(value) = update(previous) {
+                    bytes.iter().zip(value.to_le_bytes()).for_each(|(dst, byte)| dst.store(byte, Ordering::SeqCst));
+                }
+                previous
+            }
+        }
+    }
+
+    // start of the area for generated code
+    pub fn as_ptr(&self) -> *mut u8 { self.words.as_ptr() as *mut u8 }
+
+    // `AtomicU8` has the same layout as `u8`
+    fn bytes(&self) -> &[AtomicU8] { unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const AtomicU8, self.size) } }
+}
+
+impl Default for Globals {
+    fn default() -> Globals { Globals::new(0) }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+
+// table is purged of dropped values once it grows past this size, then once it doubles
+const MIN_PURGE: usize = 1024;
+
+// Hash consing: equal values are interned into the same `Interned`, which is hashed and compared by address.
+// Interning hashes value once, so its parts should be cheap to hash too, e.g. interned themselves.
+// Values are kept while there is `Interned` referring to them, dropped ones are forgotten eventually.
+pub struct Interner<T: Hash + Eq + Clone> {
+    table: Mutex<InternerTable<T>>,
+}
+
+struct InternerTable<T> {
+    values: HashMap<T, Weak<T>>,
+    purge_at: usize,
+}
+
+pub struct Interned<T>(Arc<T>);
+
+impl<T: Hash + Eq + Clone> Interner<T> {
+    pub fn new() -> Interner<T> {
+        Interner { table: Mutex::new(InternerTable { values: HashMap::new(), purge_at: MIN_PURGE }) }
+    }
+
+    pub fn intern(&self, value: T) -> Interned<T> {
+        let mut table = self.table.lock().unwrap();
+        if let Some(interned) = table.values.get(&value).and_then(Weak::upgrade) {
+            return Interned(interned);
+        }
+        let interned = Arc::new(value.clone());
+        table.values.insert(value, Arc::downgrade(&interned));
+        if table.values.len() >= table.purge_at {
+            table.values.retain(|_, interned| interned.strong_count() > 0);
+            table.purge_at = MIN_PURGE.max(table.values.len() * 2);
+        }
+        Interned(interned)
+    }
+
+    // number of values in table, including dropped ones which aren't purged yet
+    pub fn len(&self) -> usize { self.table.lock().unwrap().values.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<T: Hash + Eq + Clone> Default for Interner<T> {
+    fn default() -> Interner<T> { Interner::new() }
+}
+
+impl<T> Deref for Interned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T { self.0.as_ref() }
+}
+
+impl<T> Clone for Interned<T> {
+    fn clone(&self) -> Self { Interned(self.0.clone()) }
+}
+
+// equal values share address as long as both are alive
+impl<T> PartialEq for Interned<T> {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+}
+
+impl<T> Eq for Interned<T> {}
+
+impl<T> Hash for Interned<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) { state.write_usize(Arc::as_ptr(&self.0) as usize) }
+}
+
+impl<T: Debug> Debug for Interned<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { self.0.fmt(f) }
+}
+
+#[test]
+fn test_intern() {
+    let interner = Interner::new();
+    let a = interner.intern(vec![1, 2]);
+    assert_eq!(a, interner.intern(vec![1, 2]));
+    assert_ne!(a, interner.intern(vec![2, 1]));
+
+    // dropped values are purged, live ones stay interned
+    (0..MIN_PURGE).for_each(|i| { interner.intern(vec![i]); });
+    assert!(interner.len() < MIN_PURGE);
+    assert_eq!(a, interner.intern(vec![1, 2]));
+}
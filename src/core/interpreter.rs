@@ -1,30 +1,70 @@
 use std::num::Wrapping;
-use crate::core::api::{Command, Condition, Node, NodeKind, Ref};
+use std::sync::atomic::{fence, Ordering};
+use crate::core::api::{Command, Condition, Node, NodeKind, Ref, VecShape};
+use crate::core::driver::driver::Stop;
+use crate::core::globals::Globals;
 
-pub fn eval<N: Node>(node: N, stack: &mut [u8]) {
+// Evaluation can't be suspended, so it ends on host call as well: `Stop::HostCall`, `Stop::Uncaught` or `Stop::Finished`.
+pub fn eval<N: Node>(node: N, stack: &mut [u8]) -> Stop { eval_with_globals(node, stack, &Globals::default()) }
+
+pub fn eval_with_globals<N: Node>(node: N, stack: &mut [u8], globals: &Globals) -> Stop {
+    eval_frame(node, stack, globals).unwrap_or(Stop::Finished)
+}
+
+// returns host call or uncaught exception which ended evaluation within the frame, host call offset is from `stack` start
+fn eval_frame<N: Node>(node: N, mut stack: &mut [u8], globals: &Globals) -> Option<Stop> {
     let mut current = node;
+    // tail calls move frame start past `stack` start
+    let mut shift = 0;
     loop {
         match current.get() {
             NodeKind::Command { command, next } => {
-                eval_command(&command, stack);
+                eval_command(&command, stack, globals);
                 current = next;
             }
             NodeKind::Branch { condition, if_true, if_false } => {
-                if eval_condition(&condition, stack) {
+                if eval_condition(&condition, stack, globals) {
                     current = if_true;
                 } else {
                     current = if_false;
                 }
             }
-            NodeKind::Call { offset, call, next } => {
-                eval(call, &mut stack[(offset as usize)..]);
+            NodeKind::Call { offset, call, next } | NodeKind::PureCall { offset, call, next, .. } => {
+                if let Some(stop) = eval_frame(call, &mut stack[(offset as usize)..], globals) { return Some(shifted(stop, shift + offset as usize)); }
                 current = next;
             }
-            NodeKind::Final => { break; }
+            NodeKind::Final => { return None; }
+            NodeKind::Yield { next } => { current = next; }
+            NodeKind::Coroutine { .. } | NodeKind::Resume { .. } => { panic!("coroutines need evaluation which can be suspended") }
+            NodeKind::HostCall { function, offset, .. } => { return Some(Stop::HostCall { function, offset: shift + offset as usize }); }
+            NodeKind::TailCall { offset, call } => {
+                stack = &mut std::mem::take(&mut stack)[(offset as usize)..];
+                shift += offset as usize;
+                current = call;
+            }
+            NodeKind::Try { offset, call, next, catch } => {
+                match eval_frame(call, &mut stack[(offset as usize)..], globals) {
+                    None => { current = next; }
+                    Some(Stop::Uncaught(payload)) => {
+                        stack[(offset as usize)..(offset as usize + payload.len())].copy_from_slice(&payload);
+                        current = catch;
+                    }
+                    Some(stop) => { return Some(shifted(stop, shift + offset as usize)); }
+                }
+            }
+            NodeKind::Throw { op, size } => { return Some(Stop::Uncaught(read(op, size, stack, globals))); }
         }
     }
 }
 
+// stop of callee which frame is at `offset`
+fn shifted(stop: Stop, offset: usize) -> Stop {
+    match stop {
+        Stop::HostCall { function, offset: callee_offset } => { Stop::HostCall { function, offset: offset + callee_offset } }
+        stop => { stop }
+    }
+}
+
 pub fn get_final_kind<N: Node>(node: &N) -> NodeKind<N> {
     let kind = node.get();
     match kind {
@@ -36,37 +76,96 @@ pub fn get_final_kind<N: Node>(node: &N) -> NodeKind<N> {
         NodeKind::Branch { .. } => { kind }
         NodeKind::Call { .. } => { kind }
         NodeKind::Final => { kind }
+        NodeKind::Yield { .. } => { kind }
+        NodeKind::Coroutine { .. } => { kind }
+        NodeKind::Resume { .. } => { kind }
+        NodeKind::HostCall { .. } => { kind }
+        NodeKind::TailCall { .. } => { kind }
+        NodeKind::Try { .. } => { kind }
+        NodeKind::Throw { .. } => { kind }
+        NodeKind::PureCall { .. } => { kind }
     }
 }
 
-pub fn eval_command(command: &Command, stack: &mut [u8]) {
+pub fn eval_command(command: &Command, stack: &mut [u8], globals: &Globals) {
     match &command {
         Command::Noop => {}
         Command::PoisonFrom { .. } => {}
-        Command::Set { dst, bytes } => {
-            match dst {
-                Ref::Stack(offset) => {
-                    stack[*offset as usize..*offset as usize + bytes.len()].copy_from_slice(bytes.as_slice());
-                }
-            }
-        }
+        Command::Set { dst, bytes } => { write(*dst, bytes, stack, globals) }
         Command::Copy { size: 4, dst, op } => {
-            put_u32(*dst, stack, get_u32(*op, stack));
+            put_u32(*dst, stack, globals, get_u32(*op, stack, globals));
         }
         Command::Copy { size: 8, dst, op } => {
-            put_u64(*dst, stack, get_u64(*op, stack));
+            put_u64(*dst, stack, globals, get_u64(*op, stack, globals));
+        }
+        Command::Copy { size, dst, op } => {
+            let bytes = read(*op, *size, stack, globals);
+            write(*dst, &bytes, stack, globals);
         }
         Command::Add { size: 4, dst, op1, op2 } => {
-            put_u32(*dst, stack, get_u32(*op1, stack) + get_u32(*op2, stack))
+            put_u32(*dst, stack, globals, get_u32(*op1, stack, globals) + get_u32(*op2, stack, globals))
         }
         Command::Add { size: 8, dst, op1, op2 } => {
-            put_u64(*dst, stack, get_u64(*op1, stack) + get_u64(*op2, stack))
+            put_u64(*dst, stack, globals, get_u64(*op1, stack, globals) + get_u64(*op2, stack, globals))
         }
         Command::Sub { size: 4, dst, op1, op2 } => {
-            put_u32(*dst, stack, get_u32(*op1, stack) - get_u32(*op2, stack))
+            put_u32(*dst, stack, globals, get_u32(*op1, stack, globals) - get_u32(*op2, stack, globals))
         }
         Command::Sub { size: 8, dst, op1, op2 } => {
-            put_u64(*dst, stack, get_u64(*op1, stack) - get_u64(*op2, stack))
+            put_u64(*dst, stack, globals, get_u64(*op1, stack, globals) - get_u64(*op2, stack, globals))
+        }
+        Command::AtomicLoad { size, dst, mem } => {
+            let value = atomic_update(*size, *mem, stack, globals, |_| None);
+            put_value(*size, *dst, stack, globals, value);
+        }
+        Command::AtomicStore { size, mem, op } => {
+            let value = get_value(*size, *op, stack, globals);
+            atomic_update(*size, *mem, stack, globals, |_| Some(value));
+        }
+        Command::AtomicRmw { size, op, dst, mem, value } => {
+            let value = get_value(*size, *value, stack, globals);
+            let previous = atomic_update(*size, *mem, stack, globals, |previous| Some(op.apply(previous, value)));
+            put_value(*size, *dst, stack, globals, previous);
+        }
+        Command::AtomicCmpXchg { size, mem, expected, replacement } => {
+            let (expected_value, replacement) = (get_value(*size, *expected, stack, globals), get_value(*size, *replacement, stack, globals));
+            let previous = atomic_update(*size, *mem, stack, globals, |previous| (previous == expected_value).then_some(replacement));
+            put_value(*size, *expected, stack, globals, previous);
+        }
+        Command::Fence => { fence(Ordering::SeqCst) }
+        Command::VecBinary { op, shape, dst, op1, op2 } => {
+            let (op1, op2) = (read(*op1, 16, stack, globals), read(*op2, 16, stack, globals));
+            let mut result = [0; 16];
+            (0..shape.lanes()).for_each(|lane| set_lane(*shape, &mut result, lane, op.apply(*shape, get_lane(*shape, &op1, lane), get_lane(*shape, &op2, lane))));
+            write(*dst, &result, stack, globals);
+        }
+        Command::VecSplat { shape, dst, op } => {
+            let value = get_lane(*shape, &read(*op, shape.lane_size(), stack, globals), 0);
+            let mut result = [0; 16];
+            (0..shape.lanes()).for_each(|lane| set_lane(*shape, &mut result, lane, value));
+            write(*dst, &result, stack, globals);
+        }
+        Command::VecExtractLane { shape, signed, lane, dst, op } => {
+            let value = get_lane(*shape, &read(*op, 16, stack, globals), *lane as u32);
+            match shape.lane_size() {
+                1 | 2 => {
+                    let bits = shape.lane_size() * 8;
+                    let value = if *signed { ((((value << (32 - bits)) as u32) as i32) >> (32 - bits)) as u32 } else { value as u32 };
+                    write(*dst, &value.to_le_bytes(), stack, globals);
+                }
+                size => { write(*dst, &value.to_le_bytes()[0..size as usize], stack, globals) }
+            }
+        }
+        Command::VecReplaceLane { shape, lane, dst, op, value } => {
+            let mut result = read(*op, 16, stack, globals);
+            let value = get_lane(*shape, &read(*value, shape.lane_size(), stack, globals), 0);
+            set_lane(*shape, &mut result, *lane as u32, value);
+            write(*dst, &result, stack, globals);
+        }
+        Command::VecShuffle { lanes, dst, op1, op2 } => {
+            let bytes = [read(*op1, 16, stack, globals), read(*op2, 16, stack, globals)].concat();
+            let result: Vec<u8> = lanes.iter().map(|lane| bytes[*lane as usize]).collect();
+            write(*dst, &result, stack, globals);
         }
         _ => {
             todo!("unsupported command: {:?}", command)
@@ -75,13 +174,13 @@ pub fn eval_command(command: &Command, stack: &mut [u8]) {
     // println!("{:?} <- eval {:?}", stack, command);
 }
 
-pub fn eval_condition(condition: &Condition, stack: &mut [u8]) -> bool {
+pub fn eval_condition(condition: &Condition, stack: &mut [u8], globals: &Globals) -> bool {
     let result = match condition {
         Condition::Ne { size: 4, op1, op2 } => {
-            get_u32(*op1, stack) != get_u32(*op2, stack)
+            get_u32(*op1, stack, globals) != get_u32(*op2, stack, globals)
         }
         Condition::Ne0 { size: 4, op: src } => {
-            get_u32(*src, stack).0 != 0
+            get_u32(*src, stack, globals).0 != 0
         }
         _ => {
             todo!("unsupported condition: {:?}", condition)
@@ -91,34 +190,97 @@ pub fn eval_condition(condition: &Condition, stack: &mut [u8]) -> bool {
     result
 }
 
-pub fn get_u32(src: Ref, stack: &[u8]) -> Wrapping<u32> {
-    match src {
-        Ref::Stack(offset) => {
-            Wrapping(u32::from_le_bytes(stack[offset as usize..(offset + 4) as usize].try_into().unwrap()))
+// previous value at `mem`, which is replaced by `update(previous)` unless it's `None`, atomically if it's global
+fn atomic_update<F: FnMut(u64) -> Option<u64>>(size: u32, mem: Ref, stack: &mut [u8], globals: &Globals, mut update: F) -> u64 {
+    match mem {
+        Ref::Stack(_) => {
+            let previous = get_value(size, mem, stack, globals);
+            if let Some(value) = update(previous) { put_value(size, mem, stack, globals, value) }
+            previous
         }
+        Ref::Global(offset) => { globals.update(offset, size, update) }
     }
 }
 
-pub fn get_u64(src: Ref, stack: &[u8]) -> Wrapping<u64> {
+// 4 or 8 bytes value at `src`, driver doesn't run atomics of other sizes
+fn get_value(size: u32, src: Ref, stack: &[u8], globals: &Globals) -> u64 {
+    match size {
+        4 => { get_u32(src, stack, globals).0 as u64 }
+        8 => { get_u64(src, stack, globals).0 }
+        _ => { todo!("unsupported size: {}", size) }
+    }
+}
+
+fn put_value(size: u32, dst: Ref, stack: &mut [u8], globals: &Globals, value: u64) {
+    match size {
+        4 => { put_u32(dst, stack, globals, Wrapping(value as u32)) }
+        8 => { put_u64(dst, stack, globals, Wrapping(value)) }
+        _ => { todo!("unsupported size: {}", size) }
+    }
+}
+
+// bits of `lane` of `vector` in low bytes
+fn get_lane(shape: VecShape, vector: &[u8], lane: u32) -> u64 {
+    let size = shape.lane_size() as usize;
+    let mut bytes = [0; 8];
+    bytes[0..size].copy_from_slice(&vector[lane as usize * size..(lane as usize + 1) * size]);
+    u64::from_le_bytes(bytes)
+}
+
+fn set_lane(shape: VecShape, vector: &mut [u8], lane: u32, value: u64) {
+    let size = shape.lane_size() as usize;
+    vector[lane as usize * size..(lane as usize + 1) * size].copy_from_slice(&value.to_le_bytes()[0..size]);
+}
+
+// `size` bytes at `src`
+pub fn read(src: Ref, size: u32, stack: &[u8], globals: &Globals) -> Vec<u8> {
     match src {
-        Ref::Stack(offset) => {
-            Wrapping(u64::from_le_bytes(stack[offset as usize..(offset + 8) as usize].try_into().unwrap()))
+        Ref::Stack(offset) => { stack[offset as usize..(offset + size) as usize].to_vec() }
+        Ref::Global(offset) => {
+            let mut bytes = vec![0; size as usize];
+            globals.read(offset, &mut bytes);
+            bytes
         }
     }
 }
 
-pub fn put_u32(dst: Ref, stack: &mut [u8], value: Wrapping<u32>) {
+pub fn write(dst: Ref, bytes: &[u8], stack: &mut [u8], globals: &Globals) {
     match dst {
+        Ref::Stack(offset) => { stack[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes) }
+        Ref::Global(offset) => { globals.write(offset, bytes) }
+    }
+}
+
+pub fn get_u32(src: Ref, stack: &[u8], globals: &Globals) -> Wrapping<u32> {
+    match src {
         Ref::Stack(offset) => {
-            stack[offset as usize..(offset + 4) as usize].copy_from_slice(value.0.to_le_bytes().as_slice());
+            Wrapping(u32::from_le_bytes(stack[offset as usize..(offset + 4) as usize].try_into().unwrap()))
+        }
+        Ref::Global(offset) => {
+            let mut bytes = [0; 4];
+            globals.read(offset, &mut bytes);
+            Wrapping(u32::from_le_bytes(bytes))
         }
     }
 }
 
-pub fn put_u64(dst: Ref, stack: &mut [u8], value: Wrapping<u64>) {
-    match dst {
+pub fn get_u64(src: Ref, stack: &[u8], globals: &Globals) -> Wrapping<u64> {
+    match src {
         Ref::Stack(offset) => {
-            stack[offset as usize..(offset + 8) as usize].copy_from_slice(value.0.to_le_bytes().as_slice());
+            Wrapping(u64::from_le_bytes(stack[offset as usize..(offset + 8) as usize].try_into().unwrap()))
+        }
+        Ref::Global(offset) => {
+            let mut bytes = [0; 8];
+            globals.read(offset, &mut bytes);
+            Wrapping(u64::from_le_bytes(bytes))
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn put_u32(dst: Ref, stack: &mut [u8], globals: &Globals, value: Wrapping<u32>) {
+    write(dst, value.0.to_le_bytes().as_slice(), stack, globals)
+}
+
+pub fn put_u64(dst: Ref, stack: &mut [u8], globals: &Globals, value: Wrapping<u64>) {
+    write(dst, value.0.to_le_bytes().as_slice(), stack, globals)
+}
@@ -1,5 +1,8 @@
 pub mod api;
 pub mod utils;
+pub mod interner;
+pub mod globals;
 pub mod interpreter;
 pub mod driver;
-pub mod aux;
\ No newline at end of file
+pub mod aux;
+pub mod c_translator;
\ No newline at end of file
@@ -14,11 +14,27 @@ pub fn traverse_node<N: Node>(node: N) -> HashSet<N> {
                 rec(if_true, visited);
                 rec(if_false, visited);
             }
-            NodeKind::Call { call, next, .. } => {
+            NodeKind::Call { call, next, .. } | NodeKind::PureCall { call, next, .. } => {
                 rec(call, visited);
                 rec(next, visited);
             }
             NodeKind::Final => {}
+            NodeKind::Yield { next } | NodeKind::HostCall { next, .. } | NodeKind::Resume { next, .. } => {
+                rec(next, visited);
+            }
+            NodeKind::Coroutine { call, next, .. } => {
+                rec(call, visited);
+                rec(next, visited);
+            }
+            NodeKind::TailCall { call, .. } => {
+                rec(call, visited);
+            }
+            NodeKind::Try { call, next, catch, .. } => {
+                rec(call, visited);
+                rec(next, visited);
+                rec(catch, visited);
+            }
+            NodeKind::Throw { .. } => {}
         }
     }
 
@@ -62,6 +78,43 @@ pub fn pretty_print<N: Node>(node: N) {
             NodeKind::Final => {
                 println!("<final>")
             }
+            NodeKind::Yield { next } => {
+                println!("<yield>");
+                rec(offset, line, next, visited);
+            }
+            NodeKind::Coroutine { offset: call_offset, call, handle, next } => {
+                println!("coroutine {} {:?}", call_offset, handle);
+                rec(offset + 1, line, call, visited);
+                rec(offset, line, next, visited);
+            }
+            NodeKind::Resume { handle, next } => {
+                println!("resume {:?}", handle);
+                rec(offset, line, next, visited);
+            }
+            NodeKind::TailCall { offset: call_offset, call } => {
+                println!("tail call {}", call_offset);
+                rec(offset + 1, line, call, visited);
+            }
+            NodeKind::Try { offset: call_offset, call, next, catch } => {
+                println!("try {}", call_offset);
+                rec(offset + 1, line, call, visited);
+                print_line(offset, line);
+                println!("catch");
+                rec(offset + 1, line, catch, visited);
+                rec(offset, line, next, visited);
+            }
+            NodeKind::Throw { op, size } => {
+                println!("throw {} {:?}", size, op)
+            }
+            NodeKind::PureCall { offset: call_offset, call, next, args, results } => {
+                println!("pure call {} ({} -> {})", call_offset, args, results);
+                rec(offset + 1, line, call, visited);
+                rec(offset, line, next, visited);
+            }
+            NodeKind::HostCall { function, offset: call_offset, next } => {
+                println!("host call {} {}", function, call_offset);
+                rec(offset, line, next, visited);
+            }
         }
     }
 
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use crate::core::api::{VecOp, VecShape};
 use crate::webasm::lazy::Lazy;
 
 #[derive(Debug)]
@@ -7,6 +8,7 @@ pub struct Module {
     pub func_section: Option<Lazy<FuncSection>>,
     pub export_section: Option<Lazy<ExportSection>>,
     pub code_section: Option<Lazy<CodeSection>>,
+    pub tag_section: Option<Lazy<TagSection>>,
     pub other_sections: Vec<(u8, Lazy<UnrecognizedSection>)>,
 }
 
@@ -22,6 +24,10 @@ pub struct ExportSection(pub Vec<Export>);
 #[derive(Debug)]
 pub struct CodeSection(pub Vec<Code>);
 
+// types of exception tags, from exception handling proposal
+#[derive(Debug)]
+pub struct TagSection(pub Vec<TypeIdx>);
+
 #[derive(Debug)]
 pub struct UnrecognizedSection(pub String);
 
@@ -39,15 +45,21 @@ pub enum NumType {
     F64,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VecType {
+    V128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RefType {
     Func,
     // Extern,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValType {
     Num(NumType),
+    Vec(VecType),
     Ref(RefType),
 }
 
@@ -57,12 +69,15 @@ pub struct TypeIdx(pub u32);
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct LocalIdx(pub u32);
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct FuncIdx(pub u32);
 
 #[derive(Debug)]
 pub struct GlobalIdx(pub u32);
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TagIdx(pub u32);
+
 #[derive(Debug)]
 pub struct Export {
     pub name: String,
@@ -105,13 +120,28 @@ pub enum Instruction {
     BlockEnd,
     Return,
     Call(FuncIdx),
+    ReturnCall(FuncIdx),
+    Try { bt: BlockType },
+    Catch(TagIdx),
+    CatchAll,
+    Throw(TagIdx),
 
     LocalGet(LocalIdx),
+    LocalSet(LocalIdx),
     I32Const(i32),
     I64Const(i64),
     Eq(NumType),
     Add(NumType),
     Sub(NumType),
+
+    // vector instructions, with 0xFD prefix
+    V128Const([u8; 16]),
+    // lanes of both operands, as in `Command::VecShuffle`
+    Shuffle([u8; 16]),
+    Splat(VecShape),
+    ExtractLane { shape: VecShape, signed: bool, lane: u8 },
+    ReplaceLane { shape: VecShape, lane: u8 },
+    VecBinary(VecOp, VecShape),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Copy)]
@@ -120,7 +150,7 @@ pub struct InstructionIdx(pub u32);
 #[derive(Debug, PartialEq, Eq)]
 pub enum BlockType {
     Empty,
-    // ValType(ValType),
+    Value(ValType),
     // TypeIdx(TypeIdx),
 }
 
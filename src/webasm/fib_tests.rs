@@ -1,13 +1,21 @@
-use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
-use std::rc::Rc;
-use crate::core::api::{Node, Ref};
+use std::sync::Arc;
+use crate::core::api::{Command, Node, NodeKind, Ref};
 use crate::core::aux::cached_node::Cache;
-use crate::core::driver::driver::Driver;
+use crate::core::aux::partial_eval::{Known, PartialEvaluator};
+use crate::core::aux::shared_cache::SharedCache;
+use crate::core::c_translator::compile_and_eval;
+use crate::core::globals::Globals;
+use crate::core::driver::driver::{Driver, Engine, Outcome, Region, Stop};
+use crate::core::driver::shared_driver::SharedDriver;
 use crate::core::driver::interpreter_engine::InterpreterEngine;
 use crate::core::aux::specialized_interpreter_engine::SpecializedInterpreterEngine;
 use crate::core::driver::code_generator_engine::CodeGeneratorEngine;
+use crate::core::driver::observer::{Event, Tracer};
 use crate::core::interpreter::{eval, get_u32, put_u32};
 use crate::core::utils::{pretty_print, traverse_node};
 use crate::example::native_impl::fib4;
@@ -15,13 +23,24 @@ use crate::webasm::ast::Module;
 use crate::webasm::node::{Source, WebAsmNode};
 use crate::webasm::parser::hydrate::hydrate_module;
 
-fn fib_node(func_name: &str) -> WebAsmNode {
-    let name = String::from("data/fib.wasm");
+fn fib_node(func_name: &str) -> WebAsmNode { wasm_node("data/fib.wasm", func_name) }
+
+fn wasm_node(file_name: &str, func_name: &str) -> WebAsmNode { WebAsmNode::exported_func(wasm_source(file_name, &[]), func_name) }
+
+// `pure` are exported functions which calls are `PureCall`
+fn wasm_source(file_name: &str, pure: &[&str]) -> Arc<Source> {
+    let name = String::from(file_name);
     let mut file = File::open(&name).unwrap();
     let module = Module::read(&mut file).unwrap();
     hydrate_module(&module, &mut file);
-    let source = Rc::new(Source { uuid: 0, name: name.clone(), file: RefCell::new(file), module });
-    WebAsmNode::exported_func(source.clone(), func_name)
+    // nodes of different sources differ only by uuid, it's stable so snapshots of the same file can be loaded
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    pure.hash(&mut hasher);
+    let mut source = Source::new(hasher.finish() as u128, name.clone(), file, module);
+    let pure_funcs: Vec<_> = pure.iter().map(|name| source.exported_func_idx(name)).collect();
+    source.set_pure_funcs(pure_funcs);
+    Arc::new(source)
 }
 
 fn fib_node_32() -> WebAsmNode { fib_node("fib32") }
@@ -36,11 +55,11 @@ fn test_node_pretty_print() { pretty_print(fib_node_32()); }
 #[test]
 fn test_cached_node_pretty_print() { pretty_print(Cache::new().cache(fib_node_32())); }
 
-fn run_fib<F: FnOnce(&mut [u8])>(eval: F, n: u32) -> u32 {
+fn run_fib<T, F: FnOnce(&mut [u8]) -> T>(eval: F, n: u32) -> u32 {
     let mut stack = [0u8; 1000];
-    put_u32(Ref::Stack(0), &mut stack, Wrapping(n));
+    put_u32(Ref::Stack(0), &mut stack, &Globals::default(), Wrapping(n));
     eval(&mut stack);
-    get_u32(Ref::Stack(0), &stack).0
+    get_u32(Ref::Stack(0), &stack, &Globals::default()).0
 }
 
 fn run_fib_node<N: Node>(node: N, n: u32) -> u32 { run_fib(|stack| eval(node, stack), n) }
@@ -56,20 +75,77 @@ fn test_cached_node_eval() {
     run_fib_node(Cache::new().cache(fib_node_32()), 35);
 }
 
+#[test]
+fn test_shared_cache_eval() {
+    let cache = SharedCache::new(16);
+    assert_eq!(6765, run_fib_node(cache.cache(fib_node_32()), 20));
+    assert!(cache.len() <= 16);
+
+    // nodes of the cache are shared between threads and evaluated in the same driver
+    let driver = SharedDriver::new(InterpreterEngine::new());
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (15..20).map(|n| {
+            let (driver, node) = (&driver, cache.cache(fib_node_32()));
+            scope.spawn(move || run_fib(|stack| driver.eval(node, stack), n))
+        }).collect();
+        let results: Vec<u32> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec![610, 987, 1597, 2584, 4181], results);
+    });
+}
+
+// each fib(n) is computed once, so it's linear instead of exponential
+fn run_memoized_fib<E: Engine>(engine: E) {
+    let node = WebAsmNode::exported_func(wasm_source("data/fib.wasm", &["fib32"]), "fib32");
+    let mut driver = Driver::new(engine);
+    driver.set_memoization(Some(64));
+    assert_eq!(102334155, run_fib(|stack| driver.eval(node, stack), 40));
+}
+
+#[test]
+fn test_memoized_eval() {
+    run_memoized_fib(InterpreterEngine::new());
+    run_memoized_fib(SpecializedInterpreterEngine::new());
+}
+
+#[test]
+fn test_code_generator_memoized_eval() {
+    run_memoized_fib(CodeGeneratorEngine::new(8 * 1024).unwrap());
+}
+
+#[test]
+fn test_partial_eval() {
+    let evaluator = PartialEvaluator::new(100_000);
+    // with constant argument whole call collapses to writes of its result
+    let node = evaluator.specialize(fib_node_32(), Known::new().with(0, &20u32.to_le_bytes()));
+    assert!(traverse_node(node.clone()).iter().all(|node| {
+        matches!(node.get(), NodeKind::Command { command: Command::Set { .. }, .. } | NodeKind::Final)
+    }));
+    assert_eq!(6765, run_fib_node(node, 20));
+
+    // unknown argument leaves residual code, which computes the same
+    let node = evaluator.specialize(fib_node_32(), Known::new());
+    assert_eq!(6765, run_fib_node(node.clone(), 20));
+    assert_eq!(6765, run_fib(|stack| Driver::new(InterpreterEngine::new()).eval(node, stack), 20));
+
+    // too big to fold within limit
+    let node = PartialEvaluator::new(100).specialize(fib_node_32(), Known::new().with(0, &20u32.to_le_bytes()));
+    assert_eq!(6765, run_fib_node(node, 20));
+}
+
 #[test]
 fn test_specialized_interpreter_eval() {
     let mut interpreter = Driver::new(SpecializedInterpreterEngine::new());
     run_fib(|stack| interpreter.eval(fib_node_32(), stack), 35);
-    // interpreter.print_stats()
+    // println!("{:?}", interpreter.stats())
 }
 
 #[test]
 fn test_caching_interpreter_eval() {
-    run_fib(|stack| Driver::new(InterpreterEngine::new(false)).eval(fib_node_32(), stack), 35);
+    run_fib(|stack| Driver::new(InterpreterEngine::new()).eval(fib_node_32(), stack), 35);
 }
 
 fn code_engine_driver<N: Node>() -> Driver<N, CodeGeneratorEngine> {
-    Driver::new(CodeGeneratorEngine::new(8 * 1024, false).unwrap())
+    Driver::new(CodeGeneratorEngine::new(8 * 1024).unwrap())
 }
 
 #[test]
@@ -80,11 +156,353 @@ fn test_code_generator_eval_32() {
 
 #[test]
 fn test_code_generator_eval_64() {
-    // let res = run_fib(|stack| Driver::new(InterpreterEngine::new(true)).eval(fib_node_64(), stack), 39);
+    // let res = run_fib(|stack| Driver::new(InterpreterEngine::new()).eval(fib_node_64(), stack), 39);
     let res = run_fib(|stack| code_engine_driver().eval(fib_node_64(), stack), 39);
     assert_eq!(63245986, res);
 }
 
+#[test]
+fn test_code_generator_small_chunks_eval() {
+    // every few nodes go into separate chunk, so most of jumps are between chunks
+    let res = run_fib(|stack| Driver::new(CodeGeneratorEngine::new(256).unwrap()).eval(fib_node_32(), stack), 20);
+    assert_eq!(6765, res);
+}
+
+#[test]
+fn test_code_generator_region_eval() {
+    for (chunk_size, region) in [(8 * 1024, Region::Function), (8 * 1024, Region::Steps(4)), (256, Region::Function)] {
+        let mut engine = CodeGeneratorEngine::new(chunk_size).unwrap();
+        engine.set_region(region);
+        assert_eq!(6765, run_fib(|stack| Driver::new(engine).eval(fib_node_32(), stack), 20), "{:?}", region);
+    }
+}
+
+// `sum(n, 0)` recurses with `return_call`, so depth isn't limited by the stack
+fn sum_node() -> WebAsmNode { wasm_node("data/sum.wasm", "sum") }
+
+#[test]
+fn test_tail_call_eval() {
+    assert_eq!(50005000, run_fib_node(sum_node(), 10000));
+    assert_eq!(50005000, run_fib(|stack| Driver::new(InterpreterEngine::new()).eval(sum_node(), stack), 10000));
+    assert_eq!(50005000, run_fib(|stack| Driver::new(SpecializedInterpreterEngine::new()).eval(sum_node(), stack), 10000));
+}
+
+#[test]
+fn test_c_translator_tail_call_eval() {
+    // far deeper than native stack would allow for calls, sum wraps around
+    assert_eq!((1..=10_000_000u64).sum::<u64>() as u32, run_fib(|stack| compile_and_eval(sum_node(), stack), 10_000_000));
+}
+
+#[test]
+fn test_code_generator_tail_call_eval() {
+    assert_eq!(50005000, run_fib(|stack| code_engine_driver().eval(sum_node(), stack), 10000));
+}
+
+fn exceptions_node(func_name: &str) -> WebAsmNode { wasm_node("data/exceptions.wasm", func_name) }
+
+// thrown within the call, caught by the caller, thrown further by handler which doesn't match,
+// param written in try body is seen after it ends or throws
+fn run_exceptions<F: FnMut(WebAsmNode, &mut [u8])>(mut eval: F) {
+    for (func_name, n, expected) in [("safe", 5, 6), ("safe", 0, 142), ("outer", 5, 5), ("outer", 0, 7), ("count", 5, 11), ("count", 0, 44)] {
+        assert_eq!(expected, run_fib(|stack| eval(exceptions_node(func_name), stack), n), "{}({})", func_name, n);
+    }
+}
+
+#[test]
+fn test_exceptions_eval() {
+    run_exceptions(|node, stack| { eval(node, stack); });
+    let mut interpreter = Driver::new(InterpreterEngine::new());
+    run_exceptions(|node, stack| { interpreter.eval(node, stack); });
+    let mut specialized = Driver::new(SpecializedInterpreterEngine::new());
+    run_exceptions(|node, stack| { specialized.eval(node, stack); });
+}
+
+#[test]
+fn test_code_generator_exceptions_eval() {
+    let mut driver = code_engine_driver();
+    run_exceptions(|node, stack| { driver.eval(node, stack); });
+}
+
+#[test]
+#[should_panic(expected = "return from try body isn't supported")]
+fn test_return_from_try() { wasm_node("data/try_return.wasm", "early"); }
+
+#[test]
+#[should_panic(expected = "branch in try body isn't supported")]
+fn test_branch_in_try() { wasm_node("data/try_branch.wasm", "leave"); }
+
+fn simd_node(func_name: &str) -> WebAsmNode { wasm_node("data/simd.wasm", func_name) }
+
+fn i32s(lanes: &[i32]) -> Vec<u8> { lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect() }
+fn i64s(lanes: &[i64]) -> Vec<u8> { lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect() }
+fn f32s(lanes: &[f32]) -> Vec<u8> { lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect() }
+fn f64s(lanes: &[f64]) -> Vec<u8> { lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect() }
+fn i16s(lanes: &[i16]) -> Vec<u8> { lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect() }
+
+// params are placed at the start of the stack, where results are expected
+fn run_simd<F: FnMut(WebAsmNode, &mut [u8])>(mut eval: F) {
+    let bytes: Vec<u8> = (0..16).collect();
+    let last_negative: Vec<u8> = (0..16).map(|byte| if byte == 15 { 0xF0 } else { byte }).collect();
+    let cases = [
+        ("madd", [i32s(&[1, 2, 3, 4]), i32s(&[5, -6, 7, 8]), i32s(&[10, 20, 30, 40])].concat(), i32s(&[15, 8, 51, 72])),
+        ("hsum", i32s(&[1, 2, 3, 4]), i32s(&[10])),
+        ("reverse", bytes.clone(), bytes.iter().rev().copied().collect()),
+        // signed comparison, -5 isn't greater
+        ("count_greater", [i32s(&[1, -5, 7, 3]), i32s(&[2])].concat(), i32s(&[2])),
+        ("fmadd", [f32s(&[1.0, 2.0, -3.0, 0.5]), f32s(&[2.0; 4])].concat(), f32s(&[3.5, 5.5, -4.5, 2.5])),
+        ("set_high", [i64s(&[1, 2]), i64s(&[-1])].concat(), i64s(&[1, -1])),
+        ("last_byte", last_negative, i32s(&[0xF0 - 0x10])),
+        ("ne16", [i16s(&[1, 2, 3, 4, 5, 6, 7, 8]), i16s(&[1, 0, 3, 0, 5, 0, 7, 0])].concat(), i16s(&[0, -1, 0, -1, 0, -1, 0, -1])),
+        ("lt64", [f64s(&[1.0, 5.0]), f64s(&[2.0, 5.0])].concat(), i64s(&[-1, 0])),
+        // lanes of a * b are [15, 3 << 40], bytes are subtracted without borrows
+        ("mul_sub", [i64s(&[3, 1 << 40]), i64s(&[5, 3])].concat(), i64s(&[12, 2 << 40])),
+    ];
+    for (func_name, params, expected) in cases {
+        let mut stack = [0u8; 128];
+        stack[0..params.len()].copy_from_slice(&params);
+        eval(simd_node(func_name), &mut stack);
+        assert_eq!(expected, stack[0..expected.len()], "{}", func_name);
+    }
+}
+
+#[test]
+fn test_simd_eval() {
+    run_simd(|node, stack| { eval(node, stack); });
+    let mut interpreter = Driver::new(InterpreterEngine::new());
+    run_simd(|node, stack| { interpreter.eval(node, stack); });
+    let mut specialized = Driver::new(SpecializedInterpreterEngine::new());
+    run_simd(|node, stack| { specialized.eval(node, stack); });
+}
+
+#[test]
+fn test_code_generator_simd_eval() {
+    let mut driver = code_engine_driver();
+    run_simd(|node, stack| { driver.eval(node, stack); });
+}
+
+#[test]
+fn test_budget_eval() {
+    let mut interpreter = Driver::new(InterpreterEngine::new());
+    interpreter.set_budget(Some(8));
+    assert_eq!(6765, run_fib(|stack| interpreter.eval(fib_node_32(), stack), 20));
+
+    let mut specialized = Driver::new(SpecializedInterpreterEngine::new());
+    specialized.set_budget(Some(8));
+    assert_eq!(6765, run_fib(|stack| specialized.eval(fib_node_32(), stack), 20));
+}
+
+#[test]
+fn test_code_generator_budget_eval() {
+    let mut driver = Driver::new(CodeGeneratorEngine::new(256).unwrap());
+    driver.set_budget(Some(8));
+    assert_eq!(6765, run_fib(|stack| driver.eval(fib_node_32(), stack), 20));
+    assert_eq!(6765, run_fib(|stack| driver.eval(fib_node_32(), stack), 20));
+}
+
+fn run_fib_shared<E: Engine + Send + Sync>(engine: E) {
+    let driver = SharedDriver::new(engine);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (15..20).map(|n| {
+            let driver = &driver;
+            scope.spawn(move || run_fib(|stack| driver.eval(fib_node_32(), stack), n))
+        }).collect();
+        let results: Vec<u32> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec![610, 987, 1597, 2584, 4181], results);
+    });
+}
+
+#[test]
+fn test_shared_driver_eval() {
+    run_fib_shared(InterpreterEngine::new());
+    run_fib_shared(SpecializedInterpreterEngine::new());
+}
+
+#[test]
+fn test_code_generator_shared_driver_eval() {
+    run_fib_shared(CodeGeneratorEngine::new(8 * 1024).unwrap());
+}
+
+fn run_fib_snapshot<E: Engine, F: Fn() -> E>(engine: F, n: u32, inlining: Option<usize>) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("leshy-snapshot-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let mut driver = Driver::new(engine());
+    driver.set_inlining(inlining);
+    run_fib(|stack| driver.eval(fib_node_32(), stack), n);
+    driver.save(&path).unwrap();
+
+    // nodes are created anew, they're matched with snapshot by key only
+    let mut loaded = Driver::load(engine(), &path).unwrap();
+    loaded.set_inlining(inlining);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(driver.registered(), loaded.registered());
+    assert_eq!(6765, run_fib(|stack| loaded.eval(fib_node_32(), stack), 20));
+}
+
+#[test]
+fn test_snapshot_eval() {
+    run_fib_snapshot(InterpreterEngine::new, 20, None);
+    run_fib_snapshot(SpecializedInterpreterEngine::new, 20, None);
+    // only part of nodes is in snapshot, rest is computed after load
+    run_fib_snapshot(InterpreterEngine::new, 1, None);
+    // inlined copies are kept in snapshot
+    run_fib_snapshot(InterpreterEngine::new, 20, Some(32));
+    run_fib_snapshot(InterpreterEngine::new, 1, Some(32));
+}
+
+fn run_corrupted_snapshot<E: Engine, F: Fn() -> E>(engine: F) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("leshy-corrupted-snapshot-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let mut driver = Driver::new(engine());
+    driver.set_inlining(Some(32));
+    run_fib(|stack| driver.eval(fib_node_32(), stack), 5);
+    driver.save(&path).unwrap();
+    let saved = std::fs::read(&path).unwrap();
+
+    // node table size after magic is kept, so corrupted snapshot doesn't ask for huge table
+    let mut invalid = 0;
+    for position in 20..saved.len() {
+        for flip in [0x01, 0x80] {
+            let mut corrupted = saved.clone();
+            *corrupted.get_mut(position).unwrap() ^= flip;
+            std::fs::write(&path, corrupted).unwrap();
+            if let Err(error) = Driver::<WebAsmNode, E>::load(engine(), &path) {
+                assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+                invalid += 1;
+            }
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert!(invalid > 0);
+}
+
+#[test]
+fn test_corrupted_snapshot() {
+    run_corrupted_snapshot(InterpreterEngine::new);
+}
+
+#[test]
+fn test_code_generator_corrupted_snapshot() {
+    run_corrupted_snapshot(|| CodeGeneratorEngine::new(256).unwrap());
+}
+
+#[test]
+fn test_code_generator_snapshot_eval() {
+    run_fib_snapshot(|| CodeGeneratorEngine::new(256).unwrap(), 20, None);
+    run_fib_snapshot(|| CodeGeneratorEngine::new(256).unwrap(), 1, None);
+    run_fib_snapshot(|| CodeGeneratorEngine::new(256).unwrap(), 20, Some(32));
+}
+
+fn fib_profile<E: Engine>(engine: E, node: &WebAsmNode) -> Driver<WebAsmNode, E> {
+    let mut driver = Driver::new(engine);
+    driver.set_profiling(true);
+    assert_eq!(6765, run_fib(|stack| driver.eval(node.clone(), stack), 20));
+    driver
+}
+
+// engines might give nodes different ids, so profiles are matched by nodes, which should be of the same source
+fn assert_same_profile<E1: Engine, E2: Engine>(expected: &Driver<WebAsmNode, E1>, actual: &Driver<WebAsmNode, E2>) {
+    let (expected_profile, actual_profile) = (expected.profile(), actual.profile());
+    assert_eq!(expected_profile.len(), actual_profile.len());
+    for (id, profile) in expected_profile {
+        let node = expected.node(id).unwrap();
+        assert_eq!(Some(&profile), actual.id(node).and_then(|id| actual_profile.get(&id)), "{:?}", node);
+    }
+}
+
+#[test]
+fn test_profile_eval() {
+    let node = fib_node_32();
+    let driver = fib_profile(InterpreterEngine::new(), &node);
+    let profile = driver.profile();
+    assert!(profile.values().any(|profile| profile.calls > 0));
+    assert!(profile.values().any(|profile| profile.taken > 0 && profile.not_taken > 0));
+    assert_same_profile(&driver, &fib_profile(SpecializedInterpreterEngine::new(), &node));
+}
+
+#[test]
+fn test_code_generator_profile_eval() {
+    let node = fib_node_32();
+    assert_same_profile(&fib_profile(InterpreterEngine::new(), &node), &fib_profile(CodeGeneratorEngine::new(8 * 1024).unwrap(), &node));
+}
+
+fn fib_trace<E: Engine>(engine: E) -> Vec<Event> {
+    let tracer = Arc::new(Tracer::new());
+    let mut driver = Driver::new(engine);
+    driver.set_observer(Some(tracer.clone()));
+    assert_eq!(55, run_fib(|stack| driver.eval(fib_node_32(), stack), 10));
+    tracer.take()
+}
+
+#[test]
+fn test_observer_eval() {
+    assert_eq!(fib_trace(InterpreterEngine::new()), fib_trace(SpecializedInterpreterEngine::new()));
+}
+
+#[test]
+fn test_code_generator_observer_eval() {
+    assert_eq!(fib_trace(InterpreterEngine::new()), fib_trace(CodeGeneratorEngine::new(8 * 1024).unwrap()));
+}
+
+// runs with `fuel` refilled until fib is finished, returns result and number of refills
+fn fib_fuel<E: Engine>(engine: E, fuel: u64) -> (u32, usize) {
+    let mut driver = Driver::new(engine);
+    driver.set_fuel(Some(fuel));
+    let mut refills = 0;
+    let result = run_fib(|stack| {
+        if let Outcome::OutOfFuel(mut ctx) = driver.eval(fib_node_32(), stack) {
+            loop {
+                driver.set_fuel(Some(fuel));
+                refills += 1;
+                if driver.resume(&mut ctx, stack) == Stop::Finished { break; }
+            }
+        }
+    }, 20);
+    (result, refills)
+}
+
+#[test]
+fn test_fuel_eval() {
+    let (result, refills) = fib_fuel(InterpreterEngine::new(), 1000);
+    assert_eq!(6765, result);
+    assert!(refills > 0);
+    assert_eq!((result, refills), fib_fuel(SpecializedInterpreterEngine::new(), 1000));
+}
+
+#[test]
+fn test_code_generator_fuel_eval() {
+    let (result, refills) = fib_fuel(CodeGeneratorEngine::new(8 * 1024).unwrap(), 1000);
+    assert_eq!(6765, result);
+    assert!(refills > 0);
+}
+
+fn fib_inlined<E: Engine>(engine: E, limit: Option<usize>) -> u64 {
+    let mut driver = Driver::new(engine);
+    driver.set_profiling(true);
+    driver.set_inlining(limit);
+    assert_eq!(6765, run_fib(|stack| driver.eval(fib_node_32(), stack), 20));
+    driver.profile().values().map(|profile| profile.calls).sum()
+}
+
+#[test]
+fn test_inlining_eval() {
+    assert!(fib_inlined(InterpreterEngine::new(), Some(32)) < fib_inlined(InterpreterEngine::new(), None));
+    assert!(fib_inlined(SpecializedInterpreterEngine::new(), Some(32)) < fib_inlined(SpecializedInterpreterEngine::new(), None));
+}
+
+#[test]
+fn test_code_generator_inlining_eval() {
+    fib_inlined(CodeGeneratorEngine::new(8 * 1024).unwrap(), Some(32));
+}
+
+#[test]
+fn test_c_translator_eval() {
+    assert_eq!(6765, run_fib(|stack| compile_and_eval(fib_node_32(), stack), 20));
+    assert_eq!(6765, run_fib(|stack| compile_and_eval(fib_node_64(), stack), 20));
+    run_simd(compile_and_eval);
+}
+
 #[test]
 fn test_native() {
     println!("fib({}) = {}", 39, fib4(39));
@@ -1,18 +1,25 @@
-use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::ops::DerefMut;
-use std::rc::Rc;
-use crate::core::api::{Command, Condition, Node, NodeKind, Ref};
-use crate::webasm::ast::{Code, CodeSection, ExportSection, ExportTag, FuncIdx, FuncSection, FuncType, Instruction, InstructionIdx, Instructions, LocalIdx, Module, NumType, TypeSection, ValType};
+use std::sync::{Arc, Mutex};
+use crate::core::api::{Command, Condition, Node, NodeKind, PersistentNode, Ref, VecShape};
+use crate::core::interner::{Interned, Interner};
+use crate::core::driver::snapshot::SnapshotWriter;
+use crate::webasm::ast::{BlockType, Code, CodeSection, ExportSection, ExportTag, FuncIdx, FuncSection, FuncType, Instruction, InstructionIdx, Instructions, LocalIdx, Module, NumType, TagIdx, TypeSection, ValType, VecType};
 use crate::webasm::lazy::{Lazy, Readable};
 
 pub struct Source {
     pub uuid: u128,
     pub name: String,
-    pub file: RefCell<File>,
+    pub file: Mutex<File>,
     pub module: Module,
+    // functions which results depend on their params only, calls to them are `PureCall`
+    pure_funcs: HashSet<FuncIdx>,
+    // intermediate nodes are interned so driver hashes and compares them without walking their successors,
+    // table is dropped along with the source. Intermediate nodes of different sources are different even for the same file.
+    intermediates: Interner<NodeKind<WebAsmNode>>,
 }
 
 impl Debug for Source {
@@ -27,40 +34,70 @@ impl Debug for Source {
 pub enum WebAsmNode {
     CallFunc(CallFuncNode),
     Instruction(InstructionNode),
-    Intermediate(Box<NodeKind<WebAsmNode>>),
+    Intermediate(Interned<NodeKind<WebAsmNode>>),
 }
 
 #[derive(Debug, Clone)]
 struct FuncContext {
-    source: Rc<Source>,
+    source: Arc<Source>,
     id: FuncIdx,
 }
 
 #[derive(Debug, Clone)]
 pub struct CallFuncNode {
-    ctx: Rc<FuncContext>,
+    ctx: Arc<FuncContext>,
 }
 
 #[derive(Debug, Clone)]
 pub struct InstructionNode {
-    ctx: Rc<FuncContext>,
+    ctx: Arc<FuncContext>,
     inst: InstructionIdx,
     stack_size: u32,
 }
 
 impl Source {
+    pub fn new(uuid: u128, name: String, file: File, module: Module) -> Source {
+        Source { uuid, name, file: Mutex::new(file), module, pure_funcs: HashSet::new(), intermediates: Interner::new() }
+    }
+
+    pub fn set_pure_funcs(&mut self, funcs: impl IntoIterator<Item = FuncIdx>) {
+        self.pure_funcs = funcs.into_iter().collect();
+    }
+
     fn export_section(&self) -> &ExportSection { self.get_option(&self.module.export_section) }
     fn code_section(&self) -> &CodeSection { self.get_option(&self.module.code_section) }
     fn func_section(&self) -> &FuncSection { self.get_option(&self.module.func_section) }
     fn type_section(&self) -> &TypeSection { self.get_option(&self.module.type_section) }
 
+    pub fn exported_func_idx(&self, name: &str) -> FuncIdx {
+        let export = self.export_section().0.iter().find(|export| export.name == name);
+        assert_eq!(ExportTag::Func, export.unwrap().tag);
+        FuncIdx(export.unwrap().idx)
+    }
+
     fn func_type(&self, id: FuncIdx) -> &FuncType {
         let type_id = self.func_section().0.get(id.0 as usize).unwrap();
         self.type_section().0.get(type_id.0 as usize).unwrap()
     }
 
+    fn tag_type(&self, tag: TagIdx) -> &FuncType {
+        let type_id = self.get_option(&self.module.tag_section).0.get(tag.0 as usize).unwrap();
+        self.type_section().0.get(type_id.0 as usize).unwrap()
+    }
+
+    // Exceptions are thrown as tag params padded to the largest ones in module, followed by tag index,
+    // so handler finds tag at the same place regardless of what was thrown.
+    fn tag_offset(&self) -> u32 {
+        match &self.module.tag_section {
+            None => { 0 }
+            Some(tags) => {
+                self.get(tags).0.iter().map(|type_id| size_of(&self.type_section().0.get(type_id.0 as usize).unwrap().params)).max().unwrap_or(0)
+            }
+        }
+    }
+
     fn get<'a, T: Readable>(&'a self, lazy: &'a Lazy<T>) -> &'a T {
-        lazy.get(self.file.borrow_mut().deref_mut())
+        lazy.get(self.file.lock().unwrap().deref_mut())
     }
 
     fn get_option<'a, T: Readable>(&'a self, lazy_opt: &'a Option<Lazy<T>>) -> &'a T {
@@ -76,20 +113,37 @@ fn size_of(items: &Vec<ValType>) -> u32 {
 }
 
 impl FuncContext {
-    fn by_id(source: Rc<Source>, id: FuncIdx) -> FuncContext {
+    fn by_id(source: Arc<Source>, id: FuncIdx) -> FuncContext {
         FuncContext { source, id }
     }
 
-    fn exported(source: Rc<Source>, name: &str) -> FuncContext {
-        let export = source.export_section().0.iter().find(|export| export.name == name);
-        assert_eq!(ExportTag::Func, export.unwrap().tag);
-        Self::by_id(source.clone(), FuncIdx(export.unwrap().idx))
+    fn exported(source: Arc<Source>, name: &str) -> FuncContext {
+        let id = source.exported_func_idx(name);
+        Self::by_id(source, id)
     }
 
     fn code(&self) -> &Code { self.source.code_section().0.get(self.id.0 as usize).unwrap() }
     fn instructions(&self) -> &Instructions { self.source.get(&self.code().expr) }
     fn block_end(&self, idx: InstructionIdx) -> InstructionIdx { *self.instructions().blocks.get(&idx).unwrap() }
 
+    // instruction which block ends at `idx`
+    fn block_start(&self, idx: InstructionIdx) -> Option<InstructionIdx> {
+        self.instructions().blocks.iter().find(|(_, end)| **end == idx).map(|(start, _)| *start)
+    }
+
+    // `end` of try, `idx` is try or one of its catch clauses
+    fn try_end(&self, idx: InstructionIdx) -> InstructionIdx {
+        let mut end = self.block_end(idx);
+        while *self.instructions().instructions.get(end.0 as usize).unwrap() != Instruction::BlockEnd {
+            end = self.block_end(end);
+        }
+        end
+    }
+
+    fn opens_try(&self, idx: Option<InstructionIdx>) -> bool {
+        idx.is_some_and(|idx| matches!(self.instructions().instructions.get(idx.0 as usize).unwrap(), Instruction::Try { .. }))
+    }
+
     fn func_type(&self) -> &FuncType { self.source.func_type(self.id) }
 }
 
@@ -131,6 +185,7 @@ impl InstructionNode {
     fn val_type_size(val_type: ValType) -> u8 {
         match val_type {
             ValType::Num(num_type) => { Self::num_type_size(num_type) }
+            ValType::Vec(VecType::V128) => { 16 }
             ValType::Ref(_) => { todo!() }
         }
     }
@@ -152,8 +207,10 @@ impl InstructionNode {
         NodeKind::Command { command, next: self.next(stack_size) }
     }
 
+    fn intermediate(&self, kind: NodeKind<WebAsmNode>) -> WebAsmNode { WebAsmNode::Intermediate(self.ctx.source.intermediates.intern(kind)) }
+
     fn command_node(&self, stack_size: u32, command: Command) -> WebAsmNode {
-        WebAsmNode::Intermediate(Box::new(NodeKind::Command { command, next: self.next(stack_size) }))
+        self.intermediate(NodeKind::Command { command, next: self.next(stack_size) })
     }
 
     fn goto(&self, inst: InstructionIdx, stack_size: u32) -> WebAsmNode {
@@ -162,12 +219,12 @@ impl InstructionNode {
         if self.stack_size <= stack_size {
             node
         } else {
-            WebAsmNode::Intermediate(Box::new(
+            self.intermediate(
                 NodeKind::Command {
                     command: Command::PoisonFrom { dst: Ref::Stack(stack_size) },
                     next: node
                 }
-            ))
+            )
         }
     }
 
@@ -196,23 +253,51 @@ impl InstructionNode {
             Instruction::BlockEnd => {
                 if self.last_instruction() {
                     self.ret()
+                } else if self.ctx.opens_try(self.ctx.block_start(self.inst)) {
+                    self.try_body_end()
                 } else {
                     self.command(self.stack_size, Command::Noop)
                 }
             }
+            Instruction::Try { .. } => { self.try_block() }
+            // first clause ends try body, the rest end previous handler
+            Instruction::Catch(_) | Instruction::CatchAll => {
+                if self.ctx.opens_try(self.ctx.block_start(self.inst)) {
+                    self.try_body_end()
+                } else {
+                    NodeKind::Command { command: Command::Noop, next: self.goto(InstructionIdx(self.ctx.try_end(self.inst).0 + 1), self.stack_size) }
+                }
+            }
+            Instruction::Throw(tag) => {
+                let params_size = size_of(&self.ctx.source.tag_type(*tag).params);
+                let tag_offset = self.ctx.source.tag_offset();
+                let payload = self.stack_size - params_size;
+                NodeKind::Command {
+                    command: Command::Set { dst: Ref::Stack(payload + tag_offset), bytes: tag.0.to_le_bytes().to_vec() },
+                    next: self.intermediate(NodeKind::Throw { op: Ref::Stack(payload), size: tag_offset + 4 }),
+                }
+            }
             Instruction::Return => { self.ret() }
             Instruction::Call(id) => {
                 let params_size = size_of(&self.ctx.source.func_type(*id).params);
                 let result_size = size_of(&self.ctx.source.func_type(*id).results);
-                NodeKind::Call {
-                    offset: self.stack_size - params_size,
-                    call: WebAsmNode::CallFunc(CallFuncNode { ctx: Rc::new(FuncContext { source: self.ctx.source.clone(), id: id.clone() }) }),
-                    next: self.next(self.stack_size - params_size + result_size)
+                let offset = self.stack_size - params_size;
+                let call = WebAsmNode::CallFunc(CallFuncNode { ctx: Arc::new(FuncContext { source: self.ctx.source.clone(), id: id.clone() }) });
+                let next = self.next(self.stack_size - params_size + result_size);
+                if self.ctx.source.pure_funcs.contains(id) {
+                    NodeKind::PureCall { offset, call, next, args: params_size, results: result_size }
+                } else {
+                    NodeKind::Call { offset, call, next }
                 }
             }
+            Instruction::ReturnCall(id) => { self.return_call(*id) }
             Instruction::LocalGet(id) => {
                 self.push(self.local_size(*id) as u32, self.local_ref(*id))
             }
+            Instruction::LocalSet(id) => {
+                let size = self.local_size(*id) as u32;
+                self.command(self.stack_size - size, Command::Copy { size, dst: self.local_ref(*id), op: Ref::Stack(self.stack_size - size) })
+            }
             Instruction::I32Const(value) => {
                 self.push_const(value.to_le_bytes().to_vec())
             }
@@ -246,6 +331,29 @@ impl InstructionNode {
                 self.binary_op(*num_type, |size, dst, op1, op2|
                     Command::Sub { size, dst, op1, op2 })
             }
+            Instruction::V128Const(bytes) => { self.push_const(bytes.to_vec()) }
+            Instruction::Shuffle(lanes) => {
+                self.vec_binary_op(|dst, op1, op2| Command::VecShuffle { lanes: *lanes, dst, op1, op2 })
+            }
+            Instruction::Splat(shape) => {
+                let size = Self::lane_value_size(*shape);
+                let dst = Ref::Stack(self.stack_size - size);
+                self.command(self.stack_size - size + 16, Command::VecSplat { shape: *shape, dst, op: dst })
+            }
+            Instruction::ExtractLane { shape, signed, lane } => {
+                let dst = Ref::Stack(self.stack_size - 16);
+                let command = Command::VecExtractLane { shape: *shape, signed: *signed, lane: *lane, dst, op: dst };
+                self.command(self.stack_size - 16 + Self::lane_value_size(*shape), command)
+            }
+            Instruction::ReplaceLane { shape, lane } => {
+                let size = Self::lane_value_size(*shape);
+                let dst = Ref::Stack(self.stack_size - size - 16);
+                let value = Ref::Stack(self.stack_size - size);
+                self.command(self.stack_size - size, Command::VecReplaceLane { shape: *shape, lane: *lane, dst, op: dst, value })
+            }
+            Instruction::VecBinary(op, shape) => {
+                self.vec_binary_op(|dst, op1, op2| Command::VecBinary { op: *op, shape: *shape, dst, op1, op2 })
+            }
         };
 
         // println!("computed: {:?}", kind);
@@ -260,6 +368,14 @@ impl InstructionNode {
         self.command(self.stack_size - size, cmd(size, dst, op1, op2))
     }
 
+    fn vec_binary_op<F: FnOnce(Ref, Ref, Ref) -> Command>(&self, cmd: F) -> NodeKind<WebAsmNode> {
+        let dst = Ref::Stack(self.stack_size - 32);
+        self.command(self.stack_size - 16, cmd(dst, dst, Ref::Stack(self.stack_size - 16)))
+    }
+
+    // lanes are taken from and given as i32 if they're smaller
+    fn lane_value_size(shape: VecShape) -> u32 { shape.lane_size().max(4) }
+
     fn push_const(&self, bytes: Vec<u8>) -> NodeKind<WebAsmNode> {
         let bytes_len = bytes.len() as u32;
         self.command(self.stack_size + bytes_len,
@@ -271,6 +387,114 @@ impl InstructionNode {
                      Command::Copy { size, dst: Ref::Stack(self.stack_size), op: src })
     }
 
+    // Try body runs as a function does: params are copied to its frame, so locals are found at the same refs,
+    // and it leaves its results past them. Its frame starts past thrown payload, so params the body wrote are intact
+    // and copied back whether it ends or throws. Catch clauses run in the current frame.
+    fn try_block(&self) -> NodeKind<WebAsmNode> {
+        let params_size = size_of(&self.ctx.func_type().params);
+        let payload_size = self.ctx.source.tag_offset() + 4;
+        let frame = self.stack_size + payload_size;
+        let results_size = self.results_size(self.inst);
+        let end = self.ctx.try_end(self.inst);
+        let body = WebAsmNode::Instruction(InstructionNode { ctx: self.ctx.clone(), inst: InstructionIdx(self.inst.0 + 1), stack_size: params_size });
+        let results = NodeKind::Command {
+            command: Command::Copy { dst: Ref::Stack(self.stack_size), size: results_size, op: Ref::Stack(frame + params_size) },
+            next: self.goto(InstructionIdx(end.0 + 1), self.stack_size + results_size),
+        };
+        let handler = NodeKind::Command { command: Command::Noop, next: self.handler(self.ctx.block_end(self.inst)) };
+        self.copy_params(frame, 0, NodeKind::Try {
+            offset: self.stack_size,
+            call: self.intermediate(NodeKind::Call { offset: payload_size, call: body, next: self.intermediate(NodeKind::Final) }),
+            next: self.intermediate(self.copy_params(0, frame, results)),
+            catch: self.intermediate(self.copy_params(0, frame, handler)),
+        })
+    }
+
+    // params are copied one by one (copies support only single values) from `src` to `dst` before `kind`
+    fn copy_params(&self, dst: u32, src: u32, mut kind: NodeKind<WebAsmNode>) -> NodeKind<WebAsmNode> {
+        let params = &self.ctx.func_type().params;
+        let mut offset = size_of(params);
+        for param in params.iter().rev() {
+            let size = Self::val_type_size(*param) as u32;
+            offset -= size;
+            kind = NodeKind::Command {
+                command: Command::Copy { dst: Ref::Stack(dst + offset), size, op: Ref::Stack(src + offset) },
+                next: self.intermediate(kind),
+            };
+        }
+        kind
+    }
+
+    // Checks catch clause at `clause` against tag of payload placed at try's stack size, the next clause is checked
+    // if it doesn't match, exception is thrown further if there are no more clauses.
+    fn handler(&self, clause: InstructionIdx) -> WebAsmNode {
+        let tag_offset = self.ctx.source.tag_offset();
+        let body = |stack_size: u32| WebAsmNode::Instruction(InstructionNode { ctx: self.ctx.clone(), inst: InstructionIdx(clause.0 + 1), stack_size });
+        match self.ctx.instructions().instructions.get(clause.0 as usize).unwrap() {
+            Instruction::Catch(tag) => {
+                let tag_ref = self.stack_size + tag_offset;
+                self.intermediate(NodeKind::Command {
+                    command: Command::Set { dst: Ref::Stack(tag_ref + 4), bytes: tag.0.to_le_bytes().to_vec() },
+                    next: self.intermediate(NodeKind::Branch {
+                        condition: Condition::Ne { size: 4, op1: Ref::Stack(tag_ref), op2: Ref::Stack(tag_ref + 4) },
+                        if_true: self.handler(self.ctx.block_end(clause)),
+                        if_false: body(self.stack_size + size_of(&self.ctx.source.tag_type(*tag).params)),
+                    }),
+                })
+            }
+            Instruction::CatchAll => { body(self.stack_size) }
+            _ => {
+                self.intermediate(NodeKind::Throw { op: Ref::Stack(self.stack_size), size: tag_offset + 4 })
+            }
+        }
+    }
+
+    // results of try body are moved past params in its frame
+    fn try_body_end(&self) -> NodeKind<WebAsmNode> {
+        let results_size = self.results_size(self.ctx.block_start(self.inst).unwrap());
+        if results_size == 0 { return NodeKind::Final; }
+        let params_size = size_of(&self.ctx.func_type().params);
+        NodeKind::Command {
+            command: Command::Copy { dst: Ref::Stack(params_size), size: results_size, op: Ref::Stack(self.stack_size - results_size) },
+            next: self.intermediate(NodeKind::Final),
+        }
+    }
+
+    fn results_size(&self, block: InstructionIdx) -> u32 {
+        match self.ctx.instructions().instructions.get(block.0 as usize).unwrap() {
+            Instruction::Try { bt: BlockType::Value(val_type) } => { Self::val_type_size(*val_type) as u32 }
+            _ => { 0 }
+        }
+    }
+
+    // arguments are moved to the frame start one by one (copies support only single values),
+    // moving in ascending order is safe as destination is never after source
+    fn return_call(&self, id: FuncIdx) -> NodeKind<WebAsmNode> {
+        let params = &self.ctx.source.func_type(id).params;
+        let params_size = size_of(params);
+        let mut kind = NodeKind::Command {
+            command: Command::PoisonFrom { dst: Ref::Stack(params_size) },
+            next: self.intermediate(NodeKind::TailCall {
+                offset: 0,
+                call: WebAsmNode::CallFunc(CallFuncNode { ctx: Arc::new(FuncContext { source: self.ctx.source.clone(), id }) }),
+            }),
+        };
+        let mut offset = params_size;
+        for param in params.iter().rev() {
+            let size = Self::val_type_size(*param) as u32;
+            offset -= size;
+            kind = NodeKind::Command {
+                command: Command::Copy {
+                    dst: Ref::Stack(offset),
+                    size,
+                    op: Ref::Stack(self.stack_size - params_size + offset),
+                },
+                next: self.intermediate(kind),
+            };
+        }
+        kind
+    }
+
     fn ret(&self) -> NodeKind<WebAsmNode> {
         let ret_size = size_of(&self.ctx.func_type().results);
         NodeKind::Command {
@@ -280,18 +504,18 @@ impl InstructionNode {
                 size: ret_size,
                 op: Ref::Stack(self.stack_size - ret_size),
             },
-            next: WebAsmNode::Intermediate(Box::new(NodeKind::Command {
+            next: self.intermediate(NodeKind::Command {
                 // clean stack
                 command: Command::PoisonFrom { dst: Ref::Stack(ret_size) },
-                next: WebAsmNode::Intermediate(Box::new(NodeKind::Final)),
-            })),
+                next: self.intermediate(NodeKind::Final),
+            }),
         }
     }
 }
 
 impl WebAsmNode {
-    pub fn exported_func(source: Rc<Source>, name: &str) -> WebAsmNode {
-        WebAsmNode::CallFunc(CallFuncNode { ctx: Rc::new(FuncContext::exported(source, name)) })
+    pub fn exported_func(source: Arc<Source>, name: &str) -> WebAsmNode {
+        WebAsmNode::CallFunc(CallFuncNode { ctx: Arc::new(FuncContext::exported(source, name)) })
     }
 }
 
@@ -334,7 +558,32 @@ impl Node for WebAsmNode {
         match self {
             WebAsmNode::CallFunc(ctx) => { ctx.get_kind() }
             WebAsmNode::Instruction(node) => { node.get_kind() }
-            WebAsmNode::Intermediate(kind) => { (*kind.as_ref()).clone() }
+            WebAsmNode::Intermediate(kind) => { (**kind).clone() }
+        }
+    }
+}
+
+impl PersistentNode for WebAsmNode {
+    // mirrors `Hash`/`Eq`: source uuid, function and instruction, intermediate nodes by their kind
+    fn key(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        match self {
+            WebAsmNode::CallFunc(node) => {
+                writer.u8(0);
+                writer.bytes(&node.ctx.source.uuid.to_le_bytes());
+                writer.u32(node.ctx.id.0);
+            }
+            WebAsmNode::Instruction(node) => {
+                writer.u8(1);
+                writer.bytes(&node.ctx.source.uuid.to_le_bytes());
+                writer.u32(node.ctx.id.0);
+                writer.u32(node.inst.0);
+            }
+            WebAsmNode::Intermediate(kind) => {
+                writer.u8(2);
+                writer.kind(kind, |writer, node| writer.bytes(&node.key()));
+            }
         }
+        writer.bytes
     }
 }
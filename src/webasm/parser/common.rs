@@ -5,6 +5,9 @@ pub type Result<T> = core::result::Result<T, Error>;
 #[derive(Debug)]
 pub struct Error(Box<dyn std::error::Error>);
 
+// error for valid module which uses something translation doesn't support
+pub fn unsupported(message: &str) -> Error { Error(Box::from(message)) }
+
 impl<T: std::error::Error + 'static> From<T> for Error {
     fn from(error: T) -> Self {
         Error(Box::from(error))
@@ -7,6 +7,7 @@ pub fn hydrate_module(module: &Module, src: &mut (impl Read + Seek)) {
     hydrate_section(&module.func_section, src);
     hydrate_section(&module.export_section, src);
     hydrate_section(&module.code_section, src);
+    hydrate_section(&module.tag_section, src);
     module.other_sections.iter().for_each(|section| { section.1.get(src); });
 
     if let Some(code) = &module.code_section {
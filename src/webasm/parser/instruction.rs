@@ -1,14 +1,18 @@
 use std::io::{Read, Seek};
-use crate::webasm::ast::{FuncIdx, Instruction, LocalIdx, NumType};
-use crate::webasm::parser::common::{read_i32, read_i64, Result};
+use crate::core::api::{VecOp, VecShape};
+use crate::webasm::ast::{FuncIdx, Instruction, LocalIdx, NumType, TagIdx};
+use crate::webasm::parser::common::{read_i32, read_i64, read_u32, read_u8, Result};
 
 impl Instruction {
     pub fn read_non_blocked(src: &mut (impl Read + Seek), opcode: u8) -> Result<Instruction> {
         Ok(match opcode {
             0x01 => { Instruction::Nop }
             0x0F => { Instruction::Return }
+            0x08 => { Instruction::Throw(TagIdx::read(src)?) }
             0x10 => { Instruction::Call(FuncIdx::read(src)?) }
+            0x12 => { Instruction::ReturnCall(FuncIdx::read(src)?) }
             0x20 => { Instruction::LocalGet(LocalIdx::read(src)?) }
+            0x21 => { Instruction::LocalSet(LocalIdx::read(src)?) }
             0x41 => { Instruction::I32Const(read_i32(src)?) }
             0x42 => { Instruction::I64Const(read_i64(src)?) }
             0x46 => { Instruction::Eq(NumType::I32) }
@@ -17,7 +21,79 @@ impl Instruction {
             0x6B => { Instruction::Sub(NumType::I32) }
             0x7C => { Instruction::Add(NumType::I64) }
             0x7D => { Instruction::Sub(NumType::I64) }
+            0xFD => { Self::read_vector(src)? }
             _ => { panic!("unsupported opcode {:#04x}", opcode) }
         })
     }
-}
\ No newline at end of file
+
+    // opcodes after 0xFD prefix, memory instructions aren't supported
+    fn read_vector(src: &mut (impl Read + Seek)) -> Result<Instruction> {
+        let opcode = read_u32(src)?;
+        Ok(match opcode {
+            0x0C => { Instruction::V128Const(Self::read_bytes(src)?) }
+            0x0D => { Instruction::Shuffle(Self::read_bytes(src)?) }
+            0x0F => { Instruction::Splat(VecShape::I8x16) }
+            0x10 => { Instruction::Splat(VecShape::I16x8) }
+            0x11 => { Instruction::Splat(VecShape::I32x4) }
+            0x12 => { Instruction::Splat(VecShape::I64x2) }
+            0x13 => { Instruction::Splat(VecShape::F32x4) }
+            0x14 => { Instruction::Splat(VecShape::F64x2) }
+            0x15 => { Instruction::ExtractLane { shape: VecShape::I8x16, signed: true, lane: read_u8(src)? } }
+            0x16 => { Instruction::ExtractLane { shape: VecShape::I8x16, signed: false, lane: read_u8(src)? } }
+            0x17 => { Instruction::ReplaceLane { shape: VecShape::I8x16, lane: read_u8(src)? } }
+            0x18 => { Instruction::ExtractLane { shape: VecShape::I16x8, signed: true, lane: read_u8(src)? } }
+            0x19 => { Instruction::ExtractLane { shape: VecShape::I16x8, signed: false, lane: read_u8(src)? } }
+            0x1A => { Instruction::ReplaceLane { shape: VecShape::I16x8, lane: read_u8(src)? } }
+            0x1B => { Instruction::ExtractLane { shape: VecShape::I32x4, signed: false, lane: read_u8(src)? } }
+            0x1C => { Instruction::ReplaceLane { shape: VecShape::I32x4, lane: read_u8(src)? } }
+            0x1D => { Instruction::ExtractLane { shape: VecShape::I64x2, signed: false, lane: read_u8(src)? } }
+            0x1E => { Instruction::ReplaceLane { shape: VecShape::I64x2, lane: read_u8(src)? } }
+            0x1F => { Instruction::ExtractLane { shape: VecShape::F32x4, signed: false, lane: read_u8(src)? } }
+            0x20 => { Instruction::ReplaceLane { shape: VecShape::F32x4, lane: read_u8(src)? } }
+            0x21 => { Instruction::ExtractLane { shape: VecShape::F64x2, signed: false, lane: read_u8(src)? } }
+            0x22 => { Instruction::ReplaceLane { shape: VecShape::F64x2, lane: read_u8(src)? } }
+            // integer comparisons are eq, ne, lt_s, lt_u, gt_s, gt_u, le_s, le_u, ge_s, ge_u
+            0x23..=0x2C => { Instruction::VecBinary(Self::int_comparison(opcode - 0x23), VecShape::I8x16) }
+            0x2D..=0x36 => { Instruction::VecBinary(Self::int_comparison(opcode - 0x2D), VecShape::I16x8) }
+            0x37..=0x40 => { Instruction::VecBinary(Self::int_comparison(opcode - 0x37), VecShape::I32x4) }
+            // float comparisons are eq, ne, lt, gt, le, ge
+            0x41..=0x46 => { Instruction::VecBinary(Self::float_comparison(opcode - 0x41), VecShape::F32x4) }
+            0x47..=0x4C => { Instruction::VecBinary(Self::float_comparison(opcode - 0x47), VecShape::F64x2) }
+            0x6E => { Instruction::VecBinary(VecOp::Add, VecShape::I8x16) }
+            0x71 => { Instruction::VecBinary(VecOp::Sub, VecShape::I8x16) }
+            0x8E => { Instruction::VecBinary(VecOp::Add, VecShape::I16x8) }
+            0x91 => { Instruction::VecBinary(VecOp::Sub, VecShape::I16x8) }
+            0x95 => { Instruction::VecBinary(VecOp::Mul, VecShape::I16x8) }
+            0xAE => { Instruction::VecBinary(VecOp::Add, VecShape::I32x4) }
+            0xB1 => { Instruction::VecBinary(VecOp::Sub, VecShape::I32x4) }
+            0xB5 => { Instruction::VecBinary(VecOp::Mul, VecShape::I32x4) }
+            0xCE => { Instruction::VecBinary(VecOp::Add, VecShape::I64x2) }
+            0xD1 => { Instruction::VecBinary(VecOp::Sub, VecShape::I64x2) }
+            0xD5 => { Instruction::VecBinary(VecOp::Mul, VecShape::I64x2) }
+            // i64x2 has signed comparisons only: eq, ne, lt_s, gt_s, le_s, ge_s
+            0xD6..=0xDB => { Instruction::VecBinary(Self::float_comparison(opcode - 0xD6), VecShape::I64x2) }
+            0xE4 => { Instruction::VecBinary(VecOp::Add, VecShape::F32x4) }
+            0xE5 => { Instruction::VecBinary(VecOp::Sub, VecShape::F32x4) }
+            0xE6 => { Instruction::VecBinary(VecOp::Mul, VecShape::F32x4) }
+            0xF0 => { Instruction::VecBinary(VecOp::Add, VecShape::F64x2) }
+            0xF1 => { Instruction::VecBinary(VecOp::Sub, VecShape::F64x2) }
+            0xF2 => { Instruction::VecBinary(VecOp::Mul, VecShape::F64x2) }
+            _ => { panic!("unsupported vector opcode {:#04x}", opcode) }
+        })
+    }
+
+    fn read_bytes(src: &mut (impl Read + Seek)) -> Result<[u8; 16]> {
+        let mut bytes = [0; 16];
+        src.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn int_comparison(idx: u32) -> VecOp {
+        [VecOp::Eq, VecOp::Ne, VecOp::Lt, VecOp::LtU, VecOp::Gt, VecOp::GtU, VecOp::Le, VecOp::LeU, VecOp::Ge, VecOp::GeU][idx as usize]
+    }
+
+    // also signed comparisons of i64x2, which come in the same order
+    fn float_comparison(idx: u32) -> VecOp {
+        [VecOp::Eq, VecOp::Ne, VecOp::Lt, VecOp::Gt, VecOp::Le, VecOp::Ge][idx as usize]
+    }
+}
@@ -1,10 +1,10 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
-use lazycell::LazyCell;
+use std::sync::OnceLock;
 use crate::webasm::ast::*;
 use crate::webasm::lazy::{Lazy, Readable};
-use crate::webasm::parser::common::{read_string, read_u32, read_u8, read_vector, Result};
+use crate::webasm::parser::common::{read_string, read_u32, read_u8, read_vector, unsupported, Result};
 use crate::webasm::parser::hydrate::hydrate_module;
 
 // TODO: implement/use serde instead?
@@ -25,6 +25,7 @@ impl Module {
             func_section: None,
             export_section: None,
             code_section: None,
+            tag_section: None,
             other_sections: vec![],
         };
         loop {
@@ -40,8 +41,9 @@ impl Module {
                 3 => { Self::set_section(&mut module.func_section, start_offset, finish_offset) }
                 7 => { Self::set_section(&mut module.export_section, start_offset, finish_offset) }
                 10 => { Self::set_section(&mut module.code_section, start_offset, finish_offset) }
+                13 => { Self::set_section(&mut module.tag_section, start_offset, finish_offset) }
                 other => {
-                    let section = Lazy::<UnrecognizedSection> { start_offset, finish_offset, cell: LazyCell::new() };
+                    let section = Lazy::<UnrecognizedSection> { start_offset, finish_offset, cell: OnceLock::new() };
                     module.other_sections.push((other, section));
                 }
             }
@@ -51,7 +53,7 @@ impl Module {
     }
 
     fn set_section<T>(dst: &mut Option<Lazy<T>>, start_offset: u32, finish_offset: u32) {
-        *dst = Some(Lazy { start_offset, finish_offset, cell: LazyCell::new() })
+        *dst = Some(Lazy { start_offset, finish_offset, cell: OnceLock::new() })
     }
 }
 
@@ -83,6 +85,17 @@ impl Readable for CodeSection {
     }
 }
 
+impl Readable for TagSection {
+    type Error = crate::webasm::parser::common::Error;
+    fn read(src: &mut (impl Read + Seek), _: u64) -> Result<Self> {
+        Ok(TagSection(read_vector(src, |src| {
+            // attribute, only exception tags exist
+            assert_eq!(0, read_u8(src)?);
+            TypeIdx::read(src)
+        })?))
+    }
+}
+
 impl Readable for UnrecognizedSection {
     type Error = crate::webasm::parser::common::Error;
     fn read(src: &mut (impl Read + Seek), finish_offset: u64) -> Result<Self> {
@@ -121,7 +134,7 @@ impl Code {
         })?;
         let start_offset = src.stream_position()? as u32;
         src.seek(SeekFrom::Start(finish_offset as u64))?;
-        Ok(Code { locals, expr: Lazy { start_offset, finish_offset, cell: LazyCell::new() } })
+        Ok(Code { locals, expr: Lazy { start_offset, finish_offset, cell: OnceLock::new() } })
     }
 }
 
@@ -170,6 +183,25 @@ impl Instructions {
                     // 2) beginning of new block
                     push_block(Instruction::Else, &mut instructions, &mut current_blocks);
                 }
+                0x06 => {
+                    push_block(Instruction::Try { bt: Self::read_block_type(src)? }, &mut instructions, &mut current_blocks);
+                }
+                // same as `else`, every catch clause ends previous block and begins new one
+                0x07 => {
+                    blocks.insert(current_blocks.pop().unwrap(), InstructionIdx(instructions.len() as u32));
+                    push_block(Instruction::Catch(TagIdx::read(src)?), &mut instructions, &mut current_blocks);
+                }
+                0x19 => {
+                    blocks.insert(current_blocks.pop().unwrap(), InstructionIdx(instructions.len() as u32));
+                    push_block(Instruction::CatchAll, &mut instructions, &mut current_blocks);
+                }
+                // try body runs in a frame of its own, see `InstructionNode::try_block`
+                0x0F | 0x12 if Self::in_try_body(&instructions, &current_blocks) => {
+                    return Err(unsupported("return from try body isn't supported"));
+                }
+                0x0C | 0x0D | 0x0E if Self::in_try_body(&instructions, &current_blocks) => {
+                    return Err(unsupported("branch in try body isn't supported"));
+                }
                 other => {
                     instructions.push(Instruction::read_non_blocked(src, other)?)
                 }
@@ -178,10 +210,15 @@ impl Instructions {
         Ok(Instructions { instructions, blocks })
     }
 
+    // catch clauses replace try in open blocks, so it's there only while its body is read
+    fn in_try_body(instructions: &[Instruction], current_blocks: &[InstructionIdx]) -> bool {
+        current_blocks.iter().any(|block| matches!(instructions[block.0 as usize], Instruction::Try { .. }))
+    }
+
     fn read_block_type(src: &mut (impl Read + Seek)) -> Result<BlockType> {
         Ok(match read_u8(src)? {
             0x40 => { BlockType::Empty }
-            other => { panic!("unsupported block type {}", other) }
+            other => { BlockType::Value(ValType::from_byte(other)) }
         })
     }
 }
@@ -197,16 +234,19 @@ impl FuncType {
 }
 
 impl ValType {
-    fn read(src: &mut (impl Read + Seek)) -> Result<ValType> {
-        Ok(match read_u8(src)? {
+    fn read(src: &mut (impl Read + Seek)) -> Result<ValType> { Ok(ValType::from_byte(read_u8(src)?)) }
+
+    fn from_byte(byte: u8) -> ValType {
+        match byte {
             0x7F => { ValType::Num(NumType::I32) }
             0x7E => { ValType::Num(NumType::I64) }
             0x7D => { ValType::Num(NumType::F32) }
             0x7C => { ValType::Num(NumType::F64) }
+            0x7B => { ValType::Vec(VecType::V128) }
             0x70 => { ValType::Ref(RefType::Func) }
             0x6F => { ValType::Ref(RefType::Func) }
             other => { panic!("unsupported val type {}", other); }
-        })
+        }
     }
 }
 
@@ -222,6 +262,10 @@ impl FuncIdx {
     pub fn read(src: &mut (impl Read + Seek)) -> Result<FuncIdx> { Ok(FuncIdx(read_u32(src)?)) }
 }
 
+impl TagIdx {
+    pub fn read(src: &mut (impl Read + Seek)) -> Result<TagIdx> { Ok(TagIdx(read_u32(src)?)) }
+}
+
 #[test]
 fn test() {
     let mut file = File::open("data/fib.wasm").unwrap();